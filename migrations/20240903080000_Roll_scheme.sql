@@ -0,0 +1 @@
+ALTER TABLE nonces ADD COLUMN roll_scheme TEXT NOT NULL DEFAULT 'v1';
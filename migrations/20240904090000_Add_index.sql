@@ -0,0 +1 @@
+ALTER TABLE zaps ADD COLUMN add_index INTEGER;
@@ -0,0 +1 @@
+ALTER TABLE nonces ADD COLUMN reveal_event_id TEXT;
@@ -0,0 +1,2 @@
+-- Zap requests used to be stored in full. New ones are stored in a compact form.
+ALTER TABLE zaps ADD COLUMN request_event_format TEXT NOT NULL DEFAULT 'full';
@@ -0,0 +1,2 @@
+-- Bet states used to be stored JSON-encoded, e.g. `"PaidWinner"` including the quotes.
+UPDATE zaps SET bet_state = TRIM(bet_state, '"');
@@ -0,0 +1,2 @@
+-- Commitment to the multiplier notes in play during a round. Unknown for older rounds.
+ALTER TABLE nonces ADD COLUMN multipliers_commitment TEXT;
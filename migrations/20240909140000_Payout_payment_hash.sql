@@ -0,0 +1,2 @@
+-- The payment hash of the invoice we paid to zap a winner their payout.
+ALTER TABLE zaps ADD COLUMN payout_payment_hash TEXT;
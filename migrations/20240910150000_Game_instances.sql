@@ -0,0 +1,18 @@
+-- Several games can be hosted at once, each with its own rounds. Existing zaps and rounds belong to
+-- the default game.
+ALTER TABLE zaps ADD COLUMN game TEXT NOT NULL DEFAULT 'main';
+
+CREATE TABLE IF NOT EXISTS active_nonces (
+    game TEXT NOT NULL PRIMARY KEY,
+    nonce_event_id TEXT NOT NULL REFERENCES nonces(event_id)
+);
+INSERT INTO active_nonces (game, nonce_event_id) SELECT 'main', nonce_event_id FROM active_nonce;
+DROP TABLE active_nonce;
+
+CREATE TABLE IF NOT EXISTS latest_expired_nonces (
+    game TEXT NOT NULL PRIMARY KEY,
+    nonce_event_id TEXT NOT NULL REFERENCES nonces(event_id)
+);
+INSERT INTO latest_expired_nonces (game, nonce_event_id)
+    SELECT 'main', nonce_event_id FROM latest_expired_nonce;
+DROP TABLE latest_expired_nonce;
@@ -0,0 +1,4 @@
+-- When a round started and which game it belongs to. The start of older rounds is unknown, and they
+-- belong to the default game.
+ALTER TABLE nonces ADD COLUMN started_at datetime;
+ALTER TABLE nonces ADD COLUMN game TEXT NOT NULL DEFAULT 'main';
@@ -0,0 +1,7 @@
+-- Settlements of zap invoices we did not expect, e.g. for bets which were already settled. Kept for
+-- reconciliation.
+CREATE TABLE IF NOT EXISTS unexpected_settlements (
+    payment_hash TEXT NOT NULL REFERENCES zaps(payment_hash),
+    bet_state TEXT NOT NULL,
+    received_at datetime NOT NULL
+);
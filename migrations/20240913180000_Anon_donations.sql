@@ -0,0 +1,8 @@
+-- Donations without a zap request. They have no roller, so they are kept apart from the zaps.
+CREATE TABLE IF NOT EXISTS anon_donations (
+    payment_hash TEXT NOT NULL PRIMARY KEY,
+    amount_msats INTEGER NOT NULL,
+    game TEXT NOT NULL,
+    created_at datetime NOT NULL,
+    settled_at datetime
+);
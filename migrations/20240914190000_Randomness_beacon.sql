@@ -0,0 +1,4 @@
+-- The randomness beacon round mixed into the nonce of a round, and its randomness once revealed.
+-- Unset for rounds which do not use a beacon.
+ALTER TABLE nonces ADD COLUMN beacon_round INTEGER;
+ALTER TABLE nonces ADD COLUMN beacon_randomness TEXT;
@@ -0,0 +1,20 @@
+-- An append-only record of every settled roll, so that its outcome can be checked long after the
+-- round.
+CREATE TABLE IF NOT EXISTS roll_audits (
+    payment_hash TEXT NOT NULL PRIMARY KEY,
+    nonce_commitment_note_id TEXT NOT NULL,
+    roller_npub TEXT NOT NULL,
+    idx INTEGER NOT NULL,
+    memo_hash TEXT NOT NULL,
+    roll INTEGER NOT NULL,
+    multiplier_note_id TEXT NOT NULL,
+    multiplier TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    threshold INTEGER NOT NULL,
+    amount_msats INTEGER NOT NULL,
+    win BOOLEAN NOT NULL,
+    rolled_at datetime NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS roll_audits_nonce_commitment_note_id
+    ON roll_audits (nonce_commitment_note_id);
@@ -0,0 +1,5 @@
+-- Rounds look up their bets, payouts look up bets by state and social updates look up settled bets
+-- by time.
+CREATE INDEX IF NOT EXISTS zaps_nonce_commitment_note_id ON zaps (nonce_commitment_note_id);
+CREATE INDEX IF NOT EXISTS zaps_bet_state_bet_timestamp ON zaps (bet_state, bet_timestamp);
+CREATE INDEX IF NOT EXISTS zaps_bet_timestamp ON zaps (bet_timestamp);
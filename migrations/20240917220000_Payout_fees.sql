@@ -0,0 +1,2 @@
+-- The routing fee we paid on the payout of a winner, in msat.
+ALTER TABLE zaps ADD COLUMN fee_msat INTEGER;
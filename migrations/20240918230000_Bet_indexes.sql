@@ -0,0 +1,12 @@
+-- The index of each roller's next bet in a round, handed out atomically so that no two bets share
+-- an index, and with it a roll.
+CREATE TABLE IF NOT EXISTS bet_indexes (
+    nonce_commitment_note_id TEXT NOT NULL,
+    roller TEXT NOT NULL,
+    next_idx INTEGER NOT NULL,
+    PRIMARY KEY (nonce_commitment_note_id, roller)
+);
+
+INSERT INTO bet_indexes (nonce_commitment_note_id, roller, next_idx)
+    SELECT nonce_commitment_note_id, roller, MAX(idx) + 1 FROM zaps
+    GROUP BY nonce_commitment_note_id, roller;
@@ -0,0 +1,6 @@
+-- The settle index of the last settled invoice we handled, so that the invoice subscription resumes
+-- from it after a restart.
+CREATE TABLE IF NOT EXISTS invoice_settle_index (
+    id INTEGER NOT NULL PRIMARY KEY CHECK (id = 0),
+    settle_index INTEGER NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Relays which rejected our events, and until when we do not publish to them.
+CREATE TABLE IF NOT EXISTS relay_blacklist (
+    relay TEXT NOT NULL PRIMARY KEY,
+    blacklisted_until datetime NOT NULL
+);
@@ -0,0 +1,2 @@
+-- When the nonce of a round was last revealed. Unknown for rounds revealed before we stored it.
+ALTER TABLE nonces ADD COLUMN revealed_at datetime;
@@ -0,0 +1,2 @@
+-- When a round stops taking bets. Unknown for rounds started before we recorded it.
+ALTER TABLE nonces ADD COLUMN expires_at datetime;
@@ -0,0 +1,3 @@
+-- Donations used to be stored as `ZapInvoiceRequested`, on a round with an all-zeros event ID.
+UPDATE zaps SET bet_state = 'Donation', nonce_commitment_note_id = '', multiplier = NULL
+    WHERE bet_state = 'ZapInvoiceRequested';
@@ -0,0 +1,4 @@
+-- Lets rollers look up their bets by the ID of their zap request. Compact zap requests do not store
+-- their ID, so the zaps stored before are backfilled at startup.
+ALTER TABLE zaps ADD COLUMN request_event_id TEXT;
+CREATE INDEX IF NOT EXISTS zaps_request_event_id ON zaps (request_event_id);
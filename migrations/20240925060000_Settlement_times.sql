@@ -0,0 +1,3 @@
+-- When the invoice of a zap was settled, and when the payout of a winning bet went through.
+ALTER TABLE zaps ADD COLUMN settled_at datetime;
+ALTER TABLE zaps ADD COLUMN paid_out_at datetime;
@@ -0,0 +1,13 @@
+-- The won and lost bets of rounds deleted after the retention period, summed up per roller,
+-- multiplier and day, so that the leaderboard still counts them.
+CREATE TABLE IF NOT EXISTS archived_roller_totals (
+    game TEXT NOT NULL,
+    roller TEXT NOT NULL,
+    multiplier TEXT NOT NULL,
+    bet_state TEXT NOT NULL,
+    -- The day the bets were placed, as `YYYY-MM-DD` in UTC.
+    day TEXT NOT NULL,
+    bets INTEGER NOT NULL,
+    amount_msats INTEGER NOT NULL,
+    PRIMARY KEY (game, roller, multiplier, bet_state, day)
+);
@@ -0,0 +1,2 @@
+-- The note the main account replied to the nonce commitment note with once the round was settled.
+ALTER TABLE nonces ADD COLUMN results_event_id TEXT;
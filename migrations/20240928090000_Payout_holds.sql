@@ -0,0 +1,4 @@
+-- What the payout of a bet would be if it won, held against --max-committed-payout-sat until the
+-- bet is settled, or until `payout_held_until` if it is not paid by then.
+ALTER TABLE zaps ADD COLUMN committed_payout_sat INTEGER;
+ALTER TABLE zaps ADD COLUMN payout_held_until TIMESTAMP;
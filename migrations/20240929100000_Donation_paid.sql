@@ -0,0 +1,2 @@
+-- Donations whose invoice was settled are no longer left as `Donation`.
+UPDATE zaps SET bet_state = 'DonationPaid' WHERE bet_state = 'Donation' AND settled_at IS NOT NULL;
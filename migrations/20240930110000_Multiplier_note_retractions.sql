@@ -0,0 +1,7 @@
+-- Multiplier notes which no longer take bets, so that they stay retracted after a restart.
+CREATE TABLE IF NOT EXISTS multiplier_note_retractions (
+    game TEXT NOT NULL,
+    note_id TEXT NOT NULL,
+    retracted_at datetime NOT NULL,
+    PRIMARY KEY (game, note_id)
+);
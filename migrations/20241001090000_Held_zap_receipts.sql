@@ -0,0 +1,7 @@
+-- Zap receipts of bets which are only published once the bet was rolled, so that they can be
+-- tagged with the roll.
+CREATE TABLE IF NOT EXISTS held_zap_receipts (
+    payment_hash TEXT NOT NULL PRIMARY KEY,
+    preimage BLOB NOT NULL,
+    held_at datetime NOT NULL
+);
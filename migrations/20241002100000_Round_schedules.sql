@@ -0,0 +1,25 @@
+-- Games can run rounds of several schedules side by side, each with its own active and latest
+-- expired round. Existing rounds belong to the default schedule.
+ALTER TABLE nonces ADD COLUMN schedule TEXT NOT NULL DEFAULT 'default';
+
+CREATE TABLE IF NOT EXISTS active_nonces_by_schedule (
+    game TEXT NOT NULL,
+    schedule TEXT NOT NULL,
+    nonce_event_id TEXT NOT NULL REFERENCES nonces(event_id),
+    PRIMARY KEY (game, schedule)
+);
+INSERT INTO active_nonces_by_schedule (game, schedule, nonce_event_id)
+    SELECT game, 'default', nonce_event_id FROM active_nonces;
+DROP TABLE active_nonces;
+ALTER TABLE active_nonces_by_schedule RENAME TO active_nonces;
+
+CREATE TABLE IF NOT EXISTS latest_expired_nonces_by_schedule (
+    game TEXT NOT NULL,
+    schedule TEXT NOT NULL,
+    nonce_event_id TEXT NOT NULL REFERENCES nonces(event_id),
+    PRIMARY KEY (game, schedule)
+);
+INSERT INTO latest_expired_nonces_by_schedule (game, schedule, nonce_event_id)
+    SELECT game, 'default', nonce_event_id FROM latest_expired_nonces;
+DROP TABLE latest_expired_nonces;
+ALTER TABLE latest_expired_nonces_by_schedule RENAME TO latest_expired_nonces;
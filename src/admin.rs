@@ -0,0 +1,476 @@
+//! Routes for operators, authenticated with the `--admin-token`.
+
+use crate::db::get_bet_state_counts;
+use crate::db::get_committed_bets;
+use crate::db::get_owed_payouts;
+use crate::db::get_zaps_by_event_id;
+use crate::db::release_queued_payout;
+use crate::db::resolve_rolling_winner;
+use crate::db::BetState;
+use crate::db::CommittedBet;
+use crate::game::DEFAULT_SCHEDULE;
+use crate::nonce::get_active_nonces;
+use crate::nonce::get_last_reveal_time;
+use crate::nonce::get_round;
+use crate::nonce::reveal_nonce;
+use crate::payouts::calculate_price_money;
+use crate::payouts::PayoutRounding;
+use crate::State;
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use axum::extract::Path;
+use axum::extract::Query;
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::Extension;
+use axum::Json;
+use nostr_sdk::EventId;
+use serde_json::json;
+use serde_json::Value;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use strum::IntoEnumIterator;
+use time::OffsetDateTime;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RevealResponse {
+    /// The paid bets of the round, which are settled now.
+    pub paid_bets: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ReleaseResponse {
+    pub payment_hash: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ResolveQueryParams {
+    /// Whether the winner was paid out, e.g. as the payments of LND show.
+    pub paid: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ResolveResponse {
+    pub payment_hash: String,
+    pub bet_state: BetState,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct StatusResponse {
+    pub games: Vec<GameStatus>,
+    /// How many bets of all games are in each state.
+    pub bet_states: BTreeMap<String, u64>,
+    /// What we owe winners whose payout failed or is queued.
+    pub owed_payout_sat: u64,
+    /// What we would owe if all paid bets which are not settled yet won.
+    pub committed_payout_sat: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct GameStatus {
+    pub game: String,
+    /// The nonce commitment note ID of the running round of the default schedule, if any.
+    pub active_nonce_commitment_note_id: Option<EventId>,
+    /// The nonce commitment note IDs of the running rounds of every schedule.
+    pub active_rounds: BTreeMap<String, EventId>,
+    /// Unknown if no round was revealed since we started storing when.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_revealed_at: Option<OffsetDateTime>,
+}
+
+/// What is going on in all games, so that operators do not have to query the database.
+pub async fn get_admin_status(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<StatusResponse>, (StatusCode, Json<Value>)> {
+    authorize(&headers, state.admin_token.as_deref())?;
+
+    status(&state)
+        .await
+        .map(Json)
+        .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+async fn status(state: &State) -> anyhow::Result<StatusResponse> {
+    let mut games = Vec::new();
+    for game in state.games.iter() {
+        let active_rounds = get_active_nonces(&state.db, &game.name)
+            .await?
+            .into_iter()
+            .map(|(schedule, round)| (schedule, round.event_id))
+            .collect::<BTreeMap<_, _>>();
+        let last_revealed_at = get_last_reveal_time(&state.db, &game.name).await?;
+
+        games.push(GameStatus {
+            game: game.name.clone(),
+            active_nonce_commitment_note_id: active_rounds.get(DEFAULT_SCHEDULE).copied(),
+            active_rounds,
+            last_revealed_at,
+        });
+    }
+
+    Ok(StatusResponse {
+        games,
+        bet_states: bet_states(get_bet_state_counts(&state.db).await?),
+        owed_payout_sat: payout_sat(&get_owed_payouts(&state.db).await?, state.payout_rounding),
+        committed_payout_sat: payout_sat(
+            &get_committed_bets(&state.db).await?,
+            state.payout_rounding,
+        ),
+    })
+}
+
+/// The `counts` of bets by state, including the states without bets.
+fn bet_states(counts: Vec<(BetState, u64)>) -> BTreeMap<String, u64> {
+    let mut bet_states = BetState::iter()
+        .map(|bet_state| (bet_state.to_string(), 0))
+        .collect::<BTreeMap<_, _>>();
+    for (bet_state, count) in counts {
+        bet_states.insert(bet_state.to_string(), count);
+    }
+
+    bet_states
+}
+
+fn payout_sat(bets: &[CommittedBet], payout_rounding: PayoutRounding) -> u64 {
+    bets.iter()
+        .map(|bet| {
+            calculate_price_money(
+                bet.zap_amount_msat,
+                bet.multiplier.get_multiplier(),
+                payout_rounding,
+            )
+        })
+        .sum()
+}
+
+/// Reveal the nonce of a round, e.g. after revealing it automatically failed, and settle its bets.
+///
+/// Rounds already revealed are revealed again, which settles the bets left unsettled. The round is
+/// identified by its nonce commitment note ID, in hex or bech32 format.
+pub async fn post_admin_reveal(
+    Path(nonce_commitment_note_id): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<RevealResponse>, (StatusCode, Json<Value>)> {
+    authorize(&headers, state.admin_token.as_deref())?;
+
+    let commitment_event_id = EventId::parse(&nonce_commitment_note_id)
+        .context("Invalid nonce commitment note ID")
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, e))?;
+
+    let (game, round) = get_round(&state.db, commitment_event_id)
+        .await
+        .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .ok_or_else(|| error_response(StatusCode::NOT_FOUND, anyhow!("Unknown round")))?;
+
+    let (Some(game), Some(revealed)) = (state.games.get(&game), state.revealed.get(&game)) else {
+        return Err(error_response(
+            StatusCode::NOT_FOUND,
+            anyhow!("Round of game {game}, which is not hosted anymore"),
+        ));
+    };
+
+    check_revealable(&state.db, &game.name, commitment_event_id)
+        .await
+        .map_err(|e| error_response(StatusCode::CONFLICT, e))?;
+
+    tracing::warn!(%commitment_event_id, game = game.name, "Revealing nonce on request");
+
+    reveal_nonce(
+        &game.client,
+        &game.nonce_keys,
+        &state.db,
+        state.beacon.as_ref(),
+        revealed,
+        round.nonce,
+        round.beacon_round,
+        commitment_event_id,
+    )
+    .await
+    .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    let paid_bets = get_zaps_by_event_id(&state.db, commitment_event_id)
+        .await
+        .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .into_iter()
+        .filter(|zap| zap.bet_state == BetState::ZapPaid)
+        .count();
+
+    Ok(Json(RevealResponse { paid_bets }))
+}
+
+/// Release the queued payout of the bet with the `payment_hash` after reviewing the win, or the
+/// payout a dry run did not make, and pay it out right away.
+pub async fn post_admin_release(
+    Path(payment_hash): Path<String>,
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<ReleaseResponse>, (StatusCode, Json<Value>)> {
+    authorize(&headers, state.admin_token.as_deref())?;
+
+    let released = release_queued_payout(&state.db, &payment_hash)
+        .await
+        .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    if !released {
+        return Err(error_response(
+            StatusCode::NOT_FOUND,
+            anyhow!("No queued payout to release"),
+        ));
+    }
+
+    tracing::info!(payment_hash, "Released queued payout on request");
+
+    // The zap retry tasks pay it out now instead of with their next scheduled retry.
+    state.payouts_released.notify_waiters();
+
+    Ok(Json(ReleaseResponse { payment_hash }))
+}
+
+/// Resolve a winning bet left rolling, e.g. by a crash while paying it out, once the operator has
+/// checked whether it was paid.
+///
+/// Winners who were not paid are zapped right away.
+pub async fn post_admin_resolve(
+    Path(payment_hash): Path<String>,
+    Query(params): Query<ResolveQueryParams>,
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<ResolveResponse>, (StatusCode, Json<Value>)> {
+    authorize(&headers, state.admin_token.as_deref())?;
+
+    let bet_state = resolve_rolling_winner(
+        &state.db,
+        &payment_hash,
+        params.paid,
+        OffsetDateTime::now_utc(),
+    )
+    .await
+    .map_err(|e| error_response(StatusCode::INTERNAL_SERVER_ERROR, e))?
+    .ok_or_else(|| {
+        error_response(
+            StatusCode::NOT_FOUND,
+            anyhow!("No winner left rolling to resolve"),
+        )
+    })?;
+
+    tracing::info!(payment_hash, %bet_state, "Resolved winner left rolling on request");
+
+    if bet_state == BetState::ZapFailed {
+        state.payouts_released.notify_waiters();
+    }
+
+    Ok(Json(ResolveResponse {
+        payment_hash,
+        bet_state,
+    }))
+}
+
+/// Refuse to reveal the nonce of a round which can still take bets, since rollers would know the
+/// outcome of their bet before placing it.
+async fn check_revealable(
+    db: &SqlitePool,
+    game: &str,
+    commitment_event_id: EventId,
+) -> anyhow::Result<()> {
+    let active = get_active_nonces(db, game).await?;
+    if active
+        .iter()
+        .any(|(_, round)| round.event_id == commitment_event_id)
+    {
+        bail!("Refusing to reveal the nonce of the running round");
+    }
+
+    let payable = get_zaps_by_event_id(db, commitment_event_id)
+        .await?
+        .into_iter()
+        .filter(|zap| {
+            zap.bet_state == BetState::GameZapInvoiceRequested && !zap.invoice.is_expired()
+        })
+        .count();
+    if payable > 0 {
+        bail!("Refusing to reveal the nonce while {payable} bet invoices can still be paid");
+    }
+
+    Ok(())
+}
+
+/// Check that the request carries the `admin_token` as a bearer token.
+///
+/// Without an `admin_token`, the admin routes are disabled.
+pub fn authorize(
+    headers: &HeaderMap,
+    admin_token: Option<&str>,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    let Some(admin_token) = admin_token else {
+        return Err(error_response(
+            StatusCode::NOT_FOUND,
+            anyhow!("Admin routes are disabled"),
+        ));
+    };
+
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if tokens_match(token, admin_token) => Ok(()),
+        _ => Err(error_response(
+            StatusCode::UNAUTHORIZED,
+            anyhow!("Invalid admin token"),
+        )),
+    }
+}
+
+/// Compare in constant time, so that the token cannot be guessed from how long comparing takes.
+fn tokens_match(token: &str, admin_token: &str) -> bool {
+    token.len() == admin_token.len()
+        && token
+            .bytes()
+            .zip(admin_token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub fn error_response(status: StatusCode, err: anyhow::Error) -> (StatusCode, Json<Value>) {
+    (
+        status,
+        Json(json!({
+            "status": "ERROR",
+            "reason": format!("{err:#}"),
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use crate::db::test_round;
+    use crate::db::test_zap;
+    use crate::db::upsert_zap;
+    use crate::db::Zap;
+    use crate::game::DEFAULT_GAME;
+    use crate::multiplier::test_multiplier_note;
+    use crate::multiplier::Multipliers;
+    use crate::nonce::set_active_nonce;
+    use crate::nonce::unset_active_nonce;
+
+    #[test]
+    fn admin_routes_require_the_admin_token() {
+        let mut headers = HeaderMap::new();
+
+        assert_eq!(
+            authorize(&headers, None).unwrap_err().0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            authorize(&headers, Some("secret")).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        headers.insert(AUTHORIZATION, "Bearer secreT".parse().unwrap());
+        assert_eq!(
+            authorize(&headers, Some("secret")).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        headers.insert(AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(authorize(&headers, Some("secret")).is_ok());
+        assert_eq!(
+            authorize(&headers, None).unwrap_err().0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn rounds_taking_bets_are_not_revealed() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let commitment_event_id = EventId::all_zeros();
+        set_active_nonce(
+            &db,
+            DEFAULT_GAME,
+            DEFAULT_SCHEDULE,
+            test_round(commitment_event_id),
+        )
+        .await
+        .unwrap();
+
+        assert!(check_revealable(&db, DEFAULT_GAME, commitment_event_id)
+            .await
+            .is_err());
+
+        // Neither is the running round of any other schedule of the game.
+        let fast_event_id = EventId::from_slice(&[1; 32]).unwrap();
+        set_active_nonce(&db, DEFAULT_GAME, "fast", test_round(fast_event_id))
+            .await
+            .unwrap();
+        assert!(check_revealable(&db, DEFAULT_GAME, fast_event_id)
+            .await
+            .is_err());
+
+        // The round expired, but a bet invoice handed out on it can still be paid.
+        unset_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE)
+            .await
+            .unwrap();
+        let zap = test_zap(
+            "note1multiplier",
+            commitment_event_id,
+            BetState::GameZapInvoiceRequested,
+        );
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        upsert_zap(&db, payment_hash.clone(), zap.clone(), &multipliers)
+            .await
+            .unwrap();
+
+        assert!(check_revealable(&db, DEFAULT_GAME, commitment_event_id)
+            .await
+            .is_err());
+
+        let zap = Zap {
+            bet_state: BetState::ZapPaid,
+            ..zap
+        };
+        upsert_zap(&db, payment_hash, zap, &multipliers)
+            .await
+            .unwrap();
+
+        assert!(check_revealable(&db, DEFAULT_GAME, commitment_event_id)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn status_counts_bets_and_owed_payouts() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        for bet_state in [
+            BetState::ZapPaid,
+            BetState::ZapFailed,
+            BetState::QueuedWinner,
+        ] {
+            let zap = test_zap("note1multiplier", EventId::all_zeros(), bet_state);
+            let payment_hash = zap.invoice.payment_hash().to_string();
+            upsert_zap(&db, payment_hash, zap, &multipliers)
+                .await
+                .unwrap();
+        }
+
+        let bet_states = bet_states(get_bet_state_counts(&db).await.unwrap());
+        assert_eq!(bet_states.len(), BetState::iter().count());
+        assert_eq!(bet_states["ZapPaid"], 1);
+        assert_eq!(bet_states["ZapFailed"], 1);
+        assert_eq!(bet_states["QueuedWinner"], 1);
+        assert_eq!(bet_states["Loser"], 0);
+
+        // Test bets are 1000 sats on the 2x multiplier.
+        let owed = get_owed_payouts(&db).await.unwrap();
+        assert_eq!(payout_sat(&owed, PayoutRounding::Floor), 2 * 2_000);
+    }
+}
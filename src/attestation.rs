@@ -0,0 +1,314 @@
+//! A periodic, signed summary of the rounds of a game, so that players can keep an eye on them at
+//! a glance.
+
+use crate::db::get_zaps_by_event_id;
+use crate::db::BetState;
+use crate::db::RoundRecord;
+use crate::db::Zap;
+use crate::multiplier::Multipliers;
+use crate::nonce::get_rounds_started_between;
+use crate::payouts::calculate_price_money;
+use crate::payouts::PayoutRounding;
+use crate::roll::RollScheme;
+use anyhow::Result;
+use nostr::EventBuilder;
+use nostr::EventId;
+use nostr::ToBech32;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use strum_macros::Display;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::select;
+use tokio::sync::broadcast;
+
+/// What a fairness attestation can tell players about the rounds it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Display, clap::ValueEnum)]
+#[strum(serialize_all = "kebab-case")]
+pub enum AttestationField {
+    /// The number of rounds.
+    Rounds,
+    /// The nonce commitment note of every round.
+    Commitments,
+    /// How many of the rounds have been revealed.
+    Reveals,
+    /// The total amount of the bets placed.
+    Wagered,
+    /// The total amount paid out to winners.
+    Paid,
+    /// The roll schemes in use.
+    RollSchemes,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct AttestationStats {
+    pub commitments: Vec<EventId>,
+    pub reveals: usize,
+    pub wagered_sat: u64,
+    pub paid_sat: u64,
+    pub roll_schemes: Vec<RollScheme>,
+}
+
+/// Every `interval`, publish an attestation for the rounds of the `game` started since the last
+/// one, signed by its nonce account.
+#[allow(clippy::too_many_arguments)]
+pub async fn publish_fairness_attestations(
+    client: nostr_sdk::Client,
+    keys: nostr::Keys,
+    db: SqlitePool,
+    game: String,
+    multipliers: Multipliers,
+    payout_rounding: PayoutRounding,
+    interval: Duration,
+    fields: Vec<AttestationField>,
+    mut ctrl_c: broadcast::Receiver<()>,
+) {
+    loop {
+        select! {
+            _ = tokio::time::sleep(interval) => (),
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down fairness attestations...");
+                return;
+            },
+        }
+
+        let end_time = OffsetDateTime::now_utc();
+        // An interval reaching back further than we can represent covers every round.
+        let start_time = time::Duration::try_from(interval)
+            .ok()
+            .and_then(|interval| end_time.checked_sub(interval))
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+        match publish_attestation(
+            &client,
+            &keys,
+            &db,
+            &game,
+            &multipliers,
+            payout_rounding,
+            &fields,
+            start_time,
+            end_time,
+        )
+        .await
+        {
+            Ok(event_id) => tracing::debug!(game, %event_id, "Published fairness attestation"),
+            Err(e) => tracing::error!(game, "Could not publish fairness attestation: {e:#}"),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn publish_attestation(
+    client: &nostr_sdk::Client,
+    keys: &nostr::Keys,
+    db: &SqlitePool,
+    game: &str,
+    multipliers: &Multipliers,
+    payout_rounding: PayoutRounding,
+    fields: &[AttestationField],
+    start_time: OffsetDateTime,
+    end_time: OffsetDateTime,
+) -> Result<EventId> {
+    let mut rounds = Vec::new();
+    for round in get_rounds_started_between(db, game, start_time, end_time).await? {
+        let zaps = get_zaps_by_event_id(db, round.event_id).await?;
+        rounds.push((round, zaps));
+    }
+
+    let stats = attestation_stats(&rounds, multipliers, payout_rounding);
+    let content = format_attestation(&stats, fields, start_time, end_time)?;
+
+    let event = EventBuilder::text_note(content, []).to_event(keys)?;
+    let event_id = client.send_event(event).await?;
+
+    Ok(event_id)
+}
+
+fn attestation_stats(
+    rounds: &[(RoundRecord, Vec<Zap>)],
+    multipliers: &Multipliers,
+    payout_rounding: PayoutRounding,
+) -> AttestationStats {
+    let mut stats = AttestationStats::default();
+
+    for (round, zaps) in rounds {
+        stats.commitments.push(round.event_id);
+
+        if round.revealed {
+            stats.reveals += 1;
+        }
+
+        if !stats.roll_schemes.contains(&round.roll_scheme) {
+            stats.roll_schemes.push(round.roll_scheme);
+        }
+
+        for zap in zaps {
+            let amount_msat = zap.invoice.amount_milli_satoshis().unwrap_or_default();
+
+            match zap.bet_state {
+                BetState::ZapPaid
+                | BetState::Rolling
+                | BetState::ZapFailed
+                | BetState::ZapAbandoned
+                | BetState::QueuedWinner
+                | BetState::DryRunWinner
+                | BetState::Loser => {
+                    stats.wagered_sat += amount_msat / 1_000;
+                }
+                BetState::PaidWinner => {
+                    stats.wagered_sat += amount_msat / 1_000;
+
+                    if let Some(note) = multipliers.get_multiplier_note(&zap.multiplier_note_id) {
+                        stats.paid_sat += calculate_price_money(
+                            amount_msat,
+                            note.multiplier.get_multiplier(),
+                            payout_rounding,
+                        );
+                    }
+                }
+                // Bets which were never paid for, and donations.
+                BetState::GameZapInvoiceRequested | BetState::Donation | BetState::DonationPaid => {
+                }
+            }
+        }
+    }
+
+    stats
+}
+
+fn format_attestation(
+    stats: &AttestationStats,
+    fields: &[AttestationField],
+    start_time: OffsetDateTime,
+    end_time: OffsetDateTime,
+) -> Result<String> {
+    let mut lines = vec![format!(
+        "NostrDice fairness attestation for the rounds started between {} and {}.",
+        start_time.format(&Rfc3339)?,
+        end_time.format(&Rfc3339)?,
+    )];
+
+    for field in fields {
+        let line = match field {
+            AttestationField::Rounds => format!("Rounds: {}", stats.commitments.len()),
+            AttestationField::Commitments => {
+                let commitments = stats
+                    .commitments
+                    .iter()
+                    .map(|event_id| {
+                        format!("nostr:{}", event_id.to_bech32().expect("valid note ID"))
+                    })
+                    .collect::<Vec<_>>();
+
+                format!("Commitments: {}", commitments.join(" "))
+            }
+            AttestationField::Reveals => format!(
+                "Revealed: {} of {} rounds",
+                stats.reveals,
+                stats.commitments.len()
+            ),
+            AttestationField::Wagered => format!("Wagered: {} sats", stats.wagered_sat),
+            AttestationField::Paid => format!("Paid out: {} sats", stats.paid_sat),
+            AttestationField::RollSchemes => {
+                let roll_schemes = stats
+                    .roll_schemes
+                    .iter()
+                    .map(|roll_scheme| roll_scheme.to_string())
+                    .collect::<Vec<_>>();
+
+                format!("Roll schemes: {}", roll_schemes.join(", "))
+            }
+        };
+
+        lines.push(line);
+    }
+
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_zap;
+    use crate::multiplier::test_multiplier_note;
+    use time::macros::datetime;
+
+    fn round(i: u8, roll_scheme: RollScheme, revealed: bool) -> RoundRecord {
+        RoundRecord {
+            event_id: EventId::from_slice(&[i; 32]).unwrap(),
+            roll_scheme,
+            revealed,
+        }
+    }
+
+    #[test]
+    fn summarises_rounds_and_bets() {
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let first = round(0, RollScheme::V1, true);
+        let second = round(1, RollScheme::V2, false);
+        let rounds = [
+            (
+                first.clone(),
+                vec![
+                    test_zap("note1multiplier", first.event_id, BetState::PaidWinner),
+                    test_zap("note1multiplier", first.event_id, BetState::Loser),
+                    test_zap(
+                        "note1multiplier",
+                        first.event_id,
+                        BetState::GameZapInvoiceRequested,
+                    ),
+                ],
+            ),
+            (
+                second.clone(),
+                vec![test_zap(
+                    "note1multiplier",
+                    second.event_id,
+                    BetState::ZapPaid,
+                )],
+            ),
+        ];
+
+        let stats = attestation_stats(&rounds, &multipliers, PayoutRounding::Floor);
+
+        assert_eq!(
+            stats,
+            AttestationStats {
+                commitments: vec![first.event_id, second.event_id],
+                reveals: 1,
+                wagered_sat: 3_000,
+                paid_sat: 2_000,
+                roll_schemes: vec![RollScheme::V1, RollScheme::V2],
+            }
+        );
+    }
+
+    #[test]
+    fn attestation_only_covers_the_configured_fields() {
+        let stats = AttestationStats {
+            commitments: vec![EventId::all_zeros()],
+            reveals: 1,
+            wagered_sat: 3_000,
+            paid_sat: 2_000,
+            roll_schemes: vec![RollScheme::V2],
+        };
+
+        let attestation = format_attestation(
+            &stats,
+            &[AttestationField::Rounds, AttestationField::Paid],
+            datetime!(2024-09-10 00:00 UTC),
+            datetime!(2024-09-11 00:00 UTC),
+        )
+        .unwrap();
+
+        assert_eq!(
+            attestation,
+            "NostrDice fairness attestation for the rounds started between \
+             2024-09-10T00:00:00Z and 2024-09-11T00:00:00Z.\n\
+             Rounds: 1\n\
+             Paid out: 2000 sats"
+        );
+    }
+}
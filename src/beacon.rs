@@ -0,0 +1,197 @@
+use anyhow::bail;
+use anyhow::Context;
+use nostr::bitcoin::hashes::sha256;
+use nostr_sdk::hashes::Hash;
+use nostr_sdk::hashes::HashEngine;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const BEACON_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often we ask for the randomness of a beacon round before giving up on it, e.g. because the
+/// chain is running late or cannot be reached.
+const RANDOMNESS_ATTEMPTS: u32 = 5;
+/// How long to wait between asking for the randomness of a beacon round. Doubled after every
+/// failed attempt.
+const RANDOMNESS_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// A drand chain whose randomness is mixed into the nonce of every round.
+///
+/// Every round commits to a beacon round published after the last invoice of a bet on it expires,
+/// so that not even we know the outcome of a roll before bets close.
+#[derive(Clone, Debug)]
+pub struct RandomnessBeacon {
+    /// The URL of the chain, e.g. `https://api.drand.sh/<chain hash>`.
+    pub url: String,
+    genesis_time: u64,
+    period_secs: u64,
+}
+
+#[derive(Deserialize)]
+struct ChainInfo {
+    genesis_time: u64,
+    period: u64,
+}
+
+#[derive(Deserialize)]
+struct BeaconRound {
+    round: u64,
+    randomness: String,
+}
+
+impl RandomnessBeacon {
+    /// Look up the schedule of the chain at `url`.
+    pub async fn connect(url: String) -> anyhow::Result<Self> {
+        let url = url.trim_end_matches('/').to_string();
+        let info: ChainInfo = get_json(format!("{url}/info")).await?;
+
+        if info.period == 0 {
+            bail!("Invalid randomness beacon: period is 0");
+        }
+
+        Ok(Self {
+            url,
+            genesis_time: info.genesis_time,
+            period_secs: info.period,
+        })
+    }
+
+    /// The first beacon round published at or after `unix_time`.
+    pub fn round_at(&self, unix_time: u64) -> u64 {
+        let since_genesis = unix_time.saturating_sub(self.genesis_time);
+
+        since_genesis.div_ceil(self.period_secs) + 1
+    }
+
+    /// When the beacon `round` is published, as a UNIX timestamp.
+    pub fn published_at(&self, round: u64) -> u64 {
+        self.genesis_time + round.saturating_sub(1) * self.period_secs
+    }
+
+    /// Wait until the beacon `round` has been published and fetch its randomness, retrying a few
+    /// times if it is not available yet.
+    pub async fn wait_for_randomness(&self, round: u64) -> anyhow::Result<[u8; 32]> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let publish_in = self.published_at(round).saturating_sub(now);
+        if publish_in > 0 {
+            tracing::debug!(
+                round,
+                publish_in,
+                "Waiting for beacon round to be published"
+            );
+            tokio::time::sleep(Duration::from_secs(publish_in)).await;
+        }
+
+        let mut attempt = 1;
+        let mut delay = RANDOMNESS_RETRY_DELAY;
+        loop {
+            match self.randomness(round).await {
+                Ok(randomness) => return Ok(randomness),
+                Err(e) if attempt == RANDOMNESS_ATTEMPTS => {
+                    return Err(e.context(format!(
+                        "Failed to get randomness of beacon round {round} after \
+                         {RANDOMNESS_ATTEMPTS} attempts"
+                    )));
+                }
+                Err(e) => {
+                    tracing::warn!(round, attempt, "Failed to get beacon randomness: {e:#}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Fetch the randomness of a beacon `round`, which must have been published already.
+    pub async fn randomness(&self, round: u64) -> anyhow::Result<[u8; 32]> {
+        let beacon: BeaconRound = get_json(format!("{}/public/{round}", self.url)).await?;
+
+        if beacon.round != round {
+            bail!("Asked for beacon round {round}, got {}", beacon.round);
+        }
+
+        let mut randomness = [0; 32];
+        hex::decode_to_slice(&beacon.randomness, &mut randomness)
+            .context("Invalid beacon randomness")?;
+
+        Ok(randomness)
+    }
+}
+
+/// The seed rolls are derived from in a round mixing in beacon `randomness`:
+/// `sha256(nonce || randomness)`.
+pub fn roll_seed(nonce: [u8; 32], randomness: [u8; 32]) -> [u8; 32] {
+    let mut hasher = sha256::Hash::engine();
+    hasher.input(&nonce);
+    hasher.input(&randomness);
+
+    sha256::Hash::from_engine(hasher).to_byte_array()
+}
+
+async fn get_json<T: DeserializeOwned + Send + 'static>(url: String) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(move || {
+        ureq::get(&url)
+            .timeout(BEACON_REQUEST_TIMEOUT)
+            .call()
+            .with_context(|| format!("Failed to request {url}"))?
+            .into_json()
+            .with_context(|| format!("Invalid response from {url}"))
+    })
+    .await?
+}
+
+/// The drand quicknet chain, publishing a round every 3 seconds.
+#[cfg(test)]
+pub fn test_beacon() -> RandomnessBeacon {
+    RandomnessBeacon {
+        url:
+            "https://api.drand.sh/52db9ba70e0cc0f6eaf7803dd07447a1f5477735fd3f661792ba94600c84e971"
+                .to_string(),
+        genesis_time: 1692803367,
+        period_secs: 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_round_at_or_after_a_moment() {
+        let beacon = test_beacon();
+
+        assert_eq!(beacon.round_at(0), 1);
+        assert_eq!(beacon.round_at(1692803367), 1);
+        assert_eq!(beacon.round_at(1692803368), 2);
+        assert_eq!(beacon.round_at(1692803370), 2);
+        assert_eq!(beacon.round_at(1692803371), 3);
+    }
+
+    #[test]
+    fn rounds_are_published_when_they_start() {
+        let beacon = test_beacon();
+
+        assert_eq!(beacon.published_at(1), 1692803367);
+        assert_eq!(beacon.published_at(3), 1692803373);
+        for round in 1..10 {
+            assert_eq!(beacon.round_at(beacon.published_at(round)), round);
+        }
+    }
+
+    #[test]
+    fn roll_seed_hashes_nonce_and_randomness() {
+        let seed = roll_seed([1; 32], [2; 32]);
+
+        let mut preimage = [1; 64];
+        preimage[32..].copy_from_slice(&[2; 32]);
+        assert_eq!(seed, sha256::Hash::hash(&preimage).to_byte_array());
+        assert_ne!(seed, roll_seed([2; 32], [1; 32]));
+    }
+}
@@ -1,3 +1,8 @@
+use crate::attestation::AttestationField;
+use crate::dm::DmProtocol;
+use crate::game::GameConfig;
+use crate::payouts::PayoutRounding;
+use crate::roll::RollScheme;
 use bitcoin::Network;
 use clap::Parser;
 
@@ -35,24 +40,286 @@ pub struct Config {
     #[clap(long)]
     /// Include route hints in invoices
     pub route_hints: bool,
+    /// Maximum size of a request body accepted by the webserver. Zap requests are passed as a
+    /// query parameter, so this only needs to cover the occasional request with a body
+    #[clap(default_value_t = 64 * 1024, long)]
+    pub max_request_body_bytes: usize,
+    /// Maximum size of the path and query of a request accepted by the webserver, which carry the
+    /// zap requests
+    #[clap(default_value_t = 16 * 1024, long)]
+    pub max_request_uri_bytes: usize,
+    /// Bets with a longer zap memo are rejected. Also advertised as the LNURL comment limit
+    #[clap(default_value_t = 280, long)]
+    pub max_zap_memo_chars: usize,
+    /// Bets are rejected if they could make us owe more than this many sats in payouts across the
+    /// paid but unsettled bets of all games. The payout of a bet counts for up to a minute before
+    /// it is paid
+    #[clap(long)]
+    pub max_committed_payout_sat: Option<u64>,
+    /// How the payouts of winning bets are rounded to whole sats. Rounding up gives rollers the
+    /// fractions of a sat the house keeps otherwise, which can cost it its edge on small bets
+    #[clap(default_value_t = PayoutRounding::Floor, long, value_enum)]
+    pub payout_rounding: PayoutRounding,
+    /// The smallest amount in msats advertised to wallets paying the main account of a game, and
+    /// the smallest bet accepted. Bets of less than a sat are never accepted
+    #[clap(default_value_t = 1_000, long)]
+    pub main_min_sendable_msat: u64,
+    /// The largest amount in msats advertised to wallets paying the main account of a game.
+    /// Defaults to the highest maximum bet on the multiplier notes of the game
+    #[clap(long)]
+    pub main_max_sendable_msat: Option<u64>,
+    /// The smallest amount in msats advertised to wallets paying the nonce account of a game
+    #[clap(default_value_t = 1_000, long)]
+    pub nonce_min_sendable_msat: u64,
+    /// The largest amount in msats advertised to wallets paying the nonce account of a game
+    #[clap(default_value_t = 11_000_000_000, long)]
+    pub nonce_max_sendable_msat: u64,
+    /// The smallest amount in msats advertised to wallets paying the social account of a game
+    #[clap(default_value_t = 1_000, long)]
+    pub social_min_sendable_msat: u64,
+    /// The largest amount in msats advertised to wallets paying the social account of a game
+    #[clap(default_value_t = 11_000_000_000, long)]
+    pub social_max_sendable_msat: u64,
+    /// How long a roller's lightning address is trusted to work after we resolved it. Bets from
+    /// rollers without a working lightning address are rejected
+    #[clap(default_value_t = 60 * 60, long)]
+    pub lightning_address_cache_secs: u64,
+    /// Bets are rejected if our outbound liquidity, minus this reserve, could not cover their
+    /// payout on top of the payouts of paid but unsettled bets and of unpaid winners
+    #[clap(default_value_t = 0, long)]
+    pub house_balance_reserve_sat: u64,
+    /// How long the outbound liquidity reported by LND is trusted before asking again
+    #[clap(default_value_t = 10, long)]
+    pub house_balance_cache_secs: u64,
+    /// How many bet invoices a roller can request per minute, in bursts of up to as many
+    #[clap(default_value_t = 10, long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub invoices_per_minute_per_roller: u32,
+    /// How many bet invoices can be requested per minute from one IP, in bursts of up to as many
+    #[clap(default_value_t = 30, long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub invoices_per_minute_per_ip: u32,
+    /// Take the client IP from the `X-Forwarded-For` header, when running behind a reverse proxy
+    #[clap(long)]
+    pub trust_forwarded_for: bool,
+    /// Requests to the webserver taking longer than this are aborted
+    #[clap(default_value_t = 30, long)]
+    pub request_timeout_secs: u64,
+    /// Connections which do not send the headers of a request within this long are closed
+    #[clap(default_value_t = 10, long)]
+    pub request_header_timeout_secs: u64,
     #[arg(num_args(0..))]
     #[clap(long)]
     pub relay: Vec<String>,
+    /// The relays advertised for the main accounts in our NIP-05 documents and their NIP-65 relay
+    /// lists. Defaults to `--relay`
+    #[arg(num_args(0..))]
+    #[clap(long = "main-relay-hint")]
+    pub main_relay_hints: Vec<String>,
+    /// The relays advertised for the nonce accounts in our NIP-05 documents and their NIP-65 relay
+    /// lists. Defaults to `--relay`
+    #[arg(num_args(0..))]
+    #[clap(long = "nonce-relay-hint")]
+    pub nonce_relay_hints: Vec<String>,
+    /// The relays advertised for the social accounts in our NIP-05 documents and their NIP-65 relay
+    /// lists. Defaults to `--relay`
+    #[arg(num_args(0..))]
+    #[clap(long = "social-relay-hint")]
+    pub social_relay_hints: Vec<String>,
+    /// How long to wait for our relays to accept an event we publish
+    #[clap(default_value_t = 20, long)]
+    pub relay_send_timeout_secs: u64,
+    /// How long handling a paid invoice, e.g. publishing its zap receipt, may take. A bet whose
+    /// payment was not handled in time is still settled once its round is revealed
+    #[clap(default_value_t = 30, long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub paid_invoice_timeout_secs: u64,
+    /// How long each relay has to accept a zap receipt. Receipts count as published once any relay
+    /// accepted them, so this can be short
+    #[clap(default_value_t = 5, long)]
+    pub zap_receipt_send_timeout_secs: u64,
+    /// Publish to an embedded in-process relay instead of the configured relays
+    #[cfg(feature = "test-relay")]
+    #[clap(long)]
+    pub test_relay: bool,
+    /// Only publish zap receipts to these of the relays requested in a zap request. Our own relays
+    /// are always used. If empty, every requested relay is used
+    #[arg(num_args(0..))]
+    #[clap(long)]
+    pub receipt_relay_allow_list: Vec<String>,
+    /// How long not to publish zap receipts to a relay after it rejected one
+    #[clap(default_value_t = 24 * 60 * 60, long)]
+    pub relay_blacklist_cooldown_secs: u64,
+    /// Tag the zap receipts of bets with their roll, whether they won, and the threshold and
+    /// direction their roll had to beat. Their zap receipts are only published once they were
+    /// rolled. Zap receipts of donations are never tagged
+    #[clap(long)]
+    pub receipt_bet_tags: bool,
+    /// The memo of invoices for donations without a zap request
+    #[clap(default_value_t = String::from("Donation to NostrDice"), long)]
+    pub anon_donation_memo: String,
+    /// Donations without a zap request may carry an LNURL comment of up to this many characters,
+    /// which is added to the invoice memo. 0 does not take comments
+    #[clap(default_value_t = 140, long)]
+    pub donation_comment_chars: usize,
+    /// Have the social account of the game thank the donor publicly for donations without a zap
+    /// request
+    #[clap(long)]
+    pub anon_donation_thanks: bool,
     /// Location of multipliers file
     #[clap(long)]
     pub multipliers_file: String,
-    /// A nonce expires this long after creation.
-    #[clap(default_value_t = 60, long)]
+    /// Host an additional game with its own keys and multiplier notes, as
+    /// `<name>=<multipliers file>`. Its accounts are `<name>`, `<name>-nonce` and `<name>-social`
+    #[clap(long = "game")]
+    pub games: Vec<GameConfig>,
+    /// How often to re-fetch the multiplier notes and compare them against their pinned hashes
+    #[clap(default_value_t = 600, long)]
+    pub multiplier_pin_check_interval_secs: u64,
+    /// Returned to rollers who zap one of the round notes instead of a multiplier note
+    #[clap(
+        default_value_t = String::from(
+            "You zapped the round note. Please zap a multiplier note to place your bet."
+        ),
+        long
+    )]
+    pub round_note_zap_message: String,
+    /// A nonce expires this long after creation, unless the multipliers file of its game sets
+    /// `expire_nonce_after_secs`
+    #[clap(default_value_t = 60, long, value_parser = clap::value_parser!(u32).range(1..))]
     pub expire_nonce_after_secs: u32,
-    /// A nonce is revealed this long after _expiration_.
-    #[clap(default_value_t = 60, long)]
+    /// A nonce is revealed this long after _expiration_. Bet invoices expire shortly before their
+    /// nonce is revealed, so that every bet is paid or expired before that.
+    #[clap(default_value_t = 60, long, value_parser = clap::value_parser!(u32).range(1..))]
     pub reveal_nonce_after_secs: u32,
+    /// Have the main account of every game reply to the commitment note of each settled round with
+    /// how many rollers bet, how many of them won and how much was paid out
+    #[clap(long)]
+    pub round_results_notes: bool,
+    /// Stop taking bets on a round this long before its nonce expires, so that no bet is paid
+    /// after its round is over. Must be shorter than the rounds of every game
+    #[clap(default_value_t = 5, long)]
+    pub bet_cutoff_secs: u64,
+    /// Bet invoices expire after this long at the latest, even if their round is revealed later
+    #[clap(default_value_t = 600, long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_invoice_expiry_secs: u64,
+    /// Delete rounds revealed this many days ago, with their won and lost bets, keeping their
+    /// totals for the leaderboard. Rounds are kept forever if not set
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub retention_days: Option<u64>,
+    /// Store the zap requests of new bets without their ID and pubkey, which can be recomputed,
+    /// taking about a quarter less space. Zap requests stored before are left as they are
+    #[clap(long)]
+    pub compact_request_events: bool,
+    /// Reconcile the zaps in the database against LND's invoices, report any discrepancies and
+    /// exit
+    #[clap(long)]
+    pub reconcile_invoices: bool,
+    /// Reconcile the payouts in the database against LND's payments, report any discrepancies and
+    /// exit
+    #[clap(long)]
+    pub reconcile_payouts: bool,
+    /// Release the queued payout of the bet with this payment hash after reviewing the win, or the
+    /// payout a dry run did not make, on startup. It is paid out with the next retry of failed
+    /// zaps. `POST /admin/release/<payment hash>` releases payouts while running
+    #[clap(long = "release-queued-payout")]
+    pub release_queued_payouts: Vec<String>,
+    /// Stop taking bets on the multiplier note with this ID, e.g. because it states the wrong odds,
+    /// and publish a NIP-09 deletion for it. Bets already placed on it are still settled. The
+    /// note stays retracted after restarting without this
+    #[clap(long = "retract-multiplier-note")]
+    pub retract_multiplier_notes: Vec<String>,
+    /// How often a failed payout is retried, every 6 hours, before abandoning it. Abandoned payouts
+    /// have to be paid out manually, or released like queued payouts to retry them again
+    #[clap(default_value_t = 8, long)]
+    pub max_zap_retries: u64,
+    /// The routing fee we are always willing to pay on a payout, however small it is
+    #[clap(default_value_t = 10, long)]
+    pub payout_min_fee_sat: u64,
+    /// The routing fee we are willing to pay on a payout, in parts per million of its amount. Never
+    /// less than `--payout-min-fee-sat`
+    #[clap(default_value_t = 5_000, long)]
+    pub payout_fee_ppm: u64,
+    /// A payout attempt fails if it is not completed within this time
+    #[clap(default_value_t = 60, long, value_parser = clap::value_parser!(i32).range(1..))]
+    pub payout_timeout_secs: i32,
+    /// How often to attempt a payout which fails for want of a route, doubling its fee limit on
+    /// every retry
+    #[clap(default_value_t = 3, long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub payout_attempts: u32,
+    /// The fee limit of retried payouts is not raised beyond this
+    #[clap(default_value_t = 1_000, long)]
+    pub payout_max_fee_sat: u64,
+    /// Take bets, roll the die and settle rounds as usual, but only log the payouts of winners
+    /// instead of paying them, e.g. to try out a configuration on staging
+    #[clap(long)]
+    pub dry_run: bool,
+    /// On shutdown, how long to wait for bets being settled and payouts being made before exiting
+    #[clap(default_value_t = 5 * 60, long)]
+    pub shutdown_timeout_secs: u64,
+    /// Bearer token for the `/admin` routes, which are disabled without one
+    #[clap(long)]
+    pub admin_token: Option<String>,
+    /// How to DM rollers their results and payouts
+    #[clap(default_value_t = DmProtocol::Nip17, long, value_enum)]
+    pub dm_protocol: DmProtocol,
+    /// How many expired nonces may be waiting to be revealed before we hold off new rounds
+    #[clap(default_value_t = 16, long)]
+    pub max_pending_reveals: usize,
     /// If enabled logs will be in json format
     #[clap(short, long)]
     pub json: bool,
     /// Time after which we will post a summary of all winners
     #[clap(default_value_t = 60, long)]
     pub social_updates_time_window_minutes: u64,
+    /// Payouts bigger than this many sats are announced immediately by the social account,
+    /// instead of waiting for the next summary
+    #[clap(long)]
+    pub big_win_threshold_sat: Option<u64>,
+    /// Summaries longer than this many bytes are posted as a thread of several notes, so that
+    /// relays limiting the size of events accept them
+    #[clap(default_value_t = 8_000, long)]
+    pub social_updates_max_note_bytes: usize,
+    /// How many players who did not win are named in a summary
+    #[clap(default_value_t = 50, long)]
+    pub social_updates_max_losers_named: usize,
+    /// File listing the npubs of rollers who asked not to be named in social updates, one per
+    /// line. They are still paid out as usual, but left off the leaderboard
+    #[clap(long)]
+    pub anonymous_rollers_file: Option<String>,
+    /// YAML file overriding the phrasing of social updates. See README.md for the templates and
+    /// their placeholders
+    #[clap(long)]
+    pub social_updates_templates_file: Option<String>,
+    /// Have the nonce account of each game publish a signed summary of its rounds every
+    /// `fairness_attestation_interval_hours`
+    #[clap(long)]
+    pub fairness_attestation: bool,
+    /// How often fairness attestations are published, each covering the rounds started since the
+    /// previous one
+    #[clap(default_value_t = 24, long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub fairness_attestation_interval_hours: u64,
+    /// What the fairness attestations cover
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [
+            AttestationField::Rounds,
+            AttestationField::Commitments,
+            AttestationField::Reveals,
+            AttestationField::Wagered,
+            AttestationField::Paid,
+            AttestationField::RollSchemes,
+        ]
+    )]
+    pub fairness_attestation_fields: Vec<AttestationField>,
+    /// Mix the randomness of this drand chain, e.g. `https://api.drand.sh/<chain hash>`, into the
+    /// nonce of every new round. Each round commits to a beacon round published after the last
+    /// invoice of a bet on it expires
+    #[clap(long)]
+    pub randomness_beacon: Option<String>,
+    /// How rolls are derived for new rounds. The scheme is stored with every round, so past
+    /// rounds remain verifiable after changing it. `v3` rolls 32 bits instead of 16
+    #[clap(default_value_t = RollScheme::V1, long, value_enum)]
+    pub roll_scheme: RollScheme,
 }
 
 impl Config {
@@ -1,15 +1,31 @@
+use crate::beacon::roll_seed;
+use crate::multiplier::BetDirection;
+use crate::multiplier::Multiplier;
 use crate::multiplier::Multipliers;
+use crate::roll::RollScheme;
+use anyhow::bail;
 use anyhow::Context;
 use lightning_invoice::Bolt11Invoice;
+use nostr::bitcoin::hashes::sha256;
 use nostr::Event;
 use nostr::EventId;
+use nostr::JsonUtil;
+use nostr::Kind;
 use nostr::PublicKey;
+use nostr::Tag;
+use nostr::Timestamp;
 use nostr::ToBech32;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::json;
 use sqlx::query;
 use sqlx::query_as;
+use sqlx::SqliteExecutor;
 use sqlx::SqlitePool;
+use std::str::FromStr;
+use strum_macros::Display;
+use strum_macros::EnumIter;
+use strum_macros::EnumString;
 use time::OffsetDateTime;
 
 /// The record of a roller's bet.
@@ -20,22 +36,52 @@ pub struct Zap {
     pub request: Event,
     // The ID of the chosen multiplier note e.g. 10x.
     pub multiplier_note_id: String,
-    pub nonce_commitment_note_id: EventId,
+    /// The round the bet was placed on. Donations are not part of any round.
+    pub nonce_commitment_note_id: Option<EventId>,
     pub bet_state: BetState,
     pub zap_retries: u64,
     pub index: usize,
     /// Timestamp when the user place his bet
     pub bet_timestamp: OffsetDateTime,
+    /// The `add_index` LND assigned to the zap invoice.
+    pub add_index: Option<u64>,
+    /// The payment hash of the invoice we paid to zap the roller their payout.
+    pub payout_payment_hash: Option<String>,
+    /// The routing fee we paid on the payout, in msat.
+    pub fee_msat: Option<u64>,
+    /// The name of the game the zap was for.
+    pub game: String,
+    /// When LND settled the zap invoice.
+    pub settled_at: Option<OffsetDateTime>,
+    /// When the payout of a winning bet went through.
+    pub paid_out_at: Option<OffsetDateTime>,
 }
 
 /// The state of a roller's bet.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+///
+/// Stored in the database as the bare variant name, e.g. `PaidWinner`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Display, EnumString, EnumIter)]
 pub enum BetState {
     GameZapInvoiceRequested,
-    ZapInvoiceRequested,
+    /// A zap which is not a bet, e.g. on one of our notes. It is never part of a round.
+    Donation,
+    /// A donation whose invoice was settled and whose zap receipt was published.
+    DonationPaid,
     ZapPaid,
+    /// A paid bet whose die is being rolled. Bets left in this state, e.g. by a crash, are settled
+    /// from their recorded roll, except for winners who may have been paid already. Those are
+    /// resolved with `POST /admin/resolve/<payment hash>`.
+    Rolling,
     ZapFailed,
+    /// A winner whose payout failed again after its last retry. It is not retried anymore, and has
+    /// to be paid out manually or released to be retried again.
+    ZapAbandoned,
     PaidWinner,
+    /// A winner whose payout is held until the operator releases it.
+    QueuedWinner,
+    /// A winner settled during a dry run, whose payout was only logged. Releasing it like a queued
+    /// payout pays it out for real.
+    DryRunWinner,
     Loser,
 }
 
@@ -49,6 +95,13 @@ struct ZapRow {
     idx: i64,
     zap_retries: i64,
     bet_timestamp: OffsetDateTime,
+    add_index: Option<i64>,
+    request_event_format: String,
+    payout_payment_hash: Option<String>,
+    fee_msat: Option<i64>,
+    game: String,
+    settled_at: Option<OffsetDateTime>,
+    paid_out_at: Option<OffsetDateTime>,
 }
 
 impl TryFrom<ZapRow> for Zap {
@@ -64,25 +117,31 @@ impl TryFrom<ZapRow> for Zap {
                 index: "invoice".to_owned(),
                 source: Box::new(e),
             })?,
-            request: serde_json::from_str(&row.request_event).map_err(|e| {
-                sqlx::Error::ColumnDecode {
-                    index: "request_event".to_owned(),
-                    source: e.into(),
-                }
+            request: decode_request_event(
+                &row.roller,
+                &row.request_event,
+                &row.request_event_format,
+            )
+            .map_err(|e| sqlx::Error::ColumnDecode {
+                index: "request_event".to_owned(),
+                source: e.into(),
             })?,
             multiplier_note_id: row.multiplier_note_id,
-            nonce_commitment_note_id: row.nonce_commitment_note_id.parse().map_err(|e| {
-                sqlx::Error::ColumnDecode {
+            // Donations are stored without a round.
+            nonce_commitment_note_id: match row.nonce_commitment_note_id.as_str() {
+                "" => None,
+                id => Some(id.parse().map_err(|e| sqlx::Error::ColumnDecode {
                     index: "nonce_commitment_note_id".to_owned(),
                     source: Box::new(e),
-                }
-            })?,
-            bet_state: serde_json::from_str(&row.bet_state).map_err(|e| {
-                sqlx::Error::ColumnDecode {
+                })?),
+            },
+            bet_state: row
+                .bet_state
+                .parse()
+                .map_err(|e| sqlx::Error::ColumnDecode {
                     index: "bet_state".to_owned(),
-                    source: e.into(),
-                }
-            })?,
+                    source: Box::new(e),
+                })?,
             zap_retries: row
                 .zap_retries
                 .try_into()
@@ -92,30 +151,125 @@ impl TryFrom<ZapRow> for Zap {
                 })?,
             index: row.idx as usize,
             bet_timestamp: row.bet_timestamp,
+            add_index: row.add_index.map(|add_index| add_index as u64),
+            payout_payment_hash: row.payout_payment_hash,
+            fee_msat: row.fee_msat.map(|fee_msat| fee_msat as u64),
+            game: row.game,
+            settled_at: row.settled_at,
+            paid_out_at: row.paid_out_at,
         })
     }
 }
 
+/// How the zap request of a `Zap` is stored in the `request_event` column.
+#[derive(Debug, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum RequestEventFormat {
+    /// The event JSON, as received.
+    Full,
+    /// `[created_at, kind, tags, content, sig]`.
+    ///
+    /// The `pubkey` is already stored in the `roller` column and the `id` can be recomputed from
+    /// the other fields, so neither needs storing. For a typical zap request, with a handful of
+    /// relays, this takes 615 instead of 806 bytes, i.e. about a quarter less.
+    Compact,
+}
+
+/// The fields of a zap request we cannot derive from elsewhere, in the `Compact` format.
+type CompactRequestEvent = (Timestamp, Kind, Vec<Tag>, String, String);
+
+fn encode_request_event(event: &Event, format: RequestEventFormat) -> anyhow::Result<String> {
+    match format {
+        RequestEventFormat::Full => Ok(event.as_json()),
+        RequestEventFormat::Compact => compact_request_event(event),
+    }
+}
+
+fn compact_request_event(event: &Event) -> anyhow::Result<String> {
+    let compact: CompactRequestEvent = (
+        event.created_at,
+        event.kind,
+        event.tags.clone(),
+        event.content.clone(),
+        event.sig.to_string(),
+    );
+
+    serde_json::to_string(&compact).context("Failed to serialize zap request")
+}
+
+fn decode_request_event(roller: &str, request_event: &str, format: &str) -> anyhow::Result<Event> {
+    let format = RequestEventFormat::from_str(format).context("Unknown request event format")?;
+
+    let event = match format {
+        RequestEventFormat::Full => Event::from_json(request_event)?,
+        RequestEventFormat::Compact => {
+            let (created_at, kind, tags, content, sig): CompactRequestEvent =
+                serde_json::from_str(request_event)?;
+            let pubkey = PublicKey::from_hex(roller)?;
+            let id = EventId::new(&pubkey, &created_at, &kind, &tags, &content);
+
+            let event = json!({
+                "id": id,
+                "pubkey": pubkey,
+                "created_at": created_at,
+                "kind": kind,
+                "tags": tags,
+                "content": content,
+                "sig": sig,
+            });
+
+            Event::from_json(event.to_string())?
+        }
+    };
+
+    Ok(event)
+}
+
+/// Store the `zap`, or update it if it is stored already.
+///
+/// New zaps have their zap request stored in full, see [`insert_zap`].
 pub async fn upsert_zap(
     db: &SqlitePool,
     payment_hash: String,
     zap: Zap,
     multipliers: &Multipliers,
 ) -> anyhow::Result<()> {
-    // TODO: This does not scale with lots of zaps.
+    insert_zap(db, payment_hash, zap, multipliers, RequestEventFormat::Full).await
+}
 
+/// Like [`upsert_zap`], but a new zap has its zap request stored in the `request_event_format`.
+///
+/// The zap request of a stored zap never changes, so it is kept in the format it was stored in.
+pub async fn insert_zap(
+    db: &SqlitePool,
+    payment_hash: String,
+    zap: Zap,
+    multipliers: &Multipliers,
+    request_event_format: RequestEventFormat,
+) -> anyhow::Result<()> {
     let roller = zap.roller.to_hex();
     let invoice = zap.invoice.to_string();
-    let request = serde_json::to_string(&zap.request)?;
-    let commitment_id = zap.nonce_commitment_note_id.to_hex();
-    let bet_state = serde_json::to_string(&zap.bet_state)?;
+    let request = encode_request_event(&zap.request, request_event_format)?;
+    let request_event_id = zap.request.id.to_hex();
+    let request_event_format = request_event_format.to_string();
+    let commitment_id = zap
+        .nonce_commitment_note_id
+        .map(|id| id.to_hex())
+        .unwrap_or_default();
+    // Donations were not made on any multiplier note.
+    let multiplier = match zap.bet_state {
+        BetState::Donation => None,
+        _ => {
+            let multiplier = multipliers
+                .get_multiplier_note(&zap.multiplier_note_id)
+                .context("Failed to get multiplier note for id")?
+                .multiplier;
+            Some(serde_json::to_string(&multiplier)?)
+        }
+    };
+    let bet_state = zap.bet_state.to_string();
     let idx = zap.index as i64;
     let ts = zap.bet_timestamp;
-    let multiplier = multipliers
-        .get_multiplier_note(&zap.multiplier_note_id)
-        .context("Failed to get multiplier note for id")?
-        .multiplier;
-    let multiplier = serde_json::to_string(&multiplier)?;
     let multiplier_id = zap.multiplier_note_id;
     let zap_amount_msats: i64 = zap
         .invoice
@@ -124,17 +278,24 @@ pub async fn upsert_zap(
         .try_into()
         .context("Zap amount too large!")?;
     let zap_retries = zap.zap_retries as i64;
+    let add_index = zap.add_index.map(|add_index| add_index as i64);
+    let payout_payment_hash = zap.payout_payment_hash;
+    let fee_msat = zap.fee_msat.map(|fee_msat| fee_msat as i64);
+    let game = zap.game;
+    let settled_at = zap.settled_at;
+    let paid_out_at = zap.paid_out_at;
 
     query!(
         "INSERT INTO zaps
             (payment_hash, roller, invoice, request_event, multiplier_note_id,
              nonce_commitment_note_id, bet_state, idx, bet_timestamp, multiplier, zap_amount_msats,
-             zap_retries)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+             zap_retries, add_index, request_event_format, payout_payment_hash, fee_msat, game,
+             request_event_id, settled_at, paid_out_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+            $19, $20)
         ON CONFLICT(payment_hash) DO UPDATE SET
             roller = excluded.roller,
             invoice = excluded.invoice,
-            request_event = excluded.request_event,
             multiplier_note_id = excluded.multiplier_note_id,
             nonce_commitment_note_id = excluded.nonce_commitment_note_id,
             bet_state = excluded.bet_state,
@@ -142,7 +303,14 @@ pub async fn upsert_zap(
             bet_timestamp = excluded.bet_timestamp,
             multiplier = excluded.multiplier,
             zap_amount_msats = excluded.zap_amount_msats,
-            zap_retries = excluded.zap_retries;
+            zap_retries = excluded.zap_retries,
+            add_index = excluded.add_index,
+            payout_payment_hash = excluded.payout_payment_hash,
+            fee_msat = excluded.fee_msat,
+            game = excluded.game,
+            request_event_id = COALESCE(zaps.request_event_id, excluded.request_event_id),
+            settled_at = excluded.settled_at,
+            paid_out_at = excluded.paid_out_at;
         ",
         payment_hash,
         roller,
@@ -156,6 +324,14 @@ pub async fn upsert_zap(
         multiplier,
         zap_amount_msats,
         zap_retries,
+        add_index,
+        request_event_format,
+        payout_payment_hash,
+        fee_msat,
+        game,
+        request_event_id,
+        settled_at,
+        paid_out_at,
     )
     .execute(db)
     .await
@@ -163,13 +339,71 @@ pub async fn upsert_zap(
     .context("Failed to upsert zap")
 }
 
+/// Store the zap request ID of the zaps stored before it had a column of its own.
+///
+/// Compact zap requests do not include their ID, so it is recomputed from the other fields.
+///
+/// Returns how many zaps were backfilled.
+pub async fn backfill_request_event_ids(db: &SqlitePool) -> anyhow::Result<usize> {
+    let rows = query!(
+        "SELECT payment_hash, roller, request_event, request_event_format FROM zaps
+            WHERE request_event_id IS NULL;"
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch zaps without request event ID")?;
+
+    for row in rows.iter() {
+        let request =
+            decode_request_event(&row.roller, &row.request_event, &row.request_event_format)
+                .with_context(|| format!("Invalid zap request of zap {}", row.payment_hash))?;
+        let request_event_id = request.id.to_hex();
+
+        query!(
+            "UPDATE zaps SET request_event_id = ?1 WHERE payment_hash = ?2;",
+            request_event_id,
+            row.payment_hash,
+        )
+        .execute(db)
+        .await
+        .context("Failed to backfill request event ID")?;
+    }
+
+    Ok(rows.len())
+}
+
+/// Hand out the index of the `roller`'s next bet in the round with the `nonce_commitment_note_id`.
+///
+/// Done in a single statement, so that concurrent bets of a roller never get the same index.
+pub async fn next_bet_index(
+    db: &SqlitePool,
+    nonce_commitment_note_id: EventId,
+    roller: PublicKey,
+) -> anyhow::Result<usize> {
+    let nonce_commitment_note_id = nonce_commitment_note_id.to_hex();
+    let roller = roller.to_hex();
+    let row = query!(
+        "INSERT INTO bet_indexes (nonce_commitment_note_id, roller, next_idx) VALUES (?1, ?2, 1)
+            ON CONFLICT (nonce_commitment_note_id, roller) DO UPDATE SET next_idx = next_idx + 1
+            RETURNING next_idx;",
+        nonce_commitment_note_id,
+        roller,
+    )
+    .fetch_one(db)
+    .await
+    .context("Failed to get next bet index")?;
+
+    Ok(row.next_idx as usize - 1)
+}
+
 pub async fn get_zaps_by_event_id(db: &SqlitePool, event_id: EventId) -> anyhow::Result<Vec<Zap>> {
     let event_id = event_id.to_hex();
     query_as!(
         ZapRow,
         "SELECT
             roller, invoice, request_event, multiplier_note_id,
-            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries
+            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries, add_index,
+            request_event_format, payout_payment_hash, fee_msat, game, settled_at, paid_out_at
         FROM zaps WHERE nonce_commitment_note_id = ?1;",
         event_id,
     )
@@ -179,12 +413,44 @@ pub async fn get_zaps_by_event_id(db: &SqlitePool, event_id: EventId) -> anyhow:
     .context("Failed to fetch zaps")
 }
 
+/// The zap paid with the zap request `request_event_id`, e.g. to look up a roller's bet.
+///
+/// Wallets may fetch several invoices for the same zap request, of which at most one is paid. The
+/// paid one is preferred, and otherwise the latest.
+pub async fn get_zap_by_request_id(
+    db: &SqlitePool,
+    request_event_id: EventId,
+) -> anyhow::Result<Option<Zap>> {
+    let request_event_id = request_event_id.to_hex();
+    let requested = BetState::GameZapInvoiceRequested.to_string();
+    let donation = BetState::Donation.to_string();
+
+    query_as!(
+        ZapRow,
+        "SELECT
+            roller, invoice, request_event, multiplier_note_id,
+            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries, add_index,
+            request_event_format, payout_payment_hash, fee_msat, game, settled_at, paid_out_at
+        FROM zaps WHERE request_event_id = ?1
+        ORDER BY bet_state IN (?2, ?3), bet_timestamp DESC
+        LIMIT 1;",
+        request_event_id,
+        requested,
+        donation,
+    )
+    .try_map(Zap::try_from)
+    .fetch_optional(db)
+    .await
+    .context("Failed to fetch zap by request ID")
+}
+
 pub async fn get_zap(db: &SqlitePool, payment_hash: String) -> anyhow::Result<Option<Zap>> {
     query_as!(
         ZapRow,
         "SELECT
             roller, invoice, request_event, multiplier_note_id,
-            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries
+            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries, add_index,
+            request_event_format, payout_payment_hash, fee_msat, game, settled_at, paid_out_at
         FROM zaps WHERE payment_hash = ?1;",
         payment_hash,
     )
@@ -194,18 +460,24 @@ pub async fn get_zap(db: &SqlitePool, payment_hash: String) -> anyhow::Result<Op
     .context("Failed to fetch zaps")
 }
 
-/// Returns the zaps within a timewindow
-pub async fn get_zaps_in_time_window(
+/// The winning and losing bets placed between `start_time` and `end_time`, found through the
+/// index on bet state and bet timestamp.
+pub async fn get_settled_zaps_in_time_window(
     db: &SqlitePool,
     start_time: OffsetDateTime,
     end_time: OffsetDateTime,
 ) -> anyhow::Result<Vec<Zap>> {
+    let winner = BetState::PaidWinner.to_string();
+    let loser = BetState::Loser.to_string();
     query_as!(
         ZapRow,
         "SELECT
             roller, invoice, request_event, multiplier_note_id,
-            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries
-        FROM zaps WHERE bet_timestamp > ?1 AND bet_timestamp < ?2;",
+            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries, add_index,
+            request_event_format, payout_payment_hash, fee_msat, game, settled_at, paid_out_at
+        FROM zaps WHERE bet_state IN (?1, ?2) AND bet_timestamp > ?3 AND bet_timestamp < ?4;",
+        winner,
+        loser,
         start_time,
         end_time,
     )
@@ -215,16 +487,22 @@ pub async fn get_zaps_in_time_window(
     .context("Failed to fetch zaps")
 }
 
-pub async fn get_failed_zaps(db: &SqlitePool, max_retries: i64) -> anyhow::Result<Vec<Zap>> {
-    let bet_state = serde_json::to_string(&BetState::ZapFailed)?;
+pub async fn get_failed_zaps(
+    db: &SqlitePool,
+    game: &str,
+    max_retries: i64,
+) -> anyhow::Result<Vec<Zap>> {
+    let bet_state = BetState::ZapFailed.to_string();
     query_as!(
         ZapRow,
         "SELECT
             roller, invoice, request_event, multiplier_note_id,
-            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries
-        FROM zaps WHERE bet_state = ?1 AND zap_retries < ?2;",
+            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries, add_index,
+            request_event_format, payout_payment_hash, fee_msat, game, settled_at, paid_out_at
+        FROM zaps WHERE bet_state = ?1 AND zap_retries < ?2 AND game = ?3;",
         bet_state,
         max_retries,
+        game,
     )
     .try_map(Zap::try_from)
     .fetch_all(db)
@@ -232,42 +510,1893 @@ pub async fn get_failed_zaps(db: &SqlitePool, max_retries: i64) -> anyhow::Resul
     .context("Failed to fetch zaps")
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct Round {
-    pub nonce: [u8; 32],
-    pub event_id: EventId,
+/// The failed zaps of the `game` which were retried `max_retries` times, and whose last retry made
+/// a payment which might still go through.
+pub async fn get_exhausted_zaps_with_payouts(
+    db: &SqlitePool,
+    game: &str,
+    max_retries: i64,
+) -> anyhow::Result<Vec<Zap>> {
+    let bet_state = BetState::ZapFailed.to_string();
+    query_as!(
+        ZapRow,
+        "SELECT
+            roller, invoice, request_event, multiplier_note_id,
+            nonce_commitment_note_id, bet_state, idx, bet_timestamp, zap_retries, add_index,
+            request_event_format, payout_payment_hash, fee_msat, game, settled_at, paid_out_at
+        FROM zaps
+        WHERE bet_state = ?1 AND zap_retries >= ?2 AND game = ?3
+            AND payout_payment_hash IS NOT NULL;",
+        bet_state,
+        max_retries,
+        game,
+    )
+    .try_map(Zap::try_from)
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch zaps")
 }
 
-impl Round {
-    pub fn get_note_id(&self) -> String {
-        self.event_id.to_bech32().expect("to fit")
-    }
+/// Move the bet with the `payment_hash` from the `from` to the `to` state, in one statement.
+///
+/// Returns `false` if the bet is not in the `from` state (anymore), e.g. because LND notified us of
+/// its payment twice.
+pub async fn transition_bet_state(
+    db: &SqlitePool,
+    payment_hash: &str,
+    from: BetState,
+    to: BetState,
+) -> anyhow::Result<bool> {
+    let from = from.to_string();
+    let to = to.to_string();
+    let result = query!(
+        "UPDATE zaps SET bet_state = ?1 WHERE payment_hash = ?2 AND bet_state = ?3;",
+        to,
+        payment_hash,
+        from,
+    )
+    .execute(db)
+    .await
+    .context("Failed to transition bet state")?;
+
+    Ok(result.rows_affected() > 0)
 }
 
-pub struct RoundRow {
-    pub nonce: String,
-    pub event_id: String,
+/// Record that the invoice of the zap with the `payment_hash` was settled at `settled_at`, unless
+/// it was recorded before, e.g. because LND notified us of the payment twice.
+pub async fn set_settled_at(
+    db: &SqlitePool,
+    payment_hash: &str,
+    settled_at: OffsetDateTime,
+) -> anyhow::Result<()> {
+    query!(
+        "UPDATE zaps SET settled_at = ?1 WHERE payment_hash = ?2 AND settled_at IS NULL;",
+        settled_at,
+        payment_hash,
+    )
+    .execute(db)
+    .await
+    .context("Failed to set settlement time")?;
+
+    Ok(())
 }
 
-impl TryFrom<RoundRow> for Round {
+/// Give up on the failed zaps of the `game` which were retried `max_retries` times without paying
+/// anything, so that they are not retried anymore.
+///
+/// Zaps whose last retry made a payment are left alone, since LND may still complete it. See
+/// [`get_exhausted_zaps_with_payouts`].
+///
+/// Returns how many zaps were given up on.
+pub async fn abandon_failed_zaps(
+    db: &SqlitePool,
+    game: &str,
+    max_retries: i64,
+) -> anyhow::Result<u64> {
+    let failed = BetState::ZapFailed.to_string();
+    let abandoned = BetState::ZapAbandoned.to_string();
+    let result = query!(
+        "UPDATE zaps SET bet_state = ?1
+        WHERE bet_state = ?2 AND zap_retries >= ?3 AND game = ?4 AND payout_payment_hash IS NULL;",
+        abandoned,
+        failed,
+        max_retries,
+        game,
+    )
+    .execute(db)
+    .await
+    .context("Failed to abandon failed zaps")?;
+
+    Ok(result.rows_affected())
+}
+
+/// Release the queued payout of the bet with the `payment_hash`, the payout a dry run did not make
+/// or an abandoned payout, so that it is paid out when failed zaps are retried next.
+///
+/// Returns `false` if there is no such payout for the bet.
+pub async fn release_queued_payout(db: &SqlitePool, payment_hash: &str) -> anyhow::Result<bool> {
+    let queued = BetState::QueuedWinner.to_string();
+    let dry_run = BetState::DryRunWinner.to_string();
+    let abandoned = BetState::ZapAbandoned.to_string();
+    let failed = BetState::ZapFailed.to_string();
+    let result = query!(
+        "UPDATE zaps SET bet_state = ?1, zap_retries = 0
+            WHERE payment_hash = ?2 AND bet_state IN (?3, ?4, ?5);",
+        failed,
+        payment_hash,
+        queued,
+        dry_run,
+        abandoned,
+    )
+    .execute(db)
+    .await
+    .context("Failed to release queued payout")?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Resolve the winning bet with the `payment_hash` left [`BetState::Rolling`] while it may have
+/// been paid out, once the operator has checked whether it was.
+///
+/// A winner who was `paid` is recorded as paid out at `now`. Otherwise the payout is recorded as
+/// failed, so that it is retried. Returns the state the bet was moved to, or [`None`] if it is not
+/// a winner left rolling.
+pub async fn resolve_rolling_winner(
+    db: &SqlitePool,
+    payment_hash: &str,
+    paid: bool,
+    now: OffsetDateTime,
+) -> anyhow::Result<Option<BetState>> {
+    let rolling = BetState::Rolling.to_string();
+    let (bet_state, paid_out_at) = match paid {
+        true => (BetState::PaidWinner, Some(now)),
+        false => (BetState::ZapFailed, None),
+    };
+    let to = bet_state.to_string();
+    let result = query!(
+        "UPDATE zaps SET bet_state = ?1, paid_out_at = ?2, zap_retries = 0
+            WHERE payment_hash = ?3 AND bet_state = ?4
+            AND EXISTS (SELECT 1 FROM roll_audits WHERE payment_hash = ?3 AND win);",
+        to,
+        paid_out_at,
+        payment_hash,
+        rolling,
+    )
+    .execute(db)
+    .await
+    .context("Failed to resolve rolling winner")?;
+
+    Ok((result.rows_affected() > 0).then_some(bet_state))
+}
+
+/// A bet whose invoice was handed out but not paid (yet).
+#[derive(Debug, Clone)]
+pub struct UnpaidBet {
+    pub payment_hash: String,
+    pub invoice: Bolt11Invoice,
+}
+
+struct UnpaidBetRow {
+    payment_hash: String,
+    invoice: String,
+}
+
+impl TryFrom<UnpaidBetRow> for UnpaidBet {
     type Error = sqlx::Error;
 
-    fn try_from(row: RoundRow) -> Result<Self, Self::Error> {
-        let mut nonce = [0; 32];
-        hex::decode_to_slice(row.nonce, &mut nonce).map_err(|e| sqlx::Error::ColumnDecode {
-            index: "nonce".to_owned(),
-            source: Box::new(e),
-        })?;
+    fn try_from(row: UnpaidBetRow) -> Result<Self, Self::Error> {
+        Ok(UnpaidBet {
+            payment_hash: row.payment_hash,
+            invoice: row.invoice.parse().map_err(|e| sqlx::Error::ColumnDecode {
+                index: "invoice".to_owned(),
+                source: Box::new(e),
+            })?,
+        })
+    }
+}
 
-        Ok(Round {
-            nonce,
-            event_id: row
-                .event_id
+/// The bets of all games whose invoice was not paid (yet).
+pub async fn get_unpaid_bets(db: &SqlitePool) -> anyhow::Result<Vec<UnpaidBet>> {
+    let bet_state = BetState::GameZapInvoiceRequested.to_string();
+    query_as!(
+        UnpaidBetRow,
+        "SELECT payment_hash, invoice FROM zaps WHERE bet_state = ?1;",
+        bet_state,
+    )
+    .try_map(UnpaidBet::try_from)
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch unpaid bets")
+}
+
+/// Delete the bet with the `payment_hash`, unless its invoice was paid meanwhile.
+///
+/// Returns `false` if there is no unpaid bet with the `payment_hash`.
+pub async fn delete_unpaid_bet(db: &SqlitePool, payment_hash: &str) -> anyhow::Result<bool> {
+    let bet_state = BetState::GameZapInvoiceRequested.to_string();
+    let result = query!(
+        "DELETE FROM zaps WHERE payment_hash = ?1 AND bet_state = ?2;",
+        payment_hash,
+        bet_state,
+    )
+    .execute(db)
+    .await
+    .context("Failed to delete unpaid bet")?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// The commitment note IDs of the `game`'s rounds with bets which were paid but not settled yet,
+/// including bets left [`BetState::Rolling`].
+pub async fn get_rounds_with_paid_zaps(
+    db: &SqlitePool,
+    game: &str,
+) -> anyhow::Result<Vec<EventId>> {
+    let paid = BetState::ZapPaid.to_string();
+    let rolling = BetState::Rolling.to_string();
+    let rows = query!(
+        "SELECT DISTINCT nonce_commitment_note_id FROM zaps
+            WHERE bet_state IN (?1, ?2) AND game = ?3;",
+        paid,
+        rolling,
+        game,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch rounds with paid zaps")?;
+
+    rows.into_iter()
+        .map(|row| {
+            row.nonce_commitment_note_id
+                .parse()
+                .context("Invalid nonce commitment note ID")
+        })
+        .collect()
+}
+
+/// What we know about the invoice of a zap, to compare against LND.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZapInvoiceRecord {
+    pub payment_hash: String,
+    pub add_index: Option<u64>,
+    pub bet_state: BetState,
+}
+
+struct ZapInvoiceRecordRow {
+    payment_hash: String,
+    add_index: Option<i64>,
+    bet_state: String,
+}
+
+impl TryFrom<ZapInvoiceRecordRow> for ZapInvoiceRecord {
+    type Error = sqlx::Error;
+
+    fn try_from(row: ZapInvoiceRecordRow) -> Result<Self, Self::Error> {
+        Ok(ZapInvoiceRecord {
+            payment_hash: row.payment_hash,
+            add_index: row.add_index.map(|add_index| add_index as u64),
+            bet_state: row
+                .bet_state
                 .parse()
                 .map_err(|e| sqlx::Error::ColumnDecode {
-                    index: "event_id".to_owned(),
+                    index: "bet_state".to_owned(),
                     source: Box::new(e),
                 })?,
         })
     }
 }
+
+/// What we know about the payout of a winning bet, to compare against LND.
+#[derive(Debug, Clone)]
+pub struct PayoutRecord {
+    /// The payment hash of the roller's zap invoice.
+    pub payment_hash: String,
+    pub payout_payment_hash: Option<String>,
+    /// Unknown for bets placed before we stored it.
+    pub zap_amount_msat: Option<u64>,
+    /// Unknown for bets placed before we stored it.
+    pub multiplier: Option<Multiplier>,
+}
+
+struct PayoutRecordRow {
+    payment_hash: String,
+    payout_payment_hash: Option<String>,
+    zap_amount_msats: Option<i64>,
+    multiplier: Option<String>,
+}
+
+impl TryFrom<PayoutRecordRow> for PayoutRecord {
+    type Error = sqlx::Error;
+
+    fn try_from(row: PayoutRecordRow) -> Result<Self, Self::Error> {
+        Ok(PayoutRecord {
+            payment_hash: row.payment_hash,
+            payout_payment_hash: row.payout_payment_hash,
+            zap_amount_msat: row.zap_amount_msats.map(|amount| amount as u64),
+            multiplier: row
+                .multiplier
+                .map(|multiplier| serde_json::from_str(&multiplier))
+                .transpose()
+                .map_err(|e| sqlx::Error::ColumnDecode {
+                    index: "multiplier".to_owned(),
+                    source: e.into(),
+                })?,
+        })
+    }
+}
+
+/// The payouts of all bets we consider paid out.
+pub async fn get_payout_records(db: &SqlitePool) -> anyhow::Result<Vec<PayoutRecord>> {
+    let bet_state = BetState::PaidWinner.to_string();
+    query_as!(
+        PayoutRecordRow,
+        "SELECT payment_hash, payout_payment_hash, zap_amount_msats, multiplier FROM zaps
+            WHERE bet_state = ?1;",
+        bet_state,
+    )
+    .try_map(PayoutRecord::try_from)
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch payout records")
+}
+
+/// A bet whose payout we may still owe, because its round is running or it has not been settled yet.
+#[derive(Debug, Clone)]
+pub struct CommittedBet {
+    pub zap_amount_msat: u64,
+    pub multiplier: Multiplier,
+}
+
+struct CommittedBetRow {
+    zap_amount_msats: Option<i64>,
+    multiplier: Option<String>,
+}
+
+/// The bets of all games which are paid but not settled yet.
+pub async fn get_committed_bets(db: &SqlitePool) -> anyhow::Result<Vec<CommittedBet>> {
+    let paid = BetState::ZapPaid.to_string();
+    let rolling = BetState::Rolling.to_string();
+    let rows = query_as!(
+        CommittedBetRow,
+        "SELECT zap_amount_msats, multiplier FROM zaps WHERE bet_state IN (?1, ?2);",
+        paid,
+        rolling,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch committed bets")?;
+
+    committed_bets(rows)
+}
+
+/// Hold the `payout_sat` of the unpaid bet with the `payment_hash`, unless that would put more than
+/// `max_committed_payout_sat` at stake across the paid but unsettled bets of all games and the
+/// payouts held for unpaid bets.
+///
+/// The hold lapses at `held_until` unless the bet is paid by then, so that invoices which are never
+/// paid do not use up what may be at stake. Checking and holding is a single statement, so that
+/// concurrent bets cannot both be given the same room.
+///
+/// Returns whether the payout is held.
+pub async fn hold_payout(
+    db: &SqlitePool,
+    payment_hash: &str,
+    payout_sat: u64,
+    max_committed_payout_sat: u64,
+    now: OffsetDateTime,
+    held_until: OffsetDateTime,
+) -> anyhow::Result<bool> {
+    let payout_sat = i64::try_from(payout_sat).context("Payout too large")?;
+    let max_committed_payout_sat = i64::try_from(max_committed_payout_sat).unwrap_or(i64::MAX);
+    let requested = BetState::GameZapInvoiceRequested.to_string();
+    let paid = BetState::ZapPaid.to_string();
+    let rolling = BetState::Rolling.to_string();
+    let result = query!(
+        "UPDATE zaps SET committed_payout_sat = ?1, payout_held_until = ?2
+            WHERE payment_hash = ?3 AND bet_state = ?4
+            AND ?1 + (SELECT COALESCE(SUM(committed_payout_sat), 0) FROM zaps
+                WHERE bet_state IN (?5, ?6) OR (bet_state = ?4 AND payout_held_until > ?7)
+            ) <= ?8;",
+        payout_sat,
+        held_until,
+        payment_hash,
+        requested,
+        paid,
+        rolling,
+        now,
+        max_committed_payout_sat,
+    )
+    .execute(db)
+    .await
+    .context("Failed to hold payout")?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// The bets of all games which were won, but whose payout failed, was abandoned or is queued.
+pub async fn get_owed_payouts(db: &SqlitePool) -> anyhow::Result<Vec<CommittedBet>> {
+    let failed = BetState::ZapFailed.to_string();
+    let abandoned = BetState::ZapAbandoned.to_string();
+    let queued = BetState::QueuedWinner.to_string();
+    let rows = query_as!(
+        CommittedBetRow,
+        "SELECT zap_amount_msats, multiplier FROM zaps WHERE bet_state IN (?1, ?2, ?3);",
+        failed,
+        abandoned,
+        queued,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch owed payouts")?;
+
+    committed_bets(rows)
+}
+
+fn committed_bets(rows: Vec<CommittedBetRow>) -> anyhow::Result<Vec<CommittedBet>> {
+    rows.into_iter()
+        .filter_map(|row| match (row.zap_amount_msats, row.multiplier) {
+            (Some(zap_amount_msat), Some(multiplier)) => Some(
+                serde_json::from_str(&multiplier)
+                    .map(|multiplier| CommittedBet {
+                        zap_amount_msat: zap_amount_msat as u64,
+                        multiplier,
+                    })
+                    .context("Failed to parse multiplier"),
+            ),
+            // Bets placed before we stored these have long been settled.
+            _ => None,
+        })
+        .collect()
+}
+
+/// How many bets of all games are in each state. States without bets are left out.
+pub async fn get_bet_state_counts(db: &SqlitePool) -> anyhow::Result<Vec<(BetState, u64)>> {
+    let rows =
+        query!(r#"SELECT bet_state, COUNT(*) as "bets!: i64" FROM zaps GROUP BY bet_state;"#)
+            .fetch_all(db)
+            .await
+            .context("Failed to count bets")?;
+
+    rows.into_iter()
+        .map(|row| {
+            let bet_state = row.bet_state.parse().context("Invalid bet state")?;
+            Ok((bet_state, row.bets as u64))
+        })
+        .collect()
+}
+
+/// A donation without a zap request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnonDonation {
+    pub payment_hash: String,
+    pub amount_msat: u64,
+    pub game: String,
+    pub created_at: OffsetDateTime,
+    pub settled_at: Option<OffsetDateTime>,
+}
+
+struct AnonDonationRow {
+    payment_hash: String,
+    amount_msats: i64,
+    game: String,
+    created_at: OffsetDateTime,
+    settled_at: Option<OffsetDateTime>,
+}
+
+impl From<AnonDonationRow> for AnonDonation {
+    fn from(row: AnonDonationRow) -> Self {
+        AnonDonation {
+            payment_hash: row.payment_hash,
+            amount_msat: row.amount_msats as u64,
+            game: row.game,
+            created_at: row.created_at,
+            settled_at: row.settled_at,
+        }
+    }
+}
+
+pub async fn insert_anon_donation(db: &SqlitePool, donation: AnonDonation) -> anyhow::Result<()> {
+    let amount_msats: i64 = donation
+        .amount_msat
+        .try_into()
+        .context("Donation amount too large!")?;
+
+    query!(
+        "INSERT INTO anon_donations (payment_hash, amount_msats, game, created_at, settled_at)
+            VALUES (?1, ?2, ?3, ?4, ?5);",
+        donation.payment_hash,
+        amount_msats,
+        donation.game,
+        donation.created_at,
+        donation.settled_at,
+    )
+    .execute(db)
+    .await
+    .context("Failed to insert anonymous donation")?;
+
+    Ok(())
+}
+
+struct MultiplierNoteRetractionRow {
+    note_id: String,
+    retracted_at: OffsetDateTime,
+}
+
+/// Record that the multiplier note with the `note_id` of the `game` takes no bets from
+/// `retracted_at` on, unless it was retracted before.
+pub async fn insert_multiplier_note_retraction(
+    db: &SqlitePool,
+    game: &str,
+    note_id: &str,
+    retracted_at: OffsetDateTime,
+) -> anyhow::Result<()> {
+    query!(
+        "INSERT INTO multiplier_note_retractions (game, note_id, retracted_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT(game, note_id) DO NOTHING;",
+        game,
+        note_id,
+        retracted_at,
+    )
+    .execute(db)
+    .await
+    .context("Failed to insert multiplier note retraction")?;
+
+    Ok(())
+}
+
+/// The multiplier notes of the `game` we retracted, and since when.
+pub async fn get_multiplier_note_retractions(
+    db: &SqlitePool,
+    game: &str,
+) -> anyhow::Result<Vec<(String, OffsetDateTime)>> {
+    let rows = query_as!(
+        MultiplierNoteRetractionRow,
+        "SELECT note_id, retracted_at FROM multiplier_note_retractions WHERE game = ?1;",
+        game,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch multiplier note retractions")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.note_id, row.retracted_at))
+        .collect())
+}
+
+/// Mark the anonymous donation with the `payment_hash` as settled at `settled_at`.
+///
+/// Returns `false` if there is no such donation or it was settled before, e.g. because LND
+/// notified us of its payment twice.
+pub async fn settle_anon_donation(
+    db: &SqlitePool,
+    payment_hash: &str,
+    settled_at: OffsetDateTime,
+) -> anyhow::Result<bool> {
+    let result = query!(
+        "UPDATE anon_donations SET settled_at = ?1
+            WHERE payment_hash = ?2 AND settled_at IS NULL;",
+        settled_at,
+        payment_hash,
+    )
+    .execute(db)
+    .await
+    .context("Failed to settle anonymous donation")?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_anon_donation(
+    db: &SqlitePool,
+    payment_hash: &str,
+) -> anyhow::Result<Option<AnonDonation>> {
+    let donation = query_as!(
+        AnonDonationRow,
+        "SELECT payment_hash, amount_msats, game, created_at, settled_at FROM anon_donations
+            WHERE payment_hash = ?1;",
+        payment_hash,
+    )
+    .fetch_optional(db)
+    .await
+    .context("Failed to fetch anonymous donation")?;
+
+    Ok(donation.map(AnonDonation::from))
+}
+
+/// A settlement of a zap invoice which we did not expect in the bet's state.
+#[derive(Debug, Clone)]
+pub struct UnexpectedSettlement {
+    pub payment_hash: String,
+    /// The state of the bet when we received the settlement.
+    pub bet_state: BetState,
+    pub received_at: OffsetDateTime,
+}
+
+struct UnexpectedSettlementRow {
+    payment_hash: String,
+    bet_state: String,
+    received_at: OffsetDateTime,
+}
+
+impl TryFrom<UnexpectedSettlementRow> for UnexpectedSettlement {
+    type Error = sqlx::Error;
+
+    fn try_from(row: UnexpectedSettlementRow) -> Result<Self, Self::Error> {
+        Ok(UnexpectedSettlement {
+            payment_hash: row.payment_hash,
+            bet_state: row
+                .bet_state
+                .parse()
+                .map_err(|e| sqlx::Error::ColumnDecode {
+                    index: "bet_state".to_owned(),
+                    source: Box::new(e),
+                })?,
+            received_at: row.received_at,
+        })
+    }
+}
+
+pub async fn record_unexpected_settlement(
+    db: &SqlitePool,
+    payment_hash: &str,
+    bet_state: &BetState,
+) -> anyhow::Result<()> {
+    let bet_state = bet_state.to_string();
+    let received_at = OffsetDateTime::now_utc();
+    query!(
+        "INSERT INTO unexpected_settlements (payment_hash, bet_state, received_at)
+            VALUES (?1, ?2, ?3);",
+        payment_hash,
+        bet_state,
+        received_at,
+    )
+    .execute(db)
+    .await
+    .context("Failed to record unexpected settlement")?;
+
+    Ok(())
+}
+
+pub async fn get_unexpected_settlements(
+    db: &SqlitePool,
+) -> anyhow::Result<Vec<UnexpectedSettlement>> {
+    query_as!(
+        UnexpectedSettlementRow,
+        "SELECT payment_hash, bet_state, received_at FROM unexpected_settlements;"
+    )
+    .try_map(UnexpectedSettlement::try_from)
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch unexpected settlements")
+}
+
+/// The settle index up to which we handled every settled invoice. 0 if we have not handled any yet.
+pub async fn get_invoice_settle_index(db: &SqlitePool) -> anyhow::Result<u64> {
+    let row = query!("SELECT settle_index FROM invoice_settle_index WHERE id = 0;")
+        .fetch_optional(db)
+        .await
+        .context("Failed to fetch invoice settle index")?;
+
+    Ok(row.map_or(0, |row| row.settle_index as u64))
+}
+
+/// Record that we handled every settled invoice up to the one with the `settle_index`.
+///
+/// Invoices are handled concurrently, so the index never moves back when an update arrives late.
+pub async fn record_invoice_settle_index(db: &SqlitePool, settle_index: u64) -> anyhow::Result<()> {
+    let settle_index = settle_index as i64;
+    query!(
+        "INSERT INTO invoice_settle_index (id, settle_index) VALUES (0, ?1)
+            ON CONFLICT(id) DO UPDATE SET
+                settle_index = MAX(settle_index, excluded.settle_index);",
+        settle_index,
+    )
+    .execute(db)
+    .await
+    .context("Failed to record invoice settle index")?;
+
+    Ok(())
+}
+
+/// A relay we do not publish to until `blacklisted_until`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlacklistedRelay {
+    pub relay: String,
+    pub blacklisted_until: OffsetDateTime,
+}
+
+pub async fn upsert_blacklisted_relay(
+    db: &SqlitePool,
+    blacklisted: &BlacklistedRelay,
+) -> anyhow::Result<()> {
+    query!(
+        "INSERT INTO relay_blacklist (relay, blacklisted_until) VALUES (?1, ?2)
+            ON CONFLICT(relay) DO UPDATE SET blacklisted_until = excluded.blacklisted_until;",
+        blacklisted.relay,
+        blacklisted.blacklisted_until,
+    )
+    .execute(db)
+    .await
+    .context("Failed to blacklist relay")?;
+
+    Ok(())
+}
+
+/// Delete the relays whose blacklisting expired by `now`, returning how many.
+pub async fn delete_expired_blacklisted_relays(
+    db: &SqlitePool,
+    now: OffsetDateTime,
+) -> anyhow::Result<u64> {
+    let result = query!(
+        "DELETE FROM relay_blacklist WHERE blacklisted_until <= ?1;",
+        now,
+    )
+    .execute(db)
+    .await
+    .context("Failed to delete expired relay blacklistings")?;
+
+    Ok(result.rows_affected())
+}
+
+/// The relays blacklisted until after `now`.
+pub async fn get_blacklisted_relays(
+    db: &SqlitePool,
+    now: OffsetDateTime,
+) -> anyhow::Result<Vec<BlacklistedRelay>> {
+    query_as!(
+        BlacklistedRelay,
+        "SELECT relay, blacklisted_until FROM relay_blacklist WHERE blacklisted_until > ?1;",
+        now,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch relay blacklist")
+}
+
+/// The audit record of a settled roll, with everything needed to check its outcome.
+#[derive(Debug, Clone, Serialize)]
+pub struct RollAudit {
+    /// The payment hash of the bet's zap invoice.
+    pub payment_hash: String,
+    pub nonce_commitment_note_id: EventId,
+    pub roller_npub: String,
+    pub index: usize,
+    /// The SHA256 hash of the zap memo the roll was derived from, hex encoded.
+    pub memo_hash: String,
+    pub roll: u32,
+    pub multiplier_note_id: String,
+    pub multiplier: Multiplier,
+    pub direction: BetDirection,
+    pub threshold: u32,
+    pub amount_msat: u64,
+    pub win: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub rolled_at: OffsetDateTime,
+    /// When the bet was paid. Taken from the bet, so not part of the append-only record.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub settled_at: Option<OffsetDateTime>,
+    /// When the payout of a winning bet went through. Taken from the bet, like `settled_at`.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub paid_out_at: Option<OffsetDateTime>,
+}
+
+struct RollAuditRow {
+    payment_hash: String,
+    nonce_commitment_note_id: String,
+    roller_npub: String,
+    idx: i64,
+    memo_hash: String,
+    roll: i64,
+    multiplier_note_id: String,
+    multiplier: String,
+    direction: String,
+    threshold: i64,
+    amount_msats: i64,
+    win: bool,
+    rolled_at: OffsetDateTime,
+    settled_at: Option<OffsetDateTime>,
+    paid_out_at: Option<OffsetDateTime>,
+}
+
+impl TryFrom<RollAuditRow> for RollAudit {
+    type Error = sqlx::Error;
+
+    fn try_from(row: RollAuditRow) -> Result<Self, Self::Error> {
+        Ok(RollAudit {
+            payment_hash: row.payment_hash,
+            nonce_commitment_note_id: row.nonce_commitment_note_id.parse().map_err(|e| {
+                sqlx::Error::ColumnDecode {
+                    index: "nonce_commitment_note_id".to_owned(),
+                    source: Box::new(e),
+                }
+            })?,
+            roller_npub: row.roller_npub,
+            index: row.idx as usize,
+            memo_hash: row.memo_hash,
+            roll: row.roll as u32,
+            multiplier_note_id: row.multiplier_note_id,
+            multiplier: serde_json::from_str(&row.multiplier).map_err(|e| {
+                sqlx::Error::ColumnDecode {
+                    index: "multiplier".to_owned(),
+                    source: e.into(),
+                }
+            })?,
+            direction: serde_json::from_str(&row.direction).map_err(|e| {
+                sqlx::Error::ColumnDecode {
+                    index: "direction".to_owned(),
+                    source: e.into(),
+                }
+            })?,
+            threshold: row.threshold as u32,
+            amount_msat: row.amount_msats as u64,
+            win: row.win,
+            rolled_at: row.rolled_at,
+            settled_at: row.settled_at,
+            paid_out_at: row.paid_out_at,
+        })
+    }
+}
+
+/// Move the bet of the `audit` from [`BetState::ZapPaid`] to [`BetState::Rolling`] and record its
+/// roll, in one transaction.
+///
+/// A rolling bet thus always has its roll recorded, from which it can be settled if settling it is
+/// interrupted. Returns `false` if the bet is not waiting for its roll (anymore), e.g. because
+/// another settlement claimed it first.
+pub async fn claim_bet_for_roll(db: &SqlitePool, audit: RollAudit) -> anyhow::Result<bool> {
+    let paid = BetState::ZapPaid.to_string();
+    let rolling = BetState::Rolling.to_string();
+
+    let mut tx = db.begin().await.context("Failed to start transaction")?;
+
+    let claimed = query!(
+        "UPDATE zaps SET bet_state = ?1 WHERE payment_hash = ?2 AND bet_state = ?3;",
+        rolling,
+        audit.payment_hash,
+        paid,
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to claim bet for its roll")?
+    .rows_affected()
+        > 0;
+    if !claimed {
+        return Ok(false);
+    }
+
+    insert_roll_audit(&mut *tx, audit).await?;
+
+    tx.commit().await.context("Failed to commit transaction")?;
+
+    Ok(true)
+}
+
+/// Record a settled roll. Rolls are never rewritten, so recording a roll again is a no-op.
+async fn insert_roll_audit<'c>(
+    db: impl SqliteExecutor<'c>,
+    audit: RollAudit,
+) -> anyhow::Result<()> {
+    let nonce_commitment_note_id = audit.nonce_commitment_note_id.to_hex();
+    let idx = audit.index as i64;
+    let multiplier = serde_json::to_string(&audit.multiplier)?;
+    let direction = serde_json::to_string(&audit.direction)?;
+    let amount_msats: i64 = audit
+        .amount_msat
+        .try_into()
+        .context("Zap amount too large!")?;
+
+    query!(
+        "INSERT INTO roll_audits (payment_hash, nonce_commitment_note_id, roller_npub, idx,
+            memo_hash, roll, multiplier_note_id, multiplier, direction, threshold, amount_msats,
+            win, rolled_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+            ON CONFLICT (payment_hash) DO NOTHING;",
+        audit.payment_hash,
+        nonce_commitment_note_id,
+        audit.roller_npub,
+        idx,
+        audit.memo_hash,
+        audit.roll,
+        audit.multiplier_note_id,
+        multiplier,
+        direction,
+        audit.threshold,
+        amount_msats,
+        audit.win,
+        audit.rolled_at,
+    )
+    .execute(db)
+    .await
+    .context("Failed to insert roll audit")?;
+
+    Ok(())
+}
+
+/// The settled rolls of the round committed to by `nonce_commitment_note_id`, in bet order.
+pub async fn get_roll_audits(
+    db: &SqlitePool,
+    nonce_commitment_note_id: EventId,
+) -> anyhow::Result<Vec<RollAudit>> {
+    let nonce_commitment_note_id = nonce_commitment_note_id.to_hex();
+    query_as!(
+        RollAuditRow,
+        "SELECT roll_audits.payment_hash, roll_audits.nonce_commitment_note_id, roller_npub,
+            roll_audits.idx, memo_hash, roll, roll_audits.multiplier_note_id,
+            roll_audits.multiplier, direction, threshold, amount_msats, win, rolled_at,
+            zaps.settled_at, zaps.paid_out_at
+            FROM roll_audits LEFT JOIN zaps ON zaps.payment_hash = roll_audits.payment_hash
+            WHERE roll_audits.nonce_commitment_note_id = ?1 ORDER BY roll_audits.idx;",
+        nonce_commitment_note_id,
+    )
+    .try_map(RollAudit::try_from)
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch roll audits")
+}
+
+/// The settled roll of the bet with the `payment_hash`, if it was rolled.
+pub async fn get_roll_audit(
+    db: &SqlitePool,
+    payment_hash: &str,
+) -> anyhow::Result<Option<RollAudit>> {
+    query_as!(
+        RollAuditRow,
+        "SELECT roll_audits.payment_hash, roll_audits.nonce_commitment_note_id, roller_npub,
+            roll_audits.idx, memo_hash, roll, roll_audits.multiplier_note_id,
+            roll_audits.multiplier, direction, threshold, amount_msats, win, rolled_at,
+            zaps.settled_at, zaps.paid_out_at
+            FROM roll_audits LEFT JOIN zaps ON zaps.payment_hash = roll_audits.payment_hash
+            WHERE roll_audits.payment_hash = ?1;",
+        payment_hash,
+    )
+    .try_map(RollAudit::try_from)
+    .fetch_optional(db)
+    .await
+    .context("Failed to fetch roll audit")
+}
+
+/// The zap receipt of a rolled bet, held until it can be tagged with the roll.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeldZapReceipt {
+    pub payment_hash: String,
+    /// What the invoice of the bet was settled with.
+    pub preimage: Vec<u8>,
+    pub roll: u32,
+    pub win: bool,
+}
+
+/// Hold the zap receipt of the paid bet with the `payment_hash`, whose invoice was settled with the
+/// `preimage`, until the bet is rolled.
+pub async fn hold_zap_receipt(
+    db: &SqlitePool,
+    payment_hash: &str,
+    preimage: &[u8],
+    held_at: OffsetDateTime,
+) -> anyhow::Result<()> {
+    query!(
+        "INSERT INTO held_zap_receipts (payment_hash, preimage, held_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT(payment_hash) DO NOTHING;",
+        payment_hash,
+        preimage,
+        held_at,
+    )
+    .execute(db)
+    .await
+    .context("Failed to hold zap receipt")?;
+
+    Ok(())
+}
+
+/// The held zap receipts of the bets of the `game` which were rolled by now.
+pub async fn get_rolled_zap_receipts(
+    db: &SqlitePool,
+    game: &str,
+) -> anyhow::Result<Vec<HeldZapReceipt>> {
+    let rows = query!(
+        "SELECT held_zap_receipts.payment_hash, preimage, roll, win
+            FROM held_zap_receipts
+            JOIN roll_audits ON roll_audits.payment_hash = held_zap_receipts.payment_hash
+            JOIN zaps ON zaps.payment_hash = held_zap_receipts.payment_hash
+            WHERE zaps.game = ?1;",
+        game,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch rolled zap receipts")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| HeldZapReceipt {
+            payment_hash: row.payment_hash,
+            preimage: row.preimage,
+            roll: row.roll as u32,
+            win: row.win,
+        })
+        .collect())
+}
+
+/// Stop holding the zap receipt of the bet with the `payment_hash`, once it was published.
+pub async fn delete_held_zap_receipt(db: &SqlitePool, payment_hash: &str) -> anyhow::Result<()> {
+    query!(
+        "DELETE FROM held_zap_receipts WHERE payment_hash = ?1;",
+        payment_hash,
+    )
+    .execute(db)
+    .await
+    .context("Failed to delete held zap receipt")?;
+
+    Ok(())
+}
+
+/// The settled bets of a roller on one multiplier with one outcome, summed up.
+#[derive(Debug, Clone)]
+pub struct RollerTotals {
+    pub roller: PublicKey,
+    pub multiplier: Multiplier,
+    /// Either [`BetState::PaidWinner`] or [`BetState::Loser`].
+    pub bet_state: BetState,
+    pub bets: u64,
+    pub amount_msat: u64,
+}
+
+struct RollerTotalsRow {
+    roller: String,
+    multiplier: String,
+    bet_state: String,
+    bets: i64,
+    amount_msats: i64,
+}
+
+impl TryFrom<RollerTotalsRow> for RollerTotals {
+    type Error = sqlx::Error;
+
+    fn try_from(row: RollerTotalsRow) -> Result<Self, Self::Error> {
+        Ok(RollerTotals {
+            roller: row.roller.parse().map_err(|e| sqlx::Error::ColumnDecode {
+                index: "roller".to_owned(),
+                source: Box::new(e),
+            })?,
+            multiplier: serde_json::from_str(&row.multiplier).map_err(|e| {
+                sqlx::Error::ColumnDecode {
+                    index: "multiplier".to_owned(),
+                    source: e.into(),
+                }
+            })?,
+            bet_state: row
+                .bet_state
+                .parse()
+                .map_err(|e| sqlx::Error::ColumnDecode {
+                    index: "bet_state".to_owned(),
+                    source: Box::new(e),
+                })?,
+            bets: row.bets as u64,
+            amount_msat: row.amount_msats as u64,
+        })
+    }
+}
+
+/// The won and lost bets of every roller of the `game` placed after `since`, summed up per
+/// multiplier.
+///
+/// Bets of archived rounds are counted by the day they were placed, so a day which started before
+/// `since` counts in full.
+pub async fn get_roller_totals(
+    db: &SqlitePool,
+    game: &str,
+    since: OffsetDateTime,
+) -> anyhow::Result<Vec<RollerTotals>> {
+    let winner = BetState::PaidWinner.to_string();
+    let loser = BetState::Loser.to_string();
+    let mut totals = query_as!(
+        RollerTotalsRow,
+        r#"SELECT
+            roller, multiplier as "multiplier!", bet_state, COUNT(*) as "bets!: i64",
+            SUM(zap_amount_msats) as "amount_msats!: i64"
+        FROM zaps
+        WHERE game = ?1 AND bet_state IN (?2, ?3) AND bet_timestamp > ?4
+            AND multiplier IS NOT NULL AND zap_amount_msats IS NOT NULL
+        GROUP BY roller, multiplier, bet_state;"#,
+        game,
+        winner,
+        loser,
+        since,
+    )
+    .try_map(RollerTotals::try_from)
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch roller totals")?;
+
+    let since_day = since.date().to_string();
+    let archived = query_as!(
+        RollerTotalsRow,
+        r#"SELECT
+            roller, multiplier, bet_state, SUM(bets) as "bets!: i64",
+            SUM(amount_msats) as "amount_msats!: i64"
+        FROM archived_roller_totals
+        WHERE game = ?1 AND day >= ?2
+        GROUP BY roller, multiplier, bet_state;"#,
+        game,
+        since_day,
+    )
+    .try_map(RollerTotals::try_from)
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch archived roller totals")?;
+    totals.extend(archived);
+
+    Ok(totals)
+}
+
+/// The rounds revealed before `before`, or started before then if we do not know when they were
+/// revealed, whose bets are all won or lost.
+///
+/// Running rounds, the latest expired round of each game, and rounds with unexpected settlements
+/// are left out, since they are still needed.
+pub async fn get_archivable_rounds(
+    db: &SqlitePool,
+    before: OffsetDateTime,
+) -> anyhow::Result<Vec<EventId>> {
+    let winner = BetState::PaidWinner.to_string();
+    let loser = BetState::Loser.to_string();
+    let rows = query!(
+        "SELECT event_id FROM nonces
+            WHERE reveal_event_id IS NOT NULL AND COALESCE(revealed_at, started_at) < ?1
+                AND event_id NOT IN (SELECT nonce_event_id FROM active_nonces)
+                AND event_id NOT IN (SELECT nonce_event_id FROM latest_expired_nonces)
+                AND NOT EXISTS (SELECT 1 FROM zaps
+                    WHERE zaps.nonce_commitment_note_id = nonces.event_id
+                        AND zaps.bet_state NOT IN (?2, ?3))
+                AND NOT EXISTS (SELECT 1 FROM unexpected_settlements
+                    JOIN zaps ON zaps.payment_hash = unexpected_settlements.payment_hash
+                    WHERE zaps.nonce_commitment_note_id = nonces.event_id);",
+        before,
+        winner,
+        loser,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch archivable rounds")?;
+
+    rows.into_iter()
+        .map(|row| {
+            row.event_id
+                .parse()
+                .context("Invalid nonce commitment note ID")
+        })
+        .collect()
+}
+
+/// Delete the round with the `commitment_event_id` with its won and lost bets, their audits and
+/// bet indexes, adding the bets to the archived roller totals.
+///
+/// The round itself is kept if it has other bets, e.g. because one was paid meanwhile. Returns
+/// whether the round was deleted.
+pub async fn archive_round(db: &SqlitePool, commitment_event_id: EventId) -> anyhow::Result<bool> {
+    let event_id = commitment_event_id.to_hex();
+    let winner = BetState::PaidWinner.to_string();
+    let loser = BetState::Loser.to_string();
+
+    let mut tx = db.begin().await.context("Failed to start transaction")?;
+
+    // The bets are summed up and deleted in the same transaction, so that none is counted twice
+    // or lost.
+    query!(
+        "INSERT INTO archived_roller_totals
+            (game, roller, multiplier, bet_state, day, bets, amount_msats)
+        SELECT game, roller, multiplier, bet_state, substr(bet_timestamp, 1, 10), COUNT(*),
+            SUM(zap_amount_msats)
+            FROM zaps
+            WHERE nonce_commitment_note_id = ?1 AND bet_state IN (?2, ?3)
+                AND multiplier IS NOT NULL AND zap_amount_msats IS NOT NULL
+            GROUP BY game, roller, multiplier, bet_state, substr(bet_timestamp, 1, 10)
+        ON CONFLICT (game, roller, multiplier, bet_state, day) DO UPDATE SET
+            bets = bets + excluded.bets,
+            amount_msats = amount_msats + excluded.amount_msats;",
+        event_id,
+        winner,
+        loser,
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to archive roller totals")?;
+
+    query!(
+        "DELETE FROM roll_audits WHERE nonce_commitment_note_id = ?1;",
+        event_id
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to delete roll audits")?;
+
+    query!(
+        "DELETE FROM zaps WHERE nonce_commitment_note_id = ?1 AND bet_state IN (?2, ?3);",
+        event_id,
+        winner,
+        loser,
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to delete zaps")?;
+
+    let deleted = query!(
+        "DELETE FROM nonces WHERE event_id = ?1
+            AND NOT EXISTS (SELECT 1 FROM zaps WHERE nonce_commitment_note_id = ?1);",
+        event_id
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to delete round")?
+    .rows_affected()
+        > 0;
+
+    if deleted {
+        query!(
+            "DELETE FROM bet_indexes WHERE nonce_commitment_note_id = ?1;",
+            event_id
+        )
+        .execute(&mut *tx)
+        .await
+        .context("Failed to delete bet indexes")?;
+    }
+
+    tx.commit().await.context("Failed to commit transaction")?;
+
+    Ok(deleted)
+}
+
+pub async fn get_zap_invoice_records(db: &SqlitePool) -> anyhow::Result<Vec<ZapInvoiceRecord>> {
+    query_as!(
+        ZapInvoiceRecordRow,
+        "SELECT payment_hash, add_index, bet_state FROM zaps;"
+    )
+    .try_map(ZapInvoiceRecord::try_from)
+    .fetch_all(db)
+    .await
+    .context("Failed to fetch zap invoice records")
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Round {
+    pub nonce: [u8; 32],
+    pub event_id: EventId,
+    /// How rolls are derived for bets placed during this round.
+    pub roll_scheme: RollScheme,
+    /// See [`Multipliers::commitment`]. Unknown for rounds started before we committed to them.
+    pub multipliers_commitment: Option<sha256::Hash>,
+    /// The randomness beacon round mixed into the nonce, if any.
+    pub beacon_round: Option<u64>,
+    /// The randomness of the `beacon_round`, known once the round has been revealed.
+    pub beacon_randomness: Option<[u8; 32]>,
+    /// When the round stops taking bets. Unknown for rounds started before we recorded it.
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+impl Round {
+    pub fn get_note_id(&self) -> String {
+        self.event_id.to_bech32().expect("to fit")
+    }
+
+    /// The seed rolls of this round are derived from: the nonce, mixed with the beacon randomness
+    /// if the round uses a beacon.
+    pub fn roll_seed(&self) -> anyhow::Result<[u8; 32]> {
+        match (self.beacon_round, self.beacon_randomness) {
+            (None, _) => Ok(self.nonce),
+            (Some(_), Some(randomness)) => Ok(roll_seed(self.nonce, randomness)),
+            (Some(beacon_round), None) => {
+                bail!("Randomness of beacon round {beacon_round} is not known yet")
+            }
+        }
+    }
+}
+
+/// A round of the V1 scheme with an all-zero nonce, without a beacon, commitment or expiry.
+#[cfg(test)]
+pub fn test_round(event_id: EventId) -> Round {
+    Round {
+        nonce: [0; 32],
+        event_id,
+        roll_scheme: RollScheme::V1,
+        multipliers_commitment: None,
+        beacon_round: None,
+        beacon_randomness: None,
+        expires_at: None,
+    }
+}
+
+/// What we know about a round when summarising it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundRecord {
+    pub event_id: EventId,
+    pub roll_scheme: RollScheme,
+    /// Whether the nonce of the round has been revealed.
+    pub revealed: bool,
+}
+
+pub struct RoundRow {
+    pub nonce: String,
+    pub event_id: String,
+    pub roll_scheme: String,
+    pub multipliers_commitment: Option<String>,
+    pub beacon_round: Option<i64>,
+    pub beacon_randomness: Option<String>,
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+impl TryFrom<RoundRow> for Round {
+    type Error = sqlx::Error;
+
+    fn try_from(row: RoundRow) -> Result<Self, Self::Error> {
+        let mut nonce = [0; 32];
+        hex::decode_to_slice(row.nonce, &mut nonce).map_err(|e| sqlx::Error::ColumnDecode {
+            index: "nonce".to_owned(),
+            source: Box::new(e),
+        })?;
+
+        Ok(Round {
+            nonce,
+            event_id: row
+                .event_id
+                .parse()
+                .map_err(|e| sqlx::Error::ColumnDecode {
+                    index: "event_id".to_owned(),
+                    source: Box::new(e),
+                })?,
+            roll_scheme: row
+                .roll_scheme
+                .parse()
+                .map_err(|e| sqlx::Error::ColumnDecode {
+                    index: "roll_scheme".to_owned(),
+                    source: Box::new(e),
+                })?,
+            multipliers_commitment: row
+                .multipliers_commitment
+                .map(|commitment| commitment.parse())
+                .transpose()
+                .map_err(|e| sqlx::Error::ColumnDecode {
+                    index: "multipliers_commitment".to_owned(),
+                    source: Box::new(e),
+                })?,
+            beacon_round: row.beacon_round.map(|round| round as u64),
+            beacon_randomness: row
+                .beacon_randomness
+                .map(|randomness| {
+                    let mut bytes = [0; 32];
+                    hex::decode_to_slice(randomness, &mut bytes).map(|_| bytes)
+                })
+                .transpose()
+                .map_err(|e| sqlx::Error::ColumnDecode {
+                    index: "beacon_randomness".to_owned(),
+                    source: Box::new(e),
+                })?,
+            expires_at: row.expires_at,
+        })
+    }
+}
+
+/// A bet of 1,000 sats by a random roller, with a signed invoice and zap request.
+#[cfg(test)]
+pub fn test_zap(
+    multiplier_note_id: &str,
+    nonce_commitment_note_id: EventId,
+    bet_state: BetState,
+) -> Zap {
+    use bitcoin::hashes::Hash;
+    use bitcoin::key::Secp256k1;
+    use bitcoin::secp256k1::SecretKey;
+    use lightning_invoice::Currency;
+    use lightning_invoice::InvoiceBuilder;
+    use lightning_invoice::PaymentSecret;
+    use nostr::EventBuilder;
+    use nostr::Keys;
+
+    let roller = Keys::generate();
+    let request = EventBuilder::new(Kind::ZapRequest, "", [])
+        .to_event(&roller)
+        .unwrap();
+
+    let private_key = SecretKey::from_slice(&[1; 32]).unwrap();
+    let invoice = InvoiceBuilder::new(Currency::Regtest)
+        .amount_milli_satoshis(1_000_000)
+        // Like the invoices of zaps, commits to the zap request.
+        .description_hash(bitcoin::hashes::sha256::Hash::hash(
+            request.as_json().as_bytes(),
+        ))
+        .current_timestamp()
+        .payment_hash(bitcoin::hashes::sha256::Hash::hash(request.id.as_bytes()))
+        .payment_secret(PaymentSecret([0; 32]))
+        .min_final_cltv_expiry_delta(144)
+        .build_signed(|hash| Secp256k1::signing_only().sign_ecdsa_recoverable(hash, &private_key))
+        .unwrap();
+
+    Zap {
+        roller: roller.public_key(),
+        invoice,
+        request,
+        multiplier_note_id: multiplier_note_id.to_string(),
+        nonce_commitment_note_id: Some(nonce_commitment_note_id),
+        bet_state,
+        zap_retries: 0,
+        index: 0,
+        bet_timestamp: OffsetDateTime::now_utc(),
+        add_index: None,
+        payout_payment_hash: None,
+        fee_msat: None,
+        game: crate::game::DEFAULT_GAME.to_string(),
+        settled_at: None,
+        paid_out_at: None,
+    }
+}
+
+/// An in-memory database with all migrations applied.
+#[cfg(test)]
+pub async fn test_db() -> SqlitePool {
+    let db = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+
+    sqlx::migrate!("./migrations").run(&db).await.unwrap();
+
+    db
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::DEFAULT_GAME;
+    use crate::multiplier::test_multiplier_note;
+    use nostr::EventBuilder;
+    use nostr::Keys;
+    use nostr::TagStandard;
+    use sqlx::Row;
+    use strum::IntoEnumIterator;
+
+    fn zap_request(keys: &Keys) -> Event {
+        let tags = [
+            Tag::from_standardized(TagStandard::Relays(vec![
+                "wss://relay.damus.io".into(),
+                "wss://nos.lol".into(),
+                "wss://relay.nostr.band".into(),
+            ])),
+            Tag::from_standardized(TagStandard::Amount {
+                millisats: 21_000,
+                bolt11: None,
+            }),
+            Tag::public_key(Keys::generate().public_key()),
+            Tag::event(EventId::all_zeros()),
+        ];
+
+        EventBuilder::new(Kind::ZapRequest, "Let's go!", tags)
+            .to_event(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn bet_state_string_round_trip() {
+        for bet_state in BetState::iter() {
+            let stored = bet_state.to_string();
+
+            assert!(!stored.contains('"'));
+            assert_eq!(stored.parse::<BetState>().unwrap(), bet_state);
+        }
+
+        assert_eq!(BetState::PaidWinner.to_string(), "PaidWinner");
+    }
+
+    #[test]
+    fn compact_request_event_round_trip() {
+        let keys = Keys::generate();
+        let event = zap_request(&keys);
+
+        let compact = compact_request_event(&event).unwrap();
+        let decoded = decode_request_event(
+            &keys.public_key().to_hex(),
+            &compact,
+            &RequestEventFormat::Compact.to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(decoded, event);
+        decoded.verify().unwrap();
+
+        // At least the ID and the pubkey are no longer stored.
+        assert!(compact.len() + 128 < event.as_json().len());
+    }
+
+    #[tokio::test]
+    async fn failed_zaps_are_abandoned_after_their_last_retry() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let mut payment_hashes = Vec::new();
+        for zap_retries in [7, 8] {
+            let zap = Zap {
+                zap_retries,
+                ..test_zap("note1multiplier", EventId::all_zeros(), BetState::ZapFailed)
+            };
+            let payment_hash = zap.invoice.payment_hash().to_string();
+            upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+                .await
+                .unwrap();
+            payment_hashes.push(payment_hash);
+        }
+
+        assert_eq!(abandon_failed_zaps(&db, "halloween", 8).await.unwrap(), 0);
+        assert_eq!(abandon_failed_zaps(&db, DEFAULT_GAME, 8).await.unwrap(), 1);
+
+        let retried = get_zap(&db, payment_hashes[0].clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(retried.bet_state, BetState::ZapFailed);
+        let abandoned = get_zap(&db, payment_hashes[1].clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(abandoned.bet_state, BetState::ZapAbandoned);
+        assert!(get_exhausted_zaps_with_payouts(&db, DEFAULT_GAME, 8)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Abandoned payouts are still owed, and are retried again once released.
+        assert_eq!(get_owed_payouts(&db).await.unwrap().len(), 2);
+        assert!(release_queued_payout(&db, &payment_hashes[1])
+            .await
+            .unwrap());
+        let failed = get_failed_zaps(&db, DEFAULT_GAME, 8).await.unwrap();
+        assert_eq!(failed.len(), 2);
+        assert!(failed.iter().all(|zap| zap.zap_retries < 8));
+    }
+
+    #[tokio::test]
+    async fn multiplier_notes_stay_retracted_since_their_first_retraction() {
+        let db = test_db().await;
+        let retracted_at = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+
+        insert_multiplier_note_retraction(&db, DEFAULT_GAME, "note1five", retracted_at)
+            .await
+            .unwrap();
+        insert_multiplier_note_retraction(
+            &db,
+            DEFAULT_GAME,
+            "note1five",
+            retracted_at + time::Duration::HOUR,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            get_multiplier_note_retractions(&db, DEFAULT_GAME)
+                .await
+                .unwrap(),
+            [("note1five".to_string(), retracted_at)]
+        );
+        assert!(get_multiplier_note_retractions(&db, "halloween")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn payouts_are_held_across_overlapping_rounds_until_they_lapse() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+        let now = OffsetDateTime::now_utc();
+
+        // Unpaid bets on the overlapping rounds of two games.
+        let mut bets = Vec::new();
+        for (game, round) in [("halloween", 0), (DEFAULT_GAME, 1), (DEFAULT_GAME, 1)] {
+            let round_id = EventId::from_slice(&[round; 32]).unwrap();
+            let zap = Zap {
+                game: game.to_string(),
+                ..test_zap(
+                    "note1multiplier",
+                    round_id,
+                    BetState::GameZapInvoiceRequested,
+                )
+            };
+            let payment_hash = zap.invoice.payment_hash().to_string();
+            upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+                .await
+                .unwrap();
+            bets.push(payment_hash);
+        }
+        let hold = |payment_hash: &str, now: OffsetDateTime| {
+            hold_payout(
+                &db,
+                payment_hash,
+                2_000,
+                5_000,
+                now,
+                now + time::Duration::MINUTE,
+            )
+        };
+        let requested = BetState::GameZapInvoiceRequested;
+
+        assert!(hold(&bets[0], now).await.unwrap());
+        assert!(
+            transition_bet_state(&db, &bets[0], requested, BetState::ZapPaid)
+                .await
+                .unwrap()
+        );
+        assert!(hold(&bets[1], now).await.unwrap());
+
+        // 4_000 sats are at stake, and the bet would add 2_000.
+        assert!(!hold(&bets[2], now).await.unwrap());
+
+        // The unpaid bet no longer counts once its hold lapsed, but the paid one does.
+        let later = now + time::Duration::seconds(61);
+        assert!(hold(&bets[2], later).await.unwrap());
+        assert_eq!(get_committed_bets(&db).await.unwrap().len(), 1);
+
+        // Nor do settled bets.
+        assert!(
+            transition_bet_state(&db, &bets[0], BetState::ZapPaid, BetState::Loser)
+                .await
+                .unwrap()
+        );
+        assert!(get_committed_bets(&db).await.unwrap().is_empty());
+        assert!(hold(&bets[1], later).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn bets_of_a_round_are_found_without_a_table_scan() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let round = EventId::all_zeros();
+        let zap = test_zap("note1multiplier", round, BetState::ZapPaid);
+        upsert_zap(
+            &db,
+            zap.invoice.payment_hash().to_string(),
+            zap,
+            &multipliers,
+        )
+        .await
+        .unwrap();
+
+        // Copy the bet into 100k bets on other rounds.
+        sqlx::query(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100000)
+            INSERT INTO zaps
+                (payment_hash, roller, invoice, request_event, multiplier_note_id,
+                 nonce_commitment_note_id, bet_state, idx, bet_timestamp, multiplier,
+                 zap_amount_msats, zap_retries, add_index, request_event_format,
+                 payout_payment_hash, fee_msat, game)
+            SELECT
+                printf('%064x', i), roller, invoice, request_event, multiplier_note_id,
+                printf('%064x', i), bet_state, idx, bet_timestamp, multiplier,
+                zap_amount_msats, zap_retries, add_index, request_event_format,
+                payout_payment_hash, fee_msat, game
+            FROM zaps, n;",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let start = std::time::Instant::now();
+        let zaps = get_zaps_by_event_id(&db, round).await.unwrap();
+        assert_eq!(zaps.len(), 1);
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+
+        let plan = sqlx::query(
+            "EXPLAIN QUERY PLAN SELECT * FROM zaps WHERE nonce_commitment_note_id = ?1;",
+        )
+        .bind(round.to_hex())
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let detail: String = plan.get("detail");
+        assert!(
+            detail.contains("USING INDEX zaps_nonce_commitment_note_id"),
+            "{detail}"
+        );
+    }
+
+    #[tokio::test]
+    async fn donations_are_not_part_of_any_round() {
+        let db = test_db().await;
+
+        let zap = Zap {
+            nonce_commitment_note_id: None,
+            ..test_zap("", EventId::all_zeros(), BetState::Donation)
+        };
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        // Donations do not need a multiplier note.
+        upsert_zap(&db, payment_hash.clone(), zap, &Multipliers(vec![]))
+            .await
+            .unwrap();
+
+        let donation = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(donation.bet_state, BetState::Donation);
+        assert_eq!(donation.nonce_commitment_note_id, None);
+
+        assert!(get_zaps_by_event_id(&db, EventId::all_zeros())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(get_unpaid_bets(&db).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn zaps_can_be_looked_up_by_their_zap_request() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let zap = test_zap("note1multiplier", EventId::all_zeros(), BetState::ZapPaid);
+        let request_id = zap.request.id;
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        upsert_zap(&db, payment_hash.clone(), zap.clone(), &multipliers)
+            .await
+            .unwrap();
+
+        // Another invoice the wallet fetched for the same zap request, but did not pay.
+        let unpaid = Zap {
+            bet_state: BetState::GameZapInvoiceRequested,
+            bet_timestamp: zap.bet_timestamp + time::Duration::SECOND,
+            ..zap.clone()
+        };
+        upsert_zap(&db, "00".repeat(32), unpaid, &multipliers)
+            .await
+            .unwrap();
+
+        let zap = get_zap_by_request_id(&db, request_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(zap.invoice.payment_hash().to_string(), payment_hash);
+        assert_eq!(zap.bet_state, BetState::ZapPaid);
+        assert!(get_zap_by_request_id(&db, EventId::all_zeros())
+            .await
+            .unwrap()
+            .is_none());
+
+        // Zaps stored before the zap request ID had a column of its own.
+        sqlx::query("UPDATE zaps SET request_event_id = NULL;")
+            .execute(&db)
+            .await
+            .unwrap();
+        assert!(get_zap_by_request_id(&db, request_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        assert_eq!(backfill_request_event_ids(&db).await.unwrap(), 2);
+        assert!(get_zap_by_request_id(&db, request_id)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(backfill_request_event_ids(&db).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn the_first_settlement_time_is_kept() {
+        let db = test_db().await;
+        let zap = test_zap("", EventId::all_zeros(), BetState::Donation);
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        upsert_zap(&db, payment_hash.clone(), zap, &Multipliers(vec![]))
+            .await
+            .unwrap();
+
+        let settled_at = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap();
+        set_settled_at(&db, &payment_hash, settled_at)
+            .await
+            .unwrap();
+        set_settled_at(&db, &payment_hash, settled_at + time::Duration::minutes(1))
+            .await
+            .unwrap();
+
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(zap.settled_at, Some(settled_at));
+        assert_eq!(zap.paid_out_at, None);
+    }
+
+    #[test]
+    fn full_request_events_can_still_be_read() {
+        let keys = Keys::generate();
+        let event = zap_request(&keys);
+
+        let decoded = decode_request_event(
+            &keys.public_key().to_hex(),
+            &event.as_json(),
+            &RequestEventFormat::Full.to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(decoded, event);
+    }
+
+    #[tokio::test]
+    async fn zap_requests_keep_the_format_they_were_stored_in() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+        let format = |payment_hash: String| {
+            let db = db.clone();
+            async move {
+                let row =
+                    sqlx::query("SELECT request_event_format FROM zaps WHERE payment_hash = ?1;")
+                        .bind(payment_hash)
+                        .fetch_one(&db)
+                        .await
+                        .unwrap();
+                row.get::<String, _>("request_event_format")
+            }
+        };
+
+        let zap = test_zap(
+            "note1multiplier",
+            EventId::all_zeros(),
+            BetState::ZapRequested,
+        );
+        let compact = zap.invoice.payment_hash().to_string();
+        insert_zap(
+            &db,
+            compact.clone(),
+            zap.clone(),
+            &multipliers,
+            RequestEventFormat::Compact,
+        )
+        .await
+        .unwrap();
+        assert_eq!(format(compact.clone()).await, "compact");
+
+        let paid = Zap {
+            bet_state: BetState::ZapPaid,
+            ..zap.clone()
+        };
+        upsert_zap(&db, compact.clone(), paid, &multipliers)
+            .await
+            .unwrap();
+        assert_eq!(format(compact.clone()).await, "compact");
+
+        let stored = get_zap(&db, compact).await.unwrap().unwrap();
+        assert_eq!(stored.bet_state, BetState::ZapPaid);
+        assert_eq!(stored.request, zap.request);
+
+        // Zap requests are stored in full unless asked otherwise.
+        let full = "00".repeat(32);
+        upsert_zap(&db, full.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+        assert_eq!(format(full).await, "full");
+    }
+
+    #[tokio::test]
+    async fn concurrent_bets_of_a_roller_get_distinct_indices() {
+        // Unlike `test_db`, several connections to the same database, so that bets really race.
+        let path = std::env::temp_dir().join(format!("nostrdice-{}.db", rand::random::<u64>()));
+        let db = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(8)
+            .connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        sqlx::migrate!("./migrations").run(&db).await.unwrap();
+
+        let round = EventId::all_zeros();
+        let roller = Keys::generate().public_key();
+
+        let mut bets = tokio::task::JoinSet::new();
+        for _ in 0..20 {
+            let db = db.clone();
+            bets.spawn(async move { next_bet_index(&db, round, roller).await.unwrap() });
+        }
+        let mut indices = Vec::new();
+        while let Some(index) = bets.join_next().await {
+            indices.push(index.unwrap());
+        }
+        indices.sort();
+
+        assert_eq!(indices, (0..20).collect::<Vec<_>>());
+
+        // Other rollers and rounds count separately.
+        let other_roller = Keys::generate().public_key();
+        assert_eq!(next_bet_index(&db, round, other_roller).await.unwrap(), 0);
+        let other_round = EventId::from_slice(&[1; 32]).unwrap();
+        assert_eq!(next_bet_index(&db, other_round, roller).await.unwrap(), 0);
+
+        db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
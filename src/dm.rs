@@ -0,0 +1,274 @@
+use crate::receipt_clients::ReceiptClients;
+use anyhow::Context;
+use nostr::Event;
+use nostr::Filter;
+use nostr::Kind;
+use nostr::PublicKey;
+use nostr::ToBech32;
+use nostr_sdk::Client;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use strum_macros::Display;
+
+/// The kind of the list of relays a user wants to receive NIP-17 DMs on.
+const DM_RELAY_LIST_KIND: u16 = 10050;
+
+const FETCH_DM_RELAYS_TIMEOUT: Duration = Duration::from_secs(5);
+const SEND_DM_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How long we remember the DM relays of a recipient, or that they have none.
+const DM_RELAYS_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How many recipients we remember the DM relays of at most.
+const MAX_CACHED_DM_RELAYS: usize = 4096;
+
+/// How we send DMs to rollers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, clap::ValueEnum)]
+#[strum(serialize_all = "lowercase")]
+pub enum DmProtocol {
+    /// NIP-17 gift wrapped DMs to rollers who publish a DM relay list, NIP-04 DMs to everyone else.
+    #[default]
+    Nip17,
+    /// NIP-04 DMs only, which every client can read but which leak who talks to whom.
+    Nip04,
+}
+
+/// Sends DMs to rollers with the `DmProtocol`.
+///
+/// Rollers usually get several DMs about the same bet, so their DM relays are remembered for a
+/// while, and the clients connected to them are reused like those we publish zap receipts with.
+#[derive(Clone)]
+pub struct DmSender {
+    protocol: DmProtocol,
+    dm_relays: Arc<Mutex<DmRelays>>,
+    clients: ReceiptClients,
+}
+
+impl DmSender {
+    pub fn new(protocol: DmProtocol) -> Self {
+        Self {
+            protocol,
+            dm_relays: Arc::new(Mutex::new(DmRelays::new(DM_RELAYS_TTL))),
+            clients: ReceiptClients::new(SEND_DM_TIMEOUT),
+        }
+    }
+
+    /// The clients connected to the DM relays of recent recipients, to disconnect once idle.
+    pub fn clients(&self) -> ReceiptClients {
+        self.clients.clone()
+    }
+
+    /// Send the `message` to `to` from the account of the `client`, returning how it was sent.
+    ///
+    /// DMs which could not be sent with NIP-17 are sent with NIP-04 instead.
+    async fn send(
+        &self,
+        client: &Client,
+        to: &PublicKey,
+        message: String,
+    ) -> anyhow::Result<DmProtocol> {
+        let npub = to.to_bech32().expect("npub");
+
+        if self.protocol == DmProtocol::Nip17 {
+            match self.dm_relays(client, to).await {
+                Ok(relays) if !relays.is_empty() => {
+                    match self.send_private_msg(client, relays, to, &message).await {
+                        Ok(()) => return Ok(DmProtocol::Nip17),
+                        Err(e) => tracing::warn!(
+                            %npub,
+                            "Failed to send NIP-17 DM, falling back to NIP-04: {e:#}"
+                        ),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!(%npub, "Failed to fetch DM relays. Error: {e:#}"),
+            }
+        }
+
+        // Without a DM relay list, the roller's client most likely does not support NIP-17.
+        #[allow(deprecated)]
+        client
+            .send_direct_msg(*to, message, None)
+            .await
+            .context("Failed to send NIP-04 DM")?;
+
+        Ok(DmProtocol::Nip04)
+    }
+
+    /// The relays on the latest DM relay list of `user`, if any, fetched at most every
+    /// [`DM_RELAYS_TTL`].
+    async fn dm_relays(&self, client: &Client, user: &PublicKey) -> anyhow::Result<Vec<String>> {
+        let cached = self
+            .dm_relays
+            .lock()
+            .expect("not poisoned")
+            .get(user, Instant::now());
+        if let Some(relays) = cached {
+            return Ok(relays);
+        }
+
+        let events = client
+            .get_events_of(
+                vec![Filter::new()
+                    .author(*user)
+                    .kind(Kind::from(DM_RELAY_LIST_KIND))],
+                Some(FETCH_DM_RELAYS_TIMEOUT),
+            )
+            .await
+            .context("Failed to fetch DM relay list")?;
+
+        let relays = events
+            .into_iter()
+            .max_by_key(|event| event.created_at)
+            .map(|event| listed_dm_relays(&event))
+            .unwrap_or_default();
+
+        self.dm_relays
+            .lock()
+            .expect("not poisoned")
+            .insert(*user, relays.clone(), Instant::now());
+
+        Ok(relays)
+    }
+
+    /// Send a NIP-17 DM to the `relays` the recipient reads their DMs from.
+    async fn send_private_msg(
+        &self,
+        client: &Client,
+        relays: Vec<String>,
+        to: &PublicKey,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let author = client.signer().await?.public_key().await?;
+        let dm_client = self.clients.get_or_connect(client, author, relays).await?;
+
+        dm_client.send_private_msg(*to, message, None).await?;
+
+        Ok(())
+    }
+}
+
+pub(crate) async fn send_dm(client: &Client, dms: &DmSender, to: &PublicKey, message: String) {
+    if let Err(e) = dms.send(client, to, message).await {
+        let npub = to.to_bech32().expect("npub");
+        tracing::error!(%npub, "Failed to send DM: {e:#}");
+    }
+}
+
+fn listed_dm_relays(event: &Event) -> Vec<String> {
+    event
+        .tags()
+        .iter()
+        .filter_map(|tag| match tag.as_vec() {
+            [name, relay, ..] if name == "relay" => Some(relay.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The DM relays of recent recipients, empty for those without a DM relay list.
+struct DmRelays {
+    ttl: Duration,
+    relays: HashMap<PublicKey, (Instant, Vec<String>)>,
+}
+
+impl DmRelays {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            relays: HashMap::new(),
+        }
+    }
+
+    fn get(&self, user: &PublicKey, now: Instant) -> Option<Vec<String>> {
+        self.relays
+            .get(user)
+            .filter(|(fetched_at, _)| now.duration_since(*fetched_at) < self.ttl)
+            .map(|(_, relays)| relays.clone())
+    }
+
+    fn insert(&mut self, user: PublicKey, relays: Vec<String>, now: Instant) {
+        if self.relays.len() >= MAX_CACHED_DM_RELAYS {
+            let ttl = self.ttl;
+            self.relays
+                .retain(|_, (fetched_at, _)| now.duration_since(*fetched_at) < ttl);
+        }
+        if self.relays.len() >= MAX_CACHED_DM_RELAYS {
+            return;
+        }
+
+        self.relays.insert(user, (now, relays));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::EventBuilder;
+    use nostr::Keys;
+    use nostr::Tag;
+
+    #[test]
+    fn dm_relays_are_read_from_the_relay_tags() {
+        let event = EventBuilder::new(
+            Kind::from(DM_RELAY_LIST_KIND),
+            "",
+            [
+                Tag::parse(&["relay", "wss://inbox.nostr.wine"]).unwrap(),
+                Tag::parse(&["r", "wss://relay.damus.io"]).unwrap(),
+                Tag::parse(&["relay", "wss://auth.nostr1.com"]).unwrap(),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+
+        assert_eq!(
+            listed_dm_relays(&event),
+            ["wss://inbox.nostr.wine", "wss://auth.nostr1.com"]
+        );
+    }
+
+    #[test]
+    fn dm_relays_are_remembered_until_they_expire() {
+        let mut dm_relays = DmRelays::new(Duration::from_secs(60));
+        let (alice, bob) = (Keys::generate().public_key(), Keys::generate().public_key());
+        let now = Instant::now();
+
+        dm_relays.insert(alice, vec!["wss://inbox.nostr.wine".to_string()], now);
+        dm_relays.insert(bob, Vec::new(), now);
+
+        assert_eq!(
+            dm_relays.get(&alice, now + Duration::from_secs(59)),
+            Some(vec!["wss://inbox.nostr.wine".to_string()])
+        );
+        // Recipients without a DM relay list are remembered too.
+        assert_eq!(dm_relays.get(&bob, now), Some(Vec::new()));
+        assert_eq!(dm_relays.get(&alice, now + Duration::from_secs(60)), None);
+        assert_eq!(dm_relays.get(&Keys::generate().public_key(), now), None);
+    }
+
+    #[tokio::test]
+    async fn failed_nip17_dms_fall_back_to_nip04() {
+        let dms = DmSender::new(DmProtocol::Nip17);
+        let to = Keys::generate().public_key();
+        dms.dm_relays.lock().unwrap().insert(
+            to,
+            vec!["ws://127.0.0.1:9".to_string()],
+            Instant::now(),
+        );
+
+        // Neither the DM relay nor our relays can be reached, so both protocols are tried.
+        let client = Client::new(&Keys::generate());
+        let e = dms
+            .send(&client, &to, "You won.".to_string())
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{e:#}").contains("Failed to send NIP-04 DM"),
+            "{e:#}"
+        );
+    }
+}
@@ -0,0 +1,349 @@
+//! Exports historical rounds and their bets, for accounting and external fairness audits.
+
+use crate::admin::authorize;
+use crate::admin::error_response;
+use crate::db::get_roll_audits;
+use crate::db::get_zaps_by_event_id;
+use crate::db::BetState;
+use crate::nonce::get_revealed_rounds_between;
+use crate::nonce::RevealedRound;
+use crate::roll::RollScheme;
+use crate::State;
+use anyhow::anyhow;
+use anyhow::Context;
+use axum::body::Body;
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::Extension;
+use nostr_sdk::EventId;
+use nostr_sdk::ToBech32;
+use serde::Deserialize;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+const CSV_HEADER: &str = "game,nonce_commitment_note_id,nonce,beacon_round,beacon_randomness,\
+    roll_scheme,started_at,revealed_at,payment_hash,roller,index,multiplier_note_id,amount_msat,\
+    bet_state,roll,win,bet_timestamp,settled_at,paid_out_at\n";
+
+#[derive(Deserialize)]
+pub struct ExportQueryParams {
+    /// Only rounds started at or after this time.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    from: Option<OffsetDateTime>,
+    /// Only rounds started before this time.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    to: Option<OffsetDateTime>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    Csv,
+    #[default]
+    Json,
+}
+
+#[derive(Serialize)]
+struct ExportedRound {
+    game: String,
+    nonce_commitment_note_id: EventId,
+    /// Hex encoded.
+    nonce: String,
+    beacon_round: Option<u64>,
+    /// Hex encoded.
+    beacon_randomness: Option<String>,
+    roll_scheme: RollScheme,
+    #[serde(with = "time::serde::rfc3339::option")]
+    started_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    revealed_at: Option<OffsetDateTime>,
+    bets: Vec<ExportedBet>,
+}
+
+#[derive(Serialize)]
+struct ExportedBet {
+    /// The payment hash of the bet's zap invoice.
+    payment_hash: String,
+    roller: String,
+    index: usize,
+    multiplier_note_id: String,
+    amount_msat: Option<u64>,
+    bet_state: BetState,
+    /// Unknown for bets which were not rolled, e.g. because they were never paid.
+    roll: Option<u32>,
+    win: Option<bool>,
+    #[serde(with = "time::serde::rfc3339")]
+    bet_timestamp: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    settled_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    paid_out_at: Option<OffsetDateTime>,
+}
+
+/// Stream the revealed rounds started from `from` until `to` with all their bets, as CSV with one
+/// row per bet or as a JSON array of rounds.
+///
+/// Rounds which were not revealed yet are left out, so that their nonce is not given away.
+pub async fn get_export_rounds(
+    headers: HeaderMap,
+    Query(params): Query<ExportQueryParams>,
+    Extension(state): Extension<State>,
+) -> Response {
+    if let Err(e) = authorize(&headers, state.admin_token.as_deref()) {
+        return e.into_response();
+    }
+
+    if let (Some(from), Some(to)) = (params.from, params.to) {
+        if to < from {
+            return error_response(StatusCode::BAD_REQUEST, anyhow!("`to` is before `from`"))
+                .into_response();
+        }
+    }
+
+    let rounds = match get_revealed_rounds_between(&state.db, params.from, params.to).await {
+        Ok(rounds) => rounds,
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    };
+
+    let (mut sender, body) = Body::channel();
+    let db = state.db.clone();
+    let format = params.format;
+    tokio::spawn(async move {
+        let count = rounds.len();
+        let mut chunk = match format {
+            ExportFormat::Csv => CSV_HEADER.to_string(),
+            ExportFormat::Json => "[".to_string(),
+        };
+
+        for (i, round) in rounds.into_iter().enumerate() {
+            // Bets are only loaded one round at a time, however many rounds are exported.
+            let round = match export_round(&db, round).await {
+                Ok(round) => round,
+                Err(e) => {
+                    tracing::error!("Failed to export round: {e:#}");
+                    sender.abort();
+                    return;
+                }
+            };
+
+            match format {
+                ExportFormat::Csv => chunk.push_str(&csv_rows(&round)),
+                ExportFormat::Json => {
+                    chunk.push_str(&serde_json::to_string(&round).expect("to serialize"));
+                    if i + 1 < count {
+                        chunk.push(',');
+                    }
+                }
+            }
+
+            if sender
+                .send_data(Bytes::from(std::mem::take(&mut chunk)))
+                .await
+                .is_err()
+            {
+                tracing::debug!("Export of rounds was cancelled");
+                return;
+            }
+        }
+
+        if let ExportFormat::Json = format {
+            chunk.push(']');
+        }
+        if !chunk.is_empty() {
+            let _ = sender.send_data(Bytes::from(chunk)).await;
+        }
+    });
+
+    let content_type = match format {
+        ExportFormat::Csv => "text/csv; charset=utf-8",
+        ExportFormat::Json => "application/json",
+    };
+
+    ([(CONTENT_TYPE, content_type)], body).into_response()
+}
+
+async fn export_round(db: &SqlitePool, revealed: RevealedRound) -> anyhow::Result<ExportedRound> {
+    let RevealedRound {
+        game,
+        round,
+        started_at,
+        revealed_at,
+    } = revealed;
+
+    let mut rolls = get_roll_audits(db, round.event_id)
+        .await?
+        .into_iter()
+        .map(|audit| (audit.payment_hash.clone(), audit))
+        .collect::<HashMap<_, _>>();
+
+    let bets = get_zaps_by_event_id(db, round.event_id)
+        .await?
+        .into_iter()
+        .map(|zap| {
+            let payment_hash = zap.invoice.payment_hash().to_string();
+            let roll = rolls.remove(&payment_hash);
+
+            Ok(ExportedBet {
+                roller: zap.roller.to_bech32().context("Invalid roller")?,
+                index: zap.index,
+                multiplier_note_id: zap.multiplier_note_id,
+                amount_msat: zap.invoice.amount_milli_satoshis(),
+                bet_state: zap.bet_state,
+                roll: roll.as_ref().map(|roll| roll.roll),
+                win: roll.as_ref().map(|roll| roll.win),
+                bet_timestamp: zap.bet_timestamp,
+                settled_at: zap.settled_at,
+                paid_out_at: zap.paid_out_at,
+                payment_hash,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(ExportedRound {
+        game,
+        nonce_commitment_note_id: round.event_id,
+        nonce: hex::encode(round.nonce),
+        beacon_round: round.beacon_round,
+        beacon_randomness: round.beacon_randomness.map(hex::encode),
+        roll_scheme: round.roll_scheme,
+        started_at,
+        revealed_at,
+        bets,
+    })
+}
+
+/// One CSV row per bet of the `round`, or a single row without bet columns if it had no bets.
+fn csv_rows(round: &ExportedRound) -> String {
+    let round_fields = [
+        round.game.clone(),
+        round.nonce_commitment_note_id.to_hex(),
+        round.nonce.clone(),
+        optional(round.beacon_round),
+        round.beacon_randomness.clone().unwrap_or_default(),
+        round.roll_scheme.to_string(),
+        rfc3339(round.started_at),
+        rfc3339(round.revealed_at),
+    ];
+
+    let bet_rows = round
+        .bets
+        .iter()
+        .map(|bet| {
+            vec![
+                bet.payment_hash.clone(),
+                bet.roller.clone(),
+                bet.index.to_string(),
+                bet.multiplier_note_id.clone(),
+                optional(bet.amount_msat),
+                bet.bet_state.to_string(),
+                optional(bet.roll),
+                optional(bet.win),
+                rfc3339(Some(bet.bet_timestamp)),
+                rfc3339(bet.settled_at),
+                rfc3339(bet.paid_out_at),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let bet_rows = if bet_rows.is_empty() {
+        vec![vec![String::new(); 11]]
+    } else {
+        bet_rows
+    };
+
+    bet_rows
+        .into_iter()
+        .map(|bet_fields| {
+            let mut row = round_fields
+                .iter()
+                .chain(bet_fields.iter())
+                .map(|field| csv_field(field))
+                .collect::<Vec<_>>()
+                .join(",");
+            row.push('\n');
+            row
+        })
+        .collect()
+}
+
+/// Quote the `field` if it would otherwise break the CSV row, e.g. a game name with a comma.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn rfc3339(time: Option<OffsetDateTime>) -> String {
+    time.and_then(|time| time.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn rounds_are_exported_with_one_csv_row_per_bet() {
+        let bet = |index, roll: Option<u32>| ExportedBet {
+            payment_hash: "hash".to_string(),
+            roller: "npub1roller".to_string(),
+            index,
+            multiplier_note_id: "note1multiplier".to_string(),
+            amount_msat: Some(1_000),
+            bet_state: BetState::Loser,
+            roll,
+            win: roll.map(|_| false),
+            bet_timestamp: datetime!(2024-09-01 12:00 UTC),
+            settled_at: None,
+            paid_out_at: None,
+        };
+        let mut round = ExportedRound {
+            game: "dice, high stakes".to_string(),
+            nonce_commitment_note_id: EventId::all_zeros(),
+            nonce: "01".repeat(32),
+            beacon_round: None,
+            beacon_randomness: None,
+            roll_scheme: RollScheme::V2,
+            started_at: Some(datetime!(2024-09-01 11:00 UTC)),
+            revealed_at: None,
+            bets: vec![bet(0, Some(4_242)), bet(1, None)],
+        };
+
+        let rows = csv_rows(&round);
+        let rows = rows.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        for row in &rows {
+            assert!(row.starts_with("\"dice, high stakes\","));
+        }
+        assert!(rows[0].ends_with(
+            ",hash,npub1roller,0,note1multiplier,1000,Loser,4242,false,2024-09-01T12:00:00Z,,"
+        ));
+        assert!(rows[1].contains(",1,note1multiplier,1000,Loser,,,"));
+
+        // One more comma than separators, for the one in the quoted game name.
+        let columns = CSV_HEADER.trim_end().split(',').count();
+        assert_eq!(rows[0].matches(',').count(), columns);
+
+        // Rounds without bets still show up.
+        round.bets.clear();
+        let rows = csv_rows(&round);
+        assert_eq!(rows.lines().count(), 1);
+        assert!(rows.ends_with(&format!("2024-09-01T11:00:00Z{}\n", ",".repeat(12))));
+    }
+}
@@ -0,0 +1,305 @@
+//! Several independent games can be hosted by one process. Each game has its own keys, multiplier
+//! notes and LNURL identities, but they all share the LND node and the database.
+
+use crate::multiplier::MultiplierPins;
+use crate::multiplier::Multipliers;
+use crate::MAIN_KEY_NAME;
+use crate::NONCE_KEY_NAME;
+use crate::SOCIAL_KEY_NAME;
+use anyhow::bail;
+use nostr::Keys;
+use nostr_sdk::Client;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// The name of the game configured with `--multipliers-file`.
+///
+/// Rounds and zaps from before we hosted several games belong to it.
+pub const DEFAULT_GAME: &str = "main";
+
+/// The schedule of the rounds of a game lasting `expire_nonce_after_secs`.
+///
+/// Rounds and multiplier notes from before games ran several schedules belong to it.
+pub const DEFAULT_SCHEDULE: &str = "default";
+
+/// Rounds a game runs one after the other, side by side with the rounds of its other schedules,
+/// e.g. to take bets on some multiplier notes in rounds of a minute and on others in rounds of an
+/// hour.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundSchedule {
+    pub name: String,
+    /// How long each round takes bets.
+    pub expire_after_secs: u64,
+}
+
+/// An additional game, configured as `<name>=<multipliers file>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameConfig {
+    pub name: String,
+    pub multipliers_file: String,
+}
+
+impl FromStr for GameConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, multipliers_file) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected <name>=<multipliers file>, got {s}"))?;
+
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(format!(
+                "Game name must only contain lowercase letters, digits and dashes, got {name}"
+            ));
+        }
+
+        if [MAIN_KEY_NAME, NONCE_KEY_NAME, SOCIAL_KEY_NAME].contains(&name) {
+            return Err(format!("Game name {name} is reserved"));
+        }
+
+        if multipliers_file.is_empty() {
+            return Err(format!("Missing multipliers file for game {name}"));
+        }
+
+        Ok(GameConfig {
+            name: name.to_string(),
+            multipliers_file: multipliers_file.to_string(),
+        })
+    }
+}
+
+/// The accounts of a game.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// Posts the multiplier notes and takes the bets.
+    Main,
+    /// Posts the nonce notes.
+    Nonce,
+    /// Posts game unrelated notes.
+    Social,
+}
+
+pub const ROLES: [Role; 3] = [Role::Main, Role::Nonce, Role::Social];
+
+#[derive(Clone)]
+pub struct Game {
+    pub name: String,
+    /// The keys for the account posting the multiplier notes
+    pub main_keys: Keys,
+    /// The keys for the account posting the nonce notes
+    pub nonce_keys: Keys,
+    /// The keys for a social media account posting game unrelated posts
+    pub social_keys: Keys,
+    /// Signs with the `main_keys`, e.g. when zapping winners their payout.
+    pub client: Client,
+    pub multipliers: Multipliers,
+    pub multiplier_pins: MultiplierPins,
+    /// The schedules of rounds this game runs side by side, the [`DEFAULT_SCHEDULE`] first. Bets
+    /// on a multiplier note go into the running round of its schedule.
+    pub schedules: Vec<RoundSchedule>,
+}
+
+impl Game {
+    /// The name of the account with the `role`, as used for its LNURL and NIP-05 identity.
+    ///
+    /// The default game keeps the names it had before we hosted several games. Other games are
+    /// identified by their name, e.g. `halloween`, `halloween-nonce` and `halloween-social`.
+    pub fn identity(&self, role: Role) -> String {
+        let game = &self.name;
+
+        match (game == DEFAULT_GAME, role) {
+            (true, Role::Main) => MAIN_KEY_NAME.to_string(),
+            (true, Role::Nonce) => NONCE_KEY_NAME.to_string(),
+            (true, Role::Social) => SOCIAL_KEY_NAME.to_string(),
+            (false, Role::Main) => game.to_string(),
+            (false, Role::Nonce) => format!("{game}-{NONCE_KEY_NAME}"),
+            (false, Role::Social) => format!("{game}-{SOCIAL_KEY_NAME}"),
+        }
+    }
+
+    pub fn schedule(&self, name: &str) -> Option<&RoundSchedule> {
+        self.schedules.iter().find(|schedule| schedule.name == name)
+    }
+
+    pub fn keys(&self, role: Role) -> &Keys {
+        match role {
+            Role::Main => &self.main_keys,
+            Role::Nonce => &self.nonce_keys,
+            Role::Social => &self.social_keys,
+        }
+    }
+}
+
+/// All the games we host. The first one is the [`DEFAULT_GAME`].
+#[derive(Clone)]
+pub struct Games(Vec<Game>);
+
+impl Games {
+    pub fn new(games: Vec<Game>) -> anyhow::Result<Self> {
+        match games.first() {
+            Some(game) if game.name == DEFAULT_GAME => {}
+            _ => bail!("The {DEFAULT_GAME} game must come first"),
+        }
+
+        for game in games.iter() {
+            match game.schedules.first() {
+                Some(schedule) if schedule.name == DEFAULT_SCHEDULE => {}
+                _ => bail!(
+                    "The {DEFAULT_SCHEDULE} schedule of game {} must come first",
+                    game.name
+                ),
+            }
+
+            let mut names = HashSet::new();
+            for schedule in game.schedules.iter() {
+                if !names.insert(schedule.name.as_str()) {
+                    bail!("Game {} has two {} schedules", game.name, schedule.name);
+                }
+            }
+
+            for note in game.multipliers.0.iter() {
+                if game.schedule(&note.schedule).is_none() {
+                    bail!(
+                        "Multiplier note {} of game {} has unknown schedule {}",
+                        note.note_id,
+                        game.name,
+                        note.schedule
+                    );
+                }
+            }
+        }
+
+        let mut identities = HashSet::new();
+        for game in games.iter() {
+            for role in ROLES {
+                let identity = game.identity(role);
+                if !identities.insert(identity.clone()) {
+                    bail!("Identity {identity} of game {} is already taken", game.name);
+                }
+            }
+        }
+
+        Ok(Self(games))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Game> {
+        self.0.iter()
+    }
+
+    pub fn default_game(&self) -> &Game {
+        &self.0[0]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Game> {
+        self.0.iter().find(|game| game.name == name)
+    }
+
+    /// The game and role of the account with the LNURL or NIP-05 `identity`.
+    pub fn find_identity(&self, identity: &str) -> Option<(&Game, Role)> {
+        self.0.iter().find_map(|game| {
+            ROLES
+                .into_iter()
+                .find(|role| game.identity(*role) == identity)
+                .map(|role| (game, role))
+        })
+    }
+}
+
+/// A schedule of rounds taking bets for `expire_after_secs`.
+#[cfg(test)]
+pub fn test_schedule(name: &str, expire_after_secs: u64) -> RoundSchedule {
+    RoundSchedule {
+        name: name.to_string(),
+        expire_after_secs,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multiplier::test_multiplier_note;
+    use crate::multiplier::MultiplierNote;
+
+    fn game(name: &str) -> Game {
+        let main_keys = Keys::generate();
+
+        Game {
+            name: name.to_string(),
+            client: Client::new(&main_keys),
+            main_keys,
+            nonce_keys: Keys::generate(),
+            social_keys: Keys::generate(),
+            multipliers: Multipliers(Vec::new()),
+            multiplier_pins: MultiplierPins::default(),
+            schedules: vec![test_schedule(DEFAULT_SCHEDULE, 60)],
+        }
+    }
+
+    #[test]
+    fn parses_game_config() {
+        assert_eq!(
+            "halloween=/data/halloween.yml".parse::<GameConfig>(),
+            Ok(GameConfig {
+                name: "halloween".to_string(),
+                multipliers_file: "/data/halloween.yml".to_string(),
+            })
+        );
+
+        assert!("halloween".parse::<GameConfig>().is_err());
+        assert!("Halloween=/data/halloween.yml"
+            .parse::<GameConfig>()
+            .is_err());
+        assert!("nonce=/data/nonce.yml".parse::<GameConfig>().is_err());
+        assert!("halloween=".parse::<GameConfig>().is_err());
+    }
+
+    #[test]
+    fn finds_the_game_of_an_identity() {
+        let games = Games::new(vec![game(DEFAULT_GAME), game("halloween")]).unwrap();
+
+        let (game, role) = games.find_identity("nonce").unwrap();
+        assert_eq!((game.name.as_str(), role), (DEFAULT_GAME, Role::Nonce));
+
+        let (game, role) = games.find_identity("halloween").unwrap();
+        assert_eq!((game.name.as_str(), role), ("halloween", Role::Main));
+
+        let (game, role) = games.find_identity("halloween-social").unwrap();
+        assert_eq!((game.name.as_str(), role), ("halloween", Role::Social));
+
+        assert!(games.find_identity("christmas").is_none());
+    }
+
+    #[test]
+    fn multiplier_notes_go_into_rounds_of_a_schedule_of_their_game() {
+        let mut halloween = game("halloween");
+        halloween.multipliers = Multipliers(vec![MultiplierNote {
+            schedule: "fast".to_string(),
+            ..test_multiplier_note("note1fast")
+        }]);
+        assert!(Games::new(vec![game(DEFAULT_GAME), halloween.clone()]).is_err());
+
+        halloween.schedules.push(test_schedule("fast", 10));
+        let games = Games::new(vec![game(DEFAULT_GAME), halloween.clone()]).unwrap();
+        let halloween = games.get("halloween").unwrap();
+        assert_eq!(halloween.schedule("fast").unwrap().expire_after_secs, 10);
+        assert!(halloween.schedule("slow").is_none());
+
+        let mut halloween = halloween.clone();
+        halloween.schedules.reverse();
+        assert!(Games::new(vec![game(DEFAULT_GAME), halloween.clone()]).is_err());
+
+        halloween.schedules = vec![test_schedule(DEFAULT_SCHEDULE, 60); 2];
+        assert!(Games::new(vec![game(DEFAULT_GAME), halloween]).is_err());
+    }
+
+    #[test]
+    fn identities_must_be_unique() {
+        // `foo-nonce` would be the main account of one and the nonce account of the other game.
+        assert!(Games::new(vec![game(DEFAULT_GAME), game("foo"), game("foo-nonce")]).is_err());
+        assert!(Games::new(vec![game("foo")]).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::db::get_committed_bets;
+use crate::db::get_owed_payouts;
+use crate::multiplier::Multiplier;
+use crate::payouts::calculate_price_money;
+use crate::payouts::PayoutRounding;
+use crate::route_error::RouteError;
+use anyhow::bail;
+use anyhow::Context;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+use tonic_openssl_lnd::lnrpc::ChannelBalanceRequest;
+use tonic_openssl_lnd::LndLightningClient;
+
+/// Our outbound liquidity, as LND last reported it, so that we do not have to ask for every bet.
+#[derive(Clone, Debug)]
+pub struct HouseBalanceCache {
+    ttl: Duration,
+    outbound_sat: Arc<RwLock<Option<(Instant, u64)>>>,
+}
+
+impl HouseBalanceCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            outbound_sat: Arc::default(),
+        }
+    }
+
+    fn get(&self, now: Instant) -> Option<u64> {
+        let cached = *self.outbound_sat.read().expect("not poisoned");
+
+        cached
+            .filter(|(fetched_at, _)| now.duration_since(*fetched_at) < self.ttl)
+            .map(|(_, outbound_sat)| outbound_sat)
+    }
+
+    fn insert(&self, outbound_sat: u64, now: Instant) {
+        *self.outbound_sat.write().expect("not poisoned") = Some((now, outbound_sat));
+    }
+}
+
+/// Check that our channels could pay out the bet of `amount_msats` on the `multiplier` if it won,
+/// on top of what we already owe or might owe for other bets, keeping `reserve_sat` of outbound
+/// liquidity untouched.
+pub async fn check_house_balance(
+    db: &SqlitePool,
+    lnd: &mut LndLightningClient,
+    cache: &HouseBalanceCache,
+    reserve_sat: u64,
+    amount_msats: u64,
+    multiplier: &Multiplier,
+    payout_rounding: PayoutRounding,
+) -> anyhow::Result<()> {
+    let outbound_sat = match cache.get(Instant::now()) {
+        Some(outbound_sat) => outbound_sat,
+        None => {
+            let balance = lnd
+                .channel_balance(ChannelBalanceRequest {})
+                .await
+                .context("Failed to get channel balance")?
+                .into_inner();
+            let outbound_sat = balance
+                .local_balance
+                .map_or(balance.balance.max(0) as u64, |local| local.sat);
+
+            cache.insert(outbound_sat, Instant::now());
+
+            outbound_sat
+        }
+    };
+
+    // Bets which are paid but not settled may still win, and winners may not be paid yet.
+    let committed_sat = get_committed_bets(db)
+        .await?
+        .into_iter()
+        .chain(get_owed_payouts(db).await?)
+        .map(|bet| {
+            calculate_price_money(
+                bet.zap_amount_msat,
+                bet.multiplier.get_multiplier(),
+                payout_rounding,
+            )
+        })
+        .sum();
+
+    let payout_sat =
+        calculate_price_money(amount_msats, multiplier.get_multiplier(), payout_rounding);
+    if !covers_payout(outbound_sat, reserve_sat, committed_sat, payout_sat) {
+        tracing::warn!(
+            outbound_sat,
+            reserve_sat,
+            committed_sat,
+            payout_sat,
+            "Rejecting bet we could not pay out"
+        );
+
+        bail!(RouteError::HouseBalanceTooLow { payout_sat });
+    }
+
+    Ok(())
+}
+
+fn covers_payout(outbound_sat: u64, reserve_sat: u64, committed_sat: u64, payout_sat: u64) -> bool {
+    committed_sat.saturating_add(payout_sat) <= outbound_sat.saturating_sub(reserve_sat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payouts_must_fit_into_the_outbound_liquidity_above_the_reserve() {
+        assert!(covers_payout(100_000, 10_000, 0, 90_000));
+        assert!(!covers_payout(100_000, 10_000, 0, 90_001));
+        assert!(covers_payout(100_000, 0, 0, 100_000));
+        assert!(!covers_payout(5_000, 10_000, 0, 1));
+    }
+
+    #[test]
+    fn payouts_must_fit_next_to_the_committed_payouts() {
+        assert!(covers_payout(100_000, 10_000, 40_000, 50_000));
+        assert!(!covers_payout(100_000, 10_000, 40_000, 50_001));
+        assert!(!covers_payout(100_000, 0, u64::MAX, 1));
+    }
+
+    #[test]
+    fn cached_balances_expire() {
+        let cache = HouseBalanceCache::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        assert_eq!(cache.get(now), None);
+
+        cache.insert(50_000, now);
+        assert_eq!(cache.get(now + Duration::from_secs(9)), Some(50_000));
+        assert_eq!(cache.get(now + Duration::from_secs(10)), None);
+    }
+}
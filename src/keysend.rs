@@ -0,0 +1,135 @@
+use crate::public_http;
+use anyhow::bail;
+use anyhow::Context;
+use bitcoin::secp256k1::PublicKey;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+const KEYSEND_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The node a lightning address accepts keysend payments on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeysendTarget {
+    pub pubkey: PublicKey,
+    /// TLV records the node needs to route the payment to the owner of the lightning address.
+    pub custom_records: HashMap<u64, Vec<u8>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct KeysendResponse {
+    status: String,
+    tag: String,
+    pubkey: String,
+    #[serde(default, rename = "customData")]
+    custom_data: Vec<CustomData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CustomData {
+    #[serde(rename = "customKey")]
+    custom_key: String,
+    #[serde(rename = "customValue")]
+    custom_value: String,
+}
+
+/// Look up the keysend node behind the lightning address `lud16`, via its
+/// `/.well-known/keysend/<name>` endpoint.
+pub async fn fetch_keysend_target(lud16: String) -> anyhow::Result<KeysendTarget> {
+    let url = keysend_url(&lud16)?;
+
+    let response = tokio::task::spawn_blocking(move || {
+        public_http::get(&url, KEYSEND_REQUEST_TIMEOUT)?
+            .into_json::<KeysendResponse>()
+            .context("Invalid keysend response")
+    })
+    .await??;
+
+    parse_keysend_response(response)
+}
+
+fn keysend_url(lud16: &str) -> anyhow::Result<String> {
+    let (name, domain) = lud16
+        .split_once('@')
+        .with_context(|| format!("Invalid lightning address: {lud16}"))?;
+
+    Ok(format!("https://{domain}/.well-known/keysend/{name}"))
+}
+
+fn parse_keysend_response(response: KeysendResponse) -> anyhow::Result<KeysendTarget> {
+    if response.status != "OK" || response.tag != "keysend" {
+        bail!("Keysend is not supported: {response:?}");
+    }
+
+    let pubkey = PublicKey::from_str(&response.pubkey).context("Invalid keysend pubkey")?;
+
+    let custom_records = response
+        .custom_data
+        .into_iter()
+        .map(|data| {
+            let key = data
+                .custom_key
+                .parse()
+                .with_context(|| format!("Invalid custom record key: {}", data.custom_key))?;
+
+            Ok((key, data.custom_value.into_bytes()))
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(KeysendTarget {
+        pubkey,
+        custom_records,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keysend_response() {
+        let response = serde_json::from_str(
+            r#"{
+                "status": "OK",
+                "tag": "keysend",
+                "pubkey": "030a58b8653d32b99200a2334cfe913e51dc7d155aa0116c176657a4f1722677a3",
+                "customData": [{ "customKey": "696969", "customValue": "017rsl75kNnSke4mMHYE" }]
+            }"#,
+        )
+        .unwrap();
+
+        let target = parse_keysend_response(response).unwrap();
+
+        assert_eq!(
+            target.pubkey.to_string(),
+            "030a58b8653d32b99200a2334cfe913e51dc7d155aa0116c176657a4f1722677a3"
+        );
+        assert_eq!(
+            target.custom_records,
+            HashMap::from([(696969, b"017rsl75kNnSke4mMHYE".to_vec())])
+        );
+        assert_eq!(
+            keysend_url("alice@getalby.com").unwrap(),
+            "https://getalby.com/.well-known/keysend/alice"
+        );
+    }
+
+    #[tokio::test]
+    async fn keysend_nodes_are_not_looked_up_on_our_network() {
+        assert!(fetch_keysend_target("alice@127.0.0.1".to_string())
+            .await
+            .is_err());
+        assert!(fetch_keysend_target("alice@localhost:8080".to_string())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_lightning_addresses_without_keysend() {
+        let response =
+            serde_json::from_str(r#"{ "status": "ERROR", "tag": "", "pubkey": "" }"#).unwrap();
+
+        assert!(parse_keysend_response(response).is_err());
+    }
+}
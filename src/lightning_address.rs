@@ -0,0 +1,150 @@
+use crate::public_http;
+use crate::route_error::RouteError;
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use lnurl::lnurl::LnUrl;
+use nostr::Metadata;
+use nostr::PublicKey;
+use nostr_sdk::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+
+const LNURL_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rollers whose lightning address we resolved recently, so that we do not have to look it up for
+/// every bet.
+#[derive(Clone, Debug)]
+pub struct LightningAddressCache {
+    ttl: Duration,
+    resolved: Arc<RwLock<HashMap<PublicKey, Instant>>>,
+}
+
+impl LightningAddressCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            resolved: Arc::default(),
+        }
+    }
+
+    fn is_resolved(&self, roller: &PublicKey, now: Instant) -> bool {
+        self.resolved
+            .read()
+            .expect("not poisoned")
+            .get(roller)
+            .is_some_and(|resolved_at| now.duration_since(*resolved_at) < self.ttl)
+    }
+
+    fn insert(&self, roller: PublicKey, now: Instant) {
+        let mut resolved = self.resolved.write().expect("not poisoned");
+
+        resolved.retain(|_, resolved_at| now.duration_since(*resolved_at) < self.ttl);
+        resolved.insert(roller, now);
+    }
+}
+
+#[derive(Deserialize)]
+struct LnurlPayResponse {
+    tag: String,
+}
+
+/// Check that we will be able to pay out the `roller`, i.e. that their profile has a lightning
+/// address with a working LNURL pay endpoint.
+pub async fn check_lightning_address(
+    client: &Client,
+    cache: &LightningAddressCache,
+    roller: PublicKey,
+) -> anyhow::Result<()> {
+    if cache.is_resolved(&roller, Instant::now()) {
+        return Ok(());
+    }
+
+    let metadata = client
+        .metadata(roller)
+        .await
+        .context("Could not find profile")
+        .context(RouteError::MissingLightningAddress)?;
+
+    let url = lnurlp_url(&metadata).context(RouteError::MissingLightningAddress)?;
+
+    let response = tokio::task::spawn_blocking(move || {
+        public_http::get(&url, LNURL_REQUEST_TIMEOUT)?
+            .into_json::<LnurlPayResponse>()
+            .context("Invalid LNURL pay response")
+    })
+    .await?
+    .context(RouteError::BrokenLightningAddress)?;
+
+    if response.tag != "payRequest" {
+        return Err(anyhow!("LNURL does not accept payments: {}", response.tag)
+            .context(RouteError::BrokenLightningAddress));
+    }
+
+    cache.insert(roller, Instant::now());
+
+    Ok(())
+}
+
+/// The LNURL pay endpoint of the lightning address (`lud16`) or LNURL (`lud06`) in a profile.
+fn lnurlp_url(metadata: &Metadata) -> anyhow::Result<String> {
+    if let Some(lud16) = &metadata.lud16 {
+        let (name, domain) = lud16
+            .split_once('@')
+            .with_context(|| format!("Invalid lightning address in your profile: {lud16}"))?;
+
+        return Ok(format!("https://{domain}/.well-known/lnurlp/{name}"));
+    }
+
+    if let Some(lud06) = &metadata.lud06 {
+        let lnurl =
+            LnUrl::from_str(lud06).map_err(|e| anyhow!("Invalid LNURL in your profile: {e}"))?;
+
+        return Ok(lnurl.url);
+    }
+
+    bail!("Your profile has no lightning address. Please set one for us to pay out to");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+
+    #[test]
+    fn lnurlp_url_from_lightning_address_or_lnurl() {
+        let metadata = Metadata::new().lud16("alice@example.com");
+        assert_eq!(
+            lnurlp_url(&metadata).unwrap(),
+            "https://example.com/.well-known/lnurlp/alice"
+        );
+
+        let metadata = Metadata::new().lud06(
+            "LNURL1DP68GURN8GHJ7ETCV9KHQMR99E3K7MF09EMK2MRV944KUMMHDCHKCMN4WFK8QTMPD35KXEG9SAEVQ",
+        );
+        assert_eq!(
+            lnurlp_url(&metadata).unwrap(),
+            "https://example.com/.well-known/lnurlp/alice"
+        );
+
+        assert!(lnurlp_url(&Metadata::new()).is_err());
+    }
+
+    #[test]
+    fn resolved_lightning_addresses_expire() {
+        let cache = LightningAddressCache::new(Duration::from_secs(60));
+        let roller = Keys::generate().public_key();
+        let now = Instant::now();
+
+        assert!(!cache.is_resolved(&roller, now));
+
+        cache.insert(roller, now);
+        assert!(cache.is_resolved(&roller, now + Duration::from_secs(59)));
+        assert!(!cache.is_resolved(&roller, now + Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,128 @@
+//! Keeps track of whether LND is reachable, so that we can stop taking bets and hold payouts while
+//! it is not.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio::sync::watch;
+use tonic_openssl_lnd::lnrpc::GetInfoRequest;
+use tonic_openssl_lnd::LndLightningClient;
+
+/// How often we check whether LND is reachable.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// LND is considered unreachable if it does not answer within this time.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct LndHealth {
+    connected: Arc<watch::Sender<bool>>,
+}
+
+impl LndHealth {
+    pub fn new(connected: bool) -> Self {
+        let (connected, _) = watch::channel(connected);
+
+        Self {
+            connected: Arc::new(connected),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.connected.send_if_modified(|current| {
+            let modified = *current != connected;
+            *current = connected;
+
+            modified
+        });
+    }
+
+    /// Wait until LND is reachable again after losing the connection to it.
+    ///
+    /// Never completes if the connection is not lost.
+    pub async fn reconnected(&self) {
+        let mut connected = self.connected.subscribe();
+
+        while *connected.borrow_and_update() {
+            if connected.changed().await.is_err() {
+                return;
+            }
+        }
+
+        while !*connected.borrow_and_update() {
+            if connected.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Regularly check whether LND is reachable and update the `health` accordingly.
+pub async fn monitor_lnd(
+    mut lnd: LndLightningClient,
+    health: LndHealth,
+    mut ctrl_c: broadcast::Receiver<()>,
+) {
+    loop {
+        let connected =
+            match tokio::time::timeout(CHECK_TIMEOUT, lnd.get_info(GetInfoRequest {})).await {
+                Ok(Ok(_)) => true,
+                Ok(Err(e)) => {
+                    tracing::debug!("LND health check failed: {e:#}");
+                    false
+                }
+                Err(_) => {
+                    tracing::debug!("LND health check timed out");
+                    false
+                }
+            };
+
+        match (health.is_connected(), connected) {
+            (true, false) => {
+                tracing::warn!("Lost connection to LND. Not taking bets and holding payouts")
+            }
+            (false, true) => tracing::info!("Reconnected to LND. Taking bets again"),
+            _ => {}
+        }
+        health.set_connected(connected);
+
+        select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => (),
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down LND health checks...");
+                return;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn notices_reconnection() {
+        let health = LndHealth::new(true);
+
+        let reconnected = tokio::spawn({
+            let health = health.clone();
+            async move { health.reconnected().await }
+        });
+
+        health.set_connected(false);
+        assert!(!health.is_connected());
+        tokio::task::yield_now().await;
+        assert!(!reconnected.is_finished());
+
+        health.set_connected(true);
+        tokio::time::timeout(Duration::from_secs(1), reconnected)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,25 +1,81 @@
+use crate::admin::get_admin_status;
+use crate::admin::post_admin_release;
+use crate::admin::post_admin_resolve;
+use crate::admin::post_admin_reveal;
+use crate::attestation::publish_fairness_attestations;
+use crate::beacon::RandomnessBeacon;
 use crate::config::*;
+use crate::db::RequestEventFormat;
+use crate::dm::DmSender;
+use crate::export::get_export_rounds;
+use crate::game::Game;
+use crate::game::GameConfig;
+use crate::game::Games;
+use crate::game::RoundSchedule;
+use crate::game::DEFAULT_GAME;
+use crate::game::DEFAULT_SCHEDULE;
+use crate::house_balance::HouseBalanceCache;
+use crate::lightning_address::LightningAddressCache;
+use crate::lnd_health::monitor_lnd;
+use crate::lnd_health::LndHealth;
+use crate::metrics::get_metrics;
+use crate::multiplier::fetch_multiplier_notes;
+use crate::multiplier::retract_multiplier_note;
+use crate::multiplier::watch_multiplier_notes;
+use crate::multiplier::BetDirection;
 use crate::multiplier::Multiplier;
 use crate::multiplier::MultiplierNote;
+use crate::multiplier::MultiplierPins;
 use crate::multiplier::Multipliers;
+use crate::multiplier::PayoutTiming;
 use crate::nonce::manage_nonces;
 use crate::payouts::retry_zaps;
+use crate::payouts::settle_revealed_rounds;
+use crate::payouts::PayoutRounding;
+use crate::payouts::Settlement;
+use crate::rate_limit::rate_limit_invoices;
+use crate::rate_limit::InvoiceRateLimiter;
+use crate::receipt_clients::disconnect_idle_receipt_clients;
+use crate::receipt_clients::ReceiptClients;
+use crate::relay_blacklist::forget_expired_blacklistings;
+use crate::relay_blacklist::RelayBlacklist;
+use crate::relay_list::publish_relay_lists;
+use crate::retention::archive_old_rounds;
+use crate::roll::RollScheme;
 use crate::routes::*;
+use crate::social_updates::parse_anonymous_rollers;
 use crate::social_updates::post_social_updates;
+use crate::social_updates::SummaryLimits;
+use crate::social_updates::SummaryTemplates;
+use crate::subscriber::publish_rolled_receipts;
 use crate::subscriber::start_invoice_subscription;
+use crate::subscriber::PaidInvoices;
+use crate::unpaid_bets::sweep_unpaid_bets;
+use crate::unpaid_bets::UnpaidBetSweeps;
 use crate::zapper::start_zapper;
 use crate::zapper::LndZapper;
+use crate::zapper::PaymentLimits;
+use crate::zapper::PaymentRetries;
 use anyhow::Context;
+use axum::body::Body;
 use axum::http;
 use axum::http::Method;
+use axum::http::Request;
 use axum::http::StatusCode;
 use axum::http::Uri;
+use axum::middleware;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
 use axum::routing::get;
+use axum::routing::post;
 use axum::Extension;
 use axum::Router;
 use clap::Parser;
 use nostr::prelude::ToBech32;
+use nostr::EventId;
 use nostr::Keys;
+use nostr::PublicKey;
 use nostr_sdk::Client;
 use nostr_sdk::Options;
 use serde::Deserialize;
@@ -28,32 +84,69 @@ use serde_json::from_reader;
 use serde_json::to_string;
 use sqlx::sqlite::SqliteConnectOptions;
 use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 use tokio::spawn;
 use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tonic_openssl_lnd::lnrpc::GetInfoRequest;
 use tonic_openssl_lnd::lnrpc::GetInfoResponse;
+use tonic_openssl_lnd::LndInvoicesClient;
 use tonic_openssl_lnd::LndLightningClient;
 use tonic_openssl_lnd::LndRouterClient;
 use tower_http::cors::Any;
 use tower_http::cors::CorsLayer;
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::timeout::TimeoutLayer;
 use tracing::level_filters::LevelFilter;
+use yaml_rust2::Yaml;
 use yaml_rust2::YamlLoader;
 
+mod admin;
+mod attestation;
+mod beacon;
 mod config;
 mod db;
+mod dm;
+mod export;
+mod game;
+mod house_balance;
+mod keysend;
+mod lightning_address;
+mod lnd_health;
 mod logger;
+mod metrics;
 mod multiplier;
 mod nonce;
 mod payouts;
+mod public_http;
+mod rate_limit;
+mod receipt_clients;
+mod reconcile;
+mod relay_blacklist;
+mod relay_list;
+mod retention;
+mod roll;
+mod route_error;
 mod routes;
 mod social_updates;
 mod subscriber;
+#[cfg(feature = "test-relay")]
+mod test_relay;
+mod unpaid_bets;
 mod utils;
 mod zapper;
 
@@ -66,18 +159,52 @@ pub struct State {
     pub db: SqlitePool,
     pub lightning_client: LndLightningClient,
     pub router_client: LndRouterClient,
-    /// The keys for the account posting the multiplier notes
-    pub main_keys: Keys,
-    /// The keys for the account posting the nonce notes
-    pub nonce_keys: Keys,
-    /// The keys for a social media account posting game unrelated posts
-    pub social_keys: Keys,
+    pub invoices_client: LndInvoicesClient,
+    pub games: Games,
+    /// Whether LND is reachable. We do not take bets while it is not.
+    pub lnd_health: LndHealth,
     pub domain: String,
     pub route_hints: bool,
-    pub client: Client,
-    pub multipliers: Multipliers,
-    pub relays: Vec<String>,
+    /// Returned to rollers who zap one of our round notes instead of a multiplier note
+    pub round_note_zap_message: String,
+    /// Our `nostr.json`, rendered for every name we answer to
+    pub nip05: Nip05Documents,
     pub reveal_nonce_after_secs: u64,
+    /// No bets are taken on a round this long before it expires
+    pub bet_cutoff_secs: u64,
+    /// Invoices of bets expire when their round is revealed, but after this long at the latest
+    pub max_invoice_expiry_secs: u64,
+    /// The roll derivation scheme used for new rounds
+    pub roll_scheme: RollScheme,
+    pub max_zap_memo_chars: usize,
+    pub max_committed_payout_sat: Option<u64>,
+    pub payout_rounding: PayoutRounding,
+    /// The memo of invoices for donations without a zap request
+    pub anon_donation_memo: String,
+    pub donation_comment_chars: usize,
+    /// Rollers whose lightning address we resolved recently
+    pub lightning_addresses: LightningAddressCache,
+    /// Our outbound liquidity, fetched recently
+    pub house_balance: HouseBalanceCache,
+    /// Outbound liquidity we do not commit to payouts
+    pub house_balance_reserve_sat: u64,
+    /// Rollers who asked not to be named in social updates
+    pub anonymous_rollers: Arc<HashSet<PublicKey>>,
+    /// Relays we do not publish zap receipts to, since they reject them
+    pub relay_blacklist: RelayBlacklist,
+    pub dms: DmSender,
+    /// Lets the settlement task of each game know that a round was revealed, by game name
+    pub revealed: Arc<HashMap<String, mpsc::UnboundedSender<EventId>>>,
+    pub beacon: Option<RandomnessBeacon>,
+    /// Required by the `/admin` routes, which are disabled without it
+    pub admin_token: Option<String>,
+    /// Wakes the zap retry tasks up when an operator released a queued payout
+    pub payouts_released: Arc<Notify>,
+    /// The amounts advertised to wallets paying each account
+    pub sendable: SendableLimits,
+    /// How the zap requests of new bets are stored
+    pub request_event_format: RequestEventFormat,
+    pub invoice_rate_limiter: InvoiceRateLimiter,
 }
 
 #[tokio::main]
@@ -86,7 +213,19 @@ async fn main() -> anyhow::Result<()> {
 
     logger::init_tracing(LevelFilter::DEBUG, config.json)?;
 
-    let relays = config.clone().relay;
+    #[allow(unused_mut)]
+    let mut relays = config.clone().relay;
+
+    // Kept alive for as long as we run.
+    #[cfg(feature = "test-relay")]
+    let _test_relay = if config.test_relay {
+        let test_relay = test_relay::TestRelay::start().await?;
+        relays = vec![test_relay.url()];
+
+        Some(test_relay)
+    } else {
+        None
+    };
 
     let mut lnd_client = tonic_openssl_lnd::connect(
         config.lnd_host.clone(),
@@ -127,115 +266,297 @@ async fn main() -> anyhow::Result<()> {
 
     sqlx::migrate!("./migrations").run(&db).await?;
 
-    let (main_keys_path, nonce_keys_path, social_keys_path) = {
-        let mut main_keys_path = path.clone();
-        main_keys_path.push("main-keys.json");
+    let backfilled = db::backfill_request_event_ids(&db).await?;
+    if backfilled > 0 {
+        tracing::info!(count = backfilled, "Backfilled zap request IDs");
+    }
+
+    if config.reconcile_invoices {
+        let discrepancies =
+            reconcile::reconcile_invoices(&db, &mut lnd_client.lightning().clone()).await?;
+
+        if discrepancies.is_empty() {
+            tracing::info!("All zaps match LND's invoices");
+        }
+        for discrepancy in discrepancies {
+            tracing::warn!("Discrepancy: {discrepancy}");
+        }
+
+        return Ok(());
+    }
 
-        let mut nonce_keys_path = path.clone();
-        nonce_keys_path.push("nonce-keys.json");
+    if config.reconcile_payouts {
+        let discrepancies = reconcile::reconcile_payouts(
+            &db,
+            &mut lnd_client.lightning().clone(),
+            config.payout_rounding,
+        )
+        .await?;
 
-        let mut social_keys_path = path.clone();
-        social_keys_path.push("social-keys.json");
+        if discrepancies.is_empty() {
+            tracing::info!("All payouts match LND's payments");
+        }
+        for discrepancy in discrepancies {
+            tracing::warn!("Payout discrepancy: {discrepancy}");
+        }
+
+        return Ok(());
+    }
 
-        (main_keys_path, nonce_keys_path, social_keys_path)
+    for payment_hash in config.release_queued_payouts.iter() {
+        if db::release_queued_payout(&db, payment_hash).await? {
+            tracing::info!(payment_hash, "Released queued payout");
+        } else {
+            tracing::warn!(payment_hash, "No queued payout to release");
+        }
+    }
+
+    let (ctrl_c_tx, mut ctrl_c_rx) = {
+        let (tx, rx) = broadcast::channel(1);
+        let tx_clone = tx.clone();
+        spawn(async move {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("failed to listen for Ctrl+C shutdown signal");
+            tracing::warn!("Ctrl-C pressed; sending stop");
+            tx_clone
+                .send(())
+                .expect("failed to send Ctrl+C signal via broadcast channel");
+        });
+        (tx, rx)
     };
 
-    let main_keys = get_keys(main_keys_path);
-    let nonce_keys = get_keys(nonce_keys_path);
-    let social_keys = get_keys(social_keys_path);
+    let beacon = match &config.randomness_beacon {
+        Some(url) => Some(
+            RandomnessBeacon::connect(url.clone())
+                .await
+                .context("Failed to connect to randomness beacon")?,
+        ),
+        None => None,
+    };
 
-    let options = Options::default();
-    // Create new client
-    let client = Client::with_opts(
-        &main_keys,
-        options
-            .wait_for_send(true)
-            .send_timeout(Some(Duration::from_secs(20))),
+    let (stop_zapper, stop_zapper_rx) = oneshot::channel();
+    let (sender, zapper) = start_zapper(
+        lnd_client.router().clone(),
+        PaymentRetries {
+            max_attempts: config.payout_attempts,
+            max_fee_sat: config.payout_max_fee_sat,
+        },
+        stop_zapper_rx,
     );
-    client.add_relays(relays.clone()).await?;
+    let lnd_zapper = LndZapper {
+        sender,
+        limits: PaymentLimits {
+            min_fee_sat: config.payout_min_fee_sat,
+            fee_ppm: config.payout_fee_ppm,
+            timeout_seconds: config.payout_timeout_secs,
+        },
+        dry_run: config.dry_run,
+    };
+    if config.dry_run {
+        tracing::warn!("Dry run: winners are not paid out");
+    }
 
-    let sender = start_zapper(lnd_client.router().clone());
-    let lnd_zapper = LndZapper { sender };
+    let game_configs = [GameConfig {
+        name: DEFAULT_GAME.to_string(),
+        multipliers_file: config.multipliers_file.clone(),
+    }]
+    .into_iter()
+    .chain(config.games.clone());
 
-    client.set_zapper(lnd_zapper).await;
-    client.connect().await;
+    let mut games = Vec::new();
+    for game_config in game_configs {
+        let mut game = load_game(
+            &path,
+            game_config,
+            &relays,
+            Duration::from_secs(config.relay_send_timeout_secs),
+            lnd_zapper.clone(),
+            config.expire_nonce_after_secs as u64,
+        )
+        .await?;
 
-    let multipliers = {
-        let path = PathBuf::from(&config.multipliers_file);
-        let mut file = File::open(path).expect("Failed to open multiplier config file");
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .expect("Failed to read multiplier config file");
+        // Notes stay retracted after a restart, whether the flag is still given or not.
+        let retractions = db::get_multiplier_note_retractions(&db, &game.name).await?;
+        for (note_id, retracted_at) in retractions.iter() {
+            if game.multipliers.get_multiplier_note(note_id).is_some() {
+                game.multipliers.retract(note_id, *retracted_at)?;
+            }
+        }
 
-        let docs =
-            YamlLoader::load_from_str(&contents).expect("Failed to parse multiplier config file");
+        for note_id in config.retract_multiplier_notes.iter() {
+            if game.multipliers.get_multiplier_note(note_id).is_none() {
+                continue;
+            }
+            if retractions
+                .iter()
+                .any(|(retracted, _)| retracted == note_id)
+            {
+                tracing::debug!(game = game.name, note_id, "Multiplier note is retracted");
+                continue;
+            }
 
-        let doc = &docs[0];
+            let event_id = retract_multiplier_note(
+                &game.client,
+                &game.main_keys,
+                &mut game.multipliers,
+                note_id,
+            )
+            .await?;
+            db::insert_multiplier_note_retraction(
+                &db,
+                &game.name,
+                note_id,
+                OffsetDateTime::now_utc(),
+            )
+            .await?;
 
-        // TODO: We should verify that the provided note IDs exist, parse the contents and ensure
-        // that they represent their multiplier faithfully.
+            tracing::warn!(
+                game = game.name,
+                note_id,
+                %event_id,
+                "Retracted multiplier note"
+            );
+        }
 
-        Multipliers([
-            MultiplierNote {
-                multiplier: Multiplier::X1_05,
-                note_id: doc["x1_05"].clone().into_string().expect("1_05"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X1_1,
-                note_id: doc["x1_1"].clone().into_string().expect("1_1"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X1_33,
-                note_id: doc["x1_33"].clone().into_string().expect("1_33"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X1_5,
-                note_id: doc["x1_5"].clone().into_string().expect("1_5"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X2,
-                note_id: doc["x2"].clone().into_string().expect("2"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X3,
-                note_id: doc["x3"].clone().into_string().expect("3"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X10,
-                note_id: doc["x10"].clone().into_string().expect("10"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X25,
-                note_id: doc["x25"].clone().into_string().expect("25"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X50,
-                note_id: doc["x50"].clone().into_string().expect("50"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X100,
-                note_id: doc["x100"].clone().into_string().expect("100"),
-            },
-            MultiplierNote {
-                multiplier: Multiplier::X1000,
-                note_id: doc["x1000"].clone().into_string().expect("1000"),
-            },
-        ])
+        games.push(game);
+    }
+
+    for note_id in config.retract_multiplier_notes.iter() {
+        if !games
+            .iter()
+            .any(|game| game.multipliers.get_multiplier_note(note_id).is_some())
+        {
+            anyhow::bail!("Cannot retract unknown multiplier note {note_id}");
+        }
+    }
+    let games = Games::new(games)?;
+
+    for game in games.iter() {
+        for schedule in game.schedules.iter() {
+            if config.bet_cutoff_secs >= schedule.expire_after_secs {
+                anyhow::bail!(
+                    "Rounds of schedule {} of game {} would never take bets: --bet-cutoff-secs \
+                     ({}) must be smaller than their duration ({} seconds)",
+                    schedule.name,
+                    game.name,
+                    config.bet_cutoff_secs,
+                    schedule.expire_after_secs
+                );
+            }
+        }
+    }
+
+    let sendable = SendableLimits {
+        main_min_msat: config.main_min_sendable_msat,
+        main_max_msat: config.main_max_sendable_msat,
+        nonce: Sendable {
+            min_msat: config.nonce_min_sendable_msat,
+            max_msat: config.nonce_max_sendable_msat,
+        },
+        social: Sendable {
+            min_msat: config.social_min_sendable_msat,
+            max_msat: config.social_max_sendable_msat,
+        },
+    };
+    sendable.validate(&games)?;
+
+    let relay_hints = |hints: &Vec<String>| {
+        if hints.is_empty() {
+            relays.clone()
+        } else {
+            hints.clone()
+        }
+    };
+    let relay_hints = RelayHints {
+        main: relay_hints(&config.main_relay_hints),
+        nonce: relay_hints(&config.nonce_relay_hints),
+        social: relay_hints(&config.social_relay_hints),
+    };
+
+    let nip05 = Nip05Documents::new(&games, config.roll_scheme, &relay_hints)?;
+
+    // Relays can be slow, so we do not hold up starting the server for the relay lists.
+    spawn({
+        let games = games.clone();
+        let relay_hints = relay_hints.clone();
+        async move { publish_relay_lists(&games, &relay_hints).await }
+    });
+
+    // We just talked to LND, so we start out connected.
+    let lnd_health = LndHealth::new(true);
+
+    let anonymous_rollers = match &config.anonymous_rollers_file {
+        Some(path) => parse_anonymous_rollers(
+            &std::fs::read_to_string(path).context("Failed to read anonymous rollers file")?,
+        )?,
+        None => HashSet::new(),
+    };
+    let summary_templates = match &config.social_updates_templates_file {
+        Some(path) => SummaryTemplates::parse(
+            &std::fs::read_to_string(path).context("Failed to read social updates templates")?,
+        )?,
+        None => SummaryTemplates::default(),
     };
+    let relay_blacklist = RelayBlacklist::load(
+        db.clone(),
+        Duration::from_secs(config.relay_blacklist_cooldown_secs),
+    )
+    .await?;
+
+    let mut revealed = HashMap::new();
+    let mut revealed_rxs = HashMap::new();
+    for game in games.iter() {
+        let (revealed_tx, revealed_rx) = mpsc::unbounded_channel();
+        revealed.insert(game.name.clone(), revealed_tx);
+        revealed_rxs.insert(game.name.clone(), revealed_rx);
+    }
+
+    let dms = DmSender::new(config.dm_protocol);
 
     let state = State {
         db,
         lightning_client: lnd_client.lightning().clone(),
         router_client: lnd_client.router().clone(),
-        main_keys: main_keys.clone(),
-        nonce_keys: nonce_keys.clone(),
-        social_keys: social_keys.clone(),
+        invoices_client: lnd_client.invoices().clone(),
+        games: games.clone(),
+        lnd_health: lnd_health.clone(),
         domain: config.domain.clone(),
         route_hints: config.route_hints,
-        client: client.clone(),
-        multipliers: multipliers.clone(),
-        relays,
+        round_note_zap_message: config.round_note_zap_message.clone(),
+        nip05,
         reveal_nonce_after_secs: config.reveal_nonce_after_secs as u64,
+        bet_cutoff_secs: config.bet_cutoff_secs,
+        max_invoice_expiry_secs: config.max_invoice_expiry_secs,
+        roll_scheme: config.roll_scheme,
+        max_zap_memo_chars: config.max_zap_memo_chars,
+        max_committed_payout_sat: config.max_committed_payout_sat,
+        payout_rounding: config.payout_rounding,
+        anon_donation_memo: config.anon_donation_memo.clone(),
+        donation_comment_chars: config.donation_comment_chars,
+        lightning_addresses: LightningAddressCache::new(Duration::from_secs(
+            config.lightning_address_cache_secs,
+        )),
+        house_balance: HouseBalanceCache::new(Duration::from_secs(config.house_balance_cache_secs)),
+        house_balance_reserve_sat: config.house_balance_reserve_sat,
+        anonymous_rollers: Arc::new(anonymous_rollers),
+        relay_blacklist,
+        dms: dms.clone(),
+        revealed: Arc::new(revealed),
+        beacon: beacon.clone(),
+        admin_token: config.admin_token.clone(),
+        payouts_released: Arc::new(Notify::new()),
+        sendable,
+        request_event_format: if config.compact_request_events {
+            RequestEventFormat::Compact
+        } else {
+            RequestEventFormat::Full
+        },
+        invoice_rate_limiter: InvoiceRateLimiter::new(
+            config.invoices_per_minute_per_roller,
+            config.invoices_per_minute_per_ip,
+            config.trust_forwarded_for,
+        ),
     };
 
     let addr: std::net::SocketAddr = format!("{}:{}", config.bind, config.port)
@@ -244,100 +565,714 @@ async fn main() -> anyhow::Result<()> {
 
     tracing::info!("Webserver running on http://{}", addr);
 
-    let server_router = Router::new()
-        .route("/get-invoice-for-game/:hash", get(get_invoice_for_game))
-        .route("/get-invoice-for-zap/:hash", get(get_invoice_for_zap))
-        .route("/.well-known/lnurlp/:name", get(get_lnurl_pay))
-        .route("/.well-known/nostr.json", get(get_nip05))
-        .fallback(fallback)
-        .layer(Extension(state.clone()))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_headers(vec![http::header::CONTENT_TYPE])
-                .allow_methods([Method::GET, Method::POST]),
-        );
+    let limits = RequestLimits {
+        timeout: Duration::from_secs(config.request_timeout_secs),
+        max_body_bytes: config.max_request_body_bytes,
+        max_uri_bytes: config.max_request_uri_bytes,
+    };
+    let server_router = app_router(limits).layer(Extension(state.clone())).layer(
+        CorsLayer::new()
+            .allow_origin(Any)
+            .allow_headers(vec![http::header::CONTENT_TYPE])
+            .allow_methods([Method::GET, Method::POST]),
+    );
 
-    let server = axum::Server::bind(&addr).serve(server_router.into_make_service());
+    // Clients sending their headers slowly would otherwise keep connections open for as long as
+    // they like, since the request timeout only starts once the headers are in.
+    let server = axum::Server::bind(&addr)
+        .http1_header_read_timeout(Duration::from_secs(config.request_header_timeout_secs))
+        .serve(server_router.into_make_service_with_connect_info::<std::net::SocketAddr>());
 
-    let (ctrl_c_tx, mut ctrl_c_rx) = {
-        let (tx, rx) = broadcast::channel(1);
-        let tx_clone = tx.clone();
-        spawn(async move {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("failed to listen for Ctrl+C shutdown signal");
-            tracing::warn!("Ctrl-C pressed; sending stop");
-            tx_clone
-                .send(())
-                .expect("failed to send Ctrl+C signal via broadcast channel");
-        });
-        (tx, rx)
-    };
+    let unpaid_bet_sweeps = UnpaidBetSweeps::default();
+    let mut nonce_tasks = Vec::new();
+    let mut tasks: Vec<(String, JoinHandle<()>)> = Vec::new();
 
-    let manage_nonces = spawn(manage_nonces(
-        client.clone(),
-        nonce_keys.clone(),
-        state.db.clone(),
-        config.expire_nonce_after_secs as u64,
-        config.reveal_nonce_after_secs as u64,
-        ctrl_c_tx.subscribe(),
-    ));
+    for game in games.iter() {
+        let revealed_tx = state.revealed[&game.name].clone();
+        let revealed_rx = revealed_rxs
+            .remove(&game.name)
+            .expect("revealed channel for every game");
+
+        // Every schedule runs its own rounds, which all reveal into the game's channel.
+        for schedule in game.schedules.iter() {
+            nonce_tasks.push((
+                format!("{}/{}", game.name, schedule.name),
+                spawn(manage_nonces(
+                    game.client.clone(),
+                    game.nonce_keys.clone(),
+                    state.db.clone(),
+                    game.name.clone(),
+                    schedule.name.clone(),
+                    schedule.expire_after_secs,
+                    config.reveal_nonce_after_secs as u64,
+                    config.max_invoice_expiry_secs,
+                    config.roll_scheme,
+                    config.max_pending_reveals,
+                    game.multipliers.clone(),
+                    beacon.clone(),
+                    revealed_tx.clone(),
+                    ctrl_c_tx.subscribe(),
+                )),
+            ));
+        }
+
+        let (winners_tx, winners_rx) = mpsc::unbounded_channel();
+        let payouts = Settlement {
+            client: game.client.clone(),
+            multipliers: game.multipliers.clone(),
+            winners: winners_tx,
+            lnd_health: lnd_health.clone(),
+            zapper: lnd_zapper.clone(),
+            dms: dms.clone(),
+            payout_rounding: config.payout_rounding,
+        };
+
+        // Roll the die for paid bets once their round has been revealed
+        let settlement = spawn(settle_revealed_rounds(
+            state.db.clone(),
+            game.name.clone(),
+            payouts.clone(),
+            config.round_results_notes.then(|| game.main_keys.clone()),
+            unpaid_bet_sweeps.clone(),
+            revealed_rx,
+            ctrl_c_tx.subscribe(),
+        ));
+
+        // Post social updates about winners
+        let social_updates = spawn(post_social_updates(
+            game.client.clone(),
+            game.social_keys.clone(),
+            state.db.clone(),
+            game.multipliers.clone(),
+            config.payout_rounding,
+            game.main_keys.public_key(),
+            game.nonce_keys.public_key(),
+            config.social_updates_time_window_minutes,
+            config.big_win_threshold_sat,
+            SummaryLimits {
+                max_note_bytes: config.social_updates_max_note_bytes,
+                max_losers_named: config.social_updates_max_losers_named,
+            },
+            summary_templates.clone(),
+            state.anonymous_rollers.clone(),
+            winners_rx,
+            ctrl_c_tx.subscribe(),
+        ));
+
+        let multiplier_watcher = spawn(watch_multiplier_notes(
+            game.client.clone(),
+            game.multipliers.clone(),
+            game.multiplier_pins.clone(),
+            Duration::from_secs(config.multiplier_pin_check_interval_secs),
+            ctrl_c_tx.subscribe(),
+        ));
+
+        let zap_retries = spawn(retry_zaps(
+            state.db.clone(),
+            game.name.clone(),
+            payouts,
+            config.max_zap_retries,
+            state.payouts_released.clone(),
+            ctrl_c_tx.subscribe(),
+        ));
+
+        for (task, handle) in [
+            ("settlement", settlement),
+            ("social_updates", social_updates),
+            ("multiplier_watcher", multiplier_watcher),
+            ("zap_retries", zap_retries),
+        ] {
+            tasks.push((format!("{}/{task}", game.name), handle));
+        }
+
+        if config.fairness_attestation {
+            let interval = config
+                .fairness_attestation_interval_hours
+                .checked_mul(60 * 60)
+                .map(Duration::from_secs)
+                .context("Fairness attestation interval is too long")?;
+            let fairness_attestations = spawn(publish_fairness_attestations(
+                game.client.clone(),
+                game.nonce_keys.clone(),
+                state.db.clone(),
+                game.name.clone(),
+                game.multipliers.clone(),
+                config.payout_rounding,
+                interval,
+                config.fairness_attestation_fields.clone(),
+                ctrl_c_tx.subscribe(),
+            ));
+
+            tasks.push((
+                format!("{}/fairness_attestations", game.name),
+                fairness_attestations,
+            ));
+        }
+    }
 
     // Invoice event stream
-    spawn(start_invoice_subscription(
+    let receipt_clients =
+        ReceiptClients::new(Duration::from_secs(config.zap_receipt_send_timeout_secs));
+    let paid_invoices = PaidInvoices {
+        games: games.clone(),
+        receipt_relay_allow_list: config.receipt_relay_allow_list.clone(),
+        relay_blacklist: state.relay_blacklist.clone(),
+        receipt_clients: receipt_clients.clone(),
+        receipt_bet_tags: config.receipt_bet_tags,
+        anon_donation_thanks: config.anon_donation_thanks,
+        dms: dms.clone(),
+        reveal_nonce_after_secs: config.reveal_nonce_after_secs as u64,
+        revealed: state.revealed.clone(),
+    };
+    let invoice_subscription = spawn(start_invoice_subscription(
         state.db.clone(),
         state.lightning_client.clone(),
-        main_keys.clone(),
-        client.clone(),
-        multipliers.clone(),
+        paid_invoices.clone(),
+        Duration::from_secs(config.paid_invoice_timeout_secs),
+        ctrl_c_tx.subscribe(),
     ));
 
-    // Post social updates about winners
-    spawn(post_social_updates(
-        client.clone(),
-        social_keys.clone(),
+    // Publish the zap receipts held until their bet was rolled, also those held before a restart
+    // without `--receipt-bet-tags`
+    let held_receipts = spawn(publish_rolled_receipts(
         state.db.clone(),
-        multipliers.clone(),
-        main_keys.public_key(),
-        nonce_keys.public_key(),
-        config.social_updates_time_window_minutes,
+        paid_invoices,
+        ctrl_c_tx.subscribe(),
+    ));
+
+    let receipt_clients = spawn(disconnect_idle_receipt_clients(
+        receipt_clients,
+        ctrl_c_tx.subscribe(),
+    ));
+    let dm_clients = spawn(disconnect_idle_receipt_clients(
+        dms.clients(),
+        ctrl_c_tx.subscribe(),
+    ));
+    let relay_blacklist = spawn(forget_expired_blacklistings(
+        state.relay_blacklist.clone(),
+        ctrl_c_tx.subscribe(),
     ));
 
-    spawn(retry_zaps(
+    // Clean up the bets of rollers who never paid their invoice
+    let unpaid_bet_sweeper = spawn(sweep_unpaid_bets(
         state.db.clone(),
-        client.clone(),
-        multipliers.clone(),
+        state.lightning_client.clone(),
+        unpaid_bet_sweeps,
+        ctrl_c_tx.subscribe(),
+    ));
+
+    // Pause taking bets and paying out winners while LND is unreachable
+    let lnd_monitor = spawn(monitor_lnd(
+        state.lightning_client.clone(),
+        lnd_health,
         ctrl_c_tx.subscribe(),
     ));
 
+    tasks.push(("invoice_subscription".to_string(), invoice_subscription));
+    tasks.push(("held_receipts".to_string(), held_receipts));
+    tasks.push(("receipt_clients".to_string(), receipt_clients));
+    tasks.push(("dm_clients".to_string(), dm_clients));
+    tasks.push(("relay_blacklist".to_string(), relay_blacklist));
+    tasks.push(("unpaid_bet_sweeper".to_string(), unpaid_bet_sweeper));
+    tasks.push(("lnd_monitor".to_string(), lnd_monitor));
+
+    // Keep the database from growing forever
+    if let Some(retention_days) = config.retention_days {
+        let round_archiver = spawn(archive_old_rounds(
+            state.db.clone(),
+            Duration::from_secs(retention_days * 24 * 60 * 60),
+            ctrl_c_tx.subscribe(),
+        ));
+        tasks.push(("round_archiver".to_string(), round_archiver));
+    }
+
     let graceful = server.with_graceful_shutdown(async {
         let _ = ctrl_c_rx.recv().await;
     });
 
     // Await the server to receive the shutdown signal
 
-    let (graceful, manage_nonces) = tokio::join!(graceful, manage_nonces);
+    let (graceful, manage_nonces) = tokio::join!(graceful, async {
+        let mut results = Vec::new();
+        for (game, task) in nonce_tasks {
+            results.push((game, task.await));
+        }
+        results
+    });
 
     if let Err(e) = graceful {
         tracing::error!("shutdown error in server: {}", e);
     }
 
-    match manage_nonces {
-        Ok(Err(e)) => tracing::error!("shutdown error in manage_nonces task: {}", e),
-        Err(e) => tracing::error!("shutdown error in manage_nonces task: {}", e),
-        _ => (),
+    for (game, result) in manage_nonces {
+        match result {
+            Ok(Err(e)) => tracing::error!("shutdown error in {game}/manage_nonces task: {}", e),
+            Err(e) => tracing::error!("shutdown error in {game}/manage_nonces task: {}", e),
+            _ => (),
+        }
+    }
+
+    // Give the remaining tasks a chance to finish what they are doing. Tasks settling bets still
+    // get their payouts made meanwhile, so the zapper is only stopped once they are done.
+    let remaining_tasks = async {
+        let mut results = Vec::new();
+        for (task, handle) in tasks {
+            results.push((task, handle.await));
+        }
+
+        let _ = stop_zapper.send(());
+        results.push(("zapper".to_string(), zapper.await));
+
+        results
+    };
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    match tokio::time::timeout(shutdown_timeout, remaining_tasks).await {
+        Ok(results) => {
+            for (task, result) in results {
+                if let Err(e) = result {
+                    tracing::error!("shutdown error in {task} task: {e}");
+                }
+            }
+        }
+        Err(_) => tracing::error!("Timed out waiting for tasks to shut down"),
     }
 
-    client.disconnect().await?;
+    for game in games.iter() {
+        game.client.disconnect().await?;
+    }
 
     Ok(())
 }
 
+/// The routes of the webserver, bounding what a single request may take from us. Their handlers
+/// expect the [`State`] as an extension.
+fn app_router(limits: RequestLimits) -> Router {
+    let max_uri_bytes = limits.max_uri_bytes;
+
+    Router::new()
+        .route("/get-invoice-for-game/:hash", get(get_invoice_for_game))
+        .route(
+            "/get-invoice-for-game/:game/:hash",
+            get(get_invoice_for_game),
+        )
+        // Only covers the routes above, which create bet invoices.
+        .route_layer(middleware::from_fn(rate_limit_invoices))
+        .route("/get-invoice-for-zap/:hash", get(get_invoice_for_zap))
+        .route("/get-invoice-for-zap/:game/:hash", get(get_invoice_for_zap))
+        .route("/.well-known/lnurlp/:name", get(get_lnurl_pay))
+        .route("/.well-known/nostr.json", get(get_nip05))
+        .route("/verify-roll", get(get_verify_roll))
+        .route("/audit/:nonce_commitment_note_id", get(get_audit))
+        .route("/bets/:nonce_commitment_note_id", get(get_bets))
+        .route("/leaderboard", get(get_leaderboard))
+        .route("/health", get(get_health))
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(get_readyz))
+        .route("/metrics", get(get_metrics))
+        .route(
+            "/admin/reveal/:nonce_commitment_note_id",
+            post(post_admin_reveal),
+        )
+        .route("/admin/release/:payment_hash", post(post_admin_release))
+        .route("/admin/resolve/:payment_hash", post(post_admin_resolve))
+        .route("/admin/status", get(get_admin_status))
+        .route("/export/rounds", get(get_export_rounds))
+        .fallback(fallback)
+        .layer(TimeoutLayer::new(limits.timeout))
+        .layer(RequestBodyLimitLayer::new(limits.max_body_bytes))
+        .layer(middleware::from_fn(
+            move |request: Request<Body>, next: Next<Body>| {
+                reject_long_uris(max_uri_bytes, request, next)
+            },
+        ))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RequestLimits {
+    timeout: Duration,
+    max_body_bytes: usize,
+    /// Zap requests are passed in the query, so the body limit does not cover them.
+    max_uri_bytes: usize,
+}
+
+async fn reject_long_uris(
+    max_uri_bytes: usize,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let uri_bytes = request
+        .uri()
+        .path_and_query()
+        .map_or(0, |path_and_query| path_and_query.as_str().len());
+    if uri_bytes > max_uri_bytes {
+        return (
+            StatusCode::URI_TOO_LONG,
+            format!("URI of {uri_bytes} bytes is longer than {max_uri_bytes} bytes"),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
 async fn fallback(uri: Uri) -> (StatusCode, String) {
     (StatusCode::NOT_FOUND, format!("No route for {}", uri))
 }
 
+/// Load the keys and the multiplier notes of a game, and connect a client for its main account.
+///
+/// The keys of the default game are stored in the `data_dir`, the keys of other games in
+/// `games/<name>` within it.
+async fn load_game(
+    data_dir: &Path,
+    config: GameConfig,
+    relays: &[String],
+    send_timeout: Duration,
+    zapper: LndZapper,
+    default_expire_nonce_after_secs: u64,
+) -> anyhow::Result<Game> {
+    let keys_dir = if config.name == DEFAULT_GAME {
+        data_dir.to_path_buf()
+    } else {
+        let keys_dir = data_dir.join("games").join(&config.name);
+        std::fs::create_dir_all(&keys_dir)?;
+        keys_dir
+    };
+
+    let main_keys = get_keys(keys_dir.join("main-keys.json"));
+    let nonce_keys = get_keys(keys_dir.join("nonce-keys.json"));
+    let social_keys = get_keys(keys_dir.join("social-keys.json"));
+
+    let options = Options::default();
+    // Create new client
+    let client = Client::with_opts(
+        &main_keys,
+        options.wait_for_send(true).send_timeout(Some(send_timeout)),
+    );
+    client.add_relays(relays.to_vec()).await?;
+    client.set_zapper(zapper).await;
+    client.connect().await;
+
+    let doc = load_multipliers_file(&config.multipliers_file);
+    let multipliers = parse_multipliers(&doc);
+    let expire_nonce_after_secs =
+        parse_expire_nonce_after_secs(&doc).unwrap_or(default_expire_nonce_after_secs);
+    let schedules = std::iter::once(RoundSchedule {
+        name: DEFAULT_SCHEDULE.to_string(),
+        expire_after_secs: expire_nonce_after_secs,
+    })
+    .chain(parse_schedules(&doc))
+    .collect();
+    multipliers
+        .validate()
+        .with_context(|| format!("Invalid multipliers file {}", config.multipliers_file))?;
+
+    // Bets are settled according to the multipliers file, so every note has to agree with it.
+    let multiplier_notes = fetch_multiplier_notes(&client, &multipliers).await?;
+    for note in multipliers.0.iter() {
+        let event = multiplier_notes
+            .get(&note.note_id)
+            .with_context(|| format!("Could not fetch multiplier note {}", note.note_id))?;
+
+        note.verify_event(event, &main_keys.public_key())?;
+    }
+    let multiplier_pins = MultiplierPins::new(
+        multiplier_notes
+            .iter()
+            .map(|(note_id, event)| (note_id.as_str(), event.content.as_str())),
+    );
+
+    tracing::info!(game = config.name, "Loaded game");
+
+    Ok(Game {
+        name: config.name,
+        main_keys,
+        nonce_keys,
+        social_keys,
+        client,
+        multipliers,
+        multiplier_pins,
+        schedules,
+    })
+}
+
+fn load_multipliers_file(path: &str) -> Yaml {
+    let path = PathBuf::from(path);
+    let mut file = File::open(path).expect("Failed to open multiplier config file");
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .expect("Failed to read multiplier config file");
+
+    let docs =
+        YamlLoader::load_from_str(&contents).expect("Failed to parse multiplier config file");
+
+    docs.into_iter()
+        .next()
+        .expect("Empty multiplier config file")
+}
+
+/// How long the rounds of a game take bets, if its multipliers file overrides
+/// `--expire-nonce-after-secs`, e.g. `expire_nonce_after_secs: 600`.
+fn parse_expire_nonce_after_secs(doc: &Yaml) -> Option<u64> {
+    match &doc["expire_nonce_after_secs"] {
+        Yaml::BadValue => None,
+        secs => Some(
+            secs.as_i64()
+                .and_then(|secs| u64::try_from(secs).ok())
+                .filter(|secs| *secs > 0)
+                .expect("Invalid expire_nonce_after_secs"),
+        ),
+    }
+}
+
+/// The schedules of rounds a game runs besides its default one, each taking bets for the given
+/// number of seconds, e.g.
+///
+/// ```yaml
+/// schedules:
+///   fast: 60
+///   slow: 3600
+/// ```
+fn parse_schedules(doc: &Yaml) -> Vec<RoundSchedule> {
+    let Some(schedules) = doc["schedules"].as_hash() else {
+        return Vec::new();
+    };
+
+    schedules
+        .iter()
+        .map(|(name, secs)| {
+            let name = name.as_str().expect("Invalid schedule name").to_string();
+            if name == DEFAULT_SCHEDULE {
+                panic!(
+                    "The duration of the {DEFAULT_SCHEDULE} schedule is set with \
+                     expire_nonce_after_secs"
+                );
+            }
+            let expire_after_secs = secs
+                .as_i64()
+                .and_then(|secs| u64::try_from(secs).ok())
+                .filter(|secs| *secs > 0)
+                .unwrap_or_else(|| panic!("Invalid duration of schedule {name}"));
+
+            RoundSchedule {
+                name,
+                expire_after_secs,
+            }
+        })
+        .collect()
+}
+
+/// The multiplier notes of a multipliers file, listed under `multipliers`, or configured under the
+/// keys of the built-in multipliers in files without that list.
+fn parse_multipliers(doc: &Yaml) -> Multipliers {
+    let notes = match doc["multipliers"].as_vec() {
+        Some(entries) => entries.iter().map(parse_multiplier_note).collect(),
+        None => parse_built_in_multiplier_notes(doc),
+    };
+
+    Multipliers(notes)
+}
+
+/// A multiplier note of the `multipliers` list, e.g.
+///
+/// ```yaml
+/// multipliers:
+///   - note_id: note1...
+///     multiplier: 5
+///     lower_than: 12713
+///     max_amount_sat: 20000
+///     # Optional, the smallest bet accepted on the note.
+///     min_amount_sat: 10
+///     # Optional, defaults to `{multiplier}x`.
+///     content: 5x
+///     # Optional, defaults to `under`.
+///     direction: over
+///     # Optional, defaults to `instant`.
+///     payout: queued
+///     # Optional, one of the `schedules` whose rounds take bets on the note. Defaults to the
+///     # rounds lasting `expire_nonce_after_secs`.
+///     schedule: fast
+///     # Optional RFC 3339 timestamps restricting when bets on the note are accepted.
+///     active_from: 2024-10-31T00:00:00Z
+///     active_until: 2024-11-01T00:00:00Z
+///     # Optional, the threshold of 32-bit rolls, which the note has to state too. Defaults to
+///     # `lower_than` scaled up.
+///     lower_than_u32: 833154000
+/// ```
+fn parse_multiplier_note(entry: &Yaml) -> MultiplierNote {
+    let note_id = entry["note_id"]
+        .as_str()
+        .expect("Multiplier note without note_id")
+        .to_string();
+    let field = |key: &str| -> &Yaml {
+        match &entry[key] {
+            Yaml::BadValue => panic!("Multiplier note {note_id} without {key}"),
+            value => value,
+        }
+    };
+
+    let multiplier = field("multiplier");
+    let multiplier = multiplier
+        .as_f64()
+        .or_else(|| multiplier.as_i64().map(|multiplier| multiplier as f64))
+        .unwrap_or_else(|| panic!("Invalid multiplier of note {note_id}"))
+        as f32;
+    let lower_than = field("lower_than")
+        .as_i64()
+        .and_then(|lower_than| u16::try_from(lower_than).ok())
+        .unwrap_or_else(|| panic!("Invalid lower_than of note {note_id}"));
+    let lower_than_u32 = match &entry["lower_than_u32"] {
+        Yaml::BadValue => None,
+        lower_than_u32 => Some(
+            lower_than_u32
+                .as_i64()
+                .and_then(|lower_than_u32| u32::try_from(lower_than_u32).ok())
+                .unwrap_or_else(|| panic!("Invalid lower_than_u32 of note {note_id}")),
+        ),
+    };
+    let max_amount_sat = field("max_amount_sat")
+        .as_i64()
+        .and_then(|max_amount_sat| u64::try_from(max_amount_sat).ok())
+        .unwrap_or_else(|| panic!("Invalid max_amount_sat of note {note_id}"));
+    let min_amount_sat = match &entry["min_amount_sat"] {
+        Yaml::BadValue => None,
+        min_amount_sat => Some(
+            min_amount_sat
+                .as_i64()
+                .and_then(|min_amount_sat| u64::try_from(min_amount_sat).ok())
+                .unwrap_or_else(|| panic!("Invalid min_amount_sat of note {note_id}")),
+        ),
+    };
+    let content = entry["content"]
+        .as_str()
+        .map_or_else(|| format!("{multiplier}x"), str::to_string);
+
+    let direction = match entry["direction"].as_str() {
+        None | Some("under") => BetDirection::Under,
+        Some("over") => BetDirection::Over,
+        Some(direction) => panic!("Invalid direction {direction} of note {note_id}"),
+    };
+
+    MultiplierNote {
+        multiplier: Multiplier {
+            multiplier,
+            lower_than,
+            lower_than_u32,
+            content,
+            max_amount_sat,
+        },
+        direction,
+        active_from: parse_timestamp(entry, "active_from"),
+        active_until: parse_timestamp(entry, "active_until"),
+        payout_timing: parse_payout_timing(entry, "payout"),
+        max_amount_sat: None,
+        min_amount_sat,
+        schedule: parse_schedule(entry, "schedule"),
+        note_id,
+    }
+}
+
+/// The multiplier notes configured under the lowercase names of the built-in multipliers, e.g.
+/// `x2: note1...` and `x2_over: note1...`.
+fn parse_built_in_multiplier_notes(doc: &Yaml) -> Vec<MultiplierNote> {
+    // Optional `{key}_active_from` and `{key}_active_until` RFC 3339 timestamps restrict when
+    // bets on the note under `key` are accepted.
+    let active_window = |key: &str| {
+        let timestamp = |suffix: &str| parse_timestamp(doc, &format!("{key}_{suffix}"));
+
+        (timestamp("active_from"), timestamp("active_until"))
+    };
+
+    // An optional `{key}_max_amount_sat` overrides the default maximum bet on the note under `key`.
+    let max_amount_sat = |key: &str| parse_amount_sat(doc, &format!("{key}_max_amount_sat"));
+
+    // An optional `{key}_min_amount_sat` is the smallest bet accepted on the note under `key`.
+    let min_amount_sat = |key: &str| parse_amount_sat(doc, &format!("{key}_min_amount_sat"));
+
+    // An optional `{key}_schedule` is the schedule whose rounds take bets on the note under `key`.
+    let schedule = |key: &str| parse_schedule(doc, &format!("{key}_schedule"));
+
+    let mut notes = Vec::new();
+    for (name, multiplier) in Multiplier::built_ins() {
+        let key = name.to_lowercase();
+        let key = key.as_str();
+
+        // An optional `{key}_payout: queued` holds the payouts of the multiplier's winners for
+        // manual review instead of paying them out right away.
+        let payout_timing = parse_payout_timing(doc, &format!("{key}_payout"));
+
+        let (active_from, active_until) = active_window(key);
+        notes.push(MultiplierNote {
+            multiplier: multiplier.clone(),
+            note_id: doc[key].clone().into_string().expect(key),
+            direction: BetDirection::Under,
+            active_from,
+            active_until,
+            payout_timing,
+            max_amount_sat: max_amount_sat(key),
+            min_amount_sat: min_amount_sat(key),
+            schedule: schedule(key),
+        });
+
+        // Roll-over bets are optional and have their own notes.
+        let key = format!("{key}_over");
+        if let Some(note_id) = doc[key.as_str()].clone().into_string() {
+            let (active_from, active_until) = active_window(&key);
+            notes.push(MultiplierNote {
+                multiplier,
+                note_id,
+                direction: BetDirection::Over,
+                active_from,
+                active_until,
+                payout_timing,
+                max_amount_sat: max_amount_sat(&key),
+                min_amount_sat: min_amount_sat(&key),
+                schedule: schedule(&key),
+            });
+        }
+    }
+
+    notes
+}
+
+/// An optional amount in sats, which must be a number if it is given, e.g. not `"1000"`.
+fn parse_amount_sat(yaml: &Yaml, key: &str) -> Option<u64> {
+    match &yaml[key] {
+        Yaml::BadValue => None,
+        amount_sat => Some(
+            amount_sat
+                .as_i64()
+                .and_then(|amount_sat| u64::try_from(amount_sat).ok())
+                .unwrap_or_else(|| panic!("Invalid amount for {key}")),
+        ),
+    }
+}
+
+fn parse_schedule(yaml: &Yaml, key: &str) -> String {
+    match &yaml[key] {
+        Yaml::BadValue => DEFAULT_SCHEDULE.to_string(),
+        schedule => schedule
+            .as_str()
+            .unwrap_or_else(|| panic!("Invalid schedule for {key}"))
+            .to_string(),
+    }
+}
+
+fn parse_timestamp(yaml: &Yaml, key: &str) -> Option<OffsetDateTime> {
+    yaml[key].as_str().map(|timestamp| {
+        OffsetDateTime::parse(timestamp, &Rfc3339)
+            .unwrap_or_else(|_| panic!("Invalid timestamp for {key}"))
+    })
+}
+
+fn parse_payout_timing(yaml: &Yaml, key: &str) -> PayoutTiming {
+    match yaml[key].as_str() {
+        None | Some("instant") => PayoutTiming::Instant,
+        Some("queued") => PayoutTiming::Queued,
+        Some(timing) => panic!("Invalid payout timing {timing} for {key}"),
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct NostrKeys {
     server_key: String,
@@ -373,3 +1308,251 @@ fn get_keys(path: PathBuf) -> Keys {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+
+    fn limited_router() -> Router {
+        app_router(RequestLimits {
+            timeout: Duration::from_secs(30),
+            max_body_bytes: 64 * 1024,
+            max_uri_bytes: 16 * 1024,
+        })
+    }
+
+    fn request(uri: &str, size: usize) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(http::header::CONTENT_LENGTH, size)
+            .body(Body::from(vec![b'a'; size]))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_rejected() {
+        let response = limited_router()
+            .oneshot(request("/admin/reveal/note1round", 64 * 1024 + 1))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn zap_request_sized_body_fits() {
+        // Reaches the fallback, since no route takes a POST to `/`.
+        let response = limited_router()
+            .oneshot(request("/", 8 * 1024))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn oversized_zap_requests_in_the_query_are_rejected() {
+        let uri = |nostr_bytes| {
+            format!(
+                "/get-invoice-for-game/hash?amount=1000&nostr={}",
+                "a".repeat(nostr_bytes)
+            )
+        };
+
+        let response = limited_router()
+            .oneshot(request(&uri(16 * 1024), 0))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+
+        let response = limited_router()
+            .oneshot(request(&format!("/unknown{}", &uri(8 * 1024)), 0))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn parse(yaml: &str) -> Multipliers {
+        parse_multipliers(&YamlLoader::load_from_str(yaml).unwrap()[0])
+    }
+
+    #[test]
+    fn custom_multipliers_are_loaded_from_the_multipliers_list() {
+        let multipliers = parse(
+            "multipliers:
+  - note_id: note1five
+    multiplier: 5
+    lower_than: 12713
+    max_amount_sat: 20000
+  - note_id: note1fiveover
+    multiplier: 5
+    lower_than: 12713
+    max_amount_sat: 20000
+    content: Five times
+    direction: over
+    payout: queued
+    lower_than_u32: 833154000
+  - note_id: note1double
+    multiplier: 2.0
+    lower_than: 31784
+    max_amount_sat: 50000
+    min_amount_sat: 10
+",
+        );
+
+        let five = multipliers.get_multiplier_note("note1five").unwrap();
+        assert_eq!(
+            five.multiplier,
+            Multiplier {
+                multiplier: 5.0,
+                lower_than: 12_713,
+                lower_than_u32: None,
+                content: "5x".to_string(),
+                max_amount_sat: 20_000,
+            }
+        );
+        assert_eq!(five.direction, BetDirection::Under);
+        assert_eq!(five.payout_timing, PayoutTiming::Instant);
+        assert_eq!(five.min_amount_sat, None);
+
+        let five_over = multipliers.get_multiplier_note("note1fiveover").unwrap();
+        assert_eq!(five_over.multiplier.content, "Five times");
+        assert_eq!(five_over.direction, BetDirection::Over);
+        assert_eq!(five_over.payout_timing, PayoutTiming::Queued);
+        assert_eq!(five_over.multiplier.lower_than_u32, Some(833_154_000));
+
+        // The same as a built-in multiplier, so stored just like it.
+        let double = multipliers.get_multiplier_note("note1double").unwrap();
+        assert_eq!(
+            serde_json::to_string(&double.multiplier).unwrap(),
+            r#""X2""#
+        );
+        assert_eq!(double.min_amount_sat, Some(10));
+    }
+
+    #[test]
+    fn built_in_multipliers_are_configured_under_their_keys() {
+        let yaml = Multiplier::built_ins()
+            .map(|(name, _)| format!("{}: note1{name}\n", name.to_lowercase()))
+            .collect::<String>();
+        let multipliers = parse(&format!(
+            "{yaml}x2_over: note1over\nx2_max_amount_sat: 100\nx2_over_min_amount_sat: 10\n"
+        ));
+
+        assert_eq!(multipliers.0.len(), 12);
+        let over = multipliers.get_multiplier_note("note1over").unwrap();
+        assert_eq!(over.multiplier, Multiplier::built_in("X2").unwrap());
+        assert_eq!(over.get_max_amount_sat(), 50_000);
+        assert_eq!(over.min_amount_sat, Some(10));
+        assert_eq!(
+            multipliers
+                .get_multiplier_note("note1X2")
+                .unwrap()
+                .get_max_amount_sat(),
+            100
+        );
+    }
+
+    #[test]
+    fn games_can_override_the_round_duration() {
+        let doc = |yaml: &str| YamlLoader::load_from_str(yaml).unwrap().remove(0);
+
+        assert_eq!(
+            parse_expire_nonce_after_secs(&doc("x2: note1x2\nexpire_nonce_after_secs: 600\n")),
+            Some(600)
+        );
+        assert_eq!(parse_expire_nonce_after_secs(&doc("x2: note1x2\n")), None);
+    }
+
+    #[test]
+    fn games_can_run_several_schedules_of_rounds() {
+        let doc = YamlLoader::load_from_str(
+            "x2: note1x2\nx2_schedule: fast\nx3: note1x3\nschedules:\n  fast: 60\n  slow: 3600\n",
+        )
+        .unwrap()
+        .remove(0);
+
+        assert_eq!(
+            parse_schedules(&doc),
+            vec![
+                RoundSchedule {
+                    name: "fast".to_string(),
+                    expire_after_secs: 60,
+                },
+                RoundSchedule {
+                    name: "slow".to_string(),
+                    expire_after_secs: 3600,
+                },
+            ]
+        );
+
+        let multipliers = parse_multipliers(&doc);
+        let schedule = |note_id| {
+            multipliers
+                .get_multiplier_note(note_id)
+                .unwrap()
+                .schedule
+                .clone()
+        };
+        assert_eq!(schedule("note1x2"), "fast");
+        assert_eq!(schedule("note1x3"), DEFAULT_SCHEDULE);
+    }
+
+    #[test]
+    fn multipliers_paying_out_more_than_is_bet_are_rejected() {
+        let multipliers = parse(
+            "multipliers:
+  - note_id: note1generous
+    multiplier: 3
+    lower_than: 31784
+    max_amount_sat: 1000
+",
+        );
+
+        let error = multipliers.validate().unwrap_err();
+        assert!(format!("{error:#}").starts_with("Invalid multiplier of note note1generous: 3x"));
+    }
+
+    #[test]
+    fn minimum_bets_above_the_maximum_bet_are_rejected() {
+        let multipliers = parse(
+            "multipliers:
+  - note_id: note1double
+    multiplier: 2
+    lower_than: 31784
+    max_amount_sat: 1000
+    min_amount_sat: 1001
+",
+        );
+
+        let error = multipliers.validate().unwrap_err();
+        assert!(format!("{error:#}").starts_with("Minimum bet of 1001 sats on note note1double"));
+    }
+
+    #[test]
+    fn maximum_bets_too_big_for_msats_are_rejected() {
+        let multipliers = parse(
+            "multipliers:
+  - note_id: note1double
+    multiplier: 2
+    lower_than: 31784
+    max_amount_sat: 9223372036854775807
+",
+        );
+
+        let error = multipliers.validate().unwrap_err();
+        assert!(format!("{error:#}").starts_with("Maximum bet of 9223372036854775807 sats"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid amount for x2_max_amount_sat")]
+    fn maximum_bets_given_as_strings_are_rejected() {
+        let yaml = Multiplier::built_ins()
+            .map(|(name, _)| format!("{}: note1{name}\n", name.to_lowercase()))
+            .collect::<String>();
+
+        parse(&format!("{yaml}x2_max_amount_sat: \"100\"\n"));
+    }
+}
@@ -0,0 +1,276 @@
+use crate::multiplier::Multiplier;
+use crate::nonce::get_active_nonces;
+use crate::State;
+use axum::http::header;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Extension;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the payout latency histogram buckets, in seconds.
+const PAYOUT_LATENCY_BUCKETS: [u64; 10] = [1, 5, 15, 30, 60, 120, 300, 600, 1800, 3600];
+
+/// The metrics of this process, exposed on `/metrics`.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Counters of what happens to bets and payouts, rendered in the Prometheus text format.
+pub struct Metrics {
+    /// Bet invoices handed out, by game and multiplier.
+    bet_invoices: Mutex<BTreeMap<(String, String), u64>>,
+    /// Paid bets, by game and multiplier.
+    bets: Mutex<BTreeMap<(String, String), u64>>,
+    wagered_sat: AtomicU64,
+    paid_sat: AtomicU64,
+    routing_fees_msat: AtomicU64,
+    failed_zaps: AtomicU64,
+    /// Multiplier notes whose content no longer matches their pin.
+    tampered_multiplier_notes: AtomicU64,
+    missing_multiplier_notes: AtomicU64,
+    payout_latency: Histogram,
+}
+
+struct Histogram {
+    /// Observations per bucket of [`PAYOUT_LATENCY_BUCKETS`], plus one for the rest.
+    buckets: [AtomicU64; PAYOUT_LATENCY_BUCKETS.len() + 1],
+    sum_millis: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            bet_invoices: Mutex::new(BTreeMap::new()),
+            bets: Mutex::new(BTreeMap::new()),
+            wagered_sat: AtomicU64::new(0),
+            paid_sat: AtomicU64::new(0),
+            routing_fees_msat: AtomicU64::new(0),
+            failed_zaps: AtomicU64::new(0),
+            tampered_multiplier_notes: AtomicU64::new(0),
+            missing_multiplier_notes: AtomicU64::new(0),
+            payout_latency: Histogram {
+                buckets: [const { AtomicU64::new(0) }; PAYOUT_LATENCY_BUCKETS.len() + 1],
+                sum_millis: AtomicU64::new(0),
+            },
+        }
+    }
+
+    pub fn bet_invoice_requested(&self, game: &str, multiplier: &Multiplier) {
+        increment(&self.bet_invoices, game, multiplier);
+    }
+
+    pub fn bet_paid(&self, game: &str, multiplier: &Multiplier, amount_msat: u64) {
+        increment(&self.bets, game, multiplier);
+        self.wagered_sat
+            .fetch_add(amount_msat / 1_000, Ordering::Relaxed);
+    }
+
+    /// A win was paid out for a routing fee of `fee_msat`, `latency` after the bet was paid.
+    pub fn payout_paid(&self, amount_sat: u64, fee_msat: u64, latency: Duration) {
+        self.paid_sat.fetch_add(amount_sat, Ordering::Relaxed);
+        self.routing_fees_msat
+            .fetch_add(fee_msat, Ordering::Relaxed);
+
+        let bucket = PAYOUT_LATENCY_BUCKETS
+            .iter()
+            .position(|upper_bound| latency.as_secs_f64() <= *upper_bound as f64)
+            .unwrap_or(PAYOUT_LATENCY_BUCKETS.len());
+        self.payout_latency.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.payout_latency
+            .sum_millis
+            .fetch_add(latency.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn zap_failed(&self) {
+        self.failed_zaps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A multiplier note no longer matched its pin, so bets on it are refused from now on.
+    pub fn multiplier_note_tampered(&self) {
+        self.tampered_multiplier_notes
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A multiplier note was not returned by the relays when we re-fetched it to check its pin.
+    pub fn multiplier_note_missing(&self) {
+        self.missing_multiplier_notes
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, active_rounds: usize) -> String {
+        let mut out = String::new();
+
+        render_by_multiplier(
+            &mut out,
+            "nostrdice_bet_invoices_total",
+            "Bet invoices handed out.",
+            &self.bet_invoices,
+        );
+        render_by_multiplier(&mut out, "nostrdice_bets_total", "Paid bets.", &self.bets);
+        render_single(
+            &mut out,
+            "nostrdice_wagered_sats_total",
+            "counter",
+            "Sats wagered in paid bets.",
+            self.wagered_sat.load(Ordering::Relaxed),
+        );
+        render_single(
+            &mut out,
+            "nostrdice_paid_sats_total",
+            "counter",
+            "Sats paid out to winners.",
+            self.paid_sat.load(Ordering::Relaxed),
+        );
+        render_single(
+            &mut out,
+            "nostrdice_routing_fees_msats_total",
+            "counter",
+            "Routing fees paid on payouts, in msat.",
+            self.routing_fees_msat.load(Ordering::Relaxed),
+        );
+        render_single(
+            &mut out,
+            "nostrdice_failed_zaps_total",
+            "counter",
+            "Failed attempts to pay out a winner.",
+            self.failed_zaps.load(Ordering::Relaxed),
+        );
+        render_single(
+            &mut out,
+            "nostrdice_tampered_multiplier_notes",
+            "gauge",
+            "Multiplier notes whose content changed, which bets are refused on.",
+            self.tampered_multiplier_notes.load(Ordering::Relaxed),
+        );
+        render_single(
+            &mut out,
+            "nostrdice_missing_multiplier_notes_total",
+            "counter",
+            "Checks of a multiplier note's pin which could not fetch the note.",
+            self.missing_multiplier_notes.load(Ordering::Relaxed),
+        );
+        render_single(
+            &mut out,
+            "nostrdice_active_rounds",
+            "gauge",
+            "Games with a running round.",
+            active_rounds as u64,
+        );
+
+        let name = "nostrdice_payout_latency_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Time from paying a bet to paying out its win."
+        );
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut count = 0;
+        for (i, bucket) in self.payout_latency.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = PAYOUT_LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |upper_bound| upper_bound.to_string());
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {count}");
+        }
+        let sum = self.payout_latency.sum_millis.load(Ordering::Relaxed) as f64 / 1_000.0;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+
+        out
+    }
+}
+
+fn increment(
+    counters: &Mutex<BTreeMap<(String, String), u64>>,
+    game: &str,
+    multiplier: &Multiplier,
+) {
+    *counters
+        .lock()
+        .expect("not poisoned")
+        .entry((game.to_string(), multiplier.get_content()))
+        .or_default() += 1;
+}
+
+fn render_by_multiplier(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    counters: &Mutex<BTreeMap<(String, String), u64>>,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for ((game, multiplier), value) in counters.lock().expect("not poisoned").iter() {
+        let _ = writeln!(
+            out,
+            "{name}{{game=\"{game}\",multiplier=\"{multiplier}\"}} {value}"
+        );
+    }
+}
+
+fn render_single(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+pub async fn get_metrics(Extension(state): Extension<State>) -> impl IntoResponse {
+    let mut active_rounds = 0;
+    for game in state.games.iter() {
+        match get_active_nonces(&state.db, &game.name).await {
+            Ok(rounds) => active_rounds += rounds.len(),
+            Err(e) => tracing::error!(game = game.name, "Failed to get active nonces: {e:#}"),
+        }
+    }
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(active_rounds),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text_format() {
+        let metrics = Metrics::new();
+
+        metrics.bet_invoice_requested("main", &Multiplier::built_in("X2").unwrap());
+        metrics.bet_invoice_requested("main", &Multiplier::built_in("X2").unwrap());
+        metrics.bet_paid("main", &Multiplier::built_in("X2").unwrap(), 10_000_000);
+        metrics.payout_paid(20_000, 1_500, Duration::from_secs(90));
+        metrics.zap_failed();
+        metrics.multiplier_note_tampered();
+        metrics.multiplier_note_missing();
+        metrics.multiplier_note_missing();
+
+        let rendered = metrics.render(1);
+
+        for line in [
+            r#"nostrdice_bet_invoices_total{game="main",multiplier="2x"} 2"#,
+            r#"nostrdice_bets_total{game="main",multiplier="2x"} 1"#,
+            "nostrdice_wagered_sats_total 10000",
+            "nostrdice_paid_sats_total 20000",
+            "nostrdice_routing_fees_msats_total 1500",
+            "nostrdice_failed_zaps_total 1",
+            "nostrdice_tampered_multiplier_notes 1",
+            "nostrdice_missing_multiplier_notes_total 2",
+            "nostrdice_active_rounds 1",
+            r#"nostrdice_payout_latency_seconds_bucket{le="60"} 0"#,
+            r#"nostrdice_payout_latency_seconds_bucket{le="120"} 1"#,
+            r#"nostrdice_payout_latency_seconds_bucket{le="+Inf"} 1"#,
+            "nostrdice_payout_latency_seconds_sum 90",
+            "nostrdice_payout_latency_seconds_count 1",
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "missing {line}:\n{rendered}"
+            );
+        }
+    }
+}
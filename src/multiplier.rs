@@ -1,11 +1,39 @@
+use crate::game::DEFAULT_SCHEDULE;
+use crate::metrics::METRICS;
+use crate::roll::RollScheme;
+use crate::route_error::RouteError;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use nostr::bitcoin::hashes::sha256;
+use nostr::Event;
+use nostr::EventBuilder;
+use nostr::EventId;
+use nostr::Filter;
+use nostr::FromBech32;
+use nostr::Kind;
+use nostr::PublicKey;
+use nostr::Tag;
+use nostr::ToBech32;
+use nostr_sdk::hashes::Hash;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Formatter;
-use strum_macros::EnumIter;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::select;
+use tokio::sync::broadcast;
+
+const FETCH_MULTIPLIER_NOTES_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Clone, Debug)]
-pub struct Multipliers(pub [MultiplierNote; 11]);
+pub struct Multipliers(pub Vec<MultiplierNote>);
 
 impl Multipliers {
     pub fn get_multiplier_note(&self, note_id: &str) -> Option<MultiplierNote> {
@@ -14,12 +42,356 @@ impl Multipliers {
             .find(|multiplier| multiplier.note_id == note_id)
             .cloned()
     }
+
+    /// The highest maximum bet on any of the multiplier notes.
+    pub fn max_amount_sat(&self) -> Option<u64> {
+        self.0.iter().map(|note| note.get_max_amount_sat()).max()
+    }
+
+    /// Commit to the multiplier notes in play, and thus to the odds of every bet.
+    ///
+    /// The commitment is the SHA256 hash of one `{note_id}:{direction}:{threshold}\n` line per
+    /// note, sorted by note ID, where the direction is `under` or `over` and the threshold is the
+    /// one of 16-bit rolls. Notes with a threshold of their own for 32-bit rolls append it, as in
+    /// `{note_id}:{direction}:{threshold}:{threshold_u32}\n`.
+    pub fn commitment(&self) -> sha256::Hash {
+        let mut lines = self
+            .0
+            .iter()
+            .map(|note| {
+                let direction = match note.direction {
+                    BetDirection::Under => "under",
+                    BetDirection::Over => "over",
+                };
+
+                let threshold = note.get_threshold(RollScheme::V1);
+                match note.multiplier.lower_than_u32 {
+                    Some(_) => format!(
+                        "{}:{direction}:{threshold}:{}\n",
+                        note.note_id,
+                        note.get_threshold(RollScheme::V3)
+                    ),
+                    None => format!("{}:{direction}:{threshold}\n", note.note_id),
+                }
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+
+        sha256::Hash::hash(lines.concat().as_bytes())
+    }
+
+    /// Check the multiplier of every note, see [`Multiplier::validate`], that its maximum bet can
+    /// be counted in msats, and that its minimum bet is not bigger than its maximum bet.
+    pub fn validate(&self) -> Result<()> {
+        for note in self.0.iter() {
+            note.multiplier
+                .validate()
+                .with_context(|| format!("Invalid multiplier of note {}", note.note_id))?;
+
+            let max_amount_sat = note.get_max_amount_sat();
+            if max_amount_sat.checked_mul(1000).is_none() {
+                bail!(
+                    "Maximum bet of {max_amount_sat} sats on note {} is too big",
+                    note.note_id
+                );
+            }
+
+            if let Some(min_amount_sat) = note.min_amount_sat {
+                if min_amount_sat > max_amount_sat {
+                    bail!(
+                        "Minimum bet of {min_amount_sat} sats on note {} is bigger than its \
+                         maximum bet of {max_amount_sat} sats",
+                        note.note_id
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that these are the multiplier notes the round committed to.
+    ///
+    /// Rounds started before we committed to the multiplier notes cannot be checked.
+    pub fn verify_commitment(&self, round_commitment: Option<sha256::Hash>) -> Result<()> {
+        let Some(round_commitment) = round_commitment else {
+            return Ok(());
+        };
+
+        let commitment = self.commitment();
+        if commitment != round_commitment {
+            bail!(
+                "Multiplier notes changed during the round: committed to {round_commitment}, \
+                 but got {commitment}"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Stop accepting bets on the multiplier note with the `note_id` from `now` on.
+    ///
+    /// The note stays in play, so that the bets already placed on it are still settled and the
+    /// commitments of running rounds still match.
+    pub fn retract(&mut self, note_id: &str, now: OffsetDateTime) -> Result<()> {
+        let note = self
+            .0
+            .iter_mut()
+            .find(|note| note.note_id == note_id)
+            .with_context(|| format!("Unknown multiplier note {note_id}"))?;
+
+        note.active_until = Some(note.active_until.map_or(now, |until| until.min(now)));
+
+        Ok(())
+    }
+}
+
+/// A NIP-09 deletion request for the multiplier note with the `note_id`, to be signed by the main
+/// key which published it.
+pub fn multiplier_note_deletion(note_id: &str) -> Result<EventBuilder> {
+    let event_id = EventId::from_bech32(note_id)
+        .with_context(|| format!("Invalid multiplier note ID: {note_id}"))?;
+
+    Ok(EventBuilder::new(
+        Kind::EventDeletion,
+        "This multiplier note has been retracted. Bets on it are no longer accepted.",
+        [
+            Tag::event(event_id),
+            Tag::parse(&["k".to_string(), "1".to_string()])?,
+        ],
+    ))
+}
+
+/// Stop taking bets on a multiplier note with wrong odds, and ask relays to delete it.
+pub async fn retract_multiplier_note(
+    client: &nostr_sdk::Client,
+    keys: &nostr::Keys,
+    multipliers: &mut Multipliers,
+    note_id: &str,
+) -> Result<EventId> {
+    multipliers.retract(note_id, OffsetDateTime::now_utc())?;
+
+    let event = multiplier_note_deletion(note_id)?.to_event(keys)?;
+    let event_id = client.send_event(event).await?;
+
+    Ok(event_id)
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct MultiplierNote {
     pub multiplier: Multiplier,
     pub note_id: String,
+    #[serde(default)]
+    pub direction: BetDirection,
+    /// Bets on this note are only accepted from this moment on, if set.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub active_from: Option<OffsetDateTime>,
+    /// Bets on this note are only accepted until this moment, if set.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub active_until: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub payout_timing: PayoutTiming,
+    /// Overrides the default maximum bet of the multiplier, if set.
+    #[serde(default)]
+    pub max_amount_sat: Option<u64>,
+    /// The smallest bet accepted on this note, if set, so that high multipliers do not pay out
+    /// dust.
+    #[serde(default)]
+    pub min_amount_sat: Option<u64>,
+    /// The schedule of the rounds taking bets on this note.
+    #[serde(default = "default_schedule")]
+    pub schedule: String,
+}
+
+fn default_schedule() -> String {
+    DEFAULT_SCHEDULE.to_string()
+}
+
+/// A note paying out 2x instantly on rolls under the threshold, with no limits of its own.
+#[cfg(test)]
+pub fn test_multiplier_note(note_id: &str) -> MultiplierNote {
+    MultiplierNote {
+        multiplier: Multiplier::built_in("X2").unwrap(),
+        note_id: note_id.to_string(),
+        direction: BetDirection::Under,
+        active_from: None,
+        active_until: None,
+        payout_timing: PayoutTiming::Instant,
+        max_amount_sat: None,
+        min_amount_sat: None,
+        schedule: DEFAULT_SCHEDULE.to_string(),
+    }
+}
+
+impl MultiplierNote {
+    /// The biggest bet accepted on this note.
+    pub fn get_max_amount_sat(&self) -> u64 {
+        self.max_amount_sat
+            .unwrap_or_else(|| self.multiplier.get_max_amount_sat())
+    }
+
+    /// Check that a bet of `amount_msat` is neither smaller than the minimum nor bigger than the
+    /// maximum bet on this note.
+    pub fn check_amount(&self, amount_msat: u64) -> Result<()> {
+        if let Some(min_amount_sat) = self.min_amount_sat {
+            let min_amount_msat = min_amount_sat
+                .checked_mul(1000)
+                .with_context(|| format!("Minimum bet of {min_amount_sat} sats is too big"))?;
+            if amount_msat < min_amount_msat {
+                bail!(RouteError::AmountTooLow {
+                    amount_msat,
+                    min_amount_sat,
+                    multiplier: self.multiplier.get_content(),
+                });
+            }
+        }
+
+        let max_amount_sat = self.get_max_amount_sat();
+        let max_amount_msat = max_amount_sat
+            .checked_mul(1000)
+            .with_context(|| format!("Maximum bet of {max_amount_sat} sats is too big"))?;
+        if amount_msat > max_amount_msat {
+            bail!(RouteError::AmountTooHigh {
+                amount_msat,
+                max_amount_sat,
+                multiplier: self.multiplier.get_content(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Check that bets on this note are accepted at `now`.
+    pub fn check_active(&self, now: OffsetDateTime) -> Result<()> {
+        if let Some(active_from) = self.active_from {
+            if now < active_from {
+                bail!(RouteError::MultiplierNotYetActive {
+                    multiplier: self.multiplier.get_content(),
+                    active_from: active_from.format(&Rfc3339)?,
+                });
+            }
+        }
+
+        if let Some(active_until) = self.active_until {
+            if now >= active_until {
+                bail!(RouteError::MultiplierNoLongerActive {
+                    multiplier: self.multiplier.get_content(),
+                    active_until: active_until.format(&Rfc3339)?,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the `roll` wins a bet on this note, in a round rolling with the `scheme`.
+    pub fn is_win(&self, roll: u32, scheme: RollScheme) -> bool {
+        match self.direction {
+            BetDirection::Under => roll < self.multiplier.get_lower_than(scheme),
+            BetDirection::Over => roll > self.multiplier.get_higher_than(scheme),
+        }
+    }
+
+    /// A roll wins if it is strictly smaller (`Under`) or bigger (`Over`) than this, in a round
+    /// rolling with the `scheme`.
+    pub fn get_threshold(&self, scheme: RollScheme) -> u32 {
+        match self.direction {
+            BetDirection::Under => self.multiplier.get_lower_than(scheme),
+            BetDirection::Over => self.multiplier.get_higher_than(scheme),
+        }
+    }
+
+    /// The number the roll has to beat to win a bet on this note, e.g. "smaller than 31784".
+    pub fn get_target(&self, scheme: RollScheme) -> String {
+        match self.direction {
+            BetDirection::Under => format!("smaller than {}", self.get_threshold(scheme)),
+            BetDirection::Over => format!("bigger than {}", self.get_threshold(scheme)),
+        }
+    }
+
+    /// Check that the `event` of this note was published by `author` and states the multiplier and
+    /// threshold we settle its bets with, e.g. "Win 2x the amount you zapped if the rolled number is
+    /// lower than 31784!".
+    ///
+    /// Notes state the threshold of 16-bit rolls. Notes whose multiplier has a threshold of its
+    /// own for 32-bit rolls have to state it exactly as well, with the same phrase, e.g. "lower
+    /// than 64 (lower than 4187593 when rolling 32 bits)", so that 32-bit rolls cannot win less
+    /// often than the note promises.
+    pub fn verify_event(&self, event: &Event, author: &PublicKey) -> Result<()> {
+        if event.author() != *author {
+            bail!(
+                "Multiplier note {} was published by {}, not by us",
+                self.note_id,
+                event.author()
+            );
+        }
+
+        let (multiplier, direction, thresholds) = parse_multiplier_note(&event.content)
+            .with_context(|| format!("Failed to parse multiplier note {}", self.note_id))?;
+
+        let threshold = thresholds[0];
+        let expected_threshold = self.get_threshold(RollScheme::V1);
+        if multiplier != self.multiplier.get_multiplier()
+            || direction != self.direction
+            || threshold != expected_threshold
+        {
+            bail!(
+                "Multiplier note {} offers {multiplier}x for rolling {direction:?} {threshold}, \
+                 but we would pay out {}x for rolling {:?} {}",
+                self.note_id,
+                self.multiplier.get_multiplier(),
+                self.direction,
+                expected_threshold
+            );
+        }
+
+        if self.multiplier.lower_than_u32.is_some() {
+            let expected_threshold = self.get_threshold(RollScheme::V3);
+            if thresholds.get(1) != Some(&expected_threshold) {
+                bail!(
+                    "Multiplier note {} does not state the threshold of 32-bit rolls, {:?} {}",
+                    self.note_id,
+                    self.direction,
+                    expected_threshold
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the multiplier, the bet direction and the thresholds stated by a multiplier note, in the
+/// order they are stated. There is at least one threshold.
+fn parse_multiplier_note(content: &str) -> Result<(f32, BetDirection, Vec<u32>)> {
+    let multiplier = content
+        .split_whitespace()
+        .find_map(|word| word.strip_suffix('x')?.parse::<f32>().ok())
+        .context("No multiplier stated")?;
+
+    let (phrase, direction) = [
+        ("lower than ", BetDirection::Under),
+        ("smaller than ", BetDirection::Under),
+        ("higher than ", BetDirection::Over),
+        ("bigger than ", BetDirection::Over),
+    ]
+    .into_iter()
+    .find(|(phrase, _)| content.contains(phrase))
+    .context("No threshold stated")?;
+
+    let thresholds = content
+        .split(phrase)
+        .skip(1)
+        .map(|rest| {
+            rest.chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>()
+                .parse()
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid threshold")?;
+
+    Ok((multiplier, direction, thresholds))
 }
 
 impl fmt::Display for MultiplierNote {
@@ -28,83 +400,805 @@ impl fmt::Display for MultiplierNote {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, EnumIter, Debug)]
-pub enum Multiplier {
-    X1_05,
-    X1_1,
-    X1_33,
-    X1_5,
-    X2,
-    X3,
-    X10,
-    X25,
-    X50,
-    X100,
-    X1000,
+/// Whether a bet wins by rolling under or over the multiplier's threshold.
+///
+/// Both directions cover the same amount of numbers, so they have the same winning probability
+/// and house edge.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub enum BetDirection {
+    #[default]
+    Under,
+    Over,
+}
+
+/// When winners of a bet on a multiplier note are paid out.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub enum PayoutTiming {
+    /// As soon as the round has been revealed and the die rolled.
+    #[default]
+    Instant,
+    /// Only once the operator has reviewed the win, see `--release-queued-payout`.
+    Queued,
+}
+
+/// The multipliers we offered before they could be configured, by the name under which bets on
+/// them are stored, e.g. `X2`: the multiplier, the threshold of roll-under bets and the maximum
+/// bet.
+///
+/// Multipliers files without a `multipliers` list configure their notes under the lowercase name,
+/// e.g. `x2`.
+const BUILT_IN_MULTIPLIERS: [(&str, f32, u16, u64); 11] = [
+    ("X1_05", 1.05, 60_541, 95_238),
+    ("X1_1", 1.1, 57_789, 90_909),
+    ("X1_33", 1.33, 47_796, 75_188),
+    ("X1_5", 1.5, 42_379, 66_667),
+    ("X2", 2.0, 31_784, 50_000),
+    ("X3", 3.0, 21_189, 33_333),
+    ("X10", 10.0, 6_356, 10_000),
+    ("X25", 25.0, 2_542, 4_000),
+    ("X50", 50.0, 1_271, 2_000),
+    ("X100", 100.0, 635, 1_000),
+    ("X1000", 1000.0, 64, 100),
+];
+
+/// What a bet pays out if it wins, and how likely it is to win.
+///
+/// Built-in multipliers are stored under their name, so that bets placed before multipliers could
+/// be configured still parse, and all other multipliers with all their fields.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "StoredMultiplier", into = "StoredMultiplier")]
+pub struct Multiplier {
+    /// A winning bet pays out the bet times this.
+    pub multiplier: f32,
+    /// A 16-bit roll smaller than this wins a roll-under bet.
+    pub lower_than: u16,
+    /// A 32-bit roll smaller than this wins a roll-under bet, if set. Otherwise `lower_than` is
+    /// scaled up to 32 bits, which keeps the odds of 16-bit rolls.
+    pub lower_than_u32: Option<u32>,
+    /// How rollers are told about the multiplier, e.g. `2x`.
+    pub content: String,
+    /// The biggest bet accepted, unless overridden per multiplier note.
+    pub max_amount_sat: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredMultiplier {
+    BuiltIn(String),
+    Custom {
+        multiplier: f32,
+        lower_than: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lower_than_u32: Option<u32>,
+        content: String,
+        max_amount_sat: u64,
+    },
+}
+
+impl TryFrom<StoredMultiplier> for Multiplier {
+    type Error = String;
+
+    fn try_from(stored: StoredMultiplier) -> Result<Self, Self::Error> {
+        match stored {
+            StoredMultiplier::BuiltIn(name) => {
+                Multiplier::built_in(&name).ok_or_else(|| format!("Unknown multiplier {name}"))
+            }
+            StoredMultiplier::Custom {
+                multiplier,
+                lower_than,
+                lower_than_u32,
+                content,
+                max_amount_sat,
+            } => Ok(Multiplier {
+                multiplier,
+                lower_than,
+                lower_than_u32,
+                content,
+                max_amount_sat,
+            }),
+        }
+    }
+}
+
+impl From<Multiplier> for StoredMultiplier {
+    fn from(multiplier: Multiplier) -> Self {
+        match multiplier.built_in_name() {
+            Some(name) => StoredMultiplier::BuiltIn(name.to_string()),
+            None => StoredMultiplier::Custom {
+                multiplier: multiplier.multiplier,
+                lower_than: multiplier.lower_than,
+                lower_than_u32: multiplier.lower_than_u32,
+                content: multiplier.content,
+                max_amount_sat: multiplier.max_amount_sat,
+            },
+        }
+    }
 }
 
 impl Multiplier {
-    pub const fn get_max_amount_sat(&self) -> u64 {
-        match self {
-            Multiplier::X1000 => 100,
-            Multiplier::X100 => 1_000,
-            Multiplier::X50 => 2_000,
-            Multiplier::X25 => 4_000,
-            Multiplier::X10 => 10_000,
-            Multiplier::X3 => 33_333,
-            Multiplier::X2 => 50_000,
-            Multiplier::X1_5 => 66_667,
-            Multiplier::X1_33 => 75_188,
-            Multiplier::X1_1 => 90_909,
-            Multiplier::X1_05 => 95_238,
-        }
-    }
-
-    pub const fn get_multiplier(&self) -> f32 {
-        match self {
-            Multiplier::X1_05 => 1.05,
-            Multiplier::X1_1 => 1.10,
-            Multiplier::X1_33 => 1.33,
-            Multiplier::X1_5 => 1.5,
-            Multiplier::X2 => 2.0,
-            Multiplier::X3 => 3.0,
-            Multiplier::X10 => 10.0,
-            Multiplier::X25 => 25.0,
-            Multiplier::X50 => 50.0,
-            Multiplier::X100 => 100.0,
-            Multiplier::X1000 => 1000.0,
-        }
-    }
-
-    pub const fn get_lower_than(&self) -> u16 {
-        match self {
-            Multiplier::X1_05 => 60_541,
-            Multiplier::X1_1 => 57_789,
-            Multiplier::X1_33 => 47_796,
-            Multiplier::X1_5 => 42_379,
-            Multiplier::X2 => 31_784,
-            Multiplier::X3 => 21_189,
-            Multiplier::X10 => 6_356,
-            Multiplier::X25 => 2_542,
-            Multiplier::X50 => 1_271,
-            Multiplier::X100 => 635,
-            Multiplier::X1000 => 64,
+    /// The built-in multiplier with the `name`, e.g. `X2`.
+    pub fn built_in(name: &str) -> Option<Self> {
+        BUILT_IN_MULTIPLIERS
+            .iter()
+            .find(|(built_in, ..)| *built_in == name)
+            .map(|(_, multiplier, lower_than, max_amount_sat)| Multiplier {
+                multiplier: *multiplier,
+                lower_than: *lower_than,
+                lower_than_u32: None,
+                content: format!("{multiplier}x"),
+                max_amount_sat: *max_amount_sat,
+            })
+    }
+
+    /// All the built-in multipliers, by name.
+    pub fn built_ins() -> impl Iterator<Item = (&'static str, Self)> {
+        BUILT_IN_MULTIPLIERS
+            .iter()
+            .map(|(name, ..)| (*name, Self::built_in(name).expect("built in")))
+    }
+
+    fn built_in_name(&self) -> Option<&'static str> {
+        Self::built_ins()
+            .find(|(_, built_in)| built_in == self)
+            .map(|(name, _)| name)
+    }
+
+    /// Check that the multiplier can be won, and that the house keeps a share of every bet on
+    /// average, i.e. `0 < lower_than < 65536` and `win_probability * multiplier < 1`, with 16-bit
+    /// and with 32-bit rolls.
+    ///
+    /// A `lower_than_u32` has to round up to `lower_than`, so that multiplier notes stating the
+    /// threshold of 16-bit rolls do not promise more than 32-bit rolls keep.
+    pub fn validate(&self) -> Result<()> {
+        if self.multiplier <= 1.0 {
+            bail!("{} would not pay out more than the bet", self.content);
+        }
+        if self.lower_than == 0 {
+            bail!("{} can never be won: lower_than is 0", self.content);
         }
+
+        if let Some(lower_than_u32) = self.lower_than_u32 {
+            if u64::from(lower_than_u32).div_ceil(1 << 16) != u64::from(self.lower_than) {
+                bail!(
+                    "{}'s lower_than_u32 of {lower_than_u32} does not round up to its lower_than \
+                     of {}",
+                    self.content,
+                    self.lower_than
+                );
+            }
+        }
+
+        for scheme in [RollScheme::V1, RollScheme::V3] {
+            let expected_payout = self.win_probability(scheme) * self.get_multiplier();
+            if expected_payout >= 1.0 {
+                bail!(
+                    "{} would pay out {expected_payout} times what is bet on average with \
+                     {}-bit rolls, losing us money. Lower its lower_than of {}",
+                    self.content,
+                    8 * scheme.roll_bytes(),
+                    self.get_lower_than(scheme)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_max_amount_sat(&self) -> u64 {
+        self.max_amount_sat
+    }
+
+    pub fn get_multiplier(&self) -> f32 {
+        self.multiplier
+    }
+
+    /// A roll smaller than this wins a roll-under bet, in a round rolling with the `scheme`.
+    pub fn get_lower_than(&self, scheme: RollScheme) -> u32 {
+        match scheme {
+            RollScheme::V1 | RollScheme::V2 => u32::from(self.lower_than),
+            RollScheme::V3 => self
+                .lower_than_u32
+                .unwrap_or(u32::from(self.lower_than) << 16),
+        }
+    }
+
+    /// The chance of a roll winning a bet on this multiplier, in either direction, in a round
+    /// rolling with the `scheme`.
+    pub fn win_probability(&self, scheme: RollScheme) -> f32 {
+        (f64::from(self.get_lower_than(scheme)) / scheme.roll_count() as f64) as f32
+    }
+
+    /// The share of every bet the house keeps on average, in a round rolling with the `scheme`.
+    pub fn house_edge(&self, scheme: RollScheme) -> f32 {
+        1.0 - self.win_probability(scheme) * self.get_multiplier()
+    }
+
+    /// A roll bigger than this wins a roll-over bet, in a round rolling with the `scheme`.
+    ///
+    /// Mirrors `get_lower_than`, so that exactly as many numbers win in either direction.
+    pub fn get_higher_than(&self, scheme: RollScheme) -> u32 {
+        (scheme.roll_count() - 1) as u32 - self.get_lower_than(scheme)
     }
 
     pub fn get_content(&self) -> String {
-        match self {
-            Multiplier::X1_05 => "1.05x".to_string(),
-            Multiplier::X1_1 => "1.1x".to_string(),
-            Multiplier::X1_33 => "1.33x".to_string(),
-            Multiplier::X1_5 => "1.5x".to_string(),
-            Multiplier::X2 => "2x".to_string(),
-            Multiplier::X3 => "3x".to_string(),
-            Multiplier::X10 => "10x".to_string(),
-            Multiplier::X25 => "25x".to_string(),
-            Multiplier::X50 => "50x".to_string(),
-            Multiplier::X100 => "100x".to_string(),
-            Multiplier::X1000 => "1000x".to_string(),
+        self.content.clone()
+    }
+}
+
+/// The content hashes of the multiplier notes, pinned when the server starts.
+///
+/// Kind 1 notes cannot be edited, but we would rather not bet our payouts on every relay and
+/// client agreeing on that. If a multiplier note we fetch later no longer matches its pinned hash,
+/// the note is marked as tampered and we stop accepting bets on it.
+#[derive(Clone, Debug, Default)]
+pub struct MultiplierPins {
+    pinned: HashMap<String, sha256::Hash>,
+    tampered: Arc<RwLock<HashSet<String>>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PinStatus {
+    Unpinned,
+    Unchanged,
+    Changed {
+        pinned: sha256::Hash,
+        actual: sha256::Hash,
+    },
+}
+
+impl MultiplierPins {
+    /// Pin the `content` of each multiplier note, keyed by note ID.
+    pub fn new<'a>(notes: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let pinned = notes
+            .into_iter()
+            .map(|(note_id, content)| (note_id.to_string(), content_hash(content)))
+            .collect();
+
+        Self {
+            pinned,
+            tampered: Arc::default(),
+        }
+    }
+
+    /// Compare the current `content` of a multiplier note against its pinned hash, marking the
+    /// note as tampered if they differ.
+    pub fn check(&self, note_id: &str, content: &str) -> PinStatus {
+        let pinned = match self.pinned.get(note_id) {
+            Some(pinned) => *pinned,
+            None => return PinStatus::Unpinned,
+        };
+
+        let actual = content_hash(content);
+        if actual == pinned {
+            return PinStatus::Unchanged;
+        }
+
+        self.tampered
+            .write()
+            .expect("not poisoned")
+            .insert(note_id.to_string());
+
+        PinStatus::Changed { pinned, actual }
+    }
+
+    pub fn is_tampered(&self, note_id: &str) -> bool {
+        self.tampered
+            .read()
+            .expect("not poisoned")
+            .contains(note_id)
+    }
+}
+
+pub fn content_hash(content: &str) -> sha256::Hash {
+    sha256::Hash::hash(content.as_bytes())
+}
+
+/// Fetch the multiplier notes from the relays, keyed by note ID.
+pub async fn fetch_multiplier_notes(
+    client: &nostr_sdk::Client,
+    multipliers: &Multipliers,
+) -> Result<HashMap<String, Event>> {
+    let ids = multipliers
+        .0
+        .iter()
+        .map(|note| {
+            EventId::from_bech32(&note.note_id)
+                .with_context(|| format!("Invalid multiplier note ID: {}", note.note_id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let events = client
+        .get_events_of(
+            vec![Filter::new().ids(ids)],
+            Some(FETCH_MULTIPLIER_NOTES_TIMEOUT),
+        )
+        .await
+        .context("Failed to fetch multiplier notes")?;
+
+    let events = events
+        .into_iter()
+        .filter_map(|event| {
+            let note_id = event.id.to_bech32().ok()?;
+            Some((note_id, event))
+        })
+        .collect();
+
+    Ok(events)
+}
+
+/// Periodically re-fetch the multiplier notes and compare them against their pinned hashes.
+pub async fn watch_multiplier_notes(
+    client: nostr_sdk::Client,
+    multipliers: Multipliers,
+    pins: MultiplierPins,
+    interval: Duration,
+    mut ctrl_c: broadcast::Receiver<()>,
+) {
+    loop {
+        select! {
+            _ = tokio::time::sleep(interval) => (),
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down multiplier note watcher...");
+                return;
+            },
+        }
+
+        let events = match fetch_multiplier_notes(&client, &multipliers).await {
+            Ok(events) => events,
+            Err(e) => {
+                tracing::error!("Failed to re-fetch multiplier notes: {e:#}");
+                continue;
+            }
+        };
+
+        for note in multipliers.0.iter() {
+            let Some(event) = events.get(&note.note_id) else {
+                METRICS.multiplier_note_missing();
+                tracing::warn!(
+                    note_id = note.note_id,
+                    "Could not re-fetch multiplier note to check its pin"
+                );
+                continue;
+            };
+
+            let already_tampered = pins.is_tampered(&note.note_id);
+            if let PinStatus::Changed { pinned, actual } = pins.check(&note.note_id, &event.content)
+            {
+                if !already_tampered {
+                    METRICS.multiplier_note_tampered();
+                }
+                tracing::error!(
+                    note_id = note.note_id,
+                    %pinned,
+                    %actual,
+                    "Multiplier note content changed! Refusing bets on it"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+
+    const NOTE_ID: &str = "note1gsc66mle93sqfj8k96qj63pkma7ume6vruywkk84jee6hwkualzsynp02d";
+    const CONTENT: &str =
+        "Win 1.05x the amount you zapped if the rolled number is lower than 60541!";
+
+    fn multiplier_note(direction: BetDirection) -> MultiplierNote {
+        MultiplierNote {
+            direction,
+            ..test_multiplier_note(NOTE_ID)
         }
     }
+
+    #[test]
+    fn house_edge_is_at_most_a_few_percent() {
+        for (_, multiplier) in Multiplier::built_ins() {
+            for scheme in [RollScheme::V1, RollScheme::V3] {
+                let house_edge = multiplier.house_edge(scheme);
+                assert!(
+                    (0.02..0.032).contains(&house_edge),
+                    "{} has a house edge of {house_edge}",
+                    multiplier.get_content()
+                );
+            }
+        }
+
+        let double = Multiplier::built_in("X2").unwrap();
+        assert_eq!(double.win_probability(RollScheme::V1), 31_784.0 / 65_536.0);
+        assert_eq!(
+            double.win_probability(RollScheme::V1),
+            double.win_probability(RollScheme::V3)
+        );
+    }
+
+    #[test]
+    fn built_in_multipliers_are_valid() {
+        for (name, multiplier) in Multiplier::built_ins() {
+            assert!(multiplier.validate().is_ok(), "{name} is invalid");
+        }
+    }
+
+    #[test]
+    fn multipliers_losing_us_money_are_invalid() {
+        let multiplier = |multiplier: f32, lower_than: u16| Multiplier {
+            multiplier,
+            lower_than,
+            lower_than_u32: None,
+            content: format!("{multiplier}x"),
+            max_amount_sat: 1_000,
+        };
+
+        assert!(multiplier(5.0, 12_713).validate().is_ok());
+
+        // No house edge, or a negative one.
+        assert!(multiplier(2.0, 32_768).validate().is_err());
+        assert!(multiplier(2.0, 40_000).validate().is_err());
+        assert!(multiplier(1000.0, 66).validate().is_err());
+
+        // Can never be won, or pays out no more than the bet.
+        assert!(multiplier(2.0, 0).validate().is_err());
+        assert!(multiplier(1.0, 31_784).validate().is_err());
+
+        // The threshold of 32-bit rolls has to round up to the one of 16-bit rolls.
+        let thousand = |lower_than_u32| Multiplier {
+            lower_than_u32: Some(lower_than_u32),
+            ..multiplier(1000.0, 64)
+        };
+        assert!(thousand(4_187_593).validate().is_ok());
+        assert!(thousand(4_194_304).validate().is_ok());
+        assert!(thousand(4_128_768).validate().is_err());
+        assert!(thousand(4_194_305).validate().is_err());
+    }
+
+    #[test]
+    fn rolls_of_32_bits_can_have_a_finer_threshold() {
+        let note = MultiplierNote {
+            multiplier: Multiplier {
+                multiplier: 1000.0,
+                lower_than: 64,
+                lower_than_u32: Some(4_187_593),
+                content: "1000x".to_string(),
+                max_amount_sat: 100,
+            },
+            ..multiplier_note(BetDirection::Under)
+        };
+
+        assert_eq!(note.get_threshold(RollScheme::V1), 64);
+        assert_eq!(note.get_threshold(RollScheme::V3), 4_187_593);
+        assert!(note.is_win(4_187_592, RollScheme::V3));
+        assert!(!note.is_win(4_187_593, RollScheme::V3));
+        assert_eq!(note.get_target(RollScheme::V3), "smaller than 4187593");
+
+        // Without a threshold of their own, 32-bit rolls win as often as 16-bit ones.
+        let note = multiplier_note(BetDirection::Over);
+        assert_eq!(
+            note.get_threshold(RollScheme::V3),
+            u32::MAX - (31_784 << 16)
+        );
+        assert!(note.is_win(u32::MAX, RollScheme::V3));
+        assert!(!note.is_win((33_751 << 16) | 0xffff, RollScheme::V3));
+        assert!(note.is_win(33_752 << 16, RollScheme::V3));
+    }
+
+    #[test]
+    fn bets_on_built_in_multipliers_are_stored_under_their_name() {
+        let double = Multiplier::built_in("X2").unwrap();
+        assert_eq!(double.content, "2x");
+        assert_eq!(serde_json::to_string(&double).unwrap(), r#""X2""#);
+        assert_eq!(
+            serde_json::from_str::<Multiplier>(r#""X2""#).unwrap(),
+            double
+        );
+        assert!(serde_json::from_str::<Multiplier>(r#""X7""#).is_err());
+
+        let five = Multiplier {
+            multiplier: 5.0,
+            lower_than: 12_713,
+            lower_than_u32: None,
+            content: "5x".to_string(),
+            max_amount_sat: 20_000,
+        };
+        let stored = serde_json::to_string(&five).unwrap();
+        assert_eq!(serde_json::from_str::<Multiplier>(&stored).unwrap(), five);
+
+        let five = Multiplier {
+            lower_than_u32: Some(833_154_000),
+            ..five
+        };
+        let stored = serde_json::to_string(&five).unwrap();
+        assert_eq!(serde_json::from_str::<Multiplier>(&stored).unwrap(), five);
+    }
+
+    #[test]
+    fn multiplier_note_events_must_state_their_odds() {
+        let keys = Keys::generate();
+        let note = multiplier_note(BetDirection::Under);
+        let event = |content: &str, keys: &Keys| {
+            EventBuilder::text_note(content, []).to_event(keys).unwrap()
+        };
+
+        let content = "Win 2x the amount you zapped if the rolled number is lower than 31784!";
+        note.verify_event(&event(content, &keys), &keys.public_key())
+            .unwrap();
+
+        // Published by someone else.
+        assert!(note
+            .verify_event(&event(content, &Keys::generate()), &keys.public_key())
+            .is_err());
+
+        // Wrong threshold, multiplier or direction.
+        for content in [
+            "Win 2x the amount you zapped if the rolled number is lower than 32000!",
+            "Win 3x the amount you zapped if the rolled number is lower than 31784!",
+            "Win 2x the amount you zapped if the rolled number is higher than 31784!",
+            "Win big!",
+        ] {
+            assert!(note
+                .verify_event(&event(content, &keys), &keys.public_key())
+                .is_err());
+        }
+
+        let note = multiplier_note(BetDirection::Over);
+        let content = "Win 2x the amount you zapped if the rolled number is higher than 33751!";
+        note.verify_event(&event(content, &keys), &keys.public_key())
+            .unwrap();
+
+        // Notes with a threshold of their own for 32-bit rolls have to state it exactly.
+        let note = MultiplierNote {
+            multiplier: Multiplier {
+                multiplier: 1000.0,
+                lower_than: 64,
+                lower_than_u32: Some(4_187_593),
+                content: "1000x".to_string(),
+                max_amount_sat: 100,
+            },
+            ..multiplier_note(BetDirection::Under)
+        };
+        let content = "Win 1000x the amount you zapped if the rolled number is lower than 64 \
+                       (lower than 4187593 when rolling 32 bits)!";
+        note.verify_event(&event(content, &keys), &keys.public_key())
+            .unwrap();
+        for content in [
+            "Win 1000x the amount you zapped if the rolled number is lower than 64!",
+            "Win 1000x the amount you zapped if the rolled number is lower than 64 \
+             (lower than 4194304 when rolling 32 bits)!",
+        ] {
+            assert!(note
+                .verify_event(&event(content, &keys), &keys.public_key())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn bets_must_be_between_the_minimum_and_maximum_bet() {
+        let note = MultiplierNote {
+            min_amount_sat: Some(10),
+            max_amount_sat: Some(1_000),
+            ..multiplier_note(BetDirection::Under)
+        };
+
+        assert!(note.check_amount(10_000).is_ok());
+        assert!(note.check_amount(1_000_000).is_ok());
+
+        let error = note.check_amount(9_999).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<RouteError>().map(RouteError::code),
+            Some("AMOUNT_TOO_LOW")
+        );
+        let error = note.check_amount(1_000_001).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<RouteError>().map(RouteError::code),
+            Some("AMOUNT_TOO_HIGH")
+        );
+
+        // Without a minimum, any bet up to the maximum is accepted.
+        assert!(multiplier_note(BetDirection::Under).check_amount(1).is_ok());
+
+        let note = MultiplierNote {
+            min_amount_sat: Some(u64::MAX),
+            ..note
+        };
+        assert!(note.check_amount(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn configured_max_amount_overrides_the_default() {
+        let note = multiplier_note(BetDirection::Under);
+        assert_eq!(note.get_max_amount_sat(), 50_000);
+
+        let note = MultiplierNote {
+            max_amount_sat: Some(1_000),
+            ..note
+        };
+        assert_eq!(note.get_max_amount_sat(), 1_000);
+    }
+
+    #[test]
+    fn roll_under_wins_below_threshold() {
+        let note = multiplier_note(BetDirection::Under);
+
+        assert!(note.is_win(0, RollScheme::V1));
+        assert!(note.is_win(31_783, RollScheme::V1));
+        assert!(!note.is_win(31_784, RollScheme::V1));
+        assert!(!note.is_win(u16::MAX.into(), RollScheme::V1));
+    }
+
+    #[test]
+    fn roll_over_wins_above_threshold() {
+        let note = multiplier_note(BetDirection::Over);
+
+        assert!(!note.is_win(0, RollScheme::V1));
+        assert!(!note.is_win(33_751, RollScheme::V1));
+        assert!(note.is_win(33_752, RollScheme::V1));
+        assert!(note.is_win(u16::MAX.into(), RollScheme::V1));
+    }
+
+    #[test]
+    fn both_directions_have_the_same_odds() {
+        for (_, multiplier) in Multiplier::built_ins() {
+            let under = MultiplierNote {
+                multiplier: multiplier.clone(),
+                ..test_multiplier_note(NOTE_ID)
+            };
+            let over = MultiplierNote {
+                direction: BetDirection::Over,
+                ..under.clone()
+            };
+
+            let wins = |note: &MultiplierNote| {
+                (0..=u32::from(u16::MAX))
+                    .filter(|roll| note.is_win(*roll, RollScheme::V1))
+                    .count()
+            };
+            let under_wins = wins(&under);
+            let over_wins = wins(&over);
+
+            assert_eq!(under_wins, over_wins, "{}", multiplier.get_content());
+        }
+    }
+
+    #[test]
+    fn unchanged_note_is_accepted() {
+        let pins = MultiplierPins::new([(NOTE_ID, CONTENT)]);
+
+        assert_eq!(pins.check(NOTE_ID, CONTENT), PinStatus::Unchanged);
+        assert!(!pins.is_tampered(NOTE_ID));
+    }
+
+    #[test]
+    fn changed_note_is_marked_as_tampered() {
+        let pins = MultiplierPins::new([(NOTE_ID, CONTENT)]);
+
+        let status = pins.check(
+            NOTE_ID,
+            "Win 1.05x the amount you zapped if the rolled number is lower than 65000!",
+        );
+
+        assert!(matches!(status, PinStatus::Changed { .. }));
+        assert!(pins.is_tampered(NOTE_ID));
+        // Clones share the tampered notes.
+        assert!(pins.clone().is_tampered(NOTE_ID));
+    }
+
+    #[test]
+    fn unknown_note_is_unpinned() {
+        let pins = MultiplierPins::new([(NOTE_ID, CONTENT)]);
+
+        assert_eq!(pins.check("note1unknown", CONTENT), PinStatus::Unpinned);
+        assert!(!pins.is_tampered("note1unknown"));
+    }
+
+    #[test]
+    fn multipliers_commitment_does_not_depend_on_order() {
+        let under = multiplier_note(BetDirection::Under);
+        let over = MultiplierNote {
+            note_id: "note1over".to_string(),
+            ..multiplier_note(BetDirection::Over)
+        };
+
+        let multipliers = Multipliers(vec![under.clone(), over.clone()]);
+        let reordered = Multipliers(vec![over, under]);
+
+        assert_eq!(multipliers.commitment(), reordered.commitment());
+        assert!(reordered
+            .verify_commitment(Some(multipliers.commitment()))
+            .is_ok());
+    }
+
+    #[test]
+    fn finer_thresholds_of_32_bit_rolls_are_committed_to() {
+        let multipliers = Multipliers(vec![multiplier_note(BetDirection::Under)]);
+        let mut finer = multiplier_note(BetDirection::Under);
+        finer.multiplier.lower_than_u32 = Some(2_082_990_000);
+
+        assert_eq!(
+            multipliers.commitment(),
+            sha256::Hash::hash(format!("{NOTE_ID}:under:31784\n").as_bytes())
+        );
+        assert_eq!(
+            Multipliers(vec![finer]).commitment(),
+            sha256::Hash::hash(format!("{NOTE_ID}:under:31784:2082990000\n").as_bytes())
+        );
+    }
+
+    #[test]
+    fn changing_the_odds_breaks_the_multipliers_commitment() {
+        let multipliers = Multipliers(vec![multiplier_note(BetDirection::Under)]);
+        let flipped = Multipliers(vec![multiplier_note(BetDirection::Over)]);
+
+        assert_ne!(multipliers.commitment(), flipped.commitment());
+        assert!(flipped
+            .verify_commitment(Some(multipliers.commitment()))
+            .is_err());
+        // Rounds without a commitment cannot be checked.
+        assert!(flipped.verify_commitment(None).is_ok());
+    }
+
+    #[test]
+    fn retracted_notes_stay_in_play_but_take_no_bets() {
+        let now = time::macros::datetime!(2024-09-12 12:00 UTC);
+        let mut multipliers = Multipliers(vec![multiplier_note(BetDirection::Under)]);
+        let commitment = multipliers.commitment();
+
+        multipliers.retract(NOTE_ID, now).unwrap();
+
+        let note = multipliers.get_multiplier_note(NOTE_ID).unwrap();
+        assert!(note.check_active(now - time::Duration::SECOND).is_ok());
+        assert!(note.check_active(now).is_err());
+        assert!(multipliers.verify_commitment(Some(commitment)).is_ok());
+        assert!(multipliers.retract("note1unknown", now).is_err());
+
+        let keys = nostr::Keys::generate();
+        let deletion = multiplier_note_deletion(NOTE_ID)
+            .unwrap()
+            .to_event(&keys)
+            .unwrap();
+
+        assert_eq!(deletion.kind, Kind::EventDeletion);
+        assert_eq!(deletion.author(), keys.public_key());
+        let event_id = EventId::from_bech32(NOTE_ID).unwrap().to_hex();
+        assert!(deletion
+            .tags
+            .iter()
+            .any(|tag| tag.as_vec() == ["e".to_string(), event_id.clone()]));
+    }
+
+    #[test]
+    fn bets_are_only_accepted_within_the_active_window() {
+        let active_from = time::macros::datetime!(2024-09-01 12:00 UTC);
+        let active_until = time::macros::datetime!(2024-09-08 12:00 UTC);
+        let note = MultiplierNote {
+            active_from: Some(active_from),
+            active_until: Some(active_until),
+            ..multiplier_note(BetDirection::Under)
+        };
+
+        let before = note
+            .check_active(active_from - time::Duration::SECOND)
+            .unwrap_err();
+        assert!(before
+            .to_string()
+            .contains("not available yet. Bets open at 2024-09-01T12:00:00Z"));
+
+        assert!(note.check_active(active_from).is_ok());
+        assert!(note
+            .check_active(active_until - time::Duration::SECOND)
+            .is_ok());
+
+        let after = note.check_active(active_until).unwrap_err();
+        assert!(after
+            .to_string()
+            .contains("no longer available. Bets closed at 2024-09-08T12:00:00Z"));
+    }
+
+    #[test]
+    fn notes_without_a_window_are_always_active() {
+        let note = multiplier_note(BetDirection::Over);
+
+        assert!(note.check_active(OffsetDateTime::now_utc()).is_ok());
+    }
 }
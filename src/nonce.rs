@@ -1,9 +1,20 @@
+use crate::beacon::roll_seed;
+use crate::beacon::RandomnessBeacon;
 use crate::db;
+use crate::db::BetState;
 use crate::db::Round;
+use crate::db::RoundRecord;
 use crate::db::RoundRow;
+use crate::multiplier::Multipliers;
+use crate::payouts::calculate_price_money;
+use crate::payouts::PayoutRounding;
+use crate::roll::RollScheme;
+use crate::routes::invoice_lifetime_after_expiry_secs;
+use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
 use nostr::bitcoin::hashes::sha256;
+use nostr::Kind;
 use nostr::Tag;
 use nostr_sdk::hashes::Hash;
 use nostr_sdk::hashes::HashEngine;
@@ -15,13 +26,30 @@ use rand::thread_rng;
 use rand::Rng;
 use rand::RngCore;
 use rand::SeedableRng;
+use serde::Deserialize;
+use serde::Serialize;
 use sqlx::query;
 use sqlx::query_as;
 use sqlx::SqlitePool;
+use std::future::Future;
 use std::ops::ControlFlow;
 use std::time::Duration;
 use std::time::Instant;
+use time::OffsetDateTime;
 use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The tag of a nonce commitment note carrying the [`Multipliers::commitment`].
+const MULTIPLIERS_COMMITMENT_TAG: &str = "multipliers";
+
+/// The tag of a nonce commitment note carrying the randomness beacon and the round of it which
+/// is mixed into the nonce.
+const BEACON_TAG: &str = "beacon";
+
+/// The `d` tag of the event listing the bets of a round is this, followed by a colon and the hex
+/// encoded nonce commitment note ID.
+const BET_MANIFEST_IDENTIFIER: &str = "nostrdice-bets";
 
 /// The randomness generated by the server every round.
 struct Nonce {
@@ -36,9 +64,13 @@ struct Nonce {
     expire_after: Duration,
     /// A nonce is revealed this long after _expiration_.
     reveal_after: Duration,
+    /// The randomness beacon round mixed into the nonce, if any. It is published after the nonce
+    /// expires.
+    beacon_round: Option<u64>,
 }
 
-/// Manage nonce generation, expiration and revelation.
+/// Manage nonce generation, expiration and revelation for the rounds of the `schedule` of the
+/// `game`.
 ///
 /// Steps:
 ///
@@ -63,66 +95,156 @@ struct Nonce {
 /// The goal of this flow is to allow rollers to safely bet at any point. If they zap when there is
 /// an active nonce, and complete the payment before the zap invoice expires, they will be
 /// considered when the payouts are calculated.
+#[allow(clippy::too_many_arguments)]
 pub async fn manage_nonces(
     client: nostr_sdk::Client,
     keys: nostr::Keys,
     db: SqlitePool,
+    game: String,
+    schedule: String,
     expire_after_secs: u64,
     reveal_after_secs: u64,
+    max_invoice_expiry_secs: u64,
+    roll_scheme: RollScheme,
+    max_pending_reveals: usize,
+    multipliers: Multipliers,
+    beacon: Option<RandomnessBeacon>,
+    revealed: mpsc::UnboundedSender<EventId>,
     mut ctrl_c: broadcast::Receiver<()>,
 ) -> Result<()> {
+    let multipliers_commitment = multipliers.commitment();
+    let invoice_lifetime_secs =
+        invoice_lifetime_after_expiry_secs(reveal_after_secs, max_invoice_expiry_secs);
+
+    let (reveal_scheduler, _) = RevealScheduler::start(max_pending_reveals, {
+        let client = client.clone();
+        let keys = keys.clone();
+        let db = db.clone();
+        let beacon = beacon.clone();
+        let revealed = revealed.clone();
+        move |pending| {
+            reveal_nonce_later(
+                client.clone(),
+                keys.clone(),
+                db.clone(),
+                beacon.clone(),
+                revealed.clone(),
+                pending,
+            )
+        }
+    });
+
     // Immediately unset the nonce, so that we do not use a nonce that may have been revealed
     // already. This also ensures that we pay out any winners.
-    if let Some(round) = unset_active_nonce(&db).await? {
+    if let Some(round) = unset_active_nonce(&db, &game, &schedule).await? {
         // We may have already revealed this nonce before the restart, but doing so again does not
         // hurt.
-        if let Err(e) = reveal_nonce(&client, &keys, round.nonce, round.event_id).await {
+        if let Err(e) = reveal_nonce(
+            &client,
+            &keys,
+            &db,
+            beacon.as_ref(),
+            &revealed,
+            round.nonce,
+            round.beacon_round,
+            round.event_id,
+        )
+        .await
+        {
             tracing::error!(
                 nonce = hex::encode(round.nonce),
-                "Failed to reveal nonce after restart: {e:#}. Must publish and handle payouts \
-                 manually"
+                "Failed to reveal nonce after restart: {e:#}. Must be revealed with \
+                 POST /admin/reveal"
             );
         };
     }
 
-    // Ensure that we reveal the latest expired nonce. This also ensures that we pay out any
-    // winners.
-    if let Some(round) = get_latest_expired_nonce(&db).await? {
+    // Rounds which expired before the restart are revealed when they are due, as if we had not
+    // restarted. This also ensures that we pay out any winners.
+    schedule_unrevealed_rounds(
+        &db,
+        &game,
+        &schedule,
+        Duration::from_secs(reveal_after_secs),
+        &reveal_scheduler,
+    )
+    .await?;
+
+    // Rounds started before we recorded their expiry cannot be scheduled, so the latest one is
+    // revealed right away.
+    if let Some(round) = get_latest_expired_nonce(&db, &game, &schedule)
+        .await?
+        .filter(|round| round.expires_at.is_none())
+    {
         // We may have already revealed this nonce before the restart, but doing so again does not
         // hurt.
-        if let Err(e) = reveal_nonce(&client, &keys, round.nonce, round.event_id).await {
+        if let Err(e) = reveal_nonce(
+            &client,
+            &keys,
+            &db,
+            beacon.as_ref(),
+            &revealed,
+            round.nonce,
+            round.beacon_round,
+            round.event_id,
+        )
+        .await
+        {
             tracing::error!(
                 nonce = hex::encode(round.nonce),
-                "Failed to reveal expired nonce after restart: {e:#}. Must publish and handle \
-                 payouts manually"
+                "Failed to reveal expired nonce after restart: {e:#}. Must be revealed with \
+                 POST /admin/reveal"
             );
         };
     }
 
     loop {
-        let active_nonce = Nonce::new(thread_rng(), expire_after_secs, reveal_after_secs);
-
-        let commitment_event_id =
-            match publish_nonce_commitment(&client, &keys, active_nonce.commitment).await {
-                Ok(event_id) => event_id,
-                Err(e) => {
-                    tracing::error!("Failed to publish nonce commitment: {e:#}. Trying again");
-                    continue;
-                }
-            };
+        let active_nonce = Nonce::new(
+            thread_rng(),
+            expire_after_secs,
+            reveal_after_secs,
+            invoice_lifetime_secs,
+            beacon.as_ref(),
+        );
+
+        let expires_at = active_nonce.expires_at();
+
+        let commitment_event_id = match publish_nonce_commitment(
+            &client,
+            &keys,
+            active_nonce.commitment,
+            roll_scheme,
+            multipliers_commitment,
+            beacon.as_ref().zip(active_nonce.beacon_round),
+        )
+        .await
+        {
+            Ok(event_id) => event_id,
+            Err(e) => {
+                tracing::error!("Failed to publish nonce commitment: {e:#}. Trying again");
+                continue;
+            }
+        };
 
         if let Err(e) = set_active_nonce(
             &db,
+            &game,
+            &schedule,
             db::Round {
                 nonce: active_nonce.inner,
                 event_id: commitment_event_id,
+                roll_scheme,
+                multipliers_commitment: Some(multipliers_commitment),
+                beacon_round: active_nonce.beacon_round,
+                beacon_randomness: None,
+                expires_at: Some(expires_at),
             },
         )
         .await
         {
             tracing::error!("Failed to set active nonce: {e:#}");
 
-            if let Err(e) = unset_active_nonce(&db).await {
+            if let Err(e) = unset_active_nonce(&db, &game, &schedule).await {
                 tracing::error!("Failed to unset active nonce. This is bad! Error: {e:#}");
             }
 
@@ -145,9 +267,16 @@ pub async fn manage_nonces(
 
         if let Err(e) = set_latest_expired_nonce(
             &db,
+            &game,
+            &schedule,
             db::Round {
                 nonce: active_nonce.inner,
                 event_id: commitment_event_id,
+                roll_scheme,
+                multipliers_commitment: Some(multipliers_commitment),
+                beacon_round: active_nonce.beacon_round,
+                beacon_randomness: None,
+                expires_at: Some(expires_at),
             },
         )
         .await
@@ -160,24 +289,30 @@ pub async fn manage_nonces(
         }
 
         if exit.is_continue() {
-            tokio::spawn(reveal_nonce_later(
-                client.clone(),
-                keys.clone(),
-                active_nonce,
-                commitment_event_id,
-            ));
+            reveal_scheduler
+                .schedule(PendingReveal::new(&active_nonce, commitment_event_id))
+                .await?;
         } else {
             tracing::info!("Revealing nonce now due to Ctrl+C");
-            if let Err(e) =
-                reveal_nonce(&client, &keys, active_nonce.inner, commitment_event_id).await
+            if let Err(e) = reveal_nonce(
+                &client,
+                &keys,
+                &db,
+                beacon.as_ref(),
+                &revealed,
+                active_nonce.inner,
+                active_nonce.beacon_round,
+                commitment_event_id,
+            )
+            .await
             {
                 tracing::error!(
                     nonce = hex::encode(active_nonce.inner),
-                    "Failed to reveal nonce: {e:#}. Must publish manually"
+                    "Failed to reveal nonce: {e:#}. Must be revealed with POST /admin/reveal"
                 );
             }
 
-            if let Err(e) = unset_active_nonce(&db).await {
+            if let Err(e) = unset_active_nonce(&db, &game, &schedule).await {
                 tracing::error!(
                     "Failed to unset active nonce during shutdown: {e:#}. This could be bad!"
                 );
@@ -189,25 +324,50 @@ pub async fn manage_nonces(
 }
 
 impl Nonce {
-    fn new<R: RngCore>(rng: R, expire_after_secs: u64, reveal_after_secs: u64) -> Self {
+    /// A nonce expiring after `expire_after_secs`, on which bets can still be paid for up to
+    /// `invoice_lifetime_secs` after that.
+    fn new<R: RngCore>(
+        rng: R,
+        expire_after_secs: u64,
+        reveal_after_secs: u64,
+        invoice_lifetime_secs: u64,
+        beacon: Option<&RandomnessBeacon>,
+    ) -> Self {
         let mut rng = rand::rngs::StdRng::from_rng(rng).expect("rng");
         let nonce: [u8; 32] = rng.gen();
 
         let commitment = nonce_commitment(nonce);
 
-        Self {
+        let mut nonce = Self {
             inner: nonce,
             commitment,
             created_at: Instant::now(),
             expire_after: Duration::from_secs(expire_after_secs),
             reveal_after: Duration::from_secs(reveal_after_secs),
-        }
+            beacon_round: None,
+        };
+
+        // Bets close once the last invoice of the round expires, so the beacon round must not be
+        // known before then.
+        nonce.beacon_round = beacon.map(|beacon| {
+            let bets_close_at = nonce.expires_at() + Duration::from_secs(invoice_lifetime_secs);
+            let bets_close_at =
+                bets_close_at.unix_timestamp() as u64 + u64::from(bets_close_at.nanosecond() > 0);
+            beacon.round_at(bets_close_at)
+        });
+
+        nonce
     }
 
     fn expire_at(&self) -> Instant {
         self.created_at + self.expire_after
     }
 
+    /// Like [`Nonce::expire_at`], as a point in time rollers can be told about.
+    fn expires_at(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc() + self.expire_at().saturating_duration_since(Instant::now())
+    }
+
     fn reveal_at(&self) -> Instant {
         self.created_at + self.expire_after + self.reveal_after
     }
@@ -224,68 +384,534 @@ async fn publish_nonce_commitment(
     client: &nostr_sdk::Client,
     keys: &nostr::Keys,
     commitment: sha256::Hash,
+    roll_scheme: RollScheme,
+    multipliers_commitment: sha256::Hash,
+    beacon: Option<(&RandomnessBeacon, u64)>,
 ) -> Result<EventId> {
-    let event = EventBuilder::text_note(
-        format!(
-            "A new NostrDice round has started! Zap the note with your chosen multiplier.\n\
-             Here is the SHA256 commitment which makes the game fair: {commitment}"
-        ),
-        [Tag::from_standardized(TagStandard::Sha256(commitment))],
-    )
-    .to_event(keys)?;
+    let mut content = format!(
+        "A new NostrDice round has started! Zap the note with your chosen multiplier.\n\
+         Here is the SHA256 commitment which makes the game fair: {commitment}\n\
+         Rolls in this round are derived using roll scheme {roll_scheme}.\n\
+         The multiplier notes in play hash to {multipliers_commitment}."
+    );
+    let mut tags = vec![
+        Tag::from_standardized(TagStandard::Sha256(commitment)),
+        Tag::parse(&[
+            MULTIPLIERS_COMMITMENT_TAG.to_string(),
+            multipliers_commitment.to_string(),
+        ])?,
+    ];
+
+    if let Some((beacon, beacon_round)) = beacon {
+        content.push_str(&format!(
+            "\nRolls are seeded with SHA256(nonce | randomness) of round {beacon_round} of the \
+             randomness beacon {}.",
+            beacon.url
+        ));
+        tags.push(Tag::parse(&[
+            BEACON_TAG.to_string(),
+            beacon.url.clone(),
+            beacon_round.to_string(),
+        ])?);
+    }
+
+    let event = EventBuilder::text_note(content, tags).to_event(keys)?;
 
     let event_id = client.send_event(event.clone()).await?;
 
     Ok(event_id)
 }
 
+/// An expired nonce waiting to be revealed.
+struct PendingReveal {
+    nonce: [u8; 32],
+    beacon_round: Option<u64>,
+    commitment_event_id: EventId,
+    reveal_at: Instant,
+}
+
+impl PendingReveal {
+    fn new(nonce: &Nonce, commitment_event_id: EventId) -> Self {
+        Self {
+            nonce: nonce.inner,
+            beacon_round: nonce.beacon_round,
+            commitment_event_id,
+            reveal_at: nonce.reveal_at(),
+        }
+    }
+
+    /// The reveal of a `round` which expired before a restart, due `reveal_after` its expiry.
+    ///
+    /// Returns `None` for rounds started before we recorded their expiry.
+    fn of_round(round: &db::Round, reveal_after: Duration, now: OffsetDateTime) -> Option<Self> {
+        let reveal_at = round.expires_at? + reveal_after;
+        let due_in = Duration::try_from(reveal_at - now).unwrap_or_default();
+
+        Some(Self {
+            nonce: round.nonce,
+            beacon_round: round.beacon_round,
+            commitment_event_id: round.event_id,
+            reveal_at: Instant::now() + due_in,
+        })
+    }
+}
+
+/// Schedule the reveal of every expired round of the `schedule` of the `game` which was not
+/// revealed yet, e.g. because we restarted while it was waiting to be revealed, in the order they
+/// expired in.
+async fn schedule_unrevealed_rounds(
+    db: &SqlitePool,
+    game: &str,
+    schedule: &str,
+    reveal_after: Duration,
+    reveal_scheduler: &RevealScheduler,
+) -> Result<()> {
+    let now = OffsetDateTime::now_utc();
+    for round in get_unrevealed_expired_rounds(db, game, schedule, now).await? {
+        let Some(pending) = PendingReveal::of_round(&round, reveal_after, now) else {
+            continue;
+        };
+
+        tracing::info!(
+            commitment_event_id = %round.event_id,
+            "Scheduling reveal of round which expired before restart"
+        );
+        reveal_scheduler.schedule(pending).await?;
+    }
+
+    Ok(())
+}
+
+/// Schedules the revelation of expired nonces.
+///
+/// A single task reveals the expired nonces one after the other, in the order in which they were
+/// scheduled. Since every nonce is revealed the same amount of time after expiring, this is also
+/// the order in which they are due. The backlog of pending reveals is bounded: if it is full,
+/// scheduling another reveal waits for space, which holds off the next round.
+#[derive(Clone)]
+struct RevealScheduler {
+    sender: mpsc::Sender<PendingReveal>,
+}
+
+impl RevealScheduler {
+    fn start<F, Fut>(max_pending: usize, reveal: F) -> (Self, JoinHandle<()>)
+    where
+        F: Fn(PendingReveal) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let (sender, mut receiver) = mpsc::channel::<PendingReveal>(max_pending);
+
+        let handle = tokio::spawn(async move {
+            while let Some(pending) = receiver.recv().await {
+                reveal(pending).await;
+            }
+        });
+
+        (Self { sender }, handle)
+    }
+
+    async fn schedule(&self, pending: PendingReveal) -> Result<()> {
+        let max_pending = self.sender.max_capacity();
+        let backlog = max_pending - self.sender.capacity();
+
+        if backlog == max_pending {
+            tracing::error!(
+                backlog,
+                "Reveal backlog is full. Waiting for pending reveals before starting a new round"
+            );
+        } else if backlog >= max_pending / 2 {
+            tracing::warn!(backlog, max_pending, "Reveal backlog is growing");
+        }
+
+        self.sender
+            .send(pending)
+            .await
+            .map_err(|_| anyhow!("Reveal scheduler stopped"))
+    }
+}
+
 async fn reveal_nonce_later(
     client: nostr_sdk::Client,
     keys: nostr::Keys,
-    nonce: Nonce,
-    commitment_event_id: EventId,
+    db: SqlitePool,
+    beacon: Option<RandomnessBeacon>,
+    revealed: mpsc::UnboundedSender<EventId>,
+    PendingReveal {
+        nonce,
+        beacon_round,
+        commitment_event_id,
+        reveal_at,
+    }: PendingReveal,
 ) {
-    tracing::debug!(commitment = %nonce.commitment, "Waiting to reveal expired nonce");
+    tracing::debug!(%commitment_event_id, "Waiting to reveal expired nonce");
 
-    let reveal_at = tokio::time::Instant::from_std(nonce.reveal_at());
-    tokio::time::sleep_until(reveal_at).await;
+    tokio::time::sleep_until(tokio::time::Instant::from_std(reveal_at)).await;
 
-    if let Err(e) = reveal_nonce(&client, &keys, nonce.inner, commitment_event_id).await {
+    if let Err(e) = reveal_nonce(
+        &client,
+        &keys,
+        &db,
+        beacon.as_ref(),
+        &revealed,
+        nonce,
+        beacon_round,
+        commitment_event_id,
+    )
+    .await
+    {
         tracing::error!(
-            nonce = hex::encode(nonce.inner),
-            "Failed to reveal nonce: {e:#}. Must publish manually"
+            nonce = hex::encode(nonce),
+            "Failed to reveal nonce: {e:#}. Must be revealed with POST /admin/reveal"
         );
     };
 }
 
-async fn reveal_nonce(
+/// Publish the `nonce` of a round, and let `revealed` know that its bets can be settled.
+///
+/// If the round uses a randomness beacon, the randomness of its `beacon_round` is fetched, stored
+/// and published alongside the nonce.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn reveal_nonce(
     client: &nostr_sdk::Client,
     keys: &nostr_sdk::Keys,
+    db: &SqlitePool,
+    beacon: Option<&RandomnessBeacon>,
+    revealed: &mpsc::UnboundedSender<EventId>,
     nonce: [u8; 32],
+    beacon_round: Option<u64>,
     commitment_event_id: EventId,
 ) -> Result<()> {
-    let event = EventBuilder::text_note(
+    let mut content = format!(
+        "Revealing nonce: {}. Matching commitment: nostr:{}",
+        hex::encode(nonce),
+        commitment_event_id.to_bech32().expect("valid note ID"),
+    );
+
+    if let Some(beacon_round) = beacon_round {
+        let beacon = beacon.with_context(|| {
+            format!(
+                "Round uses beacon round {beacon_round}, but no randomness beacon is configured"
+            )
+        })?;
+
+        let randomness = beacon.wait_for_randomness(beacon_round).await?;
+        set_beacon_randomness(db, commitment_event_id, randomness).await?;
+
+        content.push_str(&format!(
+            "\nRandomness of beacon round {beacon_round}: {}. Rolls are seeded with {}.",
+            hex::encode(randomness),
+            hex::encode(roll_seed(nonce, randomness)),
+        ));
+    }
+
+    // Without the bets of the round, rollers would have to ask us for their index. Failing to
+    // publish them must not hold up the reveal though, since the bets are settled after it.
+    match publish_bet_manifest(client, keys, db, commitment_event_id).await {
+        Ok(Some(manifest_event_id)) => content.push_str(&format!(
+            "\nBets of this round: nostr:{}",
+            manifest_event_id.to_bech32().expect("valid note ID")
+        )),
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!(%commitment_event_id, "Failed to publish bets of round: {e:#}");
+        }
+    }
+
+    let event = EventBuilder::text_note(content, []).to_event(keys)?;
+
+    let reveal_event_id = client.send_event(event.clone()).await?;
+
+    tracing::debug!(%commitment_event_id, "Expired nonce revealed");
+
+    if let Err(e) = set_reveal_event_id(db, commitment_event_id, reveal_event_id).await {
+        tracing::error!(%commitment_event_id, "Failed to store reveal note ID: {e:#}");
+    }
+
+    let _ = revealed.send(commitment_event_id);
+
+    Ok(())
+}
+
+/// A bet placed on a round: with the revealed nonce, everything needed to derive its roll.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BetManifestEntry {
+    pub roller_npub: String,
+    /// The SHA256 hash of the zap memo, hex encoded.
+    pub memo_hash: String,
+    pub index: usize,
+    pub multiplier_note_id: String,
+}
+
+/// The bets placed on the round with the `commitment_event_id`, sorted by roller and index.
+///
+/// Bets whose invoice was never paid are left out, since they are not rolled.
+pub async fn get_bet_manifest(
+    db: &SqlitePool,
+    commitment_event_id: EventId,
+) -> Result<Vec<BetManifestEntry>> {
+    let mut manifest = db::get_zaps_by_event_id(db, commitment_event_id)
+        .await?
+        .into_iter()
+        .filter(|zap| {
+            !matches!(
+                zap.bet_state,
+                BetState::GameZapInvoiceRequested | BetState::Donation | BetState::DonationPaid
+            )
+        })
+        .map(|zap| BetManifestEntry {
+            roller_npub: zap.roller.to_bech32().expect("valid npub"),
+            memo_hash: sha256::Hash::hash(zap.request.content.as_bytes()).to_string(),
+            index: zap.index,
+            multiplier_note_id: zap.multiplier_note_id,
+        })
+        .collect::<Vec<_>>();
+
+    manifest.sort_by(|a, b| {
+        a.roller_npub
+            .cmp(&b.roller_npub)
+            .then_with(|| a.index.cmp(&b.index))
+    });
+
+    Ok(manifest)
+}
+
+/// The event listing the bets of a round, replacing the one published before, e.g. when revealing
+/// again after a restart.
+fn bet_manifest_event(
+    commitment_event_id: EventId,
+    manifest: &[BetManifestEntry],
+) -> Result<EventBuilder> {
+    Ok(EventBuilder::new(
+        Kind::ApplicationSpecificData,
+        serde_json::to_string(manifest)?,
+        [
+            Tag::from_standardized(TagStandard::Identifier(format!(
+                "{BET_MANIFEST_IDENTIFIER}:{}",
+                commitment_event_id.to_hex()
+            ))),
+            Tag::event(commitment_event_id),
+        ],
+    ))
+}
+
+/// Publish the bets of a round, if it took any.
+async fn publish_bet_manifest(
+    client: &nostr_sdk::Client,
+    keys: &nostr_sdk::Keys,
+    db: &SqlitePool,
+    commitment_event_id: EventId,
+) -> Result<Option<EventId>> {
+    let manifest = get_bet_manifest(db, commitment_event_id).await?;
+    if manifest.is_empty() {
+        return Ok(None);
+    }
+
+    let event = bet_manifest_event(commitment_event_id, &manifest)?.to_event(keys)?;
+    let event_id = client.send_event(event).await?;
+
+    Ok(Some(event_id))
+}
+
+/// The outcome of a settled round, as replied to its nonce commitment note.
+#[derive(Debug, Default, PartialEq)]
+pub struct RoundResults {
+    pub bets: usize,
+    pub rollers: usize,
+    pub winners: usize,
+    pub wagered_sat: u64,
+    pub paid_sat: u64,
+}
+
+/// Reply to the nonce commitment note of a settled round with its results, signed with the `keys`
+/// of the main account, unless we did so before.
+///
+/// Rounds without bets, or with bets which have not been rolled yet, are left alone.
+pub async fn publish_round_results(
+    client: &nostr_sdk::Client,
+    keys: &nostr_sdk::Keys,
+    db: &SqlitePool,
+    multipliers: &Multipliers,
+    payout_rounding: PayoutRounding,
+    commitment_event_id: EventId,
+) -> Result<Option<EventId>> {
+    if get_results_event_id(db, commitment_event_id)
+        .await?
+        .is_some()
+    {
+        return Ok(None);
+    }
+
+    let zaps = db::get_zaps_by_event_id(db, commitment_event_id).await?;
+    if zaps
+        .iter()
+        .any(|zap| matches!(zap.bet_state, BetState::ZapPaid | BetState::Rolling))
+    {
+        tracing::debug!(%commitment_event_id, "Not publishing results of unsettled round");
+        return Ok(None);
+    }
+
+    let results = round_results(&zaps, multipliers, payout_rounding);
+    if results.bets == 0 {
+        return Ok(None);
+    }
+
+    let event = round_results_event(commitment_event_id, &results)?.to_event(keys)?;
+    let results_event_id = client.send_event(event).await?;
+
+    set_results_event_id(db, commitment_event_id, results_event_id).await?;
+
+    Ok(Some(results_event_id))
+}
+
+/// Sum up the bets which were placed with the `zaps` of a round.
+fn round_results(
+    zaps: &[db::Zap],
+    multipliers: &Multipliers,
+    payout_rounding: PayoutRounding,
+) -> RoundResults {
+    let mut results = RoundResults::default();
+    let mut rollers = Vec::new();
+    // Summed up before rounding down, so that bets of fractional sats are not lost.
+    let mut wagered_msat = 0;
+
+    for zap in zaps {
+        if matches!(
+            zap.bet_state,
+            BetState::GameZapInvoiceRequested | BetState::Donation | BetState::DonationPaid
+        ) {
+            continue;
+        }
+
+        let amount_msat = zap.invoice.amount_milli_satoshis().unwrap_or_default();
+        results.bets += 1;
+        wagered_msat += amount_msat;
+        if !rollers.contains(&zap.roller) {
+            rollers.push(zap.roller);
+        }
+
+        match zap.bet_state {
+            // Winners whose payout is queued, has to be retried or was abandoned are owed it all
+            // the same.
+            BetState::PaidWinner
+            | BetState::QueuedWinner
+            | BetState::ZapFailed
+            | BetState::ZapAbandoned => {
+                results.winners += 1;
+                if let Some(note) = multipliers.get_multiplier_note(&zap.multiplier_note_id) {
+                    results.paid_sat += calculate_price_money(
+                        amount_msat,
+                        note.multiplier.get_multiplier(),
+                        payout_rounding,
+                    );
+                }
+            }
+            // Nothing was paid out to the winners of a dry run.
+            BetState::DryRunWinner => results.winners += 1,
+            _ => {}
+        }
+    }
+    results.rollers = rollers.len();
+    results.wagered_sat = wagered_msat / 1_000;
+
+    results
+}
+
+/// A reply to the nonce commitment note, so that clients show the results next to the round.
+fn round_results_event(
+    commitment_event_id: EventId,
+    results: &RoundResults,
+) -> Result<EventBuilder> {
+    let content = [
         format!(
-            "Revealing nonce: {}. Matching commitment: nostr:{}",
-            hex::encode(nonce),
-            commitment_event_id.to_bech32().expect("valid note ID"),
+            "Results of round nostr:{}",
+            commitment_event_id.to_bech32().expect("valid note ID")
         ),
-        [],
+        format!("Bets: {} by {} rollers", results.bets, results.rollers),
+        format!("Winners: {}", results.winners),
+        format!("Wagered: {} sats", results.wagered_sat),
+        format!("Paid out: {} sats", results.paid_sat),
+    ]
+    .join("\n");
+
+    // A NIP-10 reply to the commitment note as the root of the thread.
+    let root = Tag::parse(&[
+        "e".to_string(),
+        commitment_event_id.to_hex(),
+        String::new(),
+        "root".to_string(),
+    ])?;
+
+    Ok(EventBuilder::text_note(content, [root]))
+}
+
+/// The round with the given commitment note, if its nonce has been revealed.
+pub async fn get_revealed_round(
+    db: &SqlitePool,
+    commitment_event_id: EventId,
+) -> Result<Option<Round>> {
+    let event_id = commitment_event_id.to_hex();
+
+    query_as!(
+        RoundRow,
+        "SELECT event_id, nonce, roll_scheme, multipliers_commitment, beacon_round,
+            beacon_randomness, expires_at FROM nonces
+            WHERE event_id = ?1 AND reveal_event_id IS NOT NULL;",
+        event_id,
     )
-    .to_event(keys)?;
+    .try_map(Round::try_from)
+    .fetch_optional(db)
+    .await
+    .context("Failed to get revealed round")
+}
 
-    client.send_event(event.clone()).await?;
+/// The round with the given commitment note, whether its nonce has been revealed or not, and the
+/// name of its game.
+pub async fn get_round(
+    db: &SqlitePool,
+    commitment_event_id: EventId,
+) -> Result<Option<(String, Round)>> {
+    let event_id = commitment_event_id.to_hex();
 
-    tracing::debug!(%commitment_event_id, "Expired nonce revealed");
+    let Some(game) = query!("SELECT game FROM nonces WHERE event_id = ?1;", event_id)
+        .fetch_optional(db)
+        .await
+        .context("Failed to get game of round")?
+        .map(|row| row.game)
+    else {
+        return Ok(None);
+    };
 
-    Ok(())
+    let round = query_as!(
+        RoundRow,
+        "SELECT event_id, nonce, roll_scheme, multipliers_commitment, beacon_round,
+            beacon_randomness, expires_at FROM nonces
+            WHERE event_id = ?1;",
+        event_id,
+    )
+    .try_map(Round::try_from)
+    .fetch_one(db)
+    .await
+    .context("Failed to get round")?;
+
+    Ok(Some((game, round)))
 }
 
-pub async fn get_active_nonce(db: &SqlitePool) -> Result<Option<Round>> {
+/// The round of the `schedule` of the `game` taking bets, if any.
+pub async fn get_active_nonce(
+    db: &SqlitePool,
+    game: &str,
+    schedule: &str,
+) -> Result<Option<Round>> {
     sqlx::query_as!(
         RoundRow,
-        r#"SELECT nonces.event_id, nonces.nonce FROM active_nonce
-            JOIN nonces ON nonces.event_id = active_nonce.nonce_event_id;"#
+        r#"SELECT nonces.event_id, nonces.nonce, nonces.roll_scheme,
+            nonces.multipliers_commitment, nonces.beacon_round, nonces.beacon_randomness,
+            nonces.expires_at
+            FROM active_nonces
+            JOIN nonces ON nonces.event_id = active_nonces.nonce_event_id
+            WHERE active_nonces.game = ?1 AND active_nonces.schedule = ?2;"#,
+        game,
+        schedule,
     )
     .try_map(Round::try_from)
     .fetch_optional(db)
@@ -293,22 +919,74 @@ pub async fn get_active_nonce(db: &SqlitePool) -> Result<Option<Round>> {
     .context("Failed to get active nonce")
 }
 
-pub async fn set_active_nonce(db: &SqlitePool, round: Round) -> Result<()> {
+/// The rounds of the `game` taking bets, by schedule.
+pub async fn get_active_nonces(db: &SqlitePool, game: &str) -> Result<Vec<(String, Round)>> {
+    let rows = query!(
+        "SELECT schedule, nonce_event_id FROM active_nonces WHERE game = ?1 ORDER BY schedule;",
+        game,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to get active nonces")?;
+
+    let mut rounds = Vec::new();
+    for row in rows {
+        let round = query_as!(
+            RoundRow,
+            "SELECT event_id, nonce, roll_scheme, multipliers_commitment, beacon_round,
+            beacon_randomness, expires_at FROM nonces
+                WHERE event_id = ?1",
+            row.nonce_event_id,
+        )
+        .try_map(Round::try_from)
+        .fetch_one(db)
+        .await
+        .context("Failed to get active nonce")?;
+
+        rounds.push((row.schedule, round));
+    }
+
+    Ok(rounds)
+}
+
+pub async fn set_active_nonce(
+    db: &SqlitePool,
+    game: &str,
+    schedule: &str,
+    round: Round,
+) -> Result<()> {
     let event_id = round.event_id.to_hex();
     let nonce = hex::encode(round.nonce);
+    let roll_scheme = round.roll_scheme.to_string();
+    let multipliers_commitment = round
+        .multipliers_commitment
+        .map(|commitment| commitment.to_string());
+    let started_at = OffsetDateTime::now_utc();
+    let beacon_round = round.beacon_round.map(|round| round as i64);
 
     query!(
-        "INSERT INTO nonces (event_id, nonce) VALUES (?1, ?2);",
+        "INSERT INTO nonces
+            (event_id, nonce, roll_scheme, multipliers_commitment, started_at, game, beacon_round,
+                expires_at, schedule)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9);",
         event_id,
         nonce,
+        roll_scheme,
+        multipliers_commitment,
+        started_at,
+        game,
+        beacon_round,
+        round.expires_at,
+        schedule,
     )
     .execute(db)
     .await?;
 
     query!(
-        "INSERT INTO active_nonce (id, nonce_event_id) VALUES (?1, ?2)
-            ON CONFLICT(id) DO UPDATE SET nonce_event_id = excluded.nonce_event_id;",
-        0,
+        "INSERT INTO active_nonces (game, schedule, nonce_event_id) VALUES (?1, ?2, ?3)
+            ON CONFLICT(game, schedule) DO UPDATE SET nonce_event_id = excluded.nonce_event_id;",
+        game,
+        schedule,
         event_id,
     )
     .execute(db)
@@ -317,17 +995,27 @@ pub async fn set_active_nonce(db: &SqlitePool, round: Round) -> Result<()> {
     Ok(())
 }
 
-pub async fn unset_active_nonce(db: &SqlitePool) -> Result<Option<db::Round>> {
-    let id = query!("DELETE FROM active_nonce RETURNING nonce_event_id;")
-        .fetch_optional(db)
-        .await?
-        .map(|r| r.nonce_event_id);
+pub async fn unset_active_nonce(
+    db: &SqlitePool,
+    game: &str,
+    schedule: &str,
+) -> Result<Option<db::Round>> {
+    let id = query!(
+        "DELETE FROM active_nonces WHERE game = ?1 AND schedule = ?2 RETURNING nonce_event_id;",
+        game,
+        schedule,
+    )
+    .fetch_optional(db)
+    .await?
+    .map(|r| r.nonce_event_id);
 
     match id {
         None => Ok(None),
         Some(id) => query_as!(
             RoundRow,
-            "SELECT event_id, nonce FROM nonces WHERE event_id = ?1",
+            "SELECT event_id, nonce, roll_scheme, multipliers_commitment, beacon_round,
+            beacon_randomness, expires_at FROM nonces
+                WHERE event_id = ?1",
             id,
         )
         .try_map(Round::try_from)
@@ -337,13 +1025,19 @@ pub async fn unset_active_nonce(db: &SqlitePool) -> Result<Option<db::Round>> {
     }
 }
 
-pub async fn set_latest_expired_nonce(db: &SqlitePool, round: db::Round) -> anyhow::Result<()> {
+pub async fn set_latest_expired_nonce(
+    db: &SqlitePool,
+    game: &str,
+    schedule: &str,
+    round: db::Round,
+) -> anyhow::Result<()> {
     let event_id = round.event_id.to_hex();
 
     query!(
-        "INSERT INTO latest_expired_nonce (id, nonce_event_id) VALUES (?1, ?2)
-            ON CONFLICT(id) DO UPDATE SET nonce_event_id = excluded.nonce_event_id;",
-        0,
+        "INSERT INTO latest_expired_nonces (game, schedule, nonce_event_id) VALUES (?1, ?2, ?3)
+            ON CONFLICT(game, schedule) DO UPDATE SET nonce_event_id = excluded.nonce_event_id;",
+        game,
+        schedule,
         event_id,
     )
     .execute(db)
@@ -352,14 +1046,837 @@ pub async fn set_latest_expired_nonce(db: &SqlitePool, round: db::Round) -> anyh
     Ok(())
 }
 
-pub async fn get_latest_expired_nonce(db: &SqlitePool) -> anyhow::Result<Option<db::Round>> {
+pub async fn get_latest_expired_nonce(
+    db: &SqlitePool,
+    game: &str,
+    schedule: &str,
+) -> anyhow::Result<Option<db::Round>> {
     sqlx::query_as!(
         RoundRow,
-        r#"SELECT nonces.event_id, nonces.nonce FROM latest_expired_nonce
-            JOIN nonces ON nonces.event_id = latest_expired_nonce.nonce_event_id;"#
+        r#"SELECT nonces.event_id, nonces.nonce, nonces.roll_scheme,
+            nonces.multipliers_commitment, nonces.beacon_round, nonces.beacon_randomness,
+            nonces.expires_at
+            FROM latest_expired_nonces
+            JOIN nonces ON nonces.event_id = latest_expired_nonces.nonce_event_id
+            WHERE latest_expired_nonces.game = ?1 AND latest_expired_nonces.schedule = ?2;"#,
+        game,
+        schedule,
     )
     .try_map(Round::try_from)
     .fetch_optional(db)
     .await
     .context("Failed to get active nonce")
 }
+
+/// The rounds of the `schedule` of the `game` which expired by `now` without being revealed,
+/// except for its active round, in the order they expired in.
+async fn get_unrevealed_expired_rounds(
+    db: &SqlitePool,
+    game: &str,
+    schedule: &str,
+    now: OffsetDateTime,
+) -> anyhow::Result<Vec<db::Round>> {
+    sqlx::query_as!(
+        RoundRow,
+        r#"SELECT event_id, nonce, roll_scheme, multipliers_commitment, beacon_round,
+            beacon_randomness, expires_at
+            FROM nonces
+            WHERE game = ?1 AND schedule = ?2 AND reveal_event_id IS NULL AND expires_at <= ?3
+                AND event_id NOT IN
+                    (SELECT nonce_event_id FROM active_nonces WHERE game = ?1 AND schedule = ?2)
+            ORDER BY expires_at;"#,
+        game,
+        schedule,
+        now,
+    )
+    .try_map(Round::try_from)
+    .fetch_all(db)
+    .await
+    .context("Failed to get unrevealed expired rounds")
+}
+
+pub async fn set_reveal_event_id(
+    db: &SqlitePool,
+    commitment_event_id: EventId,
+    reveal_event_id: EventId,
+) -> Result<()> {
+    let commitment_event_id = commitment_event_id.to_hex();
+    let reveal_event_id = reveal_event_id.to_hex();
+    let revealed_at = OffsetDateTime::now_utc();
+
+    query!(
+        "UPDATE nonces SET reveal_event_id = ?1, revealed_at = ?2 WHERE event_id = ?3;",
+        reveal_event_id,
+        revealed_at,
+        commitment_event_id,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+async fn set_results_event_id(
+    db: &SqlitePool,
+    commitment_event_id: EventId,
+    results_event_id: EventId,
+) -> Result<()> {
+    let commitment_event_id = commitment_event_id.to_hex();
+    let results_event_id = results_event_id.to_hex();
+
+    query!(
+        "UPDATE nonces SET results_event_id = ?1 WHERE event_id = ?2;",
+        results_event_id,
+        commitment_event_id,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// The rounds of the `game` revealed between `since` and `until` whose results we have not
+/// published yet, leaving out rounds without paid bets.
+pub async fn get_rounds_without_results(
+    db: &SqlitePool,
+    game: &str,
+    since: OffsetDateTime,
+    until: OffsetDateTime,
+) -> Result<Vec<EventId>> {
+    let requested = BetState::GameZapInvoiceRequested.to_string();
+    let donation = BetState::Donation.to_string();
+    let donation_paid = BetState::DonationPaid.to_string();
+
+    let rows = query!(
+        "SELECT event_id FROM nonces
+            WHERE game = ?1 AND reveal_event_id IS NOT NULL AND results_event_id IS NULL
+                AND revealed_at BETWEEN ?2 AND ?3
+                AND EXISTS (
+                    SELECT 1 FROM zaps WHERE zaps.nonce_commitment_note_id = nonces.event_id
+                        AND zaps.bet_state NOT IN (?4, ?5, ?6)
+                );",
+        game,
+        since,
+        until,
+        requested,
+        donation,
+        donation_paid,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to get rounds without results")?;
+
+    rows.into_iter()
+        .map(|row| EventId::from_hex(&row.event_id).context("Invalid nonce commitment note ID"))
+        .collect()
+}
+
+async fn get_results_event_id(
+    db: &SqlitePool,
+    commitment_event_id: EventId,
+) -> Result<Option<EventId>> {
+    let commitment_event_id = commitment_event_id.to_hex();
+
+    let row = query!(
+        "SELECT results_event_id FROM nonces WHERE event_id = ?1;",
+        commitment_event_id,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    row.and_then(|row| row.results_event_id)
+        .map(|results_event_id| EventId::from_hex(&results_event_id))
+        .transpose()
+        .context("Invalid results note ID")
+}
+
+pub async fn set_beacon_randomness(
+    db: &SqlitePool,
+    commitment_event_id: EventId,
+    randomness: [u8; 32],
+) -> Result<()> {
+    let commitment_event_id = commitment_event_id.to_hex();
+    let randomness = hex::encode(randomness);
+
+    query!(
+        "UPDATE nonces SET beacon_randomness = ?1 WHERE event_id = ?2;",
+        randomness,
+        commitment_event_id,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// When the nonce of a round of the `game` was last revealed, if we know.
+pub async fn get_last_reveal_time(db: &SqlitePool, game: &str) -> Result<Option<OffsetDateTime>> {
+    let row = query!(
+        "SELECT revealed_at FROM nonces
+            WHERE game = ?1 AND revealed_at IS NOT NULL
+            ORDER BY revealed_at DESC LIMIT 1;",
+        game,
+    )
+    .fetch_optional(db)
+    .await
+    .context("Failed to get last reveal time")?;
+
+    Ok(row.and_then(|row| row.revealed_at))
+}
+
+/// The rounds of the `game` which started within the time window.
+pub async fn get_rounds_started_between(
+    db: &SqlitePool,
+    game: &str,
+    start_time: OffsetDateTime,
+    end_time: OffsetDateTime,
+) -> Result<Vec<RoundRecord>> {
+    let rows = query!(
+        "SELECT event_id, roll_scheme, reveal_event_id FROM nonces
+            WHERE game = ?1 AND started_at >= ?2 AND started_at < ?3
+            ORDER BY started_at;",
+        game,
+        start_time,
+        end_time,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to get rounds")?;
+
+    rows.into_iter()
+        .map(|row| {
+            Ok(RoundRecord {
+                event_id: row.event_id.parse().context("Invalid event ID")?,
+                roll_scheme: row.roll_scheme.parse().context("Invalid roll scheme")?,
+                revealed: row.reveal_event_id.is_some(),
+            })
+        })
+        .collect()
+}
+
+/// A round whose nonce has been revealed, so that it can be shared without letting anyone predict
+/// rolls.
+#[derive(Debug)]
+pub struct RevealedRound {
+    pub game: String,
+    pub round: Round,
+    /// Unknown for rounds started before we recorded it.
+    pub started_at: Option<OffsetDateTime>,
+    /// Unknown for rounds revealed before we recorded it.
+    pub revealed_at: Option<OffsetDateTime>,
+}
+
+/// The revealed rounds of all games started from `start_time` until `end_time`, oldest first.
+///
+/// Without bounds, rounds started before we recorded it are included too.
+pub async fn get_revealed_rounds_between(
+    db: &SqlitePool,
+    start_time: Option<OffsetDateTime>,
+    end_time: Option<OffsetDateTime>,
+) -> Result<Vec<RevealedRound>> {
+    let rows = query!(
+        "SELECT event_id, nonce, roll_scheme, multipliers_commitment, beacon_round,
+            beacon_randomness, expires_at, game, started_at, revealed_at FROM nonces
+            WHERE reveal_event_id IS NOT NULL
+                AND (?1 IS NULL OR started_at >= ?1) AND (?2 IS NULL OR started_at < ?2)
+            ORDER BY started_at, rowid;",
+        start_time,
+        end_time,
+    )
+    .fetch_all(db)
+    .await
+    .context("Failed to get revealed rounds")?;
+
+    rows.into_iter()
+        .map(|row| {
+            let round = Round::try_from(RoundRow {
+                nonce: row.nonce,
+                event_id: row.event_id,
+                roll_scheme: row.roll_scheme,
+                multipliers_commitment: row.multipliers_commitment,
+                beacon_round: row.beacon_round,
+                beacon_randomness: row.beacon_randomness,
+                expires_at: row.expires_at,
+            })
+            .context("Invalid round")?;
+
+            Ok(RevealedRound {
+                game: row.game,
+                round,
+                started_at: row.started_at,
+                revealed_at: row.revealed_at,
+            })
+        })
+        .collect()
+}
+
+/// A note published by the nonce account to run a round.
+#[derive(Debug, PartialEq)]
+pub enum RoundNote {
+    Commitment,
+    Reveal,
+}
+
+/// Check if the `event_id` belongs to one of the notes we publish to run our rounds.
+pub async fn get_round_note(db: &SqlitePool, event_id: EventId) -> Result<Option<RoundNote>> {
+    let event_id = event_id.to_hex();
+
+    let row = query!(
+        "SELECT event_id FROM nonces WHERE event_id = ?1 OR reveal_event_id = ?1;",
+        event_id,
+    )
+    .fetch_optional(db)
+    .await
+    .context("Failed to get round note")?;
+
+    Ok(row.map(|row| {
+        if row.event_id == event_id {
+            RoundNote::Commitment
+        } else {
+            RoundNote::Reveal
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beacon::test_beacon;
+    use crate::db::test_db;
+    use crate::db::test_round;
+    use crate::game::DEFAULT_GAME;
+    use crate::game::DEFAULT_SCHEDULE;
+    use crate::multiplier::test_multiplier_note;
+    use crate::routes::invoice_expiry_secs;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    #[test]
+    fn beacon_rounds_are_published_after_the_last_bet_invoice_expires() {
+        let beacon = test_beacon();
+
+        for (reveal_after_secs, max_invoice_expiry_secs) in [(60, 3_600), (60, 30), (5, 3_600)] {
+            let invoice_lifetime_secs =
+                invoice_lifetime_after_expiry_secs(reveal_after_secs, max_invoice_expiry_secs);
+            let nonce = Nonce::new(
+                thread_rng(),
+                600,
+                reveal_after_secs,
+                invoice_lifetime_secs,
+                Some(&beacon),
+            );
+
+            // The invoice of a bet placed as the round expires.
+            let expires_at = nonce.expires_at();
+            let invoice_expires_at = expires_at.unix_timestamp() as u64
+                + invoice_expiry_secs(
+                    Some(expires_at),
+                    reveal_after_secs,
+                    max_invoice_expiry_secs,
+                    expires_at,
+                );
+
+            let published_at = beacon.published_at(nonce.beacon_round.unwrap());
+            assert!(published_at >= invoice_expires_at);
+            // No later than that, give or take rounding to whole seconds and beacon periods.
+            assert!(published_at <= invoice_expires_at + 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn reveals_many_rapidly_expiring_nonces_once_each_in_order() {
+        let revealed = Arc::new(Mutex::new(Vec::new()));
+
+        let (scheduler, handle) = RevealScheduler::start(4, {
+            let revealed = revealed.clone();
+            move |pending: PendingReveal| {
+                let revealed = revealed.clone();
+                async move {
+                    revealed.lock().unwrap().push(pending.commitment_event_id);
+                }
+            }
+        });
+
+        let mut scheduled = Vec::new();
+        for i in 0..100 {
+            let commitment_event_id = EventId::from_slice(&[i; 32]).unwrap();
+            scheduled.push(commitment_event_id);
+
+            scheduler
+                .schedule(PendingReveal::new(
+                    &Nonce::new(thread_rng(), 0, 0, 0, None),
+                    commitment_event_id,
+                ))
+                .await
+                .unwrap();
+
+            assert!(scheduler.sender.max_capacity() - scheduler.sender.capacity() <= 4);
+        }
+
+        drop(scheduler);
+        handle.await.unwrap();
+
+        assert_eq!(*revealed.lock().unwrap(), scheduled);
+
+        // Rounds still waiting to be revealed when we restart are scheduled again after it.
+        let db = test_db().await;
+        let reveal_after = Duration::from_secs(60);
+        let now = OffsetDateTime::now_utc();
+        let round = |i: u8, expired_secs_ago: i64| Round {
+            expires_at: Some(now - time::Duration::seconds(expired_secs_ago)),
+            ..test_round(EventId::from_slice(&[i; 32]).unwrap())
+        };
+        for (i, expired_secs_ago) in [(0, 50), (1, 120), (2, 30), (3, 20), (4, -30)] {
+            set_active_nonce(
+                &db,
+                DEFAULT_GAME,
+                DEFAULT_SCHEDULE,
+                round(i, expired_secs_ago),
+            )
+            .await
+            .unwrap();
+        }
+        set_reveal_event_id(&db, round(3, 20).event_id, EventId::all_zeros())
+            .await
+            .unwrap();
+
+        let revealed = Arc::new(Mutex::new(Vec::new()));
+        let (scheduler, handle) = RevealScheduler::start(4, {
+            let revealed = revealed.clone();
+            move |pending: PendingReveal| {
+                let revealed = revealed.clone();
+                async move {
+                    let due_in = pending.reveal_at.saturating_duration_since(Instant::now());
+                    revealed
+                        .lock()
+                        .unwrap()
+                        .push((pending.commitment_event_id, due_in.as_secs_f64().round()));
+                }
+            }
+        });
+        schedule_unrevealed_rounds(&db, DEFAULT_GAME, reveal_after, &scheduler)
+            .await
+            .unwrap();
+        drop(scheduler);
+        handle.await.unwrap();
+
+        // Each is due when it would have been without the restart. Neither the revealed round nor
+        // the active one is revealed again.
+        assert_eq!(
+            *revealed.lock().unwrap(),
+            [
+                (round(1, 120).event_id, 0.0),
+                (round(0, 50).event_id, 10.0),
+                (round(2, 30).event_id, 30.0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_commitment_and_reveal_notes() {
+        let db = test_db().await;
+
+        let commitment_event_id = EventId::all_zeros();
+        let reveal_event_id = EventId::from_slice(&[1; 32]).unwrap();
+        let other_event_id = EventId::from_slice(&[2; 32]).unwrap();
+
+        set_active_nonce(
+            &db,
+            DEFAULT_GAME,
+            DEFAULT_SCHEDULE,
+            test_round(commitment_event_id),
+        )
+        .await
+        .unwrap();
+        set_reveal_event_id(&db, commitment_event_id, reveal_event_id)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_round_note(&db, commitment_event_id).await.unwrap(),
+            Some(RoundNote::Commitment)
+        );
+        assert_eq!(
+            get_round_note(&db, reveal_event_id).await.unwrap(),
+            Some(RoundNote::Reveal)
+        );
+        assert_eq!(get_round_note(&db, other_event_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn bet_manifest_lists_the_paid_bets_of_a_round() {
+        let db = test_db().await;
+        let commitment_event_id = EventId::all_zeros();
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let mut paid = Vec::new();
+        for (index, bet_state) in [
+            (0, BetState::GameZapInvoiceRequested),
+            (1, BetState::ZapPaid),
+            (2, BetState::Loser),
+        ] {
+            let unpaid = bet_state == BetState::GameZapInvoiceRequested;
+            let zap = db::Zap {
+                index,
+                ..db::test_zap("note1multiplier", commitment_event_id, bet_state)
+            };
+            if !unpaid {
+                paid.push(BetManifestEntry {
+                    roller_npub: zap.roller.to_bech32().unwrap(),
+                    memo_hash: sha256::Hash::hash(b"").to_string(),
+                    index,
+                    multiplier_note_id: "note1multiplier".to_string(),
+                });
+            }
+
+            let payment_hash = zap.invoice.payment_hash().to_string();
+            db::upsert_zap(&db, payment_hash, zap, &multipliers)
+                .await
+                .unwrap();
+        }
+        paid.sort_by(|a, b| a.roller_npub.cmp(&b.roller_npub));
+
+        let manifest = get_bet_manifest(&db, commitment_event_id).await.unwrap();
+        assert_eq!(manifest, paid);
+
+        let event = bet_manifest_event(commitment_event_id, &manifest)
+            .unwrap()
+            .to_event(&nostr::Keys::generate())
+            .unwrap();
+        assert_eq!(event.kind, Kind::ApplicationSpecificData);
+        assert_eq!(
+            serde_json::from_str::<Vec<BetManifestEntry>>(&event.content).unwrap(),
+            paid
+        );
+        assert!(event.tags.iter().any(|tag| tag.as_vec()
+            == [
+                "d".to_string(),
+                format!("nostrdice-bets:{}", "0".repeat(64))
+            ]));
+    }
+
+    #[test]
+    fn round_results_sum_up_the_bets_of_a_round() {
+        let commitment_event_id = EventId::all_zeros();
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let zap = |bet_state| db::test_zap("note1multiplier", commitment_event_id, bet_state);
+        let winner = zap(BetState::PaidWinner);
+        let zaps = vec![
+            zap(BetState::GameZapInvoiceRequested),
+            db::Zap {
+                roller: winner.roller,
+                ..zap(BetState::Loser)
+            },
+            winner,
+            zap(BetState::QueuedWinner),
+            // Won, but not paid out.
+            zap(BetState::DryRunWinner),
+        ];
+
+        let results = round_results(&zaps, &multipliers, PayoutRounding::Floor);
+        assert_eq!(
+            results,
+            RoundResults {
+                bets: 4,
+                rollers: 3,
+                winners: 3,
+                wagered_sat: 4_000,
+                paid_sat: 4_000,
+            }
+        );
+
+        let event = round_results_event(commitment_event_id, &results)
+            .unwrap()
+            .to_event(&nostr::Keys::generate())
+            .unwrap();
+        assert_eq!(event.kind, Kind::TextNote);
+        assert!(event.content.contains("Bets: 4 by 3 rollers\nWinners: 3\n"));
+        assert!(event.content.ends_with("Paid out: 4000 sats"));
+        assert!(event.tags.iter().any(|tag| tag.as_vec()
+            == [
+                "e".to_string(),
+                "0".repeat(64),
+                String::new(),
+                "root".to_string()
+            ]));
+    }
+
+    #[tokio::test]
+    async fn rolls_of_beacon_rounds_are_seeded_with_the_beacon_randomness() {
+        let db = test_db().await;
+
+        let commitment_event_id = EventId::all_zeros();
+        set_active_nonce(
+            &db,
+            DEFAULT_GAME,
+            DEFAULT_SCHEDULE,
+            Round {
+                nonce: [1; 32],
+                beacon_round: Some(42),
+                ..test_round(commitment_event_id)
+            },
+        )
+        .await
+        .unwrap();
+
+        let round = get_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(round.beacon_round, Some(42));
+        assert!(round.roll_seed().is_err());
+
+        set_beacon_randomness(&db, commitment_event_id, [2; 32])
+            .await
+            .unwrap();
+        set_reveal_event_id(&db, commitment_event_id, EventId::all_zeros())
+            .await
+            .unwrap();
+
+        let round = get_revealed_round(&db, commitment_event_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(round.roll_seed().unwrap(), roll_seed([1; 32], [2; 32]));
+    }
+
+    #[tokio::test]
+    async fn stores_the_multipliers_commitment_of_a_round() {
+        let db = test_db().await;
+
+        let multipliers_commitment = sha256::Hash::hash(b"multipliers");
+        set_active_nonce(
+            &db,
+            DEFAULT_GAME,
+            DEFAULT_SCHEDULE,
+            Round {
+                multipliers_commitment: Some(multipliers_commitment),
+                ..test_round(EventId::all_zeros())
+            },
+        )
+        .await
+        .unwrap();
+
+        let round = get_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(round.multipliers_commitment, Some(multipliers_commitment));
+    }
+
+    #[tokio::test]
+    async fn finds_revealed_rounds_whose_results_are_not_published() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let round = |i| test_round(EventId::from_slice(&[i; 32]).unwrap());
+        let start = OffsetDateTime::now_utc() - time::Duration::seconds(1);
+        for (i, bet_state) in [
+            (0, BetState::Loser),
+            (1, BetState::Loser),
+            (2, BetState::GameZapInvoiceRequested),
+        ] {
+            set_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE, round(i))
+                .await
+                .unwrap();
+            set_reveal_event_id(&db, round(i).event_id, round(9).event_id)
+                .await
+                .unwrap();
+
+            let zap = db::test_zap("note1multiplier", round(i).event_id, bet_state);
+            let payment_hash = zap.invoice.payment_hash().to_string();
+            db::upsert_zap(&db, payment_hash, zap, &multipliers)
+                .await
+                .unwrap();
+        }
+        // Still running.
+        set_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE, round(3))
+            .await
+            .unwrap();
+        set_results_event_id(&db, round(1).event_id, round(9).event_id)
+            .await
+            .unwrap();
+        let end = OffsetDateTime::now_utc() + time::Duration::seconds(1);
+
+        let rounds = get_rounds_without_results(&db, DEFAULT_GAME, start, end)
+            .await
+            .unwrap();
+        assert_eq!(rounds, [round(0).event_id]);
+
+        // Rounds revealed after `until` are not due yet.
+        let rounds =
+            get_rounds_without_results(&db, DEFAULT_GAME, start - time::Duration::HOUR, start)
+                .await
+                .unwrap();
+        assert!(rounds.is_empty());
+    }
+
+    #[tokio::test]
+    async fn every_game_has_its_own_active_nonce() {
+        let db = test_db().await;
+
+        let round = |i| Round {
+            nonce: [i; 32],
+            ..test_round(EventId::from_slice(&[i; 32]).unwrap())
+        };
+        set_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE, round(0))
+            .await
+            .unwrap();
+        set_active_nonce(&db, "halloween", DEFAULT_SCHEDULE, round(1))
+            .await
+            .unwrap();
+
+        let unset = unset_active_nonce(&db, "halloween", DEFAULT_SCHEDULE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(unset.nonce, [1; 32]);
+        assert!(get_active_nonce(&db, "halloween", DEFAULT_SCHEDULE)
+            .await
+            .unwrap()
+            .is_none());
+
+        let active = get_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(active.nonce, [0; 32]);
+    }
+
+    #[tokio::test]
+    async fn every_schedule_of_a_game_has_its_own_rounds() {
+        let db = test_db().await;
+
+        let round = |i| Round {
+            nonce: [i; 32],
+            expires_at: Some(OffsetDateTime::now_utc() - time::Duration::seconds(1)),
+            ..test_round(EventId::from_slice(&[i; 32]).unwrap())
+        };
+        set_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE, round(0))
+            .await
+            .unwrap();
+        set_active_nonce(&db, DEFAULT_GAME, "fast", round(1))
+            .await
+            .unwrap();
+        set_active_nonce(&db, DEFAULT_GAME, "fast", round(2))
+            .await
+            .unwrap();
+
+        let active = get_active_nonce(&db, DEFAULT_GAME, "fast")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(active.nonce, [2; 32]);
+        let active = get_active_nonces(&db, DEFAULT_GAME).await.unwrap();
+        assert_eq!(
+            active
+                .iter()
+                .map(|(schedule, round)| (schedule.as_str(), round.nonce))
+                .collect::<Vec<_>>(),
+            vec![(DEFAULT_SCHEDULE, [0; 32]), ("fast", [2; 32])]
+        );
+
+        // Only the replaced round of the schedule is waiting to be revealed.
+        let now = OffsetDateTime::now_utc();
+        let unrevealed = get_unrevealed_expired_rounds(&db, DEFAULT_GAME, "fast", now)
+            .await
+            .unwrap();
+        assert_eq!(unrevealed.len(), 1);
+        assert_eq!(unrevealed[0].nonce, [1; 32]);
+        assert!(
+            get_unrevealed_expired_rounds(&db, DEFAULT_GAME, DEFAULT_SCHEDULE, now)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        unset_active_nonce(&db, DEFAULT_GAME, "fast").await.unwrap();
+        assert!(get_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn finds_the_rounds_of_a_game_by_start() {
+        let db = test_db().await;
+
+        let start = OffsetDateTime::now_utc();
+        let round = |i| Round {
+            nonce: [i; 32],
+            roll_scheme: RollScheme::V2,
+            ..test_round(EventId::from_slice(&[i; 32]).unwrap())
+        };
+        set_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE, round(0))
+            .await
+            .unwrap();
+        set_active_nonce(&db, "halloween", DEFAULT_SCHEDULE, round(1))
+            .await
+            .unwrap();
+        set_reveal_event_id(&db, round(0).event_id, round(2).event_id)
+            .await
+            .unwrap();
+        let end = OffsetDateTime::now_utc() + time::Duration::seconds(1);
+
+        let rounds = get_rounds_started_between(&db, DEFAULT_GAME, start, end)
+            .await
+            .unwrap();
+        assert_eq!(
+            rounds,
+            vec![RoundRecord {
+                event_id: round(0).event_id,
+                roll_scheme: RollScheme::V2,
+                revealed: true,
+            }]
+        );
+
+        let rounds = get_rounds_started_between(&db, DEFAULT_GAME, end, end + time::Duration::HOUR)
+            .await
+            .unwrap();
+        assert!(rounds.is_empty());
+    }
+
+    #[cfg(feature = "test-relay")]
+    #[tokio::test]
+    async fn nonce_commitment_is_published_to_relay() {
+        use crate::test_relay::TestRelay;
+        use nostr::Keys;
+        use nostr_sdk::Options;
+
+        let relay = TestRelay::start().await.unwrap();
+
+        let keys = Keys::generate();
+        let client = nostr_sdk::Client::with_opts(&keys, Options::default().wait_for_send(true));
+        client.add_relay(relay.url()).await.unwrap();
+        client.connect().await;
+        relay.wait_for_connection(&client).await.unwrap();
+
+        let commitment = nonce_commitment([0; 32]);
+        let multipliers_commitment = sha256::Hash::hash(b"multipliers");
+        let event_id = publish_nonce_commitment(
+            &client,
+            &keys,
+            commitment,
+            RollScheme::V2,
+            multipliers_commitment,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let events = relay.events();
+        assert_eq!(events.len(), 1);
+
+        let event = &events[0];
+        assert_eq!(event.id, event_id);
+        assert_eq!(event.author(), keys.public_key());
+        assert!(event.content.contains("roll scheme v2"));
+        assert!(event.tags.iter().any(|tag| tag.as_vec()
+            == [
+                MULTIPLIERS_COMMITMENT_TAG.to_string(),
+                multipliers_commitment.to_string()
+            ]));
+    }
+}
@@ -1,30 +1,255 @@
+use crate::db::abandon_failed_zaps;
+use crate::db::claim_bet_for_roll;
+use crate::db::get_exhausted_zaps_with_payouts;
 use crate::db::get_failed_zaps;
+use crate::db::get_roll_audit;
+use crate::db::get_rounds_with_paid_zaps;
+use crate::db::get_zaps_by_event_id;
+use crate::db::transition_bet_state;
 use crate::db::upsert_zap;
 use crate::db::BetState;
+use crate::db::RollAudit;
 use crate::db::Zap;
+use crate::dm::send_dm;
+use crate::dm::DmSender;
+use crate::keysend::fetch_keysend_target;
+use crate::lnd_health::LndHealth;
+use crate::metrics::METRICS;
+use crate::multiplier::Multiplier;
 use crate::multiplier::Multipliers;
+use crate::multiplier::PayoutTiming;
+use crate::nonce::get_revealed_round;
+use crate::nonce::get_rounds_without_results;
+use crate::nonce::publish_round_results;
+use crate::roll::generate_roll;
+use crate::roll::RollScheme;
+use crate::social_updates::WinnerPaid;
+use crate::unpaid_bets::UnpaidBetSweeps;
+use crate::zapper::track_payment;
+use crate::zapper::LndZapper;
+use crate::zapper::PaymentOutcome;
 use anyhow::bail;
+use anyhow::Context;
 use nostr::bitcoin::hashes::sha256;
-use nostr::bitcoin::hashes::HashEngine;
+use nostr::bitcoin::hashes::Hash;
 use nostr::prelude::ZapType;
 use nostr::ToBech32;
 use nostr_sdk::client::ZapDetails;
-use nostr_sdk::hashes::Hash;
 use nostr_sdk::Client;
+use nostr_sdk::EventId;
+use nostr_sdk::Keys;
 use nostr_sdk::PublicKey;
 use sqlx::SqlitePool;
+use std::sync::Arc;
 use std::time::Duration;
+use strum_macros::Display;
+use time::OffsetDateTime;
 use tokio::select;
 use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::sync::Notify;
 
 const RETRY_ZAP_INTERVAL: Duration = Duration::from_secs(60 * 60 * 6); // 6 hours
-const MAX_ZAP_RETRIES: i64 = 8; // Last retry will be 2 days later
 
-pub async fn roll_the_die(
+/// How often the rounds with paid bets are settled again, in case a bet was paid after its round
+/// had been settled without us hearing about it, e.g. because handling its payment timed out.
+const RESETTLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long after the reveal of a round its results are published at the earliest.
+const RESULTS_DELAY: Duration = Duration::from_secs(2 * 60);
+
+/// How long we keep trying to publish the results of a round, so that rounds revealed before we
+/// published results are not all published at once.
+const RESULTS_RETRY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What settling the bets of a game and paying out its winners takes.
+#[derive(Clone)]
+pub struct Settlement {
+    pub client: Client,
+    pub multipliers: Multipliers,
+    /// Told about every winner paid, for the social updates.
+    pub winners: mpsc::UnboundedSender<WinnerPaid>,
+    pub lnd_health: LndHealth,
+    pub zapper: LndZapper,
+    pub dms: DmSender,
+    pub payout_rounding: PayoutRounding,
+}
+
+/// Settle the paid bets of every round of the `game` as soon as its nonce has been revealed.
+///
+/// Rolls are only computed once the nonce is public, so that we never know the outcome of a bet
+/// before the roller can verify it. Bets of rounds revealed before a restart are settled first,
+/// and bets paid late are settled every [`RESETTLE_INTERVAL`].
+///
+/// Once a round is settled, the `unpaid_bet_sweeps` are told to clean up the bets on it which were
+/// never paid. If we are given the `round_results_keys` of the main account, the results of
+/// settled rounds are also replied to their nonce commitment notes, see
+/// [`publish_pending_results`].
+pub async fn settle_revealed_rounds(
+    db: SqlitePool,
+    game: String,
+    settlement: Settlement,
+    round_results_keys: Option<Keys>,
+    unpaid_bet_sweeps: UnpaidBetSweeps,
+    mut revealed: mpsc::UnboundedReceiver<EventId>,
+    mut ctrl_c: broadcast::Receiver<()>,
+) {
+    // The first tick completes right away.
+    let mut resettle = tokio::time::interval(RESETTLE_INTERVAL);
+
+    loop {
+        select! {
+            _ = resettle.tick() => {
+                settle_rounds_with_paid_bets(&db, &game, &settlement).await;
+
+                if let Some(keys) = &round_results_keys {
+                    let now = OffsetDateTime::now_utc();
+                    publish_pending_results(&db, &game, &settlement, keys, now).await;
+                }
+            },
+            commitment_event_id = revealed.recv() => {
+                let Some(commitment_event_id) = commitment_event_id else {
+                    break;
+                };
+
+                if let Err(e) = settle_round(&db, &settlement, commitment_event_id).await {
+                    tracing::error!(%commitment_event_id, "Failed to settle round: {e:#}");
+                }
+
+                unpaid_bet_sweeps.round_closed();
+            },
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down settlement task...");
+                break;
+            },
+        }
+    }
+}
+
+/// Settle every revealed round of the `game` which has paid bets left.
+async fn settle_rounds_with_paid_bets(db: &SqlitePool, game: &str, settlement: &Settlement) {
+    let rounds = match get_rounds_with_paid_zaps(db, game).await {
+        Ok(rounds) => rounds,
+        Err(e) => {
+            tracing::error!("Failed to get rounds with unsettled bets: {e:#}");
+            return;
+        }
+    };
+
+    for commitment_event_id in rounds {
+        // Rounds which are still running will be settled once they are revealed.
+        if let Err(e) = settle_round(db, settlement, commitment_event_id).await {
+            tracing::debug!(%commitment_event_id, "Not settling round yet: {e:#}");
+        }
+    }
+}
+
+/// Reply to the commitment notes of the rounds of the `game` we settled with their results, until
+/// publishing them worked.
+///
+/// Results are published [`RESULTS_DELAY`] after the reveal at the earliest, once the invoices of
+/// the round have long expired and bets paid at the last moment are settled, so that they are
+/// counted too. Rounds whose results could not be published within [`RESULTS_RETRY_WINDOW`] are
+/// given up on.
+async fn publish_pending_results(
+    db: &SqlitePool,
+    game: &str,
+    settlement: &Settlement,
+    keys: &Keys,
+    now: OffsetDateTime,
+) {
+    let rounds =
+        match get_rounds_without_results(db, game, now - RESULTS_RETRY_WINDOW, now - RESULTS_DELAY)
+            .await
+        {
+            Ok(rounds) => rounds,
+            Err(e) => {
+                tracing::error!("Failed to get rounds without results: {e:#}");
+                return;
+            }
+        };
+
+    for commitment_event_id in rounds {
+        publish_results(db, settlement, keys, commitment_event_id).await;
+    }
+}
+
+/// Reply to the commitment note of a settled round with its results, logging if that does not
+/// work.
+async fn publish_results(
+    db: &SqlitePool,
+    settlement: &Settlement,
+    keys: &Keys,
+    commitment_event_id: EventId,
+) {
+    match publish_round_results(
+        &settlement.client,
+        keys,
+        db,
+        &settlement.multipliers,
+        settlement.payout_rounding,
+        commitment_event_id,
+    )
+    .await
+    {
+        Ok(Some(results_event_id)) => {
+            tracing::info!(%commitment_event_id, %results_event_id, "Published round results");
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!(%commitment_event_id, "Failed to publish round results: {e:#}");
+        }
+    }
+}
+
+/// Roll the die for every paid bet of a round, refusing to do so before its nonce is revealed.
+async fn settle_round(
+    db: &SqlitePool,
+    settlement: &Settlement,
+    commitment_event_id: EventId,
+) -> anyhow::Result<()> {
+    let round = get_revealed_round(db, commitment_event_id)
+        .await?
+        .context("Round has not been revealed yet")?;
+
+    settlement
+        .multipliers
+        .verify_commitment(round.multipliers_commitment)
+        .context("Refusing to roll the die. Must settle the bets manually")?;
+
+    // For rounds using a randomness beacon, rolls are derived from the nonce mixed with it.
+    let seed = round.roll_seed().context("Refusing to roll the die")?;
+
+    let zaps = get_zaps_by_event_id(db, commitment_event_id).await?;
+    for zap in zaps {
+        let result = match zap.bet_state {
+            BetState::ZapPaid => {
+                roll_the_die(db, settlement, &zap, round.roll_scheme, seed, zap.index).await
+            }
+            BetState::Rolling => {
+                resume_roll(db, settlement, &zap, round.roll_scheme, seed, zap.index).await
+            }
+            _ => continue,
+        };
+        if let Err(e) = result {
+            tracing::error!(%commitment_event_id, "Failed to roll the die. Error: {e:#}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Roll the die for a paid bet and settle it, unless it has been rolled already.
+///
+/// All rolls go through here, so that no bet is rolled twice, e.g. by the settlements of a round
+/// revealed twice in quick succession. Rolling again could pay out a winner twice. Only the one
+/// settlement moving the bet from [`BetState::ZapPaid`] to [`BetState::Rolling`] gets to roll it,
+/// recording the roll in the same transaction.
+async fn roll_the_die(
     db: &SqlitePool,
+    settlement: &Settlement,
     zap: &Zap,
-    client: Client,
-    multipliers: Multipliers,
+    roll_scheme: RollScheme,
     nonce: [u8; 32],
     index: usize,
 ) -> anyhow::Result<()> {
@@ -35,32 +260,72 @@ pub async fn roll_the_die(
         invoice,
         ..
     } = zap;
+    let Settlement {
+        client,
+        multipliers,
+        dms,
+        ..
+    } = settlement;
     let roller_npub = roller.to_bech32().expect("npub");
-    let roll = generate_roll(nonce, index, *roller, request.content.clone());
+    let payment_hash = invoice.payment_hash().to_string();
+    let round_id = zap.nonce_commitment_note_id.context("Bet without round")?;
 
-    let multiplier = match multipliers
+    let multiplier_note = match multipliers
         .0
         .iter()
         .find(|note| &note.note_id == multiplier_note_id)
     {
-        Some(note) => &note.multiplier,
+        Some(note) => note,
         None => {
             bail!("Zap for unknown multiplier note ID. roller_npub={roller_npub}, zap={zap:?}");
         }
     };
 
-    let threshold = multiplier.get_lower_than();
-    if roll >= threshold {
+    let roll = generate_roll(roll_scheme, nonce, index, *roller, request.content.clone());
+
+    let win = multiplier_note.is_win(roll, roll_scheme);
+    let audit = RollAudit {
+        payment_hash: payment_hash.clone(),
+        nonce_commitment_note_id: round_id,
+        roller_npub: roller_npub.clone(),
+        index,
+        memo_hash: sha256::Hash::hash(request.content.as_bytes()).to_string(),
+        roll,
+        multiplier_note_id: multiplier_note_id.clone(),
+        multiplier: multiplier_note.multiplier.clone(),
+        direction: multiplier_note.direction,
+        threshold: multiplier_note.get_threshold(roll_scheme),
+        amount_msat: invoice.amount_milli_satoshis().unwrap_or_default(),
+        win,
+        rolled_at: OffsetDateTime::now_utc(),
+        settled_at: zap.settled_at,
+        paid_out_at: None,
+    };
+
+    // The bet may have been settled since the round's bets were fetched.
+    if !claim_bet_for_roll(db, audit).await? {
+        tracing::warn!(
+            %roller_npub,
+            payment_hash,
+            "Not rolling the die for a bet which is not waiting for its roll"
+        );
+
+        return Ok(());
+    }
+
+    let target = multiplier_note.get_target(roll_scheme);
+    if !win {
         tracing::debug!(
             %roller_npub,
             "Roller did not win this time. \
-             Aimed for <{threshold}, got {roll}"
+             Aimed for {target}, got {roll}"
         );
 
         send_dm(
-            &client,
+            client,
+            dms,
             roller,
-            format!("You lost. You rolled {roll}, which was bigger than {threshold}. Try again!"),
+            format!("You lost. You rolled {roll}, which was not {target}. Try again!"),
         )
         .await;
 
@@ -68,145 +333,399 @@ pub async fn roll_the_die(
             bet_state: BetState::Loser,
             ..zap.clone()
         };
-        upsert_zap(db, invoice.payment_hash().to_string(), zap, &multipliers).await?;
+        upsert_zap(db, payment_hash, zap, multipliers).await?;
 
         return Ok(());
     }
 
     send_dm(
-        &client,
+        client,
+        dms,
         roller,
-        format!("You won. You rolled {roll}, which was lower than {threshold}."),
+        format!("You won. You rolled {roll}, which was {target}."),
     )
     .await;
 
     tracing::info!(
         %roller_npub,
-        "Roller is a winner! Aimed for <{threshold}, got {roll}"
+        "Roller is a winner! Aimed for {target}, got {roll}"
     );
 
-    try_zap(db, &client, &multipliers, zap).await?;
+    if multiplier_note.payout_timing == PayoutTiming::Queued {
+        tracing::info!(
+            %roller_npub,
+            payment_hash = %invoice.payment_hash(),
+            "Queued payout for manual review"
+        );
+
+        send_dm(
+            client,
+            dms,
+            roller,
+            "Your payout will be zapped to you once we have reviewed your win.".to_string(),
+        )
+        .await;
+
+        let zap = Zap {
+            bet_state: BetState::QueuedWinner,
+            ..zap.clone()
+        };
+        upsert_zap(db, payment_hash, zap, multipliers).await?;
+
+        return Ok(());
+    }
+
+    try_zap(db, settlement, zap).await?;
 
     Ok(())
 }
 
-async fn try_zap(
+/// Settle a bet left [`BetState::Rolling`], e.g. because storing its outcome failed or we crashed
+/// while settling it, from its recorded roll.
+///
+/// Losers and queued winners are settled without telling the roller again. Winners paid right
+/// away are left rolling, since we may have paid them already, and have to be resolved with
+/// `POST /admin/resolve/<payment hash>`.
+async fn resume_roll(
     db: &SqlitePool,
-    client: &Client,
-    multipliers: &Multipliers,
+    settlement: &Settlement,
     zap: &Zap,
+    roll_scheme: RollScheme,
+    nonce: [u8; 32],
+    index: usize,
 ) -> anyhow::Result<()> {
-    let Zap {
-        roller,
-        multiplier_note_id,
-        invoice,
-        ..
-    } = zap;
-    let roller_npub = roller.to_bech32().expect("npub");
+    let payment_hash = zap.invoice.payment_hash().to_string();
+
+    let Some(audit) = get_roll_audit(db, &payment_hash).await? else {
+        // Claimed before rolls were recorded together with the claim. Nothing was settled, so the
+        // bet can be rolled like any other.
+        if transition_bet_state(db, &payment_hash, BetState::Rolling, BetState::ZapPaid).await? {
+            let zap = Zap {
+                bet_state: BetState::ZapPaid,
+                ..zap.clone()
+            };
+            roll_the_die(db, settlement, &zap, roll_scheme, nonce, index).await?;
+        }
 
-    let multiplier = match multipliers
+        return Ok(());
+    };
+
+    let payout_timing = settlement
+        .multipliers
         .0
         .iter()
-        .find(|note| &note.note_id == multiplier_note_id)
-    {
-        Some(note) => &note.multiplier,
-        None => {
-            bail!("Zap for unknown multiplier note ID. roller_npub={roller_npub}, zap={zap:?}");
+        .find(|note| note.note_id == audit.multiplier_note_id)
+        .map(|note| note.payout_timing)
+        .with_context(|| format!("Rolled bet for unknown multiplier note ID. zap={zap:?}"))?;
+
+    let bet_state = match (audit.win, payout_timing) {
+        (false, _) => BetState::Loser,
+        (true, PayoutTiming::Queued) => BetState::QueuedWinner,
+        (true, PayoutTiming::Instant) => {
+            tracing::error!(
+                payment_hash,
+                "Winner left rolling, who may have been paid already. \
+                 Must be resolved with POST /admin/resolve/<payment hash>"
+            );
+
+            return Ok(());
         }
     };
 
-    let zap_amount_msat = invoice
-        .amount_milli_satoshis()
-        .expect("amount to be present");
-    let amount_sat = calculate_price_money(zap_amount_msat, multiplier.get_multiplier());
+    if transition_bet_state(db, &payment_hash, BetState::Rolling, bet_state.clone()).await? {
+        tracing::info!(payment_hash, %bet_state, "Settled bet left rolling");
+    }
+
+    Ok(())
+}
+
+/// Zap the winner their payout, returning whether it was paid.
+///
+/// If the winner cannot be zapped before any payment is made, e.g. because their zap endpoint is
+/// broken, they are paid with a keysend payment instead.
+async fn try_zap(db: &SqlitePool, settlement: &Settlement, zap: &Zap) -> anyhow::Result<bool> {
+    let Settlement {
+        client,
+        multipliers,
+        lnd_health,
+        zapper,
+        dms,
+        ..
+    } = settlement;
+    let Zap {
+        roller, invoice, ..
+    } = zap;
+    let roller_npub = roller.to_bech32().expect("npub");
+
+    let (multiplier, amount_sat) = payout(settlement, zap)?;
 
     tracing::debug!(
         %roller_npub,
         "Sending {} * {} = {amount_sat} to {roller_npub} for hitting a {} multiplier",
-        zap_amount_msat / 1_000,
+        invoice.amount_milli_satoshis().unwrap_or_default() / 1_000,
         multiplier.get_multiplier(),
         multiplier.get_content()
     );
 
+    if zapper.dry_run {
+        // The bet is settled without even fetching an invoice from the winner, and without
+        // announcing a payout which never happened.
+        tracing::info!(%roller_npub, "Dry run: not zapping payout of {amount_sat} sats");
+
+        let zap = Zap {
+            bet_state: BetState::DryRunWinner,
+            ..zap.clone()
+        };
+        upsert_zap(db, invoice.payment_hash().to_string(), zap, multipliers).await?;
+
+        return Ok(true);
+    }
+
     let zap_details = ZapDetails::new(ZapType::Public)
         .message(format!("Won a {}x bet on NostrDice!", multiplier.get_multiplier()).to_string());
 
-    let zap = if let Err(e) = client.zap(zap.roller, amount_sat, Some(zap_details)).await {
-        tracing::error!(%roller_npub, "Failed to zap. Error: {e:#}");
+    let (result, payment) =
+        match track_payment(client.zap(zap.roller, amount_sat, Some(zap_details))).await {
+            // Nothing was paid, so we cannot end up paying the winner twice.
+            (Err(e), None) if lnd_health.is_connected() => {
+                tracing::warn!(%roller_npub, "Failed to zap, trying keysend. Error: {e:#}");
 
-        send_dm(
-            client,
-            roller,
-            "Sorry, we failed to zap you your payout.".to_string(),
-        )
-        .await;
+                track_payment(keysend_payout(client, zapper, roller, amount_sat)).await
+            }
+            (result, payment) => (result.map_err(anyhow::Error::from), payment),
+        };
+    let payout_payment_hash = payment.as_ref().map(|payment| payment.payment_hash.clone());
+    let fee_msat = payment.and_then(|payment| payment.fee_msat);
+
+    let paid = result.is_ok();
+    let zap = if let Err(e) = result {
+        METRICS.zap_failed();
+
+        if lnd_health.is_connected() {
+            tracing::error!(%roller_npub, "Failed to zap. Error: {e:#}");
+
+            send_dm(
+                client,
+                dms,
+                roller,
+                "Sorry, we failed to zap you your payout.".to_string(),
+            )
+            .await;
+        } else {
+            // The payout is held and retried once LND is back.
+            tracing::warn!(%roller_npub, "Failed to zap while LND is unreachable. Error: {e:#}");
+
+            send_dm(
+                client,
+                dms,
+                roller,
+                "Our Lightning node is unreachable right now. \
+                 We will zap you your payout once it is back."
+                    .to_string(),
+            )
+            .await;
+        }
 
         Zap {
             bet_state: BetState::ZapFailed,
+            payout_payment_hash,
             ..zap.clone()
         }
     } else {
-        Zap {
-            bet_state: BetState::PaidWinner,
-            ..zap.clone()
-        }
+        paid_winner(settlement, zap, payout_payment_hash, fee_msat)?
     };
 
     upsert_zap(db, invoice.payment_hash().to_string(), zap, multipliers).await?;
 
-    Ok(())
+    Ok(paid)
 }
 
-async fn send_dm(client: &Client, to: &PublicKey, message: String) {
-    let npub = to.to_bech32().expect("npub");
+/// The multiplier the `zap` won on, and its payout in sats.
+fn payout<'a>(settlement: &'a Settlement, zap: &Zap) -> anyhow::Result<(&'a Multiplier, u64)> {
+    let multiplier = match settlement
+        .multipliers
+        .0
+        .iter()
+        .find(|note| note.note_id == zap.multiplier_note_id)
+    {
+        Some(note) => &note.multiplier,
+        None => {
+            let roller_npub = zap.roller.to_bech32().expect("npub");
+            bail!("Zap for unknown multiplier note ID. roller_npub={roller_npub}, zap={zap:?}");
+        }
+    };
+
+    let zap_amount_msat = zap
+        .invoice
+        .amount_milli_satoshis()
+        .expect("amount to be present");
+    let amount_sat = calculate_price_money(
+        zap_amount_msat,
+        multiplier.get_multiplier(),
+        settlement.payout_rounding,
+    );
 
-    // The `send_private_message` function (NIP17) seems to be not supported by major nostr clients.
-    #[allow(deprecated)]
-    if let Err(e) = client.send_direct_msg(*to, message, None).await {
-        tracing::error!(
-            %npub,
-            "Failed to send DM: {e:#}"
-        );
-    }
+    Ok((multiplier, amount_sat))
 }
 
-pub fn calculate_price_money(amount_msat: u64, multiplier: f32) -> u64 {
-    ((amount_msat as f32 / 1000.0) * multiplier).floor() as u64
+/// The winning `zap` once it was paid out with the payment with the `payout_payment_hash`.
+///
+/// Records the payout in the metrics and announces the winner.
+fn paid_winner(
+    settlement: &Settlement,
+    zap: &Zap,
+    payout_payment_hash: Option<String>,
+    fee_msat: Option<u64>,
+) -> anyhow::Result<Zap> {
+    let (multiplier, amount_sat) = payout(settlement, zap)?;
+
+    let paid_out_at = OffsetDateTime::now_utc();
+    // Bets paid before we recorded when only have the time their invoice was requested.
+    let latency = paid_out_at - zap.settled_at.unwrap_or(zap.bet_timestamp);
+    METRICS.payout_paid(
+        amount_sat,
+        fee_msat.unwrap_or_default(),
+        latency.try_into().unwrap_or_default(),
+    );
+
+    let _ = settlement.winners.send(WinnerPaid {
+        roller: zap.roller,
+        multiplier: multiplier.clone(),
+        amount_sat,
+    });
+
+    Ok(Zap {
+        bet_state: BetState::PaidWinner,
+        payout_payment_hash,
+        fee_msat,
+        paid_out_at: Some(paid_out_at),
+        ..zap.clone()
+    })
 }
 
-fn generate_roll(nonce: [u8; 32], index: usize, roller_npub: PublicKey, memo: String) -> u16 {
-    let mut hasher = sha256::Hash::engine();
+/// Find out whether the earlier payout of the failed `zap` might have gone through after all,
+/// e.g. because LND became unreachable while making it. Returns whether it can be zapped again.
+///
+/// If the earlier payout succeeded, the bet is recorded as paid. While LND might still complete
+/// it, or we cannot find out, it is not zapped again, so that the winner is not paid twice.
+async fn check_earlier_payout(
+    db: &SqlitePool,
+    settlement: &Settlement,
+    zap: &Zap,
+) -> anyhow::Result<bool> {
+    let Some(payout_payment_hash) = &zap.payout_payment_hash else {
+        // Nothing was paid.
+        return Ok(true);
+    };
+
+    let outcome = settlement
+        .zapper
+        .payment_outcome(payout_payment_hash)
+        .await
+        .context("Failed to look up earlier payout")?;
+
+    match outcome {
+        PaymentOutcome::Failed => Ok(true),
+        PaymentOutcome::InFlight => {
+            tracing::warn!(
+                ?zap,
+                "Earlier payout still in flight, not zapping again yet"
+            );
+
+            Ok(false)
+        }
+        PaymentOutcome::Succeeded { fee_msat } => {
+            tracing::info!(?zap, "Earlier payout went through after all");
 
-    let nonce = hex::encode(nonce);
-    let nonce = nonce.as_bytes();
+            let paid = paid_winner(
+                settlement,
+                zap,
+                Some(payout_payment_hash.clone()),
+                Some(fee_msat),
+            )?;
+            upsert_zap(
+                db,
+                zap.invoice.payment_hash().to_string(),
+                paid,
+                &settlement.multipliers,
+            )
+            .await?;
 
-    let roller_npub = roller_npub.to_bech32().expect("valid npub");
-    let roller_npub = roller_npub.as_bytes();
+            Ok(false)
+        }
+    }
+}
 
-    let memo = memo.as_bytes();
+/// Pay the `roller` with a keysend payment to the node behind their lightning address.
+async fn keysend_payout(
+    client: &Client,
+    zapper: &LndZapper,
+    roller: &PublicKey,
+    amount_sat: u64,
+) -> anyhow::Result<()> {
+    let metadata = client
+        .metadata(*roller)
+        .await
+        .context("Failed to fetch metadata")?;
+    let lud16 = metadata
+        .lud16
+        .context("No lightning address to look up a keysend node for")?;
 
-    let index = index.to_string();
-    let index = index.as_bytes();
+    let target = fetch_keysend_target(lud16).await?;
 
-    hasher.input(nonce);
-    hasher.input(roller_npub);
-    hasher.input(memo);
-    hasher.input(index);
+    zapper.keysend(target, amount_sat).await
+}
 
-    let roll = sha256::Hash::from_engine(hasher);
-    let roll = roll.to_byte_array();
+/// How the payouts of winning bets are rounded to whole sats.
+///
+/// Rounding down lets the house keep the fractions of a sat, which is most of what small bets on
+/// low multipliers would win, e.g. a 1.05x win on 19 sats pays out 19 sats. Rounding up gives them
+/// to the rollers instead, which on small enough bets pays out more than the win probability makes
+/// up for, e.g. the house loses money on 1.05x bets of up to 12 sats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, clap::ValueEnum)]
+#[strum(serialize_all = "lowercase")]
+pub enum PayoutRounding {
+    #[default]
+    Floor,
+    /// To the nearest sat, half a sat up.
+    Round,
+    Ceil,
+}
 
-    let roll = hex::encode(roll);
+/// The payout in sats of a winning bet of `amount_msat`, rounded with the `rounding`.
+///
+/// Bets of at least a sat pay out at least a sat, so that no roller wins nothing. Smaller bets are
+/// never accepted.
+pub fn calculate_price_money(amount_msat: u64, multiplier: f32, rounding: PayoutRounding) -> u64 {
+    // Multipliers like 1.05 are not exactly representable, so they are rounded to the six
+    // decimals they are configured with, and the payout to whole msats, before rounding to sats.
+    // Otherwise a 1.05x win on 1000 sats could be floored to 1049 sats.
+    let multiplier = (f64::from(multiplier) * 1e6).round() / 1e6;
+    let payout_msat = (amount_msat as f64 * multiplier).round() as u64;
 
-    let roll = roll.get(0..4).expect("long enough");
+    let payout_sat = match rounding {
+        PayoutRounding::Floor => payout_msat / 1_000,
+        PayoutRounding::Round => (payout_msat + 500) / 1_000,
+        PayoutRounding::Ceil => payout_msat.div_ceil(1_000),
+    };
 
-    u16::from_str_radix(roll, 16).expect("valid hex")
+    if amount_msat >= 1_000 {
+        payout_sat.max(1)
+    } else {
+        payout_sat
+    }
 }
 
+/// Regularly retry the failed zaps of the `game`, and as soon as LND is reachable again after an
+/// outage.
+///
+/// A zap is retried up to `max_zap_retries` times, after which it has to be paid out manually.
 pub async fn retry_zaps(
     db: SqlitePool,
-    client: Client,
-    multipliers: Multipliers,
+    game: String,
+    settlement: Settlement,
+    max_zap_retries: u64,
+    payouts_released: Arc<Notify>,
     mut ctrl_c: broadcast::Receiver<()>,
 ) {
     // Give other tasks a while to start up
@@ -219,76 +738,825 @@ pub async fn retry_zaps(
     }
 
     loop {
-        tracing::info!("Retrying failed zaps...");
-
-        let failed = get_failed_zaps(&db, MAX_ZAP_RETRIES)
-            .await
-            .expect("Failed to get failed zaps");
+        if let Err(e) = retry_failed_zaps(&db, &game, &settlement, max_zap_retries, &ctrl_c).await {
+            tracing::error!("Failed to retry failed zaps: {e:#}");
+        }
 
-        for mut zap in failed {
-            if !ctrl_c.is_empty() {
+        select! {
+            _ = tokio::time::sleep(RETRY_ZAP_INTERVAL) => (),
+            _ = settlement.lnd_health.reconnected() => {
+                tracing::info!("LND is reachable again; retrying held zaps...");
+            },
+            _ = payouts_released.notified() => {
+                tracing::info!("Queued payout released; retrying failed zaps...");
+            },
+            _ = ctrl_c.recv() => {
                 tracing::warn!("Got Ctrl+C; shutting down zap retry task...");
                 break;
+            },
+        }
+    }
+}
+
+/// Retry every failed zap of the `game` once.
+///
+/// Nothing is retried while LND is unreachable, so that an outage does not use up the retries.
+async fn retry_failed_zaps(
+    db: &SqlitePool,
+    game: &str,
+    settlement: &Settlement,
+    max_zap_retries: u64,
+    ctrl_c: &broadcast::Receiver<()>,
+) -> anyhow::Result<()> {
+    let lnd_health = &settlement.lnd_health;
+    if !lnd_health.is_connected() {
+        tracing::warn!("Not retrying failed zaps while LND is unreachable");
+        return Ok(());
+    }
+
+    tracing::info!("Retrying failed zaps...");
+
+    let failed = get_failed_zaps(db, game, max_zap_retries as i64)
+        .await
+        .context("Failed to get failed zaps")?;
+
+    for mut zap in failed {
+        if !ctrl_c.is_empty() {
+            tracing::warn!("Got Ctrl+C; shutting down zap retry task...");
+            return Ok(());
+        }
+
+        if !lnd_health.is_connected() {
+            tracing::warn!("Lost connection to LND; holding remaining failed zaps");
+            return Ok(());
+        }
+
+        // There is a small chance of a race condition here - if we get the ctrl C after this
+        // point, we could theoretically zap them before inserting the updated zap into the
+        // database. Then, the next time the app is started, it would be zapped again.
+        // Since this retry only occurs every 6 hours, and the chance of failure should be
+        // small, it is recommended to simply not restart the application until
+        // "Retried all failed zaps" is seen in the logs.
+
+        match check_earlier_payout(db, settlement, &zap).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(error) => {
+                tracing::error!(?zap, %error, "Not retrying zap whose earlier payout is unknown");
+                continue;
             }
+        }
 
-            // There is a small chance of a race condition here - if we get the ctrl C after this
-            // point, we could theoretically zap them before inserting the updated zap into the
-            // database. Then, the next time the app is started, it would be zapped again.
-            // Since this retry only occurs every 6 hours, and the chance of failure should be
-            // small, it is recommended to simply not restart the application until
-            // "Retried all failed zaps" is seen in the logs.
-
-            zap.zap_retries += 1;
-            match try_zap(&db, &client, &multipliers, &zap).await {
-                Ok(_) => tracing::info!(?zap, "Successfully retried zap"),
-                Err(error) => tracing::error!(?zap, %error, "Failed to retry zap"),
+        zap.zap_retries += 1;
+        match try_zap(db, settlement, &zap).await {
+            Ok(true) => tracing::info!(?zap, "Successfully retried zap"),
+            Ok(false) if zap.zap_retries >= max_zap_retries => {
+                tracing::error!(?zap, "Zap failed on its last retry")
             }
+            Ok(false) => tracing::warn!(?zap, "Retried zap failed again"),
+            Err(error) => tracing::error!(?zap, %error, "Failed to retry zap"),
         }
+    }
 
-        tracing::info!("Retried all failed zaps.");
+    let mut abandoned = abandon_failed_zaps(db, game, max_zap_retries as i64).await?;
 
-        select! {
-            _ = tokio::time::sleep(RETRY_ZAP_INTERVAL) => (),
-            _ = ctrl_c.recv() => {
-                tracing::warn!("Got Ctrl+C; shutting down zap retry task...");
-                break;
-            },
+    // A zap whose last retry made a payment is only given up on once LND reports that it failed,
+    // so that a payout which went through after all is not paid out manually again.
+    let exhausted = get_exhausted_zaps_with_payouts(db, game, max_zap_retries as i64)
+        .await
+        .context("Failed to get zaps out of retries")?;
+    for zap in exhausted {
+        match check_earlier_payout(db, settlement, &zap).await {
+            Ok(true) => {
+                let payment_hash = zap.invoice.payment_hash().to_string();
+                if transition_bet_state(
+                    db,
+                    &payment_hash,
+                    BetState::ZapFailed,
+                    BetState::ZapAbandoned,
+                )
+                .await?
+                {
+                    abandoned += 1;
+                }
+            }
+            Ok(false) => {}
+            Err(error) => tracing::error!(?zap, %error, "Not abandoning zap of unknown outcome"),
         }
     }
+
+    if abandoned > 0 {
+        tracing::info!(abandoned, "Abandoned zaps which ran out of retries");
+    }
+
+    tracing::info!("Retried all failed zaps.");
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::delete_held_zap_receipt;
+    use crate::db::get_roll_audits;
+    use crate::db::get_rolled_zap_receipts;
+    use crate::db::get_zap;
+    use crate::db::hold_zap_receipt;
+    use crate::db::release_queued_payout;
+    use crate::db::resolve_rolling_winner;
+    use crate::db::test_db;
+    use crate::db::test_round;
+    use crate::db::test_zap;
+    use crate::db::HeldZapReceipt;
+    use crate::db::Round;
+    use crate::dm::DmProtocol;
+    use crate::game::DEFAULT_GAME;
+    use crate::game::DEFAULT_SCHEDULE;
+    use crate::multiplier::test_multiplier_note;
     use crate::multiplier::Multiplier;
-    use crate::payouts::calculate_price_money;
-    use crate::payouts::generate_roll;
+    use crate::multiplier::MultiplierNote;
+    use crate::nonce::set_active_nonce;
+    use crate::nonce::set_reveal_event_id;
+    use crate::zapper::Payment;
+    use crate::zapper::PaymentLimits;
+    use nostr::Keys;
 
-    #[test]
-    /// You can verify the outcome by visiting this URL:
-    /// https://emn178.github.io/online-tools/sha256.html?input=0000000000000000000000000000000000000000000000000000000000000000npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32Hello%2C%20world!%20%F0%9F%94%970&input_type=utf-8&output_type=hex&hmac_enabled=0&hmac_input_type=utf-8
-    /// then take the first 4 digits of the hex and convert it to a decimal number.
-    /// https://www.rapidtables.com/convert/number/hex-to-decimal.html?x=9d6b
-    fn generate_roll_test() {
-        let nonce = [0u8; 32];
-
-        let roller_npub =
-            PublicKey::parse("npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32")
+    #[tokio::test]
+    async fn bets_are_not_settled_before_the_nonce_is_revealed() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let commitment_event_id = EventId::all_zeros();
+        set_active_nonce(
+            &db,
+            DEFAULT_GAME,
+            DEFAULT_SCHEDULE,
+            Round {
+                multipliers_commitment: Some(multipliers.commitment()),
+                ..test_round(commitment_event_id)
+            },
+        )
+        .await
+        .unwrap();
+
+        let zap = test_zap("note1multiplier", commitment_event_id, BetState::ZapPaid);
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+
+        let (settlement, mut winners_rx) = test_settlement(&multipliers);
+        let result = settle_round(&db, &settlement, commitment_event_id).await;
+
+        assert!(result.is_err());
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::ZapPaid);
+        assert!(winners_rx.try_recv().is_err());
+    }
+
+    /// Settles the bets on the `multipliers` with a [`stopped_zapper`], returning where the paid
+    /// winners are sent.
+    fn test_settlement(
+        multipliers: &Multipliers,
+    ) -> (Settlement, mpsc::UnboundedReceiver<WinnerPaid>) {
+        let (winners, winners_rx) = mpsc::unbounded_channel();
+        let settlement = Settlement {
+            client: Client::new(&Keys::generate()),
+            multipliers: multipliers.clone(),
+            winners,
+            lnd_health: LndHealth::new(true),
+            zapper: stopped_zapper(),
+            dms: DmSender::new(DmProtocol::Nip04),
+            payout_rounding: PayoutRounding::Floor,
+        };
+
+        (settlement, winners_rx)
+    }
+
+    /// A zapper which has stopped, so that every payment fails.
+    fn stopped_zapper() -> LndZapper {
+        LndZapper {
+            sender: mpsc::channel(1).0,
+            limits: PaymentLimits {
+                min_fee_sat: 10,
+                fee_ppm: 5_000,
+                timeout_seconds: 60,
+            },
+            dry_run: false,
+        }
+    }
+
+    /// A zapper which answers every lookup of an earlier payment with the `outcome`, and fails
+    /// every payment.
+    fn looking_up_zapper(outcome: PaymentOutcome) -> LndZapper {
+        let (sender, mut receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some(payment) = receiver.recv().await {
+                if let Payment::LookUp(look_up) = payment {
+                    let _ = look_up.sender.send(Ok(outcome));
+                }
+            }
+        });
+
+        LndZapper {
+            sender,
+            ..stopped_zapper()
+        }
+    }
+
+    /// The roller of [`winning_bet`].
+    const WINNING_ROLLER: &str = "npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32";
+
+    /// With this nonce, the first bet of [`WINNING_ROLLER`] without a memo rolls 22148 with
+    /// [`RollScheme::V1`], which wins on a 2x multiplier note.
+    const WINNING_NONCE: [u8; 32] = [3; 32];
+
+    /// A revealed round with a winning bet on a 2x multiplier note with the `payout_timing`.
+    ///
+    /// Returns the multipliers, the round's commitment note ID and the bet's payment hash.
+    async fn winning_bet(
+        db: &SqlitePool,
+        payout_timing: PayoutTiming,
+    ) -> (Multipliers, EventId, String) {
+        let multipliers = Multipliers(vec![MultiplierNote {
+            payout_timing,
+            ..test_multiplier_note("note1multiplier")
+        }]);
+
+        let commitment_event_id = EventId::all_zeros();
+        set_active_nonce(
+            db,
+            DEFAULT_GAME,
+            DEFAULT_SCHEDULE,
+            Round {
+                nonce: WINNING_NONCE,
+                multipliers_commitment: Some(multipliers.commitment()),
+                ..test_round(commitment_event_id)
+            },
+        )
+        .await
+        .unwrap();
+        set_reveal_event_id(db, commitment_event_id, EventId::all_zeros())
+            .await
+            .unwrap();
+
+        let zap = Zap {
+            roller: PublicKey::parse(WINNING_ROLLER).unwrap(),
+            ..test_zap("note1multiplier", commitment_event_id, BetState::ZapPaid)
+        };
+        let roll = generate_roll(
+            RollScheme::V1,
+            WINNING_NONCE,
+            zap.index,
+            zap.roller,
+            zap.request.content.clone(),
+        );
+        assert_eq!(roll, 22148);
+        assert!(multipliers.0[0].is_win(roll, RollScheme::V1));
+
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        upsert_zap(db, payment_hash.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+
+        (multipliers, commitment_event_id, payment_hash)
+    }
+
+    #[tokio::test]
+    async fn winners_are_held_while_lnd_is_unreachable() {
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Instant).await;
+
+        // Without a zapper, zapping fails like it would without LND.
+        let (settlement, mut winners_rx) = test_settlement(&multipliers);
+        let settlement = Settlement {
+            lnd_health: LndHealth::new(false),
+            ..settlement
+        };
+
+        settle_round(&db, &settlement, commitment_event_id)
+            .await
+            .unwrap();
+
+        let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::ZapFailed);
+        assert_eq!(zap.zap_retries, 0);
+        assert!(winners_rx.try_recv().is_err());
+
+        // Retrying while LND is still unreachable does not use up the retries.
+        let (_ctrl_c_tx, ctrl_c) = broadcast::channel(1);
+        retry_failed_zaps(&db, DEFAULT_GAME, &settlement, 8, &ctrl_c)
+            .await
+            .unwrap();
+
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::ZapFailed);
+        assert_eq!(zap.zap_retries, 0);
+    }
+
+    #[tokio::test]
+    async fn payouts_of_unknown_outcome_are_not_zapped_again() {
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Instant).await;
+
+        let (settlement, mut winners_rx) = test_settlement(&multipliers);
+        settle_round(&db, &settlement, commitment_event_id)
+            .await
+            .unwrap();
+
+        // E.g. LND became unreachable while making the payout, after we passed it the invoice.
+        let payout_payment_hash = Some("ab".repeat(32));
+        let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+        let failed = Zap {
+            payout_payment_hash: payout_payment_hash.clone(),
+            ..zap
+        };
+        upsert_zap(&db, payment_hash.clone(), failed.clone(), &multipliers)
+            .await
+            .unwrap();
+
+        // Neither while LND might still complete the payout, nor while it cannot tell us, is the
+        // winner zapped again.
+        let (_ctrl_c_tx, ctrl_c) = broadcast::channel(1);
+        for zapper in [
+            looking_up_zapper(PaymentOutcome::InFlight),
+            stopped_zapper(),
+        ] {
+            let settlement = Settlement {
+                zapper,
+                ..settlement.clone()
+            };
+            retry_failed_zaps(&db, DEFAULT_GAME, &settlement, 8, &ctrl_c)
+                .await
                 .unwrap();
-        let memo = "Hello, world! 🔗".to_string();
 
-        let n = generate_roll(nonce, 0, roller_npub, memo);
+            let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+            assert_eq!(zap.bet_state, BetState::ZapFailed);
+            assert_eq!(zap.zap_retries, 0);
+            assert_eq!(zap.payout_payment_hash, payout_payment_hash);
+            assert!(winners_rx.try_recv().is_err());
+        }
 
-        println!("You rolled a {n}");
+        // Once LND reports that the payout failed, the winner is zapped again.
+        let settlement = Settlement {
+            zapper: looking_up_zapper(PaymentOutcome::Failed),
+            ..settlement.clone()
+        };
+        retry_failed_zaps(&db, DEFAULT_GAME, &settlement, 8, &ctrl_c)
+            .await
+            .unwrap();
 
-        assert_eq!(n, 40299);
+        let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::ZapFailed);
+        assert_eq!(zap.zap_retries, 1);
+
+        // If it went through after all, the winner is paid.
+        upsert_zap(&db, payment_hash.clone(), failed, &multipliers)
+            .await
+            .unwrap();
+        let settlement = Settlement {
+            zapper: looking_up_zapper(PaymentOutcome::Succeeded { fee_msat: 1_000 }),
+            ..settlement
+        };
+        retry_failed_zaps(&db, DEFAULT_GAME, &settlement, 8, &ctrl_c)
+            .await
+            .unwrap();
+
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::PaidWinner);
+        assert_eq!(zap.zap_retries, 0);
+        assert_eq!(zap.payout_payment_hash, payout_payment_hash);
+        assert_eq!(zap.fee_msat, Some(1_000));
+        assert!(zap.paid_out_at.is_some());
+        assert_eq!(winners_rx.try_recv().unwrap().amount_sat, 2_000);
+    }
+
+    #[tokio::test]
+    async fn zaps_out_of_retries_are_abandoned_once_their_payout_failed() {
+        let db = test_db().await;
+        let (multipliers, _, payment_hash) = winning_bet(&db, PayoutTiming::Instant).await;
+
+        // The last retry made a payment we did not hear back about.
+        let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+        let zap = Zap {
+            bet_state: BetState::ZapFailed,
+            zap_retries: 8,
+            payout_payment_hash: Some("ab".repeat(32)),
+            ..zap
+        };
+        upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+
+        let (settlement, _winners_rx) = test_settlement(&multipliers);
+        let (_ctrl_c_tx, ctrl_c) = broadcast::channel(1);
+        for (outcome, bet_state) in [
+            (PaymentOutcome::InFlight, BetState::ZapFailed),
+            (PaymentOutcome::Failed, BetState::ZapAbandoned),
+        ] {
+            let settlement = Settlement {
+                zapper: looking_up_zapper(outcome),
+                ..settlement.clone()
+            };
+            retry_failed_zaps(&db, DEFAULT_GAME, &settlement, 8, &ctrl_c)
+                .await
+                .unwrap();
+
+            let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+            assert_eq!(zap.bet_state, bet_state);
+            assert_eq!(zap.zap_retries, 8);
+        }
+    }
+
+    #[tokio::test]
+    async fn instant_payouts_are_zapped_on_settlement() {
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Instant).await;
+
+        // Without a zapper, the zap fails. That it was attempted is all we care about here.
+        let (settlement, _winners_rx) = test_settlement(&multipliers);
+
+        settle_round(&db, &settlement, commitment_event_id)
+            .await
+            .unwrap();
+
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::ZapFailed);
+    }
+
+    #[tokio::test]
+    async fn dry_runs_settle_winners_without_paying_them() {
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Instant).await;
+
+        let (settlement, mut winners_rx) = test_settlement(&multipliers);
+        let settlement = Settlement {
+            zapper: LndZapper {
+                dry_run: true,
+                ..stopped_zapper()
+            },
+            ..settlement
+        };
+
+        settle_round(&db, &settlement, commitment_event_id)
+            .await
+            .unwrap();
+
+        let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::DryRunWinner);
+        assert_eq!(zap.payout_payment_hash, None);
+        assert_eq!(zap.paid_out_at, None);
+        assert!(winners_rx.try_recv().is_err());
+
+        // Once released, the payout is zapped for real with the next retry.
+        assert!(release_queued_payout(&db, &payment_hash).await.unwrap());
+
+        let failed = get_failed_zaps(&db, DEFAULT_GAME, 8).await.unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].invoice.payment_hash().to_string(), payment_hash);
+    }
+
+    #[tokio::test]
+    async fn held_zap_receipts_are_released_once_their_bet_is_rolled() {
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Queued).await;
+        let preimage = [1; 32];
+
+        hold_zap_receipt(&db, &payment_hash, &preimage, OffsetDateTime::now_utc())
+            .await
+            .unwrap();
+        assert!(get_rolled_zap_receipts(&db, DEFAULT_GAME)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let (settlement, _winners_rx) = test_settlement(&multipliers);
+        settle_round(&db, &settlement, commitment_event_id)
+            .await
+            .unwrap();
+
+        let audit = &get_roll_audits(&db, commitment_event_id).await.unwrap()[0];
+        assert_eq!(
+            get_rolled_zap_receipts(&db, DEFAULT_GAME).await.unwrap(),
+            [HeldZapReceipt {
+                payment_hash: payment_hash.clone(),
+                preimage: preimage.to_vec(),
+                roll: audit.roll,
+                win: true,
+            }]
+        );
+        assert!(get_rolled_zap_receipts(&db, "halloween")
+            .await
+            .unwrap()
+            .is_empty());
+
+        delete_held_zap_receipt(&db, &payment_hash).await.unwrap();
+        assert!(get_rolled_zap_receipts(&db, DEFAULT_GAME)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_payouts_wait_for_their_release() {
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Queued).await;
+
+        let (settlement, mut winners_rx) = test_settlement(&multipliers);
+
+        settle_round(&db, &settlement, commitment_event_id)
+            .await
+            .unwrap();
+
+        let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::QueuedWinner);
+        assert!(winners_rx.try_recv().is_err());
+        assert!(get_failed_zaps(&db, DEFAULT_GAME, 8)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Once released, the payout is zapped with the next retry.
+        assert!(release_queued_payout(&db, &payment_hash).await.unwrap());
+        assert!(!release_queued_payout(&db, &payment_hash).await.unwrap());
+
+        let failed = get_failed_zaps(&db, DEFAULT_GAME, 8).await.unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].invoice.payment_hash().to_string(), payment_hash);
+    }
+
+    #[tokio::test]
+    async fn bets_paid_after_their_round_was_settled_are_settled_too() {
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Queued).await;
+
+        let (settlement, _winners_rx) = test_settlement(&multipliers);
+        settle_rounds_with_paid_bets(&db, DEFAULT_GAME, &settlement).await;
+
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::QueuedWinner);
+
+        // E.g. marked as paid after handling its payment timed out, without settling the round.
+        let zap = test_zap("note1multiplier", commitment_event_id, BetState::ZapPaid);
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+
+        settle_rounds_with_paid_bets(&db, DEFAULT_GAME, &settlement).await;
+
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_ne!(zap.bet_state, BetState::ZapPaid);
+        assert!(get_rounds_with_paid_zaps(&db, DEFAULT_GAME)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn settled_rolls_are_audited() {
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Queued).await;
+
+        let (settlement, _winners_rx) = test_settlement(&multipliers);
+
+        settle_round(&db, &settlement, commitment_event_id)
+            .await
+            .unwrap();
+
+        let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+        let audits = get_roll_audits(&db, commitment_event_id).await.unwrap();
+        assert_eq!(audits.len(), 1);
+
+        let audit = &audits[0];
+        assert_eq!(audit.payment_hash, payment_hash);
+        assert_eq!(audit.roller_npub, zap.roller.to_bech32().unwrap());
+        assert_eq!(audit.index, zap.index);
+        assert_eq!(audit.roll, 22148);
+        assert_eq!(
+            audit.threshold,
+            multipliers.0[0].get_threshold(RollScheme::V1)
+        );
+        assert_eq!(audit.amount_msat, 1_000_000);
+        assert!(audit.win);
+    }
+
+    #[tokio::test]
+    async fn bets_settled_meanwhile_are_not_rolled_again() {
+        // Either being rolled or rolled already by a settlement which overtook this one.
+        for bet_state in [BetState::Rolling, BetState::PaidWinner] {
+            let db = test_db().await;
+            let (multipliers, commitment_event_id, payment_hash) =
+                winning_bet(&db, PayoutTiming::Instant).await;
+
+            // The bet as fetched by the overtaken settlement.
+            let stale = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+            let settled = Zap {
+                bet_state: bet_state.clone(),
+                ..stale.clone()
+            };
+            upsert_zap(&db, payment_hash.clone(), settled, &multipliers)
+                .await
+                .unwrap();
+
+            let (settlement, mut winners_rx) = test_settlement(&multipliers);
+            roll_the_die(
+                &db,
+                &settlement,
+                &stale,
+                RollScheme::V1,
+                WINNING_NONCE,
+                stale.index,
+            )
+            .await
+            .unwrap();
+
+            let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+            assert_eq!(zap.bet_state, bet_state);
+            assert!(get_roll_audits(&db, commitment_event_id)
+                .await
+                .unwrap()
+                .is_empty());
+            assert!(winners_rx.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn bets_left_rolling_are_settled_from_their_roll() {
+        for (payout_timing, settled) in [
+            (PayoutTiming::Queued, BetState::QueuedWinner),
+            // Might have been paid already.
+            (PayoutTiming::Instant, BetState::Rolling),
+        ] {
+            let db = test_db().await;
+            let (multipliers, commitment_event_id, payment_hash) =
+                winning_bet(&db, payout_timing).await;
+            let (settlement, _winners_rx) = test_settlement(&multipliers);
+
+            settle_round(&db, &settlement, commitment_event_id)
+                .await
+                .unwrap();
+
+            // E.g. we crashed after the roll.
+            let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+            let rolling = Zap {
+                bet_state: BetState::Rolling,
+                ..zap
+            };
+            upsert_zap(&db, payment_hash.clone(), rolling, &multipliers)
+                .await
+                .unwrap();
+
+            settle_rounds_with_paid_bets(&db, DEFAULT_GAME, &settlement).await;
+
+            let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+            assert_eq!(zap.bet_state, settled);
+            assert_eq!(
+                get_roll_audits(&db, commitment_event_id)
+                    .await
+                    .unwrap()
+                    .len(),
+                1
+            );
+
+            if settled == BetState::Rolling {
+                // Once the operator has checked that the winner was paid.
+                let now = OffsetDateTime::now_utc();
+                assert_eq!(
+                    resolve_rolling_winner(&db, &payment_hash, true, now)
+                        .await
+                        .unwrap(),
+                    Some(BetState::PaidWinner)
+                );
+                let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+                assert_eq!(zap.bet_state, BetState::PaidWinner);
+                assert!(zap.paid_out_at.is_some());
+            }
+            assert_eq!(
+                resolve_rolling_winner(&db, &payment_hash, false, OffsetDateTime::now_utc())
+                    .await
+                    .unwrap(),
+                None
+            );
+        }
+
+        // Bets claimed before rolls were recorded with the claim are rolled again.
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Queued).await;
+        let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+        let rolling = Zap {
+            bet_state: BetState::Rolling,
+            ..zap
+        };
+        upsert_zap(&db, payment_hash.clone(), rolling, &multipliers)
+            .await
+            .unwrap();
+
+        let (settlement, _winners_rx) = test_settlement(&multipliers);
+        settle_round(&db, &settlement, commitment_event_id)
+            .await
+            .unwrap();
+
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::QueuedWinner);
+        assert_eq!(
+            get_roll_audits(&db, commitment_event_id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn overlapping_settlements_roll_a_bet_once() {
+        let db = test_db().await;
+        let (multipliers, commitment_event_id, payment_hash) =
+            winning_bet(&db, PayoutTiming::Queued).await;
+
+        let (settlement, _winners_rx) = test_settlement(&multipliers);
+        let (db, settlement) = (&db, &settlement);
+        let settle = move || settle_round(db, settlement, commitment_event_id);
+
+        // Both settlements fetch the bet while it is still waiting for its roll.
+        let (first, second) = tokio::join!(settle(), settle());
+        first.unwrap();
+        second.unwrap();
+
+        let zap = get_zap(db, payment_hash.clone()).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::QueuedWinner);
+        assert_eq!(
+            get_roll_audits(db, commitment_event_id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn winning_dust_bets_pay_out_at_least_a_sat() {
+        let multiplier = Multiplier::built_in("X1_05").unwrap().get_multiplier();
+        let payout =
+            |amount_msat| calculate_price_money(amount_msat, multiplier, PayoutRounding::Floor);
+
+        assert_eq!(payout(1_000), 1);
+        // Less than a sat is not worth a sat, though we do not take such bets anyway.
+        assert_eq!(payout(500), 0);
+        assert_eq!(payout(0), 0);
+
+        let multiplier = Multiplier::built_in("X2").unwrap().get_multiplier();
+        assert_eq!(
+            calculate_price_money(20_500, multiplier, PayoutRounding::Floor),
+            41
+        );
+    }
+
+    #[test]
+    fn payouts_are_rounded_with_the_rounding_mode() {
+        let multiplier = Multiplier::built_in("X1_05").unwrap().get_multiplier();
+        let payouts = |amount_msat| {
+            [
+                PayoutRounding::Floor,
+                PayoutRounding::Round,
+                PayoutRounding::Ceil,
+            ]
+            .map(|rounding| calculate_price_money(amount_msat, multiplier, rounding))
+        };
+
+        // 19.95 sats.
+        assert_eq!(payouts(19_000), [19, 20, 20]);
+        // 10.5 sats, rounded half up.
+        assert_eq!(payouts(10_000), [10, 11, 11]);
+        // 10.45 sats.
+        assert_eq!(payouts(9_952), [10, 10, 11]);
+        // Whole sats are never rounded, however imprecise the multiplier is as a float.
+        assert_eq!(payouts(20_000), [21, 21, 21]);
+        assert_eq!(payouts(1_000_000_000), [1_050_000, 1_050_000, 1_050_000]);
+        // A sat still wins a sat, whatever it is rounded to.
+        assert_eq!(calculate_price_money(1_000, 0.5, PayoutRounding::Floor), 1);
+        // Less than a sat is only rounded.
+        assert_eq!(payouts(1), [0, 0, 1]);
     }
 
     #[test]
     pub fn test_multipliers_1_05() {
         let amount_msat = 1_000_000;
 
-        let amount_sat = calculate_price_money(amount_msat, Multiplier::X1_05.get_multiplier());
+        let amount_sat = calculate_price_money(
+            amount_msat,
+            Multiplier::built_in("X1_05").unwrap().get_multiplier(),
+            PayoutRounding::Floor,
+        );
 
         assert_eq!((1000.0 * 1.05) as u64, amount_sat)
     }
@@ -297,7 +1565,11 @@ mod tests {
     pub fn test_multipliers_1_1() {
         let amount_msat = 1_000_000;
 
-        let amount_sat = calculate_price_money(amount_msat, Multiplier::X1_1.get_multiplier());
+        let amount_sat = calculate_price_money(
+            amount_msat,
+            Multiplier::built_in("X1_1").unwrap().get_multiplier(),
+            PayoutRounding::Floor,
+        );
 
         assert_eq!((1000.0 * 1.1) as u64, amount_sat)
     }
@@ -306,7 +1578,11 @@ mod tests {
     pub fn test_multipliers_1_5() {
         let amount_msat = 1_000_000;
 
-        let amount_sat = calculate_price_money(amount_msat, Multiplier::X1_5.get_multiplier());
+        let amount_sat = calculate_price_money(
+            amount_msat,
+            Multiplier::built_in("X1_5").unwrap().get_multiplier(),
+            PayoutRounding::Floor,
+        );
 
         assert_eq!((1000.0 * 1.5) as u64, amount_sat)
     }
@@ -315,7 +1591,11 @@ mod tests {
     pub fn test_multipliers_2() {
         let amount_msat = 1_000_000;
 
-        let amount_sat = calculate_price_money(amount_msat, Multiplier::X2.get_multiplier());
+        let amount_sat = calculate_price_money(
+            amount_msat,
+            Multiplier::built_in("X2").unwrap().get_multiplier(),
+            PayoutRounding::Floor,
+        );
 
         assert_eq!((1000.0 * 2.0) as u64, amount_sat)
     }
@@ -0,0 +1,175 @@
+//! Requests to the URLs in rollers' profiles, e.g. their LNURL pay endpoint.
+//!
+//! Anyone can put any URL into their profile, and we must not let them make us request our own
+//! services with it. Only https URLs are requested, and only if their host resolves to public
+//! addresses.
+
+use anyhow::bail;
+use anyhow::Context;
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+/// GET the `url` from a roller's profile, failing after the `timeout`.
+///
+/// This blocks, so it has to be called with `tokio::task::spawn_blocking`.
+pub fn get(url: &str, timeout: Duration) -> anyhow::Result<ureq::Response> {
+    let host = https_host(url)?;
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+        if !is_public(ip) {
+            bail!("Refusing to request non-public address {ip}");
+        }
+    }
+
+    // Redirects could take us to plain http.
+    let agent = ureq::AgentBuilder::new()
+        .resolver(resolve_public)
+        .redirects(0)
+        .timeout(timeout)
+        .build();
+
+    agent
+        .get(url)
+        .call()
+        .with_context(|| format!("Failed to request {url}"))
+}
+
+/// The host of an https `url`, including the brackets of an IPv6 address.
+fn https_host(url: &str) -> anyhow::Result<&str> {
+    let Some(rest) = url.strip_prefix("https://") else {
+        bail!("Refusing to request {url}, which is not an https URL");
+    };
+
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    if authority.contains('@') {
+        bail!("Refusing to request {url}, which has user info");
+    }
+
+    let host = match authority.rsplit_once(':') {
+        // Not the colons of an IPv6 address.
+        Some((host, port)) if !port.contains(']') => host,
+        _ => authority,
+    };
+    if host.is_empty() {
+        bail!("Refusing to request {url}, which has no host");
+    }
+
+    Ok(host)
+}
+
+/// Resolve the `netloc` the way ureq would, but only to public addresses, so that a host name
+/// pointing at our own network cannot be requested.
+fn resolve_public(netloc: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs = netloc
+        .to_socket_addrs()?
+        .filter(|addr| is_public(addr.ip()))
+        .collect::<Vec<_>>();
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{netloc} does not resolve to a public address"),
+        ));
+    }
+
+    Ok(addrs)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    // The shared address space of carrier-grade NATs, 100.64.0.0/10.
+    let shared = a == 100 && (b & 0b1100_0000) == 64;
+
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || shared
+        || a == 0)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    // Unique local addresses, fc00::/7.
+    let unique_local = (first & 0xfe00) == 0xfc00;
+    // Link-local addresses, fe80::/10.
+    let link_local = (first & 0xffc0) == 0xfe80;
+
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_hosts_of_https_urls_are_requested() {
+        assert_eq!(
+            https_host("https://getalby.com/.well-known/lnurlp/alice").unwrap(),
+            "getalby.com"
+        );
+        assert_eq!(
+            https_host("https://example.com:8443/x").unwrap(),
+            "example.com"
+        );
+        assert_eq!(
+            https_host("https://[2001:db8::1]:443/").unwrap(),
+            "[2001:db8::1]"
+        );
+
+        assert!(https_host("http://example.com/.well-known/lnurlp/alice").is_err());
+        assert!(https_host("file:///etc/passwd").is_err());
+        assert!(https_host("https://user@example.com/").is_err());
+        assert!(https_host("https:///x").is_err());
+    }
+
+    #[test]
+    fn only_public_addresses_are_requested() {
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} is not public");
+        }
+
+        for ip in ["1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} is public");
+        }
+    }
+
+    #[test]
+    fn urls_pointing_at_our_network_are_not_requested() {
+        let timeout = Duration::from_secs(1);
+
+        assert!(get("https://127.0.0.1/.well-known/lnurlp/alice", timeout).is_err());
+        assert!(get("https://[::1]/.well-known/lnurlp/alice", timeout).is_err());
+        assert!(resolve_public("localhost:443").is_err());
+    }
+}
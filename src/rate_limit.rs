@@ -0,0 +1,344 @@
+use crate::route_error::RouteError;
+use crate::State;
+use axum::extract::ConnectInfo;
+use axum::extract::Query;
+use axum::http::HeaderMap;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::Extension;
+use nostr::Event;
+use nostr::JsonUtil;
+use nostr::PublicKey;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How many DMs with the terms of their bets a roller gets per minute, however many invoices they
+/// may request.
+const BET_TERMS_DMS_PER_MINUTE: u32 = 3;
+
+/// Limits how many bet invoices each roller and each client IP can request, so that nobody can
+/// have LND create invoices without end.
+#[derive(Clone, Debug)]
+pub struct InvoiceRateLimiter {
+    rollers: TokenBuckets<PublicKey>,
+    ips: TokenBuckets<IpAddr>,
+    /// The DMs sent along with the invoices, per recipient.
+    dms: TokenBuckets<PublicKey>,
+    /// Whether to take the client IP from the `X-Forwarded-For` header set by a reverse proxy.
+    trust_forwarded_for: bool,
+}
+
+impl InvoiceRateLimiter {
+    pub fn new(
+        invoices_per_minute_per_roller: u32,
+        invoices_per_minute_per_ip: u32,
+        trust_forwarded_for: bool,
+    ) -> Self {
+        Self {
+            rollers: TokenBuckets::per_minute(invoices_per_minute_per_roller),
+            ips: TokenBuckets::per_minute(invoices_per_minute_per_ip),
+            dms: TokenBuckets::per_minute(BET_TERMS_DMS_PER_MINUTE),
+            trust_forwarded_for,
+        }
+    }
+
+    /// Whether an invoice may be created for the `roller`, requesting it from the `ip`.
+    fn allow(&self, roller: Option<PublicKey>, ip: Option<IpAddr>, now: Instant) -> bool {
+        // The IP is charged first, so that switching pubkeys does not get around its limit, nor
+        // make us track a bucket for every pubkey.
+        let ip_allowed = ip.map_or(true, |ip| self.ips.take(ip_key(ip), now));
+        if !ip_allowed {
+            return false;
+        }
+
+        roller.map_or(true, |roller| self.rollers.take(roller, now))
+    }
+
+    /// Whether the terms of a bet may be DMed to the `roller`, so that nobody can use our keys to
+    /// flood someone with DMs by requesting invoices.
+    pub fn allow_dm(&self, roller: PublicKey, now: Instant) -> bool {
+        self.dms.take(roller, now)
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+        if self.trust_forwarded_for {
+            // The proxy appends the address it was connected from to whatever the client sent.
+            let forwarded_for = headers
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok());
+            if forwarded_for.is_some() {
+                return forwarded_for;
+            }
+        }
+
+        peer.map(|peer| peer.ip())
+    }
+}
+
+/// Rejects requests for bet invoices from rollers and client IPs which requested too many of them
+/// recently, with an LNURL error.
+pub async fn rate_limit_invoices<B>(
+    Extension(state): Extension<State>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limiter = &state.invoice_rate_limiter;
+
+    // Only zap requests signed by the roller count against their pubkey, so that nobody can use up
+    // the invoices of someone else.
+    let roller = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+        .ok()
+        .and_then(|Query(params)| Event::from_json(params.get("nostr")?).ok())
+        .filter(|zap_request| zap_request.verify().is_ok())
+        .map(|zap_request| zap_request.author());
+
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| *peer);
+    let ip = limiter.client_ip(request.headers(), peer);
+
+    if !limiter.allow(roller, ip, Instant::now()) {
+        tracing::warn!(?roller, ?ip, "Rate limiting bet invoice requests");
+
+        return RouteError::RateLimited.to_response().into_response();
+    }
+
+    next.run(request).await
+}
+
+/// The key of the bucket of a client `ip`.
+///
+/// IPv6 clients usually get a whole /64 prefix to pick addresses from, so they are limited by
+/// prefix instead of by address.
+fn ip_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+/// One token bucket per key, holding up to `capacity` tokens and refilling by `capacity` tokens
+/// every `refill_period`.
+///
+/// A bucket not taken from for a `refill_period` is full again, so it is forgotten, so that rollers
+/// and clients who stopped requesting invoices do not take up memory.
+#[derive(Clone, Debug)]
+struct TokenBuckets<K> {
+    capacity: f64,
+    refill_period: Duration,
+    buckets: Arc<Mutex<Buckets<K>>>,
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    buckets: HashMap<K, Bucket>,
+    /// When each bucket was taken from, oldest first, to forget the idle ones without going
+    /// through all of them.
+    takes: VecDeque<(Instant, K)>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<K: Eq + Hash + Clone> TokenBuckets<K> {
+    fn per_minute(capacity: u32) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_period: Duration::from_secs(60),
+            buckets: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                takes: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Take a token from the bucket of the `key`, if it has one left.
+    fn take(&self, key: K, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().expect("not poisoned");
+        buckets.forget_idle(now, self.refill_period);
+
+        let bucket = buckets.buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        buckets.takes.push_back((now, key));
+
+        allowed
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        let refill = self.capacity * elapsed.as_secs_f64() / self.refill_period.as_secs_f64();
+
+        (bucket.tokens + refill).min(self.capacity)
+    }
+}
+
+impl<K: Eq + Hash> Buckets<K> {
+    /// Forget the buckets not taken from for the `refill_period` until `now`.
+    fn forget_idle(&mut self, now: Instant, refill_period: Duration) {
+        while let Some((taken, _)) = self.takes.front() {
+            if now.saturating_duration_since(*taken) < refill_period {
+                break;
+            }
+
+            let (taken, key) = self.takes.pop_front().expect("front");
+            // Buckets taken from since are not idle, and have a later take of their own.
+            if self
+                .buckets
+                .get(&key)
+                .is_some_and(|bucket| bucket.updated == taken)
+            {
+                self.buckets.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let buckets = TokenBuckets::per_minute(2);
+        let now = Instant::now();
+
+        assert!(buckets.take("roller", now));
+        assert!(buckets.take("roller", now));
+        assert!(!buckets.take("roller", now));
+        assert!(buckets.take("other roller", now));
+
+        assert!(!buckets.take("roller", now + Duration::from_secs(29)));
+        assert!(buckets.take("roller", now + Duration::from_secs(31)));
+        assert!(!buckets.take("roller", now + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn idle_buckets_are_forgotten() {
+        let buckets = TokenBuckets::per_minute(2);
+        let now = Instant::now();
+
+        assert!(buckets.take("roller", now));
+        assert!(buckets.take("other roller", now + Duration::from_secs(30)));
+        assert!(buckets.take("roller", now + Duration::from_secs(50)));
+
+        let tracked = |buckets: &TokenBuckets<&str>| {
+            let buckets = buckets.buckets.lock().unwrap();
+            (buckets.buckets.len(), buckets.takes.len())
+        };
+        assert_eq!(tracked(&buckets), (2, 3));
+
+        // The first take of the roller is stale, since they took another token since.
+        assert!(buckets.take("third roller", now + Duration::from_secs(60)));
+        assert_eq!(tracked(&buckets), (3, 3));
+
+        assert!(buckets.take("third roller", now + Duration::from_secs(110)));
+        assert_eq!(tracked(&buckets), (1, 2));
+        assert!(buckets.take("third roller", now + Duration::from_secs(300)));
+        assert_eq!(tracked(&buckets), (1, 1));
+    }
+
+    #[test]
+    fn ipv6_clients_are_limited_by_their_prefix() {
+        let limiter = InvoiceRateLimiter::new(100, 1, false);
+        let now = Instant::now();
+
+        assert!(limiter.allow(None, "2001:db8:1:2::1".parse().ok(), now));
+        assert!(!limiter.allow(None, "2001:db8:1:2:ffff::7".parse().ok(), now));
+        assert!(limiter.allow(None, "2001:db8:1:3::1".parse().ok(), now));
+
+        assert!(limiter.allow(None, "203.0.113.7".parse().ok(), now));
+        assert!(!limiter.allow(None, "::ffff:203.0.113.7".parse().ok(), now));
+    }
+
+    #[test]
+    fn rejected_ips_do_not_charge_rollers() {
+        let limiter = InvoiceRateLimiter::new(1, 1, false);
+        let now = Instant::now();
+        let ip = "203.0.113.7".parse().ok();
+        let alice = Keys::generate().public_key();
+
+        assert!(limiter.allow(None, ip, now));
+        assert!(!limiter.allow(Some(alice), ip, now));
+        assert!(limiter.rollers.buckets.lock().unwrap().buckets.is_empty());
+        assert!(limiter.allow(Some(alice), "198.51.100.1".parse().ok(), now));
+    }
+
+    #[test]
+    fn rollers_and_ips_are_limited_separately() {
+        let limiter = InvoiceRateLimiter::new(1, 2, false);
+        let now = Instant::now();
+        let ip = "203.0.113.7".parse().ok();
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+
+        assert!(limiter.allow(Some(alice), ip, now));
+        assert!(!limiter.allow(Some(alice), "198.51.100.1".parse().ok(), now));
+        assert!(limiter.allow(Some(bob), ip, now));
+
+        // New pubkeys do not get around the limit of the IP.
+        assert!(!limiter.allow(Some(Keys::generate().public_key()), ip, now));
+        assert!(!limiter.allow(None, ip, now));
+    }
+
+    #[test]
+    fn dms_are_limited_per_recipient() {
+        let limiter = InvoiceRateLimiter::new(100, 100, false);
+        let now = Instant::now();
+        let alice = Keys::generate().public_key();
+
+        for _ in 0..BET_TERMS_DMS_PER_MINUTE {
+            assert!(limiter.allow_dm(alice, now));
+        }
+        assert!(!limiter.allow_dm(alice, now));
+        assert!(limiter.allow_dm(Keys::generate().public_key(), now));
+        assert!(limiter.allow_dm(alice, now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn client_ip_is_only_taken_from_a_trusted_proxy() {
+        let peer = "127.0.0.1:4000".parse().ok();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.1, 203.0.113.7".parse().unwrap(),
+        );
+
+        assert_eq!(
+            InvoiceRateLimiter::new(1, 1, false).client_ip(&headers, peer),
+            "127.0.0.1".parse().ok()
+        );
+        assert_eq!(
+            InvoiceRateLimiter::new(1, 1, true).client_ip(&headers, peer),
+            "203.0.113.7".parse().ok()
+        );
+    }
+}
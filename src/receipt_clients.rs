@@ -0,0 +1,317 @@
+use nostr::PublicKey;
+use nostr_sdk::Client;
+use nostr_sdk::Options;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::broadcast;
+
+/// How many ephemeral clients we keep connected at most.
+const MAX_CLIENTS: usize = 64;
+
+/// An ephemeral client which did not publish a zap receipt for this long is disconnected.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How often we look for idle clients, even if no zap receipts are published.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The clients we publish zap receipts with, keyed by the game account they publish for and the
+/// relays they are connected to.
+///
+/// Rollers usually request the same relays in all their zap requests, so that their payouts can
+/// reuse a connected client instead of connecting to every relay again. The clients we send NIP-17
+/// DMs with are kept the same way, see [`crate::dm::DmSender`].
+///
+/// Clients still held by someone, e.g. because they are sending a zap receipt, are not
+/// disconnected to make room or because they look idle.
+#[derive(Clone)]
+pub struct ReceiptClients {
+    send_timeout: Duration,
+    clients: Arc<Mutex<LruCache<(PublicKey, Vec<String>), Client>>>,
+}
+
+impl ReceiptClients {
+    /// Clients give each relay `send_timeout` to accept a zap receipt.
+    pub fn new(send_timeout: Duration) -> Self {
+        Self {
+            send_timeout,
+            clients: Arc::new(Mutex::new(LruCache::new(MAX_CLIENTS, IDLE_TIMEOUT))),
+        }
+    }
+
+    /// A client signing with the signer of `og_client` and connected to the `relays`.
+    pub async fn get_or_connect(
+        &self,
+        og_client: &Client,
+        author: PublicKey,
+        relays: Vec<String>,
+    ) -> anyhow::Result<Arc<Client>> {
+        let mut relays = relays
+            .iter()
+            .map(|relay| relay.trim_end_matches('/').to_string())
+            .collect::<Vec<_>>();
+        relays.sort();
+        relays.dedup();
+        let key = (author, relays);
+
+        let (cached, evicted) = self
+            .clients
+            .lock()
+            .expect("not poisoned")
+            .get(&key, Instant::now());
+        disconnect(evicted).await;
+
+        if let Some(client) = cached {
+            return Ok(client);
+        }
+
+        let client = Client::with_opts(
+            og_client.signer().await?,
+            Options::default()
+                .wait_for_send(true)
+                .send_timeout(Some(self.send_timeout)),
+        );
+        client.add_relays(key.1.clone()).await?;
+        client.connect().await;
+        client.set_zapper(og_client.zapper().await?).await;
+
+        let (client, evicted) = self.clients.lock().expect("not poisoned").insert(
+            key,
+            Arc::new(client),
+            Instant::now(),
+        );
+        disconnect(evicted).await;
+
+        Ok(client)
+    }
+
+    async fn disconnect_idle(&self) {
+        let evicted = self
+            .clients
+            .lock()
+            .expect("not poisoned")
+            .evict_idle(Instant::now());
+        disconnect(evicted).await;
+    }
+
+    pub async fn disconnect_all(&self) {
+        let evicted = self.clients.lock().expect("not poisoned").clear();
+        disconnect(evicted).await;
+    }
+}
+
+/// Disconnect the ephemeral clients which have been idle for too long.
+///
+/// On Ctrl+C, the remaining ones are left to be disconnected once no more zap receipts are being
+/// published with them.
+pub async fn disconnect_idle_receipt_clients(
+    clients: ReceiptClients,
+    mut ctrl_c: broadcast::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(IDLE_CHECK_INTERVAL) => clients.disconnect_idle().await,
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down zap receipt client task...");
+                return;
+            },
+        }
+    }
+}
+
+async fn disconnect(clients: Vec<Arc<Client>>) {
+    for client in clients {
+        if let Err(e) = client.disconnect().await {
+            tracing::warn!("Failed to disconnect zap receipt client: {e:#}");
+        }
+    }
+}
+
+/// A map of at most `capacity` entries, which forgets entries not used for `ttl` and makes room by
+/// forgetting the least recently used one.
+///
+/// Entries whose value is still held outside of the cache are in use, and are never forgotten, so
+/// the cache grows past its `capacity` while all entries are in use.
+///
+/// Forgotten entries are handed back, so that the caller can clean them up.
+struct LruCache<K, V> {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<K, (Instant, Arc<V>)>,
+}
+
+impl<K: Clone + Eq + Hash, V> LruCache<K, V> {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// The entry for the `key`, if it is still fresh, and the entries which expired.
+    fn get(&mut self, key: &K, now: Instant) -> (Option<Arc<V>>, Vec<Arc<V>>) {
+        let evicted = self.evict_idle(now);
+
+        let value = self.entries.get_mut(key).map(|(last_used, value)| {
+            *last_used = now;
+            value.clone()
+        });
+
+        (value, evicted)
+    }
+
+    /// Insert the `value` unless there already is an entry for the `key`, e.g. because it was
+    /// inserted concurrently, in which case the existing entry wins and the `value` is handed back
+    /// as evicted.
+    ///
+    /// Returns the entry for the `key` and the evicted entries.
+    fn insert(&mut self, key: K, value: Arc<V>, now: Instant) -> (Arc<V>, Vec<Arc<V>>) {
+        let mut evicted = self.evict_idle(now);
+
+        if let Some((last_used, existing)) = self.entries.get_mut(&key) {
+            *last_used = now;
+            evicted.push(value);
+            return (existing.clone(), evicted);
+        }
+
+        while self.entries.len() >= self.capacity {
+            let Some(lru) = self
+                .entries
+                .iter()
+                .filter(|(_, (_, value))| !in_use(value))
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+
+            if let Some((_, value)) = self.entries.remove(&lru) {
+                evicted.push(value);
+            }
+        }
+
+        self.entries.insert(key, (now, value.clone()));
+
+        (value, evicted)
+    }
+
+    fn evict_idle(&mut self, now: Instant) -> Vec<Arc<V>> {
+        let idle = self
+            .entries
+            .iter()
+            .filter(|(_, (last_used, value))| {
+                now.duration_since(*last_used) >= self.ttl && !in_use(value)
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        idle.into_iter()
+            .filter_map(|key| self.entries.remove(&key))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    fn clear(&mut self) -> Vec<Arc<V>> {
+        self.entries.drain().map(|(_, (_, value))| value).collect()
+    }
+}
+
+/// Whether the cached `value` is also held outside of the cache.
+fn in_use<V>(value: &Arc<V>) -> bool {
+    Arc::strong_count(value) > 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn entries_are_reused_until_they_are_idle_for_too_long() {
+        let mut cache = LruCache::new(2, TTL);
+        let now = Instant::now();
+
+        assert_eq!(cache.get(&"damus", now), (None, vec![]));
+        assert_eq!(
+            cache.insert("damus", Arc::new(1), now),
+            (Arc::new(1), vec![])
+        );
+
+        // Using an entry keeps it fresh.
+        let later = now + TTL / 2;
+        assert_eq!(cache.get(&"damus", later), (Some(Arc::new(1)), vec![]));
+        assert_eq!(
+            cache.get(&"damus", later + TTL / 2),
+            (Some(Arc::new(1)), vec![])
+        );
+
+        assert_eq!(
+            cache.get(&"damus", later + TTL / 2 + TTL),
+            (None, vec![Arc::new(1)])
+        );
+    }
+
+    #[test]
+    fn the_least_recently_used_entry_makes_room() {
+        let mut cache = LruCache::new(2, TTL);
+        let now = Instant::now();
+
+        cache.insert("damus", Arc::new(1), now);
+        cache.insert("nos.lol", Arc::new(2), now + Duration::from_secs(1));
+        cache.get(&"damus", now + Duration::from_secs(2));
+
+        assert_eq!(
+            cache.insert("primal", Arc::new(3), now + Duration::from_secs(3)),
+            (Arc::new(3), vec![Arc::new(2)])
+        );
+        assert_eq!(
+            cache.get(&"damus", now + Duration::from_secs(3)).0,
+            Some(Arc::new(1))
+        );
+        assert_eq!(cache.get(&"nos.lol", now + Duration::from_secs(3)).0, None);
+    }
+
+    #[test]
+    fn entries_in_use_are_not_forgotten() {
+        let mut cache = LruCache::new(1, TTL);
+        let now = Instant::now();
+
+        let (sending, _) = cache.insert("damus", Arc::new(1), now);
+
+        // The cache grows past its capacity instead of forgetting the entry in use.
+        assert_eq!(
+            cache.insert("nos.lol", Arc::new(2), now + Duration::from_secs(1)),
+            (Arc::new(2), vec![])
+        );
+        assert_eq!(
+            cache.get(&"damus", now + TTL * 2),
+            (Some(Arc::new(1)), vec![Arc::new(2)])
+        );
+        assert_eq!(cache.entries.len(), 1);
+
+        drop(sending);
+        assert_eq!(
+            cache.get(&"damus", now + TTL * 4),
+            (None, vec![Arc::new(1)])
+        );
+    }
+
+    #[test]
+    fn concurrently_inserted_entries_are_not_replaced() {
+        let mut cache = LruCache::new(2, TTL);
+        let now = Instant::now();
+
+        cache.insert("damus", Arc::new(1), now);
+
+        assert_eq!(
+            cache.insert("damus", Arc::new(2), now),
+            (Arc::new(1), vec![Arc::new(2)])
+        );
+        assert_eq!(cache.clear(), vec![Arc::new(1)]);
+    }
+}
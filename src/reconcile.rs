@@ -0,0 +1,534 @@
+use crate::db::get_payout_records;
+use crate::db::get_unexpected_settlements;
+use crate::db::get_zap_invoice_records;
+use crate::db::BetState;
+use crate::db::PayoutRecord;
+use crate::db::ZapInvoiceRecord;
+use crate::payouts::calculate_price_money;
+use crate::payouts::PayoutRounding;
+use anyhow::Context;
+use anyhow::Result;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::fmt;
+use time::OffsetDateTime;
+use tonic_openssl_lnd::lnrpc;
+use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
+use tonic_openssl_lnd::lnrpc::payment::PaymentStatus;
+use tonic_openssl_lnd::LndLightningClient;
+
+const LIST_INVOICES_PAGE_SIZE: u64 = 1_000;
+const LIST_PAYMENTS_PAGE_SIZE: u64 = 1_000;
+
+/// What LND knows about an invoice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LndInvoiceRecord {
+    pub add_index: u64,
+    pub settled: bool,
+}
+
+/// A zap whose record in the database disagrees with LND.
+#[derive(Debug, PartialEq)]
+pub enum Discrepancy {
+    /// LND does not know the zap invoice.
+    MissingInvoice { payment_hash: String },
+    /// LND assigned a different `add_index` to the zap invoice than the one we stored.
+    AddIndexMismatch {
+        payment_hash: String,
+        ours: u64,
+        lnd: u64,
+    },
+    /// LND settled the zap invoice, but we never handled the payment.
+    UnhandledSettlement {
+        payment_hash: String,
+        bet_state: BetState,
+    },
+    /// We consider the zap invoice paid, but LND never settled it.
+    NotSettled {
+        payment_hash: String,
+        bet_state: BetState,
+    },
+    /// We were notified of the settlement of the zap invoice while its bet was already handled.
+    UnexpectedSettlement {
+        payment_hash: String,
+        bet_state: BetState,
+        received_at: OffsetDateTime,
+    },
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::MissingInvoice { payment_hash } => {
+                write!(f, "{payment_hash}: invoice unknown to LND")
+            }
+            Discrepancy::AddIndexMismatch {
+                payment_hash,
+                ours,
+                lnd,
+            } => write!(
+                f,
+                "{payment_hash}: stored add_index {ours} but LND has {lnd}"
+            ),
+            Discrepancy::UnhandledSettlement {
+                payment_hash,
+                bet_state,
+            } => write!(
+                f,
+                "{payment_hash}: invoice settled but zap still in {bet_state:?}"
+            ),
+            Discrepancy::NotSettled {
+                payment_hash,
+                bet_state,
+            } => write!(
+                f,
+                "{payment_hash}: zap in {bet_state:?} but invoice not settled"
+            ),
+            Discrepancy::UnexpectedSettlement {
+                payment_hash,
+                bet_state,
+                received_at,
+            } => write!(
+                f,
+                "{payment_hash}: invoice settlement received at {received_at} while zap was in \
+                 {bet_state:?}"
+            ),
+        }
+    }
+}
+
+/// Cross-check the zaps in our database against the invoices known to LND.
+pub async fn reconcile_invoices(
+    db: &SqlitePool,
+    lnd: &mut LndLightningClient,
+) -> Result<Vec<Discrepancy>> {
+    let zaps = get_zap_invoice_records(db).await?;
+    let invoices = list_invoices(lnd).await?;
+
+    let mut discrepancies = find_discrepancies(&zaps, &invoices);
+
+    for settlement in get_unexpected_settlements(db).await? {
+        discrepancies.push(Discrepancy::UnexpectedSettlement {
+            payment_hash: settlement.payment_hash,
+            bet_state: settlement.bet_state,
+            received_at: settlement.received_at,
+        });
+    }
+
+    Ok(discrepancies)
+}
+
+fn find_discrepancies(
+    zaps: &[ZapInvoiceRecord],
+    invoices: &HashMap<String, LndInvoiceRecord>,
+) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+
+    for zap in zaps {
+        let payment_hash = zap.payment_hash.clone();
+
+        let invoice = match invoices.get(&zap.payment_hash) {
+            Some(invoice) => invoice,
+            None => {
+                discrepancies.push(Discrepancy::MissingInvoice { payment_hash });
+                continue;
+            }
+        };
+
+        if let Some(ours) = zap.add_index {
+            if ours != invoice.add_index {
+                discrepancies.push(Discrepancy::AddIndexMismatch {
+                    payment_hash: payment_hash.clone(),
+                    ours,
+                    lnd: invoice.add_index,
+                });
+            }
+        }
+
+        let handled = !matches!(
+            zap.bet_state,
+            BetState::GameZapInvoiceRequested | BetState::Donation
+        );
+
+        match (invoice.settled, handled) {
+            (true, false) => discrepancies.push(Discrepancy::UnhandledSettlement {
+                payment_hash,
+                bet_state: zap.bet_state.clone(),
+            }),
+            (false, true) => discrepancies.push(Discrepancy::NotSettled {
+                payment_hash,
+                bet_state: zap.bet_state.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    discrepancies
+}
+
+/// Fetch every invoice known to LND, keyed by payment hash.
+async fn list_invoices(lnd: &mut LndLightningClient) -> Result<HashMap<String, LndInvoiceRecord>> {
+    let mut invoices = HashMap::new();
+    let mut index_offset = 0;
+
+    loop {
+        let resp = lnd
+            .list_invoices(lnrpc::ListInvoiceRequest {
+                index_offset,
+                num_max_invoices: LIST_INVOICES_PAGE_SIZE,
+                ..Default::default()
+            })
+            .await
+            .context("Failed to list invoices")?
+            .into_inner();
+
+        if resp.invoices.is_empty() {
+            break;
+        }
+
+        for invoice in resp.invoices {
+            invoices.insert(
+                hex::encode(&invoice.r_hash),
+                LndInvoiceRecord {
+                    add_index: invoice.add_index,
+                    settled: InvoiceState::from_i32(invoice.state) == Some(InvoiceState::Settled),
+                },
+            );
+        }
+
+        index_offset = resp.last_index_offset;
+    }
+
+    Ok(invoices)
+}
+
+/// What LND knows about a payment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LndPaymentRecord {
+    pub value_msat: u64,
+    pub succeeded: bool,
+}
+
+/// A payout we consider made which LND disagrees with.
+#[derive(Debug, PartialEq)]
+pub enum PayoutDiscrepancy {
+    /// We did not record which payment made the payout.
+    UnknownPayment { payment_hash: String },
+    /// LND does not know the payout payment.
+    MissingPayment {
+        payment_hash: String,
+        payout_payment_hash: String,
+    },
+    /// LND never completed the payout payment.
+    NotSucceeded {
+        payment_hash: String,
+        payout_payment_hash: String,
+    },
+    /// LND paid a different amount than the bet should have paid out.
+    AmountMismatch {
+        payment_hash: String,
+        payout_payment_hash: String,
+        expected_msat: u64,
+        lnd_msat: u64,
+    },
+}
+
+impl fmt::Display for PayoutDiscrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayoutDiscrepancy::UnknownPayment { payment_hash } => {
+                write!(f, "{payment_hash}: payout payment not recorded")
+            }
+            PayoutDiscrepancy::MissingPayment {
+                payment_hash,
+                payout_payment_hash,
+            } => write!(
+                f,
+                "{payment_hash}: payout payment {payout_payment_hash} unknown to LND"
+            ),
+            PayoutDiscrepancy::NotSucceeded {
+                payment_hash,
+                payout_payment_hash,
+            } => write!(
+                f,
+                "{payment_hash}: payout payment {payout_payment_hash} did not succeed"
+            ),
+            PayoutDiscrepancy::AmountMismatch {
+                payment_hash,
+                payout_payment_hash,
+                expected_msat,
+                lnd_msat,
+            } => write!(
+                f,
+                "{payment_hash}: payout payment {payout_payment_hash} paid {lnd_msat} msat \
+                 instead of {expected_msat} msat"
+            ),
+        }
+    }
+}
+
+/// Cross-check the payouts in our database against the payments made by LND.
+///
+/// Payouts are expected to be rounded with the `payout_rounding`, so payouts made with another
+/// rounding mode show up as mismatched amounts.
+pub async fn reconcile_payouts(
+    db: &SqlitePool,
+    lnd: &mut LndLightningClient,
+    payout_rounding: PayoutRounding,
+) -> Result<Vec<PayoutDiscrepancy>> {
+    let payouts = get_payout_records(db).await?;
+    let payments = list_payments(lnd).await?;
+
+    Ok(find_payout_discrepancies(
+        &payouts,
+        &payments,
+        payout_rounding,
+    ))
+}
+
+fn find_payout_discrepancies(
+    payouts: &[PayoutRecord],
+    payments: &HashMap<String, LndPaymentRecord>,
+    payout_rounding: PayoutRounding,
+) -> Vec<PayoutDiscrepancy> {
+    let mut discrepancies = Vec::new();
+
+    for payout in payouts {
+        let payment_hash = payout.payment_hash.clone();
+
+        let payout_payment_hash = match &payout.payout_payment_hash {
+            Some(payout_payment_hash) => payout_payment_hash.clone(),
+            None => {
+                discrepancies.push(PayoutDiscrepancy::UnknownPayment { payment_hash });
+                continue;
+            }
+        };
+
+        let payment = match payments.get(&payout_payment_hash) {
+            Some(payment) => payment,
+            None => {
+                discrepancies.push(PayoutDiscrepancy::MissingPayment {
+                    payment_hash,
+                    payout_payment_hash,
+                });
+                continue;
+            }
+        };
+
+        if !payment.succeeded {
+            discrepancies.push(PayoutDiscrepancy::NotSucceeded {
+                payment_hash,
+                payout_payment_hash,
+            });
+            continue;
+        }
+
+        // Bets placed before we stored these cannot be checked.
+        if let (Some(zap_amount_msat), Some(multiplier)) =
+            (payout.zap_amount_msat, &payout.multiplier)
+        {
+            let expected_msat = calculate_price_money(
+                zap_amount_msat,
+                multiplier.get_multiplier(),
+                payout_rounding,
+            ) * 1_000;
+
+            if expected_msat != payment.value_msat {
+                discrepancies.push(PayoutDiscrepancy::AmountMismatch {
+                    payment_hash,
+                    payout_payment_hash,
+                    expected_msat,
+                    lnd_msat: payment.value_msat,
+                });
+            }
+        }
+    }
+
+    discrepancies
+}
+
+/// Fetch every payment made by LND, keyed by payment hash.
+async fn list_payments(lnd: &mut LndLightningClient) -> Result<HashMap<String, LndPaymentRecord>> {
+    let mut payments = HashMap::new();
+    let mut index_offset = 0;
+
+    loop {
+        let resp = lnd
+            .list_payments(lnrpc::ListPaymentsRequest {
+                include_incomplete: true,
+                index_offset,
+                max_payments: LIST_PAYMENTS_PAGE_SIZE,
+                ..Default::default()
+            })
+            .await
+            .context("Failed to list payments")?
+            .into_inner();
+
+        if resp.payments.is_empty() {
+            break;
+        }
+
+        for payment in resp.payments {
+            let succeeded =
+                PaymentStatus::from_i32(payment.status) == Some(PaymentStatus::Succeeded);
+
+            // A payment hash can be attempted more than once. A single successful attempt counts.
+            let record = payments
+                .entry(payment.payment_hash)
+                .or_insert(LndPaymentRecord {
+                    value_msat: payment.value_msat as u64,
+                    succeeded,
+                });
+            if succeeded {
+                *record = LndPaymentRecord {
+                    value_msat: payment.value_msat as u64,
+                    succeeded,
+                };
+            }
+        }
+
+        index_offset = resp.last_index_offset;
+    }
+
+    Ok(payments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multiplier::Multiplier;
+
+    fn zap(payment_hash: &str, add_index: u64, bet_state: BetState) -> ZapInvoiceRecord {
+        ZapInvoiceRecord {
+            payment_hash: payment_hash.to_string(),
+            add_index: Some(add_index),
+            bet_state,
+        }
+    }
+
+    fn invoice(add_index: u64, settled: bool) -> LndInvoiceRecord {
+        LndInvoiceRecord { add_index, settled }
+    }
+
+    #[test]
+    fn consistent_zaps_have_no_discrepancies() {
+        let zaps = [
+            zap("a", 1, BetState::GameZapInvoiceRequested),
+            zap("b", 2, BetState::Loser),
+            zap("c", 3, BetState::PaidWinner),
+            zap("d", 4, BetState::Donation),
+            zap("e", 5, BetState::DonationPaid),
+        ];
+        let invoices = HashMap::from([
+            ("a".to_string(), invoice(1, false)),
+            ("b".to_string(), invoice(2, true)),
+            ("c".to_string(), invoice(3, true)),
+            ("d".to_string(), invoice(4, false)),
+            ("e".to_string(), invoice(5, true)),
+        ]);
+
+        assert!(find_discrepancies(&zaps, &invoices).is_empty());
+    }
+
+    #[test]
+    fn reports_every_kind_of_discrepancy() {
+        let zaps = [
+            zap("missing", 1, BetState::ZapPaid),
+            zap("mismatch", 2, BetState::Loser),
+            zap("unhandled", 3, BetState::GameZapInvoiceRequested),
+            zap("unsettled", 4, BetState::PaidWinner),
+        ];
+        let invoices = HashMap::from([
+            ("mismatch".to_string(), invoice(5, true)),
+            ("unhandled".to_string(), invoice(3, true)),
+            ("unsettled".to_string(), invoice(4, false)),
+        ]);
+
+        let discrepancies = find_discrepancies(&zaps, &invoices);
+
+        assert_eq!(
+            discrepancies,
+            vec![
+                Discrepancy::MissingInvoice {
+                    payment_hash: "missing".to_string()
+                },
+                Discrepancy::AddIndexMismatch {
+                    payment_hash: "mismatch".to_string(),
+                    ours: 2,
+                    lnd: 5
+                },
+                Discrepancy::UnhandledSettlement {
+                    payment_hash: "unhandled".to_string(),
+                    bet_state: BetState::GameZapInvoiceRequested
+                },
+                Discrepancy::NotSettled {
+                    payment_hash: "unsettled".to_string(),
+                    bet_state: BetState::PaidWinner
+                },
+            ]
+        );
+    }
+
+    fn payout(payment_hash: &str, payout_payment_hash: Option<&str>) -> PayoutRecord {
+        PayoutRecord {
+            payment_hash: payment_hash.to_string(),
+            payout_payment_hash: payout_payment_hash.map(str::to_string),
+            zap_amount_msat: Some(1_000_000),
+            multiplier: Some(Multiplier::built_in("X2").unwrap()),
+        }
+    }
+
+    fn payment(value_msat: u64, succeeded: bool) -> LndPaymentRecord {
+        LndPaymentRecord {
+            value_msat,
+            succeeded,
+        }
+    }
+
+    #[test]
+    fn consistent_payouts_have_no_discrepancies() {
+        let payouts = [payout("a", Some("payout_a"))];
+        let payments = HashMap::from([("payout_a".to_string(), payment(2_000_000, true))]);
+
+        assert!(find_payout_discrepancies(&payouts, &payments, PayoutRounding::Floor).is_empty());
+    }
+
+    #[test]
+    fn reports_every_kind_of_payout_discrepancy() {
+        let payouts = [
+            payout("unknown", None),
+            payout("missing", Some("payout_missing")),
+            payout("failed", Some("payout_failed")),
+            payout("mismatch", Some("payout_mismatch")),
+        ];
+        let payments = HashMap::from([
+            ("payout_failed".to_string(), payment(2_000_000, false)),
+            ("payout_mismatch".to_string(), payment(1_000_000, true)),
+        ]);
+
+        let discrepancies = find_payout_discrepancies(&payouts, &payments, PayoutRounding::Floor);
+
+        assert_eq!(
+            discrepancies,
+            vec![
+                PayoutDiscrepancy::UnknownPayment {
+                    payment_hash: "unknown".to_string()
+                },
+                PayoutDiscrepancy::MissingPayment {
+                    payment_hash: "missing".to_string(),
+                    payout_payment_hash: "payout_missing".to_string()
+                },
+                PayoutDiscrepancy::NotSucceeded {
+                    payment_hash: "failed".to_string(),
+                    payout_payment_hash: "payout_failed".to_string()
+                },
+                PayoutDiscrepancy::AmountMismatch {
+                    payment_hash: "mismatch".to_string(),
+                    payout_payment_hash: "payout_mismatch".to_string(),
+                    expected_msat: 2_000_000,
+                    lnd_msat: 1_000_000
+                },
+            ]
+        );
+    }
+}
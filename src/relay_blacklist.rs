@@ -0,0 +1,300 @@
+use crate::db::delete_expired_blacklisted_relays;
+use crate::db::get_blacklisted_relays;
+use crate::db::upsert_blacklisted_relay;
+use crate::db::BlacklistedRelay;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+
+/// A relay failing to accept this many of our events in a row is blacklisted, even if it does not
+/// say that it rejects them.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// What a relay says in NIP-01 `OK` messages, or in messages of its own, when it will not accept an
+/// event however often we try.
+const REJECTIONS: [&str; 3] = ["blocked", "restricted", "not admitted"];
+
+/// How often relays whose blacklisting expired, or which stopped failing, are forgotten.
+const FORGET_EXPIRED_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Relays which reject our events, e.g. because they do not accept zap receipts, so that we stop
+/// waiting on them until a cooldown expires.
+///
+/// Blacklisted relays are persisted, so that we do not have to find out again after a restart.
+#[derive(Clone, Debug)]
+pub struct RelayBlacklist {
+    db: SqlitePool,
+    cooldown: Duration,
+    /// Keyed by relay URL, without trailing slash.
+    relays: Arc<RwLock<HashMap<String, RelayRecord>>>,
+}
+
+#[derive(Debug, Default)]
+struct RelayRecord {
+    consecutive_failures: u32,
+    last_failure: Option<OffsetDateTime>,
+    blacklisted_until: Option<OffsetDateTime>,
+}
+
+impl RelayBlacklist {
+    /// Load the relays which are still blacklisted.
+    pub async fn load(db: SqlitePool, cooldown: Duration) -> anyhow::Result<Self> {
+        let relays = get_blacklisted_relays(&db, OffsetDateTime::now_utc())
+            .await?
+            .into_iter()
+            .map(|blacklisted| {
+                (
+                    blacklisted.relay,
+                    RelayRecord {
+                        consecutive_failures: 0,
+                        last_failure: None,
+                        blacklisted_until: Some(blacklisted.blacklisted_until),
+                    },
+                )
+            })
+            .collect();
+
+        Ok(Self {
+            db,
+            cooldown,
+            relays: Arc::new(RwLock::new(relays)),
+        })
+    }
+
+    pub fn is_blacklisted(&self, relay: &str) -> bool {
+        self.is_blacklisted_at(relay, OffsetDateTime::now_utc())
+    }
+
+    fn is_blacklisted_at(&self, relay: &str, now: OffsetDateTime) -> bool {
+        self.relays
+            .read()
+            .expect("not poisoned")
+            .get(normalize(relay))
+            .and_then(|record| record.blacklisted_until)
+            .is_some_and(|blacklisted_until| now < blacklisted_until)
+    }
+
+    /// The `relay` failed to accept one of our events with the `error`.
+    pub async fn failed(&self, relay: &str, error: &str) {
+        let Some(blacklisted) = self.record_failure(relay, error, OffsetDateTime::now_utc()) else {
+            return;
+        };
+
+        tracing::warn!(
+            relay,
+            until = %blacklisted.blacklisted_until,
+            "Blacklisting relay which does not accept our events: {error}"
+        );
+
+        if let Err(e) = upsert_blacklisted_relay(&self.db, &blacklisted).await {
+            tracing::error!(relay, "{e:#}");
+        }
+    }
+
+    /// Returns the relay if it is now blacklisted.
+    fn record_failure(
+        &self,
+        relay: &str,
+        error: &str,
+        now: OffsetDateTime,
+    ) -> Option<BlacklistedRelay> {
+        let mut relays = self.relays.write().expect("not poisoned");
+        let record = relays.entry(normalize(relay).to_string()).or_default();
+        record.consecutive_failures += 1;
+        record.last_failure = Some(now);
+
+        let error = error.to_lowercase();
+        let rejected = REJECTIONS.iter().any(|rejection| error.contains(rejection));
+        if !rejected && record.consecutive_failures < MAX_CONSECUTIVE_FAILURES {
+            return None;
+        }
+
+        let blacklisted_until = now + self.cooldown;
+        record.consecutive_failures = 0;
+        record.blacklisted_until = Some(blacklisted_until);
+
+        Some(BlacklistedRelay {
+            relay: normalize(relay).to_string(),
+            blacklisted_until,
+        })
+    }
+
+    pub fn accepted(&self, relay: &str) {
+        if let Some(record) = self
+            .relays
+            .write()
+            .expect("not poisoned")
+            .get_mut(normalize(relay))
+        {
+            record.consecutive_failures = 0;
+        }
+    }
+
+    /// Forget the relays whose blacklisting expired, and those which have not failed for a
+    /// cooldown, so that the relays of every zap request we published to do not pile up.
+    async fn forget_expired(&self) {
+        let now = OffsetDateTime::now_utc();
+        self.forget_expired_at(now);
+
+        match delete_expired_blacklisted_relays(&self.db, now).await {
+            Ok(0) => {}
+            Ok(deleted) => tracing::debug!(deleted, "Deleted expired relay blacklistings"),
+            Err(e) => tracing::error!("{e:#}"),
+        }
+    }
+
+    fn forget_expired_at(&self, now: OffsetDateTime) {
+        self.relays
+            .write()
+            .expect("not poisoned")
+            .retain(|_, record| {
+                let blacklisted = record
+                    .blacklisted_until
+                    .is_some_and(|blacklisted_until| now < blacklisted_until);
+                let failing = record.consecutive_failures > 0
+                    && record
+                        .last_failure
+                        .is_some_and(|last_failure| now < last_failure + self.cooldown);
+
+                blacklisted || failing
+            });
+    }
+}
+
+/// Forget expired relay blacklistings every [`FORGET_EXPIRED_INTERVAL`].
+pub async fn forget_expired_blacklistings(
+    blacklist: RelayBlacklist,
+    mut ctrl_c: broadcast::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(FORGET_EXPIRED_INTERVAL) => blacklist.forget_expired().await,
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down relay blacklist task...");
+                return;
+            },
+        }
+    }
+}
+
+fn normalize(relay: &str) -> &str {
+    relay.trim_end_matches('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    const COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+    #[tokio::test]
+    async fn relays_rejecting_our_events_are_blacklisted_until_the_cooldown_expires() {
+        let blacklist = RelayBlacklist::load(test_db().await, COOLDOWN)
+            .await
+            .unwrap();
+        let now = OffsetDateTime::now_utc();
+
+        let blacklisted = blacklist.record_failure(
+            "wss://purplepag.es/",
+            "event not published: blocked: kind 9735 not allowed",
+            now,
+        );
+        assert_eq!(
+            blacklisted,
+            Some(BlacklistedRelay {
+                relay: "wss://purplepag.es".to_string(),
+                blacklisted_until: now + COOLDOWN,
+            })
+        );
+
+        assert!(blacklist.is_blacklisted_at("wss://purplepag.es", now));
+        assert!(blacklist.is_blacklisted_at("wss://purplepag.es/", now + COOLDOWN / 2));
+        assert!(!blacklist.is_blacklisted_at("wss://purplepag.es", now + COOLDOWN));
+        assert!(!blacklist.is_blacklisted_at("wss://relay.damus.io", now));
+    }
+
+    #[tokio::test]
+    async fn relays_failing_repeatedly_are_blacklisted() {
+        let blacklist = RelayBlacklist::load(test_db().await, COOLDOWN)
+            .await
+            .unwrap();
+        let now = OffsetDateTime::now_utc();
+
+        assert!(blacklist
+            .record_failure("wss://nos.lol", "timeout", now)
+            .is_none());
+        assert!(blacklist
+            .record_failure("wss://nos.lol", "timeout", now)
+            .is_none());
+        blacklist.accepted("wss://nos.lol");
+        assert!(blacklist
+            .record_failure("wss://nos.lol", "timeout", now)
+            .is_none());
+        assert!(blacklist
+            .record_failure("wss://nos.lol", "timeout", now)
+            .is_none());
+        assert!(!blacklist.is_blacklisted_at("wss://nos.lol", now));
+
+        assert!(blacklist
+            .record_failure("wss://nos.lol", "timeout", now)
+            .is_some());
+        assert!(blacklist.is_blacklisted_at("wss://nos.lol", now));
+    }
+
+    #[tokio::test]
+    async fn expired_relays_are_forgotten() {
+        let db = test_db().await;
+        let blacklist = RelayBlacklist::load(db.clone(), COOLDOWN).await.unwrap();
+        let now = OffsetDateTime::now_utc();
+
+        blacklist.record_failure("wss://purplepag.es", "blocked", now);
+        blacklist.record_failure("wss://nos.lol", "timeout", now + COOLDOWN / 2);
+        blacklist.record_failure("wss://relay.damus.io", "timeout", now);
+        blacklist.accepted("wss://relay.damus.io");
+
+        blacklist.forget_expired_at(now + COOLDOWN);
+        let relays = blacklist.relays.read().unwrap();
+        assert_eq!(relays.keys().collect::<Vec<_>>(), ["wss://nos.lol"]);
+        drop(relays);
+
+        blacklist.forget_expired_at(now + COOLDOWN * 2);
+        assert!(blacklist.relays.read().unwrap().is_empty());
+
+        let expired = BlacklistedRelay {
+            relay: "wss://purplepag.es".to_string(),
+            blacklisted_until: now,
+        };
+        upsert_blacklisted_relay(&db, &expired).await.unwrap();
+        assert_eq!(
+            delete_expired_blacklisted_relays(&db, now).await.unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn blacklisted_relays_survive_a_restart() {
+        let db = test_db().await;
+
+        let blacklist = RelayBlacklist::load(db.clone(), COOLDOWN).await.unwrap();
+        blacklist
+            .failed("wss://purplepag.es", "restricted: not a paid member")
+            .await;
+
+        let blacklist = RelayBlacklist::load(db.clone(), COOLDOWN).await.unwrap();
+        assert!(blacklist.is_blacklisted("wss://purplepag.es"));
+
+        // Relays whose cooldown expired are not loaded.
+        let blacklist = RelayBlacklist::load(db.clone(), Duration::ZERO)
+            .await
+            .unwrap();
+        blacklist.failed("wss://nos.lol", "blocked").await;
+
+        let blacklist = RelayBlacklist::load(db, COOLDOWN).await.unwrap();
+        assert!(!blacklist.is_blacklisted("wss://nos.lol"));
+    }
+}
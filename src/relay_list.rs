@@ -0,0 +1,141 @@
+//! Announces the relays of our accounts as NIP-65 relay lists, so that clients know where to find
+//! their notes.
+
+use crate::game::Games;
+use crate::game::ROLES;
+use crate::routes::RelayHints;
+use anyhow::Context;
+use nostr::Event;
+use nostr::Filter;
+use nostr::Keys;
+use nostr::Kind;
+use nostr::Tag;
+use nostr_sdk::Client;
+use nostr_sdk::EventBuilder;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+const FETCH_RELAY_LIST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Publish a relay list for every account of the `games`, with the relays configured for its role.
+///
+/// Accounts whose latest relay list already names these relays are left alone, so that we only
+/// republish after the configuration changed.
+pub async fn publish_relay_lists(games: &Games, relay_hints: &RelayHints) {
+    for game in games.iter() {
+        for role in ROLES {
+            let relays = relay_hints.for_role(role);
+            if relays.is_empty() {
+                continue;
+            }
+
+            let identity = game.identity(role);
+            match publish_relay_list(&game.client, game.keys(role), relays).await {
+                Ok(true) => tracing::info!(identity, ?relays, "Published relay list"),
+                Ok(false) => tracing::debug!(identity, "Relay list is up to date"),
+                Err(e) => tracing::error!(identity, "Failed to publish relay list: {e:#}"),
+            }
+        }
+    }
+}
+
+/// Returns whether the relay list of the account with the `keys` had to be published.
+async fn publish_relay_list(
+    client: &Client,
+    keys: &Keys,
+    relays: &[String],
+) -> anyhow::Result<bool> {
+    let events = client
+        .get_events_of(
+            vec![Filter::new()
+                .author(keys.public_key())
+                .kind(Kind::RelayList)
+                .limit(1)],
+            Some(FETCH_RELAY_LIST_TIMEOUT),
+        )
+        .await
+        .context("Failed to fetch relay list")?;
+
+    let published = events.into_iter().max_by_key(|event| event.created_at);
+    if published.is_some_and(|event| listed_relays(&event) == normalized(relays)) {
+        return Ok(false);
+    }
+
+    let event = relay_list_event(relays)?.to_event(keys)?;
+    client.send_event(event).await?;
+
+    Ok(true)
+}
+
+/// A NIP-65 relay list of the `relays`, which we both read from and write to.
+fn relay_list_event(relays: &[String]) -> anyhow::Result<EventBuilder> {
+    let tags = relays
+        .iter()
+        .map(|relay| Tag::parse(&["r", relay.as_str()]))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(EventBuilder::new(Kind::RelayList, "", tags))
+}
+
+fn listed_relays(event: &Event) -> BTreeSet<String> {
+    let relays = event
+        .tags()
+        .iter()
+        .filter_map(|tag| match tag.as_vec() {
+            [name, relay, ..] if name == "r" => Some(relay.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    normalized(&relays)
+}
+
+/// The `relays` regardless of their order and trailing slashes.
+fn normalized(relays: &[String]) -> BTreeSet<String> {
+    relays
+        .iter()
+        .map(|relay| relay.trim_end_matches('/').to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relay_lists_only_change_with_the_configured_relays() {
+        let relays = [
+            "wss://relay.damus.io".to_string(),
+            "wss://nos.lol/".to_string(),
+        ];
+
+        let event = relay_list_event(&relays)
+            .unwrap()
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert_eq!(event.kind, Kind::RelayList);
+        assert_eq!(
+            event
+                .tags
+                .iter()
+                .map(|tag| tag.as_vec().to_vec())
+                .collect::<Vec<_>>(),
+            relays
+                .iter()
+                .map(|relay| vec!["r".to_string(), relay.clone()])
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            listed_relays(&event),
+            normalized(&[
+                "wss://nos.lol".to_string(),
+                "wss://relay.damus.io/".to_string()
+            ])
+        );
+        assert_ne!(
+            listed_relays(&event),
+            normalized(&["wss://relay.damus.io".to_string()])
+        );
+    }
+}
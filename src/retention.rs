@@ -0,0 +1,140 @@
+//! Deletes old rounds and their settled bets, so that the database does not grow forever.
+
+use crate::db::archive_round;
+use crate::db::get_archivable_rounds;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::select;
+use tokio::sync::broadcast;
+
+/// How often we look for rounds which are past the retention period.
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Regularly archive the rounds revealed longer than `retention` ago.
+pub async fn archive_old_rounds(
+    db: SqlitePool,
+    retention: Duration,
+    mut ctrl_c: broadcast::Receiver<()>,
+) {
+    loop {
+        if let Err(e) = archive_rounds_before(&db, OffsetDateTime::now_utc() - retention).await {
+            tracing::error!("Failed to archive old rounds: {e:#}");
+        }
+
+        select! {
+            _ = tokio::time::sleep(ARCHIVE_INTERVAL) => (),
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down round archiver...");
+                return;
+            },
+        }
+    }
+}
+
+async fn archive_rounds_before(db: &SqlitePool, before: OffsetDateTime) -> anyhow::Result<()> {
+    let mut archived = 0;
+    for round in get_archivable_rounds(db, before).await? {
+        if archive_round(db, round).await? {
+            archived += 1;
+        }
+    }
+
+    if archived > 0 {
+        tracing::info!(archived, "Archived rounds past the retention period");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::get_roller_totals;
+    use crate::db::get_zaps_by_event_id;
+    use crate::db::test_db;
+    use crate::db::test_round;
+    use crate::db::test_zap;
+    use crate::db::upsert_zap;
+    use crate::db::BetState;
+    use crate::game::DEFAULT_GAME;
+    use crate::game::DEFAULT_SCHEDULE;
+    use crate::multiplier::test_multiplier_note;
+    use crate::multiplier::Multipliers;
+    use crate::nonce::get_round;
+    use crate::nonce::set_active_nonce;
+    use crate::nonce::set_reveal_event_id;
+    use nostr::EventId;
+
+    #[tokio::test]
+    async fn only_settled_rounds_are_archived_and_still_count_on_the_leaderboard() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let round = |byte| EventId::from_slice(&[byte; 32]).unwrap();
+        let rounds = [
+            (round(1), vec![BetState::PaidWinner, BetState::Loser]),
+            (round(2), vec![BetState::Loser, BetState::ZapPaid]),
+            // Still running.
+            (round(3), vec![BetState::Loser]),
+        ];
+        for (event_id, bet_states) in rounds.iter() {
+            set_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE, test_round(*event_id))
+                .await
+                .unwrap();
+            set_reveal_event_id(&db, *event_id, EventId::all_zeros())
+                .await
+                .unwrap();
+
+            for bet_state in bet_states {
+                let zap = test_zap("note1multiplier", *event_id, bet_state.clone());
+                let payment_hash = zap.invoice.payment_hash().to_string();
+                upsert_zap(&db, payment_hash, zap, &multipliers)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let before = leaderboard(&db).await;
+
+        // Rounds revealed within the retention period are kept.
+        archive_rounds_before(&db, OffsetDateTime::now_utc() - time::Duration::minutes(1))
+            .await
+            .unwrap();
+        assert!(get_round(&db, round(1)).await.unwrap().is_some());
+
+        archive_rounds_before(&db, OffsetDateTime::now_utc() + time::Duration::minutes(1))
+            .await
+            .unwrap();
+
+        assert!(get_round(&db, round(1)).await.unwrap().is_none());
+        assert!(get_zaps_by_event_id(&db, round(1))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(get_zaps_by_event_id(&db, round(2)).await.unwrap().len(), 2);
+        assert_eq!(get_zaps_by_event_id(&db, round(3)).await.unwrap().len(), 1);
+
+        assert_eq!(leaderboard(&db).await, before);
+    }
+
+    /// The totals of every roller, in a stable order.
+    async fn leaderboard(db: &SqlitePool) -> Vec<(String, BetState, u64, u64)> {
+        let mut totals = get_roller_totals(db, DEFAULT_GAME, OffsetDateTime::UNIX_EPOCH)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|totals| {
+                (
+                    totals.roller.to_hex(),
+                    totals.bet_state,
+                    totals.bets,
+                    totals.amount_msat,
+                )
+            })
+            .collect::<Vec<_>>();
+        totals.sort_by(|a, b| a.0.cmp(&b.0));
+
+        totals
+    }
+}
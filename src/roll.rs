@@ -0,0 +1,182 @@
+use nostr::bitcoin::hashes::sha256;
+use nostr::ToBech32;
+use nostr_sdk::hashes::Hash;
+use nostr_sdk::PublicKey;
+use serde::Deserialize;
+use serde::Serialize;
+use strum_macros::Display;
+use strum_macros::EnumString;
+
+/// The layout of the preimage which is hashed to derive a roll.
+///
+/// Schemes are versioned so that external verifiers can always reproduce the roll of a past round:
+/// the scheme used is published in the nonce commitment note and stored alongside the round.
+/// Changing the derivation means adding a new variant, never editing an existing one.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumString,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum RollScheme {
+    /// `sha256(hex(nonce) | roller_npub | memo | index)`.
+    #[default]
+    V1,
+    /// `sha256(hex(nonce) | roller_npub | index | memo)`.
+    V2,
+    /// Like `V2`, but rolls are 32 bits instead of 16, so that thresholds of rare multipliers can
+    /// be set more precisely.
+    V3,
+}
+
+impl RollScheme {
+    /// How many bytes of the digest make up the roll.
+    pub fn roll_bytes(self) -> usize {
+        match self {
+            RollScheme::V1 | RollScheme::V2 => 2,
+            RollScheme::V3 => 4,
+        }
+    }
+
+    /// How many different numbers can be rolled, i.e. one more than the biggest roll.
+    pub fn roll_count(self) -> u64 {
+        1 << (8 * self.roll_bytes())
+    }
+}
+
+/// Everything needed to check the roll of a bet by hand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RollDerivation {
+    /// The bytes hashed to derive the roll, laid out as described by the roll scheme.
+    pub preimage: Vec<u8>,
+    /// The SHA256 digest of the `preimage`.
+    pub digest: sha256::Hash,
+    pub roll: u32,
+}
+
+/// Derive the roll for a bet, in the range 0-65535, or 0-4294967295 with [`RollScheme::V3`].
+///
+/// The roll is the decimal value of the first 2 bytes, or 4 bytes with [`RollScheme::V3`], of the
+/// SHA256 digest of the preimage described by the `scheme`. The `roller_npub` is hashed in its
+/// bech32 encoding and the `index` as a decimal string.
+pub fn generate_roll(
+    scheme: RollScheme,
+    nonce: [u8; 32],
+    index: usize,
+    roller_npub: PublicKey,
+    memo: String,
+) -> u32 {
+    derive_roll(scheme, nonce, index, roller_npub, &memo).roll
+}
+
+/// Like [`generate_roll`], but also returns the intermediate values.
+pub fn derive_roll(
+    scheme: RollScheme,
+    nonce: [u8; 32],
+    index: usize,
+    roller_npub: PublicKey,
+    memo: &str,
+) -> RollDerivation {
+    let nonce = hex::encode(nonce);
+    let nonce = nonce.as_bytes();
+
+    let roller_npub = roller_npub.to_bech32().expect("valid npub");
+    let roller_npub = roller_npub.as_bytes();
+
+    let memo = memo.as_bytes();
+
+    let index = index.to_string();
+    let index = index.as_bytes();
+
+    let preimage = match scheme {
+        RollScheme::V1 => [nonce, roller_npub, memo, index].concat(),
+        RollScheme::V2 | RollScheme::V3 => [nonce, roller_npub, index, memo].concat(),
+    };
+
+    let digest = sha256::Hash::hash(&preimage);
+    let roll = digest.to_byte_array()[..scheme.roll_bytes()]
+        .iter()
+        .fold(0, |roll, byte| (roll << 8) | u32::from(*byte));
+
+    RollDerivation {
+        preimage,
+        digest,
+        roll,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    /// You can verify the outcome by visiting this URL:
+    /// https://emn178.github.io/online-tools/sha256.html?input=0000000000000000000000000000000000000000000000000000000000000000npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32Hello%2C%20world!%20%F0%9F%94%970&input_type=utf-8&output_type=hex&hmac_enabled=0&hmac_input_type=utf-8
+    /// then take the first 4 digits of the hex and convert it to a decimal number.
+    /// https://www.rapidtables.com/convert/number/hex-to-decimal.html?x=9d6b
+    fn generate_roll_test() {
+        let nonce = [0u8; 32];
+
+        let roller_npub =
+            PublicKey::parse("npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32")
+                .unwrap();
+        let memo = "Hello, world! 🔗".to_string();
+
+        let n = generate_roll(RollScheme::V1, nonce, 0, roller_npub, memo);
+
+        println!("You rolled a {n}");
+
+        assert_eq!(n, 40299);
+    }
+
+    #[test]
+    fn generate_roll_v2_test() {
+        let nonce = [0u8; 32];
+
+        let roller_npub =
+            PublicKey::parse("npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32")
+                .unwrap();
+        let memo = "Hello, world! 🔗".to_string();
+
+        let n = generate_roll(RollScheme::V2, nonce, 0, roller_npub, memo);
+
+        assert_eq!(n, 64318);
+    }
+
+    #[test]
+    fn generate_roll_v3_test() {
+        let nonce = [0u8; 32];
+
+        let roller_npub =
+            PublicKey::parse("npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32")
+                .unwrap();
+        let memo = "Hello, world! 🔗".to_string();
+
+        let n = generate_roll(RollScheme::V3, nonce, 0, roller_npub, memo);
+
+        // The first 2 bytes are the roll of the same bet with roll scheme v2.
+        assert_eq!(n >> 16, 64318);
+        assert_eq!(n, 4_215_185_038);
+    }
+
+    #[test]
+    fn roll_scheme_string_round_trip() {
+        for scheme in [RollScheme::V1, RollScheme::V2, RollScheme::V3] {
+            let parsed = RollScheme::from_str(&scheme.to_string()).unwrap();
+
+            assert_eq!(parsed, scheme);
+        }
+
+        assert_eq!(RollScheme::V1.to_string(), "v1");
+    }
+}
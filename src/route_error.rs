@@ -0,0 +1,299 @@
+use axum::http::StatusCode;
+use axum::Json;
+use serde_json::json;
+use serde_json::Value;
+use std::fmt;
+
+/// The code of errors which are not a [`RouteError`].
+const INVALID_REQUEST: &str = "INVALID_REQUEST";
+
+/// Why we did not hand out an invoice, or did not answer a request otherwise, in a way clients can
+/// handle.
+///
+/// Besides the LNURL `reason` wallets show to rollers, error responses carry a stable `code`, so
+/// that clients do not have to match on how the reason is phrased.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteError {
+    MissingAmount,
+    InvalidZapRequest,
+    MissingZapRequest,
+    UnknownGame {
+        game: String,
+    },
+    /// The hash in the callback URL is not the one of an LNURL-pay endpoint of the game.
+    InvalidCallbackHash,
+    UnknownZapRequest,
+    /// The zap was a donation, not a bet.
+    NotABet,
+    /// The round is unknown, or its nonce has not been revealed yet.
+    RoundNotRevealed,
+    LndUnreachable,
+    MissingLightningAddress,
+    BrokenLightningAddress,
+    NotAMultiplierNote,
+    /// Rollers zapped one of our round notes, with the message telling them what to zap instead.
+    RoundNoteZapped {
+        message: String,
+    },
+    MultiplierNoteChanged {
+        multiplier: String,
+    },
+    MultiplierNotYetActive {
+        multiplier: String,
+        active_from: String,
+    },
+    MultiplierNoLongerActive {
+        multiplier: String,
+        active_until: String,
+    },
+    /// Less than we advertise as the minimum sendable amount, whatever the multiplier.
+    BelowMinSendable {
+        amount_msat: u64,
+        min_sendable_msat: u64,
+    },
+    AmountTooLow {
+        amount_msat: u64,
+        min_amount_sat: u64,
+        multiplier: String,
+    },
+    AmountTooHigh {
+        amount_msat: u64,
+        max_amount_sat: u64,
+        multiplier: String,
+    },
+    ZapMemoTooLong {
+        chars: usize,
+        max_chars: usize,
+    },
+    CommentTooLong {
+        chars: usize,
+        max_chars: usize,
+    },
+    InvalidPayerData,
+    TooMuchAtStake,
+    HouseBalanceTooLow {
+        payout_sat: u64,
+    },
+    NoActiveNonce,
+    BetsClosed {
+        bet_cutoff_secs: u64,
+        remaining_secs: i64,
+    },
+    RateLimited,
+}
+
+impl RouteError {
+    /// Never changed once released, unlike the wording of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RouteError::MissingAmount => "MISSING_AMOUNT",
+            RouteError::InvalidZapRequest => "INVALID_ZAP_REQUEST",
+            RouteError::MissingZapRequest => "MISSING_ZAP_REQUEST",
+            RouteError::UnknownGame { .. } => "UNKNOWN_GAME",
+            RouteError::InvalidCallbackHash => "INVALID_CALLBACK_HASH",
+            RouteError::UnknownZapRequest => "UNKNOWN_ZAP_REQUEST",
+            RouteError::NotABet => "NOT_A_BET",
+            RouteError::RoundNotRevealed => "ROUND_NOT_REVEALED",
+            RouteError::LndUnreachable => "LND_UNREACHABLE",
+            RouteError::MissingLightningAddress => "MISSING_LIGHTNING_ADDRESS",
+            RouteError::BrokenLightningAddress => "BROKEN_LIGHTNING_ADDRESS",
+            RouteError::NotAMultiplierNote => "NOT_A_MULTIPLIER_NOTE",
+            RouteError::RoundNoteZapped { .. } => "ROUND_NOTE_ZAPPED",
+            RouteError::MultiplierNoteChanged { .. } => "MULTIPLIER_NOTE_CHANGED",
+            RouteError::MultiplierNotYetActive { .. } => "MULTIPLIER_NOT_YET_ACTIVE",
+            RouteError::MultiplierNoLongerActive { .. } => "MULTIPLIER_NO_LONGER_ACTIVE",
+            RouteError::BelowMinSendable { .. } | RouteError::AmountTooLow { .. } => {
+                "AMOUNT_TOO_LOW"
+            }
+            RouteError::AmountTooHigh { .. } => "AMOUNT_TOO_HIGH",
+            RouteError::ZapMemoTooLong { .. } => "ZAP_MEMO_TOO_LONG",
+            RouteError::CommentTooLong { .. } => "COMMENT_TOO_LONG",
+            RouteError::InvalidPayerData => "INVALID_PAYER_DATA",
+            RouteError::TooMuchAtStake => "TOO_MUCH_AT_STAKE",
+            RouteError::HouseBalanceTooLow { .. } => "HOUSE_BALANCE_TOO_LOW",
+            RouteError::NoActiveNonce => "NO_ACTIVE_NONCE",
+            RouteError::BetsClosed { .. } => "BETS_CLOSED",
+            RouteError::RateLimited => "RATE_LIMITED",
+        }
+    }
+
+    /// The error response, with the `code` and the error as the `reason`.
+    pub fn to_response(&self) -> (StatusCode, Json<Value>) {
+        error_response(self.status(), self.code(), self.to_string())
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            RouteError::UnknownGame { .. }
+            | RouteError::UnknownZapRequest
+            | RouteError::NotABet
+            | RouteError::RoundNotRevealed => StatusCode::NOT_FOUND,
+            RouteError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::MissingAmount => write!(f, "Missing amount parameter"),
+            RouteError::InvalidZapRequest => write!(f, "Invalid zap request"),
+            RouteError::MissingZapRequest => {
+                write!(f, "Cannot play the game without a zap request")
+            }
+            RouteError::UnknownGame { game } => write!(f, "Unknown game {game}"),
+            RouteError::InvalidCallbackHash => write!(f, "Invalid callback URL"),
+            RouteError::UnknownZapRequest => write!(f, "Unknown zap request"),
+            RouteError::NotABet => write!(f, "This zap was not a bet"),
+            RouteError::RoundNotRevealed => write!(f, "Unknown round, or not revealed yet"),
+            RouteError::LndUnreachable => write!(
+                f,
+                "Not taking bets while our Lightning node is unreachable. Please try again later"
+            ),
+            RouteError::MissingLightningAddress => write!(
+                f,
+                "Your profile has no valid lightning address. Please set one for us to pay out to"
+            ),
+            RouteError::BrokenLightningAddress => write!(
+                f,
+                "Your lightning address does not work. We would not be able to pay you out"
+            ),
+            RouteError::NotAMultiplierNote => {
+                write!(f, "Zapped note which wasn't a multiplier note")
+            }
+            RouteError::RoundNoteZapped { message } => write!(f, "{message}"),
+            RouteError::MultiplierNoteChanged { multiplier } => write!(
+                f,
+                "Not accepting bets on the {multiplier} multiplier: its note has changed."
+            ),
+            RouteError::MultiplierNotYetActive {
+                multiplier,
+                active_from,
+            } => write!(
+                f,
+                "The {multiplier} multiplier is not available yet. Bets open at {active_from}."
+            ),
+            RouteError::MultiplierNoLongerActive {
+                multiplier,
+                active_until,
+            } => write!(
+                f,
+                "The {multiplier} multiplier is no longer available. Bets closed at {active_until}."
+            ),
+            RouteError::BelowMinSendable {
+                amount_msat,
+                min_sendable_msat,
+            } => write!(
+                f,
+                "Zapped amount ({amount_msat} msat) is too low. \
+                 The minimum bet is {min_sendable_msat} msat."
+            ),
+            RouteError::AmountTooLow {
+                amount_msat,
+                min_amount_sat,
+                multiplier,
+            } => write!(
+                f,
+                "Zapped amount ({amount_msat} msat) is too low for the multiplier {multiplier}. \
+                 The minimum bet is {min_amount_sat} sats."
+            ),
+            RouteError::AmountTooHigh {
+                amount_msat,
+                max_amount_sat,
+                multiplier,
+            } => write!(
+                f,
+                "Zapped amount ({amount_msat} msat) is too high for the multiplier {multiplier}. \
+                 The maximum bet is {max_amount_sat} sats."
+            ),
+            RouteError::ZapMemoTooLong { chars, max_chars } => write!(
+                f,
+                "Zap memo is too long: {chars} characters, at most {max_chars} allowed."
+            ),
+            RouteError::CommentTooLong { chars, max_chars } => write!(
+                f,
+                "Comment is too long: {chars} characters, at most {max_chars} allowed."
+            ),
+            RouteError::InvalidPayerData => write!(f, "Invalid payer data"),
+            RouteError::TooMuchAtStake => write!(
+                f,
+                "Not accepting this bet right now, too much is at stake in the running rounds. \
+                 Please try a smaller amount or wait for the next round."
+            ),
+            RouteError::HouseBalanceTooLow { payout_sat } => write!(
+                f,
+                "Not accepting this bet right now, we could not pay out a win of {payout_sat} \
+                 sats. Please try a smaller amount or a lower multiplier."
+            ),
+            RouteError::NoActiveNonce => write!(f, "Cannot accept zap without active nonce"),
+            RouteError::BetsClosed {
+                bet_cutoff_secs,
+                remaining_secs,
+            } => write!(
+                f,
+                "Bets close {bet_cutoff_secs} seconds before the end of a round, and this round \
+                 ends in {remaining_secs} seconds. Please bet again on the next round."
+            ),
+            RouteError::RateLimited => write!(
+                f,
+                "Too many bets in a short time. Please try again in a minute."
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// The response for an error of one of our routes, with the code of the [`RouteError`] it was
+/// caused by.
+///
+/// Other errors are answered with their outermost message, which is meant for rollers, while what
+/// caused it is only logged.
+pub fn handle_anyhow_error(err: anyhow::Error) -> (StatusCode, Json<Value>) {
+    match err.downcast_ref::<RouteError>() {
+        Some(route_error) => route_error.to_response(),
+        None => error_response(StatusCode::BAD_REQUEST, INVALID_REQUEST, format!("{err}")),
+    }
+}
+
+fn error_response(status: StatusCode, code: &str, reason: String) -> (StatusCode, Json<Value>) {
+    let err = json!({
+        "status": "ERROR",
+        "code": code,
+        "reason": reason,
+    });
+
+    (status, Json(err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn route_errors_carry_their_code_however_they_were_wrapped() {
+        let err = Err::<(), _>(RouteError::ZapMemoTooLong {
+            chars: 4,
+            max_chars: 3,
+        })
+        .context("Failed to get invoice")
+        .unwrap_err();
+
+        let (status, Json(body)) = handle_anyhow_error(err);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body,
+            json!({
+                "status": "ERROR",
+                "code": "ZAP_MEMO_TOO_LONG",
+                "reason": "Zap memo is too long: 4 characters, at most 3 allowed.",
+            })
+        );
+
+        let (_, Json(body)) = handle_anyhow_error(anyhow::anyhow!("Invalid roller npub"));
+        assert_eq!(body["code"], INVALID_REQUEST);
+        assert_eq!(body["reason"], "Invalid roller npub");
+    }
+}
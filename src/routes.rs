@@ -1,20 +1,43 @@
+use crate::beacon::roll_seed;
 use crate::db;
-use crate::db::upsert_zap;
+use crate::db::insert_zap;
+use crate::db::AnonDonation;
 use crate::db::BetState;
 use crate::db::Zap;
+use crate::dm::send_dm;
+use crate::game::Game;
+use crate::game::Games;
+use crate::game::Role;
+use crate::game::DEFAULT_GAME;
+use crate::game::ROLES;
+use crate::house_balance::check_house_balance;
+use crate::lightning_address::check_lightning_address;
+use crate::metrics::METRICS;
+use crate::multiplier::BetDirection;
+use crate::multiplier::Multiplier;
 use crate::multiplier::MultiplierNote;
+use crate::multiplier::Multipliers;
+use crate::nonce;
 use crate::nonce::get_active_nonce;
 use crate::nonce::nonce_commitment;
+use crate::payouts::calculate_price_money;
+use crate::payouts::PayoutRounding;
+use crate::roll::derive_roll;
+use crate::roll::RollScheme;
+use crate::route_error::handle_anyhow_error;
+use crate::route_error::RouteError;
 use crate::utils;
 use crate::State;
-use crate::MAIN_KEY_NAME;
-use crate::NONCE_KEY_NAME;
-use crate::SOCIAL_KEY_NAME;
+use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
 use axum::extract::Path;
 use axum::extract::Query;
+use axum::http::header;
+use axum::http::HeaderMap;
 use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
 use axum::Extension;
 use axum::Json;
 use lightning_invoice::Bolt11Invoice;
@@ -22,50 +45,53 @@ use lnurl::pay::PayResponse;
 use lnurl::Tag;
 use nostr::bitcoin::hashes::sha256;
 use nostr::Event;
+use nostr::EventBuilder;
 use nostr::JsonUtil;
+use nostr::Kind;
 use nostr::ToBech32;
 use nostr_sdk::hashes::Hash;
 use nostr_sdk::EventId;
 use nostr_sdk::PublicKey;
+use nostr_sdk::TagStandard;
 use serde::de;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde_json::json;
 use serde_json::Value;
+use sqlx::SqlitePool;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
+use std::future::Future;
+use std::iter;
 use std::str::FromStr;
+use std::sync::Arc;
 use time::OffsetDateTime;
+use tonic_openssl_lnd::invoicesrpc;
 use tonic_openssl_lnd::lnrpc;
+use tonic_openssl_lnd::LndInvoicesClient;
+
+/// The `d` tag of the signed game rules in the NIP-05 fairness document.
+const FAIRNESS_RULES_IDENTIFIER: &str = "nostrdice-rules";
 
 /// Returns an invoice if a user wants to play a game
 pub async fn get_invoice_for_game(
+    Path(path): Path<HashMap<String, String>>,
     Query(params): Query<HashMap<String, String>>,
     Extension(state): Extension<State>,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let game = get_game(&state, &path)?;
+    check_callback_hash(&game, &[Role::Main], &state.domain, &path)?;
+
     let (amount_msats, zap_request) = match params.get("amount").and_then(|a| a.parse::<u64>().ok())
     {
-        None => Err((
-            StatusCode::BAD_REQUEST,
-            Json(json!({
-                "status": "ERROR",
-                "reason": "Missing amount parameter",
-            })),
-        )),
+        None => Err(RouteError::MissingAmount.to_response()),
         Some(amount_msats) => {
             let zap_request = params.get("nostr").map_or_else(
                 || Ok(None),
                 |event_str| {
                     Event::from_json(event_str)
-                        .map_err(|_| {
-                            (
-                                StatusCode::BAD_REQUEST,
-                                Json(json!({
-                                    "status": "ERROR",
-                                    "reason": "Invalid zap request",
-                                })),
-                            )
-                        })
+                        .map_err(|_| RouteError::InvalidZapRequest.to_response())
                         .map(Some)
                 },
             )?;
@@ -74,7 +100,7 @@ pub async fn get_invoice_for_game(
         }
     }?;
 
-    match get_invoice_for_game_impl(state, amount_msats, zap_request).await {
+    match get_invoice_for_game_impl(state, game, amount_msats, zap_request).await {
         Ok(invoice) => Ok(Json(json!({
             "pr": invoice,
             "routers": []
@@ -88,32 +114,22 @@ pub async fn get_invoice_for_game(
 
 /// Returns an invoice if a user wants to zap us for donation reasons
 pub async fn get_invoice_for_zap(
+    Path(path): Path<HashMap<String, String>>,
     Query(params): Query<HashMap<String, String>>,
     Extension(state): Extension<State>,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let game = get_game(&state, &path)?;
+    let role = check_callback_hash(&game, &[Role::Nonce, Role::Social], &state.domain, &path)?;
+
     let (amount_msats, zap_request) = match params.get("amount").and_then(|a| a.parse::<u64>().ok())
     {
-        None => Err((
-            StatusCode::BAD_REQUEST,
-            Json(json!({
-                "status": "ERROR",
-                "reason": "Missing amount parameter",
-            })),
-        )),
+        None => Err(RouteError::MissingAmount.to_response()),
         Some(amount_msats) => {
             let zap_request = params.get("nostr").map_or_else(
                 || Ok(None),
                 |event_str| {
                     Event::from_json(event_str)
-                        .map_err(|_| {
-                            (
-                                StatusCode::BAD_REQUEST,
-                                Json(json!({
-                                    "status": "ERROR",
-                                    "reason": "Invalid zap request",
-                                })),
-                            )
-                        })
+                        .map_err(|_| RouteError::InvalidZapRequest.to_response())
                         .map(Some)
                 },
             )?;
@@ -122,7 +138,20 @@ pub async fn get_invoice_for_zap(
         }
     }?;
 
-    match get_invoice_for_zap_impl(state, amount_msats, zap_request).await {
+    let comment = params.get("comment").cloned();
+    let payer_data = params.get("payerdata").cloned();
+
+    match get_invoice_for_zap_impl(
+        state,
+        game,
+        role,
+        amount_msats,
+        zap_request,
+        comment,
+        payer_data,
+    )
+    .await
+    {
         Ok(invoice) => Ok(Json(json!({
             "pr": invoice,
             "routers": []
@@ -134,9 +163,47 @@ pub async fn get_invoice_for_zap(
     }
 }
 
-/// The roller's zap invoice memo specifies the terms of the bet.
+/// The game named in the callback URL of its LNURL identities.
 ///
-/// The roller can verify the terms of the bet before sending the
+/// Callback URLs without a game name were handed out before we hosted several games. They belong
+/// to the [`DEFAULT_GAME`].
+fn get_game(
+    state: &State,
+    path: &HashMap<String, String>,
+) -> Result<Game, (StatusCode, Json<Value>)> {
+    let name = path.get("game").map(String::as_str).unwrap_or(DEFAULT_GAME);
+
+    state.games.get(name).cloned().ok_or_else(|| {
+        RouteError::UnknownGame {
+            game: name.to_string(),
+        }
+        .to_response()
+    })
+}
+
+/// Check that the `hash` in the callback URL is the one [`get_lnurl_pay`] handed out for one of
+/// the `roles` of the `game`, so that callback URLs of one endpoint cannot be used for another.
+///
+/// Returns the role of the account whose callback URL it is.
+fn check_callback_hash(
+    game: &Game,
+    roles: &[Role],
+    domain: &str,
+    path: &HashMap<String, String>,
+) -> Result<Role, (StatusCode, Json<Value>)> {
+    let hash = path.get("hash").map(String::as_str).unwrap_or_default();
+
+    roles
+        .iter()
+        .copied()
+        .find(|role| hash.eq_ignore_ascii_case(&callback_hash(game, *role, domain)))
+        .ok_or_else(|| RouteError::InvalidCallbackHash.to_response())
+}
+
+/// The terms of the bet, DMed to the roller with the invoice.
+///
+/// The invoice itself only commits to the zap request, as NIP-57 requires for the zap receipt to
+/// be valid. The roller can verify the terms of the bet before sending the
 /// payment. To do this, they must:
 ///
 /// - Check that the `multiplier_note_id` corresponds to their chosen multiplier.
@@ -144,9 +211,11 @@ pub async fn get_invoice_for_zap(
 /// - Check that the `roller_npub` matches their own npub.
 ///
 /// - Check that the `memo_hash` matches the hash of their zap memo.
-fn zap_invoice_memo(
+#[allow(clippy::too_many_arguments)]
+fn bet_terms(
     nonce_commitment_note_id: EventId,
     nonce_commitment: sha256::Hash,
+    roll_scheme: RollScheme,
     multiplier_note: MultiplierNote,
     roller_npub: PublicKey,
     zap_memo: String,
@@ -155,88 +224,160 @@ fn zap_invoice_memo(
 ) -> String {
     let nonce_commitment_note_id = nonce_commitment_note_id.to_bech32().expect("valid note");
 
-    let multiplier_note_id = multiplier_note.note_id;
+    let multiplier_note_id = multiplier_note.note_id.clone();
 
     let roller_npub = roller_npub.to_bech32().expect("valid npub");
 
     let memo_hash = sha256::Hash::hash(zap_memo.as_bytes());
 
     format!(
-        "Bet {} sats that you will roll a number smaller than {}, \
+        "Bet {} sats that you will roll a number {}, \
          to multiply your wager by {}. nonce_commitment_note_id: {nonce_commitment_note_id}, \
          nonce_commitment: {nonce_commitment}, multiplier_note_id: {multiplier_note_id}, \
          roller_npub: {roller_npub}, memo_hash: {memo_hash}, index: {index}",
         amount_msats / 1_000,
-        multiplier_note.multiplier.get_lower_than(),
+        multiplier_note.get_target(roll_scheme),
+        multiplier_note.multiplier.get_content(),
+    )
+}
+
+/// The terms of a bet in plain words, sent to the roller before they pay along with the
+/// [`bet_terms`].
+fn bet_terms_dm(
+    nonce_commitment_note_id: EventId,
+    roll_scheme: RollScheme,
+    multiplier_note: &MultiplierNote,
+    amount_msats: u64,
+    index: usize,
+) -> String {
+    format!(
+        "If you pay the invoice, you bet {} sats that you will roll a number {}, \
+         to multiply your wager by {}. Your roll will be number {index} of the round \
+         nostr:{}. The multiplier note is nostr:{}.",
+        amount_msats / 1_000,
+        multiplier_note.get_target(roll_scheme),
         multiplier_note.multiplier.get_content(),
+        nonce_commitment_note_id.to_bech32().expect("valid note"),
+        multiplier_note.note_id,
     )
 }
 
 pub(crate) async fn get_invoice_for_game_impl(
     state: State,
+    game: Game,
     amount_msats: u64,
     zap_request: Option<Event>,
 ) -> anyhow::Result<String> {
+    // We could not pay out the winners, so we do not take their bets.
+    if !state.lnd_health.is_connected() {
+        bail!(RouteError::LndUnreachable);
+    }
+
     let mut lnd = state.lightning_client.clone();
     let zap_request = match zap_request.as_ref() {
         // TODO: Maybe we should get rid of this branch altogether.
-        None => bail!("Cannot play the game without a zap request"),
-        Some(event) => match event.kind() {
-            // TODO: Validate as valid zap request.
-            nostr::Kind::ZapRequest => event,
-            _ => bail!("Invalid Nostr event: not a zap request"),
-        },
+        None => bail!(RouteError::MissingZapRequest),
+        Some(event) => {
+            utils::validate_zap_request(event, &game.main_keys.public_key(), amount_msats)?;
+            event
+        }
     };
 
-    // TODO: Check if the user has a Lightning address configured.
+    // Wallets are told not to send less, and we could not pay out less than a sat.
+    let min_sendable_msat = state.sendable.main_min_msat.max(1_000);
+    if amount_msats < min_sendable_msat {
+        bail!(RouteError::BelowMinSendable {
+            amount_msat: amount_msats,
+            min_sendable_msat,
+        });
+    }
+
+    // We could not pay them out if they won.
+    check_lightning_address(&game.client, &state.lightning_addresses, zap_request.pubkey).await?;
 
-    let zapped_note_id = utils::get_zapped_note_id(zap_request)?
-        .to_bech32()
-        .expect("valid note ID");
+    let zapped_note_id = utils::get_zapped_note_id(zap_request)?;
 
-    let multiplier_note = match state.multipliers.get_multiplier_note(&zapped_note_id) {
+    let multiplier_note = match game
+        .multipliers
+        .get_multiplier_note(&zapped_note_id.to_bech32().expect("valid note ID"))
+    {
         Some(multiplier_note) => multiplier_note,
         None => {
-            bail!("Zapped note which wasn't a multiplier note");
+            return Err(not_a_multiplier_note(
+                &state.db,
+                zapped_note_id,
+                &state.round_note_zap_message,
+            )
+            .await);
         }
     };
 
-    if amount_msats > multiplier_note.multiplier.get_max_amount_sat() * 1000 {
-        bail!(
-            "Zapped amount ({amount_msats} msat) is too high for the multiplier {}.",
-            multiplier_note.multiplier.get_content()
-        );
+    if game.multiplier_pins.is_tampered(&multiplier_note.note_id) {
+        bail!(RouteError::MultiplierNoteChanged {
+            multiplier: multiplier_note.multiplier.get_content(),
+        });
     }
 
-    // Better check that we are taking bets before adding the zap invoice.
-    let round = get_active_nonce(&state.db)
+    multiplier_note.check_active(OffsetDateTime::now_utc())?;
+
+    multiplier_note.check_amount(amount_msats)?;
+
+    check_zap_memo_length(&zap_request.content, state.max_zap_memo_chars)?;
+
+    check_house_balance(
+        &state.db,
+        &mut lnd,
+        &state.house_balance,
+        state.house_balance_reserve_sat,
+        amount_msats,
+        &multiplier_note.multiplier,
+        state.payout_rounding,
+    )
+    .await?;
+
+    // Better check that we are taking bets before adding the zap invoice. The bet goes into the
+    // running round of the note's schedule.
+    let round = get_active_nonce(&state.db, &game.name, &multiplier_note.schedule)
         .await?
-        .context("Cannot accept zap without active nonce")?;
+        .context(RouteError::NoActiveNonce)?;
 
-    // TODO: we could run into a race condition calculating the index, if the user would try to zap
-    // very fast multiple times.
-    let zaps = db::get_zaps_by_event_id(&state.db, round.event_id).await?;
+    let now = OffsetDateTime::now_utc();
+    check_bet_cutoff(round.expires_at, state.bet_cutoff_secs, now)?;
 
-    let index = zaps
-        .iter()
-        .filter(|z| z.roller == zap_request.pubkey)
-        .collect::<Vec<_>>()
-        .len();
+    let index = db::next_bet_index(&state.db, round.event_id, zap_request.pubkey).await?;
 
-    let memo = zap_invoice_memo(
+    let terms = bet_terms(
         round.event_id,
         nonce_commitment(round.nonce),
+        round.roll_scheme,
         multiplier_note.clone(),
         zap_request.author(),
         zap_request.content.clone(),
         amount_msats,
         index,
     );
+    let dm = format!(
+        "{}\n\n{terms}",
+        bet_terms_dm(
+            round.event_id,
+            round.roll_scheme,
+            &multiplier_note,
+            amount_msats,
+            index,
+        )
+    );
+    let expiry = invoice_expiry_secs(
+        round.expires_at,
+        state.reveal_nonce_after_secs,
+        state.max_invoice_expiry_secs,
+        now,
+    );
     let invoice = lnrpc::Invoice {
         value_msat: amount_msats as i64,
-        // Once an active nonce has expired, this is how long it will take us to reveal it.
-        expiry: state.reveal_nonce_after_secs as i64,
-        memo,
+        description_hash: sha256::Hash::hash(zap_request.as_json().as_bytes())
+            .to_byte_array()
+            .to_vec(),
+        expiry: expiry as i64,
         private: state.route_hints,
         ..Default::default()
     };
@@ -250,52 +391,296 @@ pub(crate) async fn get_invoice_for_game_impl(
         invoice,
         request: zap_request.clone(),
         multiplier_note_id: multiplier_note.note_id,
-        nonce_commitment_note_id: round.event_id,
+        nonce_commitment_note_id: Some(round.event_id),
         bet_state: BetState::GameZapInvoiceRequested,
         zap_retries: 0,
         index,
         bet_timestamp: OffsetDateTime::now_utc(),
+        add_index: Some(resp.add_index),
+        payout_payment_hash: None,
+        fee_msat: None,
+        game: game.name.clone(),
+        settled_at: None,
+        paid_out_at: None,
     };
 
     // At this stage, this `Zap` indicates the roller's _intention_ to bet. They have until the zap
     // invoice's expiry to complete the bet.
-    upsert_zap(&state.db, hex::encode(resp.r_hash), zap, &state.multipliers).await?;
+    let payment_hash = hex::encode(&resp.r_hash);
+    persist_zap_or_cancel_invoice(
+        &payment_hash,
+        insert_zap(
+            &state.db,
+            payment_hash.clone(),
+            zap,
+            &game.multipliers,
+            state.request_event_format,
+        ),
+        cancel_invoice(state.invoices_client.clone(), resp.r_hash.clone()),
+    )
+    .await?;
+
+    if let Some(max_committed_payout_sat) = state.max_committed_payout_sat {
+        let payout_sat = calculate_price_money(
+            amount_msats,
+            multiplier_note.multiplier.get_multiplier(),
+            state.payout_rounding,
+        );
+        let held_until = now + time::Duration::seconds(expiry.min(PAYOUT_HOLD_SECS) as i64);
+        let held = db::hold_payout(
+            &state.db,
+            &payment_hash,
+            payout_sat,
+            max_committed_payout_sat,
+            now,
+            held_until,
+        )
+        .await?;
+
+        if !held {
+            tracing::warn!(
+                payment_hash,
+                payout_sat,
+                "Rejecting bet exceeding the maximum committed payout"
+            );
+
+            if let Err(e) = cancel_invoice(state.invoices_client.clone(), resp.r_hash).await {
+                tracing::error!(
+                    payment_hash,
+                    "Failed to cancel invoice of rejected bet: {e:#}"
+                );
+            }
+
+            bail!(RouteError::TooMuchAtStake);
+        }
+    }
+
+    METRICS.bet_invoice_requested(&game.name, &multiplier_note.multiplier);
+
+    let roller = zap_request.pubkey;
+    if state
+        .invoice_rate_limiter
+        .allow_dm(roller, std::time::Instant::now())
+    {
+        // Relays can be slow, so we hand out the invoice without waiting for the DM.
+        let client = game.client.clone();
+        let dms = state.dms.clone();
+        tokio::spawn(async move { send_dm(&client, &dms, &roller, dm).await });
+    } else {
+        tracing::debug!(%roller, "Not DMing the terms of another bet this minute");
+    }
 
     Ok(resp.payment_request)
 }
 
+/// How long the payout of a bet counts against `--max-committed-payout-sat` while its invoice is
+/// not paid. Long enough for a wallet to pay right away, short enough that invoices which are never
+/// paid do not keep others from betting.
+const PAYOUT_HOLD_SECS: u64 = 60;
+
+/// The zap memo is part of the roll input, so we bound its length.
+///
+/// The length is counted in characters, like the LNURL `commentAllowed` limit we advertise.
+fn check_zap_memo_length(memo: &str, max_chars: usize) -> anyhow::Result<()> {
+    let chars = memo.chars().count();
+    if chars > max_chars {
+        bail!(RouteError::ZapMemoTooLong { chars, max_chars });
+    }
+
+    Ok(())
+}
+
+/// Bets placed just before a round expires may only be paid once it has been revealed, so we stop
+/// taking them `bet_cutoff_secs` before the round `expires_at`.
+///
+/// Rounds started before we recorded their expiry take bets until they expire.
+fn check_bet_cutoff(
+    expires_at: Option<OffsetDateTime>,
+    bet_cutoff_secs: u64,
+    now: OffsetDateTime,
+) -> anyhow::Result<()> {
+    let Some(expires_at) = expires_at else {
+        return Ok(());
+    };
+
+    let remaining = expires_at - now;
+    if remaining < time::Duration::seconds(bet_cutoff_secs as i64) {
+        bail!(RouteError::BetsClosed {
+            bet_cutoff_secs,
+            remaining_secs: remaining.whole_seconds().max(0),
+        });
+    }
+
+    Ok(())
+}
+
+/// Bet invoices expire this long before their round is revealed, so that a payment LND is still
+/// settling does not reach us after the round has been settled.
+const INVOICE_EXPIRY_MARGIN_SECS: u64 = 10;
+
+/// How long the invoice of a bet on a round which `expires_at` stays payable: until shortly before
+/// the round is revealed, `reveal_nonce_after_secs` after it expires, but at most
+/// `max_invoice_expiry_secs`.
+///
+/// Rounds started before we recorded their expiry are revealed at most `reveal_nonce_after_secs`
+/// after a bet on them, since they expire before that.
+pub fn invoice_expiry_secs(
+    expires_at: Option<OffsetDateTime>,
+    reveal_nonce_after_secs: u64,
+    max_invoice_expiry_secs: u64,
+    now: OffsetDateTime,
+) -> u64 {
+    let until_reveal = match expires_at {
+        Some(expires_at) => {
+            (expires_at - now).whole_seconds().max(0) as u64 + reveal_nonce_after_secs
+        }
+        None => reveal_nonce_after_secs,
+    };
+
+    // An expiry of 0 would give the invoice LND's default expiry instead.
+    until_reveal
+        .saturating_sub(INVOICE_EXPIRY_MARGIN_SECS)
+        .clamp(1, max_invoice_expiry_secs)
+}
+
+/// How long after a round expires the invoice of a bet on it stays payable at most, see
+/// [`invoice_expiry_secs`]. Bets on the round only close then.
+pub fn invoice_lifetime_after_expiry_secs(
+    reveal_nonce_after_secs: u64,
+    max_invoice_expiry_secs: u64,
+) -> u64 {
+    // The longest-lived invoice is the one of the last bet, placed as the round expires.
+    let now = OffsetDateTime::now_utc();
+
+    invoice_expiry_secs(
+        Some(now),
+        reveal_nonce_after_secs,
+        max_invoice_expiry_secs,
+        now,
+    )
+}
+
+/// Persist the `Zap` for an invoice we just added, cancelling the invoice if that fails.
+///
+/// Otherwise we would hand out a payable invoice we have no record of, and a paid bet would be
+/// mistaken for a donation.
+async fn persist_zap_or_cancel_invoice(
+    payment_hash: &str,
+    persist: impl Future<Output = anyhow::Result<()>>,
+    cancel: impl Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    let e = match persist.await {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+
+    if let Err(cancel_error) = cancel.await {
+        tracing::error!(
+            payment_hash,
+            "Failed to cancel invoice after failing to store zap: {cancel_error:#}. \
+             Payments to it will not be handled"
+        );
+    }
+
+    Err(e.context("Failed to store zap"))
+}
+
+async fn cancel_invoice(mut lnd: LndInvoicesClient, payment_hash: Vec<u8>) -> anyhow::Result<()> {
+    lnd.cancel_invoice(invoicesrpc::CancelInvoiceMsg { payment_hash })
+        .await
+        .context("Failed to cancel invoice")?;
+
+    Ok(())
+}
+
+/// The error for a bet on a note which is not a multiplier note.
+///
+/// Zapping one of our round notes instead of a multiplier note is an easy mistake to make, so we
+/// let the roller know what they did wrong.
+async fn not_a_multiplier_note(
+    db: &SqlitePool,
+    zapped_note_id: EventId,
+    round_note_zap_message: &str,
+) -> anyhow::Error {
+    match nonce::get_round_note(db, zapped_note_id).await {
+        Ok(Some(_)) => RouteError::RoundNoteZapped {
+            message: round_note_zap_message.to_string(),
+        }
+        .into(),
+        Ok(None) => RouteError::NotAMultiplierNote.into(),
+        Err(e) => e.context(RouteError::NotAMultiplierNote),
+    }
+}
+
+/// An invoice for a donation to the account with the `role` in the `game`. Donations without a
+/// zap request may carry a LUD-12 `comment` and LUD-18 `payer_data`, which end up in the invoice
+/// memo for the operator to see. Their invoice commits to the LNURL metadata and the `payer_data`,
+/// as LUD-18 wants.
+///
+/// Zap requests must be for that account, since its keys sign the zap receipt. Zap requests
+/// referencing one of its notes get a receipt threaded under the note.
 pub(crate) async fn get_invoice_for_zap_impl(
     state: State,
+    game: Game,
+    role: Role,
     amount_msats: u64,
     zap_request: Option<Event>,
+    comment: Option<String>,
+    payer_data: Option<String>,
 ) -> anyhow::Result<String> {
     let mut lnd = state.lightning_client.clone();
     let zap_request = match zap_request.as_ref() {
         None => {
+            let memo = donation_memo(
+                &state.anon_donation_memo,
+                comment.as_deref(),
+                payer_data.as_deref(),
+                state.donation_comment_chars,
+            )?;
+            let metadata = lnurl_metadata(&game.identity(role), &state.domain);
             let request = lnrpc::Invoice {
                 value_msat: amount_msats as i64,
-                memo: "Donation to NostrDice".to_string(),
+                // Only kept by LND, since the invoice has the description hash instead.
+                memo,
+                description_hash: donation_description_hash(&metadata, payer_data.as_deref()),
                 private: state.route_hints,
                 ..Default::default()
             };
 
             let resp = lnd.add_invoice(request).await?.into_inner();
 
+            // Without a record, the payment would be mistaken for a bet we lost track of.
+            let payment_hash = hex::encode(&resp.r_hash);
+            let donation = AnonDonation {
+                payment_hash: payment_hash.clone(),
+                amount_msat: amount_msats,
+                game: game.name.clone(),
+                created_at: OffsetDateTime::now_utc(),
+                settled_at: None,
+            };
+            persist_zap_or_cancel_invoice(
+                &payment_hash,
+                db::insert_anon_donation(&state.db, donation),
+                cancel_invoice(state.invoices_client.clone(), resp.r_hash),
+            )
+            .await?;
+
             return Ok(resp.payment_request);
         }
-        Some(event) => match event.kind() {
-            nostr::Kind::ZapRequest => event,
-            _ => bail!("Invalid Nostr event: not a zap request"),
-        },
+        Some(event) => {
+            utils::validate_zap_request(event, &game.keys(role).public_key(), amount_msats)?;
+            event
+        }
     };
 
+    // Without a memo, the invoice only commits to the zap request, as NIP-57 wants for the zap
+    // receipt to be valid.
     let invoice = lnrpc::Invoice {
         value_msat: amount_msats as i64,
         description_hash: sha256::Hash::hash(zap_request.as_json().as_bytes())
             .to_byte_array()
             .to_vec(),
         expiry: 60 * 5,
-        memo: "Thank you for the donation".to_string(),
         private: state.route_hints,
         ..Default::default()
     };
@@ -309,74 +694,263 @@ pub(crate) async fn get_invoice_for_zap_impl(
         invoice,
         request: zap_request.clone(),
         multiplier_note_id: String::new(),
-        nonce_commitment_note_id: EventId::all_zeros(),
-        bet_state: BetState::ZapInvoiceRequested,
+        nonce_commitment_note_id: None,
+        bet_state: BetState::Donation,
         zap_retries: 0,
         index: 0,
         bet_timestamp: OffsetDateTime::now_utc(),
+        add_index: Some(resp.add_index),
+        payout_payment_hash: None,
+        fee_msat: None,
+        game: game.name.clone(),
+        settled_at: None,
+        paid_out_at: None,
     };
 
-    // invoice's expiry to complete the bet.
-    upsert_zap(&state.db, hex::encode(resp.r_hash), zap, &state.multipliers).await?;
+    let payment_hash = hex::encode(&resp.r_hash);
+    persist_zap_or_cancel_invoice(
+        &payment_hash,
+        insert_zap(
+            &state.db,
+            payment_hash.clone(),
+            zap,
+            &game.multipliers,
+            state.request_event_format,
+        ),
+        cancel_invoice(state.invoices_client.clone(), resp.r_hash),
+    )
+    .await?;
 
     Ok(resp.payment_request)
 }
 
+/// The range of amounts in msats a wallet may send to an account.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sendable {
+    pub min_msat: u64,
+    pub max_msat: u64,
+}
+
+/// The ranges of amounts advertised in the LNURL pay responses of each account.
+#[derive(Debug, Clone, Copy)]
+pub struct SendableLimits {
+    pub main_min_msat: u64,
+    /// Derived from the multiplier notes of the game if not set, so that wallets do not offer to
+    /// send bets we reject.
+    pub main_max_msat: Option<u64>,
+    pub nonce: Sendable,
+    pub social: Sendable,
+}
+
+impl SendableLimits {
+    pub fn for_role(&self, role: Role, multipliers: &Multipliers) -> Sendable {
+        match role {
+            Role::Main => Sendable {
+                min_msat: self.main_min_msat,
+                max_msat: self.main_max_msat.unwrap_or_else(|| {
+                    // Validated to fit in msats, see `Multipliers::validate`.
+                    multipliers.max_amount_sat().map_or(0, |max_amount_sat| {
+                        max_amount_sat.checked_mul(1000).unwrap_or(u64::MAX)
+                    })
+                }),
+            },
+            Role::Nonce => self.nonce,
+            Role::Social => self.social,
+        }
+    }
+
+    /// Check that every account of the `games` can be sent something.
+    pub fn validate(&self, games: &Games) -> anyhow::Result<()> {
+        for game in games.iter() {
+            for role in ROLES {
+                let sendable = self.for_role(role, &game.multipliers);
+                if sendable.min_msat > sendable.max_msat {
+                    bail!(
+                        "Minimum sendable of {} msats exceeds the maximum of {} msats for the \
+                         {role:?} account of game {}",
+                        sendable.min_msat,
+                        sendable.max_msat,
+                        game.name,
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// LND refuses invoices with a longer memo.
+const MAX_INVOICE_MEMO_BYTES: usize = 639;
+
+/// The memo of an invoice for a donation without a zap request, with the LUD-12 `comment` and the
+/// name or pubkey of the LUD-18 `payer_data` the payer sent along.
+fn donation_memo(
+    memo: &str,
+    comment: Option<&str>,
+    payer_data: Option<&str>,
+    max_comment_chars: usize,
+) -> anyhow::Result<String> {
+    let comment = comment.filter(|comment| !comment.is_empty());
+    if let Some(comment) = comment {
+        let chars = comment.chars().count();
+        if chars > max_comment_chars {
+            bail!(RouteError::CommentTooLong {
+                chars,
+                max_chars: max_comment_chars,
+            });
+        }
+    }
+
+    let payer = match payer_data {
+        Some(payer_data) => {
+            let payer_data = serde_json::from_str::<PayerData>(payer_data)
+                .context(RouteError::InvalidPayerData)?;
+            payer_data.name.or(payer_data.pubkey)
+        }
+        None => None,
+    };
+
+    let mut memo = memo.to_string();
+    if let Some(payer) = payer.filter(|payer| !payer.is_empty()) {
+        memo.push_str(&format!(" from {payer}"));
+    }
+    if let Some(comment) = comment {
+        memo.push_str(&format!(": {comment}"));
+    }
+
+    let mut end = memo.len().min(MAX_INVOICE_MEMO_BYTES);
+    while !memo.is_char_boundary(end) {
+        end -= 1;
+    }
+    memo.truncate(end);
+
+    Ok(memo)
+}
+
+/// The description hash of the invoice for a donation without a zap request: the hash of the
+/// LNURL `metadata`, followed by the LUD-18 `payer_data` if the payer sent any.
+fn donation_description_hash(metadata: &str, payer_data: Option<&str>) -> Vec<u8> {
+    let description = format!("{metadata}{}", payer_data.unwrap_or_default());
+
+    sha256::Hash::hash(description.as_bytes())
+        .to_byte_array()
+        .to_vec()
+}
+
+/// The LUD-06 metadata of the LNURL-pay endpoint of the identity `name`.
+///
+/// Its hash is part of the callback URL, so it must not change with the other fields of the
+/// [`LnurlPayResponse`].
+fn lnurl_metadata(name: &str, domain: &str) -> String {
+    format!("[[\"text/identifier\",\"{name}@{domain}\"],[\"text/plain\",\"Sats for {name}\"]]")
+}
+
+/// The hex encoded hash of the [`lnurl_metadata`] of the account with the `role` in the `game`,
+/// as it is part of the callback URL of its LNURL-pay endpoint.
+fn callback_hash(game: &Game, role: Role, domain: &str) -> String {
+    let metadata = lnurl_metadata(&game.identity(role), domain);
+
+    hex::encode(sha256::Hash::hash(metadata.as_bytes()))
+}
+
+/// The LNURL-pay response, with the LUD-18 `payerData` wallets can tell us who pays with.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct LnurlPayResponse {
+    #[serde(flatten)]
+    pub pay: PayResponse,
+    #[serde(rename = "payerData")]
+    pub payer_data: PayerDataRequest,
+}
+
+/// The LUD-18 payer data we ask wallets for. Rollers are identified by their zap request, so none
+/// of it is mandatory.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct PayerDataRequest {
+    pub name: PayerDataField,
+    pub pubkey: PayerDataField,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct PayerDataField {
+    pub mandatory: bool,
+}
+
+/// The LUD-18 payer data a wallet sent along with a payment.
+#[derive(serde::Deserialize)]
+struct PayerData {
+    name: Option<String>,
+    pubkey: Option<String>,
+}
+
 pub async fn get_lnurl_pay(
     Path(name): Path<String>,
     Extension(state): Extension<State>,
-) -> Result<Json<PayResponse>, (StatusCode, Json<Value>)> {
-    let metadata = format!(
-        "[[\"text/identifier\",\"{name}@{}\"],[\"text/plain\",\"Sats for {name}\"]]",
-        state.domain,
-    );
-
-    let hash = sha256::Hash::hash(metadata.as_bytes());
+) -> Result<Json<LnurlPayResponse>, (StatusCode, Json<Value>)> {
+    let metadata = lnurl_metadata(&name, &state.domain);
 
     tracing::debug!("Received request to zap for {name}");
 
-    let (pk, callback_url_path) = match name.as_str() {
-        MAIN_KEY_NAME => (state.main_keys.public_key(), "get-invoice-for-game"),
-        NONCE_KEY_NAME => (state.nonce_keys.public_key(), "get-invoice-for-zap"),
-        SOCIAL_KEY_NAME => (state.social_keys.public_key(), "get-invoice-for-zap"),
-        _ => (state.social_keys.public_key(), "get-invoice-for-zap"),
+    // Unknown names are treated as the social account of the default game, and get its callback
+    // URL.
+    let (game, role) = state
+        .games
+        .find_identity(&name)
+        .unwrap_or((state.games.default_game(), Role::Social));
+
+    let pk = game.keys(role).public_key();
+    let callback_url_path = match role {
+        Role::Main => "get-invoice-for-game",
+        Role::Nonce | Role::Social => "get-invoice-for-zap",
+    };
+
+    // Wallets limit the zap comment to this, which we reject bets and donations over.
+    let comment_allowed = match role {
+        Role::Main => Some(state.max_zap_memo_chars as u32),
+        Role::Nonce | Role::Social => {
+            Some(state.donation_comment_chars as u32).filter(|chars| *chars > 0)
+        }
     };
 
     let callback = format!(
-        "https://{}/{}/{}",
+        "https://{}/{}/{}/{}",
         state.domain,
         callback_url_path,
-        hex::encode(hash)
+        game.name,
+        callback_hash(game, role, &state.domain)
     );
 
     let pk = bitcoin::key::XOnlyPublicKey::from_slice(&pk.serialize()).expect("valid PK");
 
+    let sendable = state.sendable.for_role(role, &game.multipliers);
+
     let resp = PayResponse {
         callback,
-        min_sendable: 1_000,
-        max_sendable: 11_000_000_000,
+        min_sendable: sendable.min_msat,
+        max_sendable: sendable.max_msat,
         tag: Tag::PayRequest,
         metadata,
-        comment_allowed: None,
+        comment_allowed,
         allows_nostr: Some(true),
         nostr_pubkey: Some(pk),
     };
 
-    Ok(Json(resp))
-}
-
-pub(crate) fn handle_anyhow_error(err: anyhow::Error) -> (StatusCode, Json<Value>) {
-    let err = json!({
-        "status": "ERROR",
-        "reason": format!("{err}"),
-    });
-    (StatusCode::BAD_REQUEST, Json(err))
+    Ok(Json(LnurlPayResponse {
+        pay: resp,
+        payer_data: PayerDataRequest {
+            name: PayerDataField { mandatory: false },
+            pubkey: PayerDataField { mandatory: false },
+        },
+    }))
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Nip05QueryParams {
     #[serde(default, deserialize_with = "empty_string_as_none")]
     name: Option<String>,
+    /// Whether to include the [`Fairness`] document in the response.
+    #[serde(default)]
+    fairness: bool,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -384,72 +958,692 @@ pub struct Nip05Response {
     /// a pair of nip05 username and their corresponding pubkeys in hex format
     pub names: HashMap<String, String>,
     pub relays: HashMap<String, Vec<String>>,
+    /// Only present if explicitly requested, so that the default response is standard NIP-05.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fairness: Option<Fairness>,
+}
+
+/// Lets players confirm that the rules we publish are the ones we enforce.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Fairness {
+    /// The role of each of our accounts, keyed by pubkey in hex format.
+    pub roles: HashMap<String, String>,
+    /// A [`GameRules`] statement, signed by the main account.
+    pub rules: Event,
+}
+
+/// The rules the server enforces when rolling the die.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct GameRules {
+    /// The roll derivation scheme used for new rounds.
+    pub roll_scheme: RollScheme,
+    pub multipliers: Vec<MultiplierRule>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct MultiplierRule {
+    pub note_id: String,
+    pub multiplier: f32,
+    pub direction: BetDirection,
+    /// A roll of the game's roll scheme wins if it is strictly smaller (`Under`) or bigger
+    /// (`Over`) than this.
+    pub threshold: u32,
+    pub max_amount_sat: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_amount_sat: Option<u64>,
+    pub win_probability: f32,
+    /// The share of every bet the house keeps on average.
+    pub house_edge: f32,
+}
+
+/// How long clients may cache our NIP-05 documents, which only change when we restart.
+const NIP05_MAX_AGE_SECS: u64 = 60 * 60;
+
+/// The relays each kind of account publishes to, advertised in the NIP-05 documents and the NIP-65
+/// relay lists of the accounts.
+#[derive(Debug, Clone)]
+pub struct RelayHints {
+    pub main: Vec<String>,
+    pub nonce: Vec<String>,
+    pub social: Vec<String>,
+}
+
+impl RelayHints {
+    pub fn for_role(&self, role: Role) -> &[String] {
+        match role {
+            Role::Main => &self.main,
+            Role::Nonce => &self.nonce,
+            Role::Social => &self.social,
+        }
+    }
+}
+
+/// A rendered [`Nip05Response`] and its entity tag.
+#[derive(Debug)]
+struct Nip05Document {
+    body: String,
+    etag: String,
+}
+
+/// The NIP-05 documents of every name we answer to, rendered once since our accounts do not
+/// change while we run.
+///
+/// Keyed by name and whether the [`Fairness`] document is included. Without a name, the accounts
+/// of all our games are listed.
+#[derive(Debug, Clone)]
+pub struct Nip05Documents(Arc<HashMap<(Option<String>, bool), Nip05Document>>);
+
+impl Nip05Documents {
+    pub fn new(
+        games: &Games,
+        roll_scheme: RollScheme,
+        relay_hints: &RelayHints,
+    ) -> anyhow::Result<Self> {
+        let names = iter::once(None).chain(
+            games
+                .iter()
+                .flat_map(|game| ROLES.map(|role| Some(game.identity(role)))),
+        );
+
+        let mut documents = HashMap::new();
+        for name in names {
+            for fairness in [false, true] {
+                let response =
+                    nip05_response(games, name.as_deref(), fairness, roll_scheme, relay_hints)?;
+                let body = serde_json::to_string(&response)?;
+                let etag = format!("\"{}\"", sha256::Hash::hash(body.as_bytes()));
+
+                documents.insert((name.clone(), fairness), Nip05Document { body, etag });
+            }
+        }
+
+        Ok(Self(Arc::new(documents)))
+    }
+
+    /// Unknown names get the document without a name.
+    fn get(&self, name: Option<&str>, fairness: bool) -> &Nip05Document {
+        self.0
+            .get(&(name.map(str::to_string), fairness))
+            .or_else(|| self.0.get(&(None, fairness)))
+            .expect("document without a name")
+    }
 }
 
 pub async fn get_nip05(
     params: Query<Nip05QueryParams>,
+    headers: HeaderMap,
     Extension(state): Extension<State>,
-) -> Result<Json<Nip05Response>, (StatusCode, Json<Value>)> {
-    let all = Nip05Response {
-        names: HashMap::from([
-            (
-                MAIN_KEY_NAME.to_string(),
-                state.main_keys.public_key().to_hex(),
-            ),
-            (
-                NONCE_KEY_NAME.to_string(),
-                state.nonce_keys.public_key().to_hex(),
-            ),
-            (
-                SOCIAL_KEY_NAME.to_string(),
-                state.social_keys.public_key().to_hex(),
-            ),
-        ]),
-        relays: HashMap::from([
-            (state.main_keys.public_key().to_hex(), state.relays.clone()),
-            (state.nonce_keys.public_key().to_hex(), state.relays.clone()),
+) -> Response {
+    let document = state.nip05.get(params.name.as_deref(), params.fairness);
+
+    let cache_headers = [
+        (header::ETAG, document.etag.clone()),
+        (
+            header::CACHE_CONTROL,
+            format!("public, max-age={NIP05_MAX_AGE_SECS}"),
+        ),
+    ];
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|etag| etag.trim() == "*" || etag.trim() == document.etag)
+        });
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
+    (
+        cache_headers,
+        [(header::CONTENT_TYPE, "application/json")],
+        document.body.clone(),
+    )
+        .into_response()
+}
+
+fn nip05_response(
+    games: &Games,
+    name: Option<&str>,
+    fairness: bool,
+    roll_scheme: RollScheme,
+    relay_hints: &RelayHints,
+) -> anyhow::Result<Nip05Response> {
+    let identity = name.and_then(|name| games.find_identity(name));
+
+    // Without a known name, we list the accounts of all our games.
+    let accounts = match identity {
+        Some((game, role)) => vec![(game, role)],
+        None => games
+            .iter()
+            .flat_map(|game| ROLES.into_iter().map(move |role| (game, role)))
+            .collect(),
+    };
+
+    let fairness = if fairness {
+        let game = identity.map_or(games.default_game(), |(game, _)| game);
+
+        Some(fairness_document(game, roll_scheme)?)
+    } else {
+        None
+    };
+
+    let names = accounts
+        .iter()
+        .map(|(game, role)| (game.identity(*role), game.keys(*role).public_key().to_hex()))
+        .collect();
+    let relays = accounts
+        .iter()
+        .map(|(game, role)| {
             (
-                state.social_keys.public_key().to_hex(),
-                state.relays.clone(),
-            ),
-        ]),
+                game.keys(*role).public_key().to_hex(),
+                relay_hints.for_role(*role).to_vec(),
+            )
+        })
+        .collect();
+
+    Ok(Nip05Response {
+        names,
+        relays,
+        fairness,
+    })
+}
+
+/// Bumped whenever the shape of the [`VerifyRollResponse`] changes.
+///
+/// Version 2 added `roll_bits`, since rolls of [`RollScheme::V3`] are 32 bits. Version 3 added
+/// `settled_at` and `paid_out_at`.
+pub const VERIFY_ROLL_SCHEMA_VERSION: u32 = 3;
+
+#[derive(serde::Deserialize)]
+pub struct VerifyRollQueryParams {
+    /// The ID of the roller's zap request, in hex or bech32 format. Its bet is looked up instead
+    /// of taking the other parameters.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    zap_request: Option<String>,
+    /// The revealed nonce of the round, hex encoded.
+    #[serde(default)]
+    nonce: String,
+    /// The revealed randomness of the round's beacon round, hex encoded, if it uses a randomness
+    /// beacon.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    beacon_randomness: Option<String>,
+    /// The npub of the roller.
+    #[serde(default)]
+    roller: String,
+    #[serde(default)]
+    index: usize,
+    /// The memo of the roller's zap request.
+    #[serde(default)]
+    memo: String,
+    /// The roll scheme of the round, as published in its nonce commitment note.
+    #[serde(default)]
+    roll_scheme: RollScheme,
+    /// Evaluate the roll against the multiplier notes of this game instead of the default one.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    game: Option<String>,
+}
+
+/// How a roll was derived, and whether it wins a bet on each of the multiplier notes.
+///
+/// The shape is versioned with [`VERIFY_ROLL_SCHEMA_VERSION`], so that clients can tell when it
+/// changes. The roll scheme tells them how the preimage is laid out.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct VerifyRollResponse {
+    pub schema_version: u32,
+    pub roll_scheme: RollScheme,
+    /// The bytes hashed to derive the roll, hex encoded.
+    pub preimage: String,
+    /// The SHA256 digest of the preimage, hex encoded.
+    pub digest: String,
+    /// How many leading bits of the digest make up the roll: 16, or 32 with roll scheme `v3`.
+    pub roll_bits: usize,
+    /// The decimal value of the first `roll_bits` bits of the digest.
+    pub roll: u32,
+    pub multipliers: Vec<MultiplierOutcome>,
+    /// When the bet was paid, if it was looked up by its zap request.
+    #[serde(
+        default,
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub settled_at: Option<OffsetDateTime>,
+    /// When the payout of a winning bet went through, if it was looked up by its zap request.
+    #[serde(
+        default,
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub paid_out_at: Option<OffsetDateTime>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct MultiplierOutcome {
+    pub note_id: String,
+    pub multiplier: f32,
+    pub direction: BetDirection,
+    /// A roll wins if it is strictly smaller (`Under`) or bigger (`Over`) than this, which
+    /// depends on the roll scheme.
+    pub threshold: u32,
+    pub win: bool,
+}
+
+/// Derive the roll of a bet from the revealed nonce, so that clients can check it.
+pub async fn get_verify_roll(
+    Query(params): Query<VerifyRollQueryParams>,
+    Extension(state): Extension<State>,
+) -> Result<Json<VerifyRollResponse>, (StatusCode, Json<Value>)> {
+    if let Some(zap_request) = &params.zap_request {
+        let request_id = EventId::parse(zap_request)
+            .context("Invalid zap request ID")
+            .map_err(handle_anyhow_error)?;
+
+        return verify_bet_roll(&state, request_id).await.map(Json);
+    }
+
+    let name = params.game.as_deref().unwrap_or(DEFAULT_GAME);
+    let game = state.games.get(name).ok_or_else(|| {
+        RouteError::UnknownGame {
+            game: name.to_string(),
+        }
+        .to_response()
+    })?;
+
+    let mut nonce = parse_nonce(&params.nonce).map_err(handle_anyhow_error)?;
+    if let Some(randomness) = &params.beacon_randomness {
+        let randomness = parse_nonce(randomness)
+            .context("Invalid beacon randomness")
+            .map_err(handle_anyhow_error)?;
+        nonce = roll_seed(nonce, randomness);
+    }
+    let roller = PublicKey::parse(&params.roller)
+        .context("Invalid roller npub")
+        .map_err(handle_anyhow_error)?;
+
+    Ok(Json(verify_roll(
+        params.roll_scheme,
+        nonce,
+        params.index,
+        roller,
+        &params.memo,
+        &game.multipliers,
+    )))
+}
+
+/// Derive the roll of the bet placed with the zap request `request_id`, once its round has been
+/// revealed.
+async fn verify_bet_roll(
+    state: &State,
+    request_id: EventId,
+) -> Result<VerifyRollResponse, (StatusCode, Json<Value>)> {
+    let zap = db::get_zap_by_request_id(&state.db, request_id)
+        .await
+        .map_err(handle_anyhow_error)?
+        .ok_or_else(|| RouteError::UnknownZapRequest.to_response())?;
+    let round_id = zap
+        .nonce_commitment_note_id
+        .ok_or_else(|| RouteError::NotABet.to_response())?;
+
+    let round = nonce::get_revealed_round(&state.db, round_id)
+        .await
+        .map_err(handle_anyhow_error)?
+        .ok_or_else(|| RouteError::RoundNotRevealed.to_response())?;
+    let seed = round.roll_seed().map_err(handle_anyhow_error)?;
+
+    let game = state.games.get(&zap.game).ok_or_else(|| {
+        RouteError::UnknownGame {
+            game: zap.game.clone(),
+        }
+        .to_response()
+    })?;
+
+    Ok(VerifyRollResponse {
+        settled_at: zap.settled_at,
+        paid_out_at: zap.paid_out_at,
+        ..verify_roll(
+            round.roll_scheme,
+            seed,
+            zap.index,
+            zap.roller,
+            &zap.request.content,
+            &game.multipliers,
+        )
+    })
+}
+
+/// The audit records of all settled rolls of a round, so that anyone can check its outcomes.
+///
+/// The round is identified by its nonce commitment note ID, in hex or bech32 format.
+pub async fn get_audit(
+    Path(nonce_commitment_note_id): Path<String>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Vec<db::RollAudit>>, (StatusCode, Json<Value>)> {
+    let event_id = EventId::parse(&nonce_commitment_note_id)
+        .context("Invalid nonce commitment note ID")
+        .map_err(handle_anyhow_error)?;
+
+    let audits = db::get_roll_audits(&state.db, event_id)
+        .await
+        .map_err(handle_anyhow_error)?;
+
+    Ok(Json(audits))
+}
+
+/// The bets placed on a revealed round, so that rollers can derive every roll without asking us
+/// for their index.
+///
+/// The round is identified by its nonce commitment note ID, in hex or bech32 format.
+pub async fn get_bets(
+    Path(nonce_commitment_note_id): Path<String>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Vec<nonce::BetManifestEntry>>, (StatusCode, Json<Value>)> {
+    let event_id = EventId::parse(&nonce_commitment_note_id)
+        .context("Invalid nonce commitment note ID")
+        .map_err(handle_anyhow_error)?;
+
+    let revealed = nonce::get_revealed_round(&state.db, event_id)
+        .await
+        .map_err(handle_anyhow_error)?;
+    if revealed.is_none() {
+        return Err(RouteError::RoundNotRevealed.to_response());
+    }
+
+    let manifest = nonce::get_bet_manifest(&state.db, event_id)
+        .await
+        .map_err(handle_anyhow_error)?;
+
+    Ok(Json(manifest))
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct LeaderboardQueryParams {
+    /// Only count bets placed this long ago at most, e.g. `7d`, `24h` or `30m`.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    window: Option<String>,
+    /// How many rollers to list.
+    #[serde(default = "default_leaderboard_limit")]
+    limit: usize,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    game: Option<String>,
+}
+
+fn default_leaderboard_limit() -> usize {
+    10
+}
+
+const MAX_LEADERBOARD_LIMIT: usize = 100;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub struct LeaderboardEntry {
+    pub roller: String,
+    pub bets: u64,
+    pub wagered_sat: u64,
+    pub paid_sat: u64,
+    /// What the roller won minus what they wagered.
+    pub net_sat: i64,
+}
+
+/// The rollers who won the most sats net, over all time or the last `window`.
+pub async fn get_leaderboard(
+    Query(params): Query<LeaderboardQueryParams>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Vec<LeaderboardEntry>>, (StatusCode, Json<Value>)> {
+    let name = params.game.as_deref().unwrap_or(DEFAULT_GAME);
+    if state.games.get(name).is_none() {
+        return Err(RouteError::UnknownGame {
+            game: name.to_string(),
+        }
+        .to_response());
+    }
+
+    let since = match &params.window {
+        Some(window) => {
+            window_start(OffsetDateTime::now_utc(), window).map_err(handle_anyhow_error)?
+        }
+        None => OffsetDateTime::UNIX_EPOCH,
     };
-    if let Some(name) = &params.name {
-        return match name.as_str() {
-            MAIN_KEY_NAME => Ok(Json(Nip05Response {
-                names: HashMap::from([(
-                    MAIN_KEY_NAME.to_string(),
-                    state.main_keys.public_key().to_hex(),
-                )]),
-                relays: HashMap::from([(
-                    state.main_keys.public_key().to_hex(),
-                    state.relays.clone(),
-                )]),
-            })),
-            NONCE_KEY_NAME => Ok(Json(Nip05Response {
-                names: HashMap::from([(
-                    NONCE_KEY_NAME.to_string(),
-                    state.nonce_keys.public_key().to_hex(),
-                )]),
-                relays: HashMap::from([(
-                    state.nonce_keys.public_key().to_hex(),
-                    state.relays.clone(),
-                )]),
-            })),
-            SOCIAL_KEY_NAME => Ok(Json(Nip05Response {
-                names: HashMap::from([(
-                    SOCIAL_KEY_NAME.to_string(),
-                    state.social_keys.public_key().to_hex(),
-                )]),
-                relays: HashMap::from([(
-                    state.social_keys.public_key().to_hex(),
-                    state.relays.clone(),
-                )]),
-            })),
-            _ => Ok(Json(all)),
-        };
+
+    let totals = db::get_roller_totals(&state.db, name, since)
+        .await
+        .map_err(handle_anyhow_error)?;
+
+    Ok(Json(leaderboard(
+        totals,
+        params.limit.min(MAX_LEADERBOARD_LIMIT),
+        state.payout_rounding,
+        &state.anonymous_rollers,
+    )))
+}
+
+/// When the `window` ending `now` started.
+fn window_start(now: OffsetDateTime, window: &str) -> anyhow::Result<OffsetDateTime> {
+    now.checked_sub(parse_window(window)?)
+        .ok_or_else(|| anyhow!("Window {window} is too long"))
+}
+
+fn parse_window(window: &str) -> anyhow::Result<time::Duration> {
+    let invalid = || anyhow!("Invalid window {window}. Expected e.g. 7d, 24h or 30m");
+
+    let unit = window.chars().last().ok_or_else(invalid)?;
+    let amount: u32 = window[..window.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+
+    match unit {
+        'd' => Ok(time::Duration::days(amount.into())),
+        'h' => Ok(time::Duration::hours(amount.into())),
+        'm' => Ok(time::Duration::minutes(amount.into())),
+        _ => Err(invalid()),
+    }
+}
+
+/// Rank rollers by their net winnings. Payouts are derived from the wagers, like when paying out.
+///
+/// The `anonymous_rollers` are left out.
+fn leaderboard(
+    totals: Vec<db::RollerTotals>,
+    limit: usize,
+    payout_rounding: PayoutRounding,
+    anonymous_rollers: &HashSet<PublicKey>,
+) -> Vec<LeaderboardEntry> {
+    let mut rollers: HashMap<PublicKey, LeaderboardEntry> = HashMap::new();
+    for totals in totals {
+        if anonymous_rollers.contains(&totals.roller) {
+            continue;
+        }
+
+        let entry = rollers
+            .entry(totals.roller)
+            .or_insert_with(|| LeaderboardEntry {
+                roller: totals.roller.to_bech32().expect("npub"),
+                bets: 0,
+                wagered_sat: 0,
+                paid_sat: 0,
+                net_sat: 0,
+            });
+
+        entry.bets += totals.bets;
+        entry.wagered_sat += totals.amount_msat / 1_000;
+        if totals.bet_state == BetState::PaidWinner {
+            entry.paid_sat += calculate_price_money(
+                totals.amount_msat,
+                totals.multiplier.get_multiplier(),
+                payout_rounding,
+            );
+        }
+        entry.net_sat = entry.paid_sat as i64 - entry.wagered_sat as i64;
     }
-    Ok(Json(all))
+
+    let mut entries = rollers.into_values().collect::<Vec<_>>();
+    entries.sort_by(|a, b| {
+        b.net_sat
+            .cmp(&a.net_sat)
+            .then_with(|| a.roller.cmp(&b.roller))
+    });
+    entries.truncate(limit);
+
+    entries
+}
+
+fn parse_nonce(nonce: &str) -> anyhow::Result<[u8; 32]> {
+    let nonce = hex::decode(nonce).context("Nonce must be hex encoded")?;
+
+    nonce
+        .try_into()
+        .map_err(|_| anyhow!("Nonce must be 32 bytes long"))
+}
+
+fn verify_roll(
+    roll_scheme: RollScheme,
+    nonce: [u8; 32],
+    index: usize,
+    roller: PublicKey,
+    memo: &str,
+    multipliers: &Multipliers,
+) -> VerifyRollResponse {
+    let derivation = derive_roll(roll_scheme, nonce, index, roller, memo);
+
+    let multipliers = multipliers
+        .0
+        .iter()
+        .map(|note| MultiplierOutcome {
+            note_id: note.note_id.clone(),
+            multiplier: note.multiplier.get_multiplier(),
+            direction: note.direction,
+            threshold: note.get_threshold(roll_scheme),
+            win: note.is_win(derivation.roll, roll_scheme),
+        })
+        .collect();
+
+    VerifyRollResponse {
+        schema_version: VERIFY_ROLL_SCHEMA_VERSION,
+        roll_scheme,
+        preimage: hex::encode(&derivation.preimage),
+        digest: derivation.digest.to_string(),
+        roll_bits: 8 * roll_scheme.roll_bytes(),
+        roll: derivation.roll,
+        multipliers,
+        settled_at: None,
+        paid_out_at: None,
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HealthResponse {
+    pub lnd_connected: bool,
+}
+
+/// Report whether we are able to take bets, i.e. whether LND is reachable.
+pub async fn get_health(Extension(state): Extension<State>) -> (StatusCode, Json<HealthResponse>) {
+    let lnd_connected = state.lnd_health.is_connected();
+
+    let status = if lnd_connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(HealthResponse { lnd_connected }))
+}
+
+/// Liveness check: we are up and serving requests.
+pub async fn get_healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ReadinessResponse {
+    pub lnd_connected: bool,
+    /// The games with a schedule which has no running round to take bets on.
+    pub games_without_round: Vec<String>,
+}
+
+/// Readiness check: LND is reachable and every schedule of every game has a running round.
+pub async fn get_readyz(
+    Extension(state): Extension<State>,
+) -> (StatusCode, Json<ReadinessResponse>) {
+    let lnd_connected = state.lnd_health.is_connected();
+
+    let mut games_without_round = Vec::new();
+    for game in state.games.iter() {
+        for schedule in game.schedules.iter() {
+            let has_round = match get_active_nonce(&state.db, &game.name, &schedule.name).await {
+                Ok(round) => round.is_some(),
+                Err(e) => {
+                    tracing::error!(
+                        game = game.name,
+                        schedule = schedule.name,
+                        "Failed to get active nonce: {e:#}"
+                    );
+                    false
+                }
+            };
+
+            if !has_round {
+                games_without_round.push(game.name.clone());
+                break;
+            }
+        }
+    }
+
+    let status = if lnd_connected && games_without_round.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadinessResponse {
+            lnd_connected,
+            games_without_round,
+        }),
+    )
+}
+
+fn fairness_document(game: &Game, roll_scheme: RollScheme) -> anyhow::Result<Fairness> {
+    let roles = HashMap::from([
+        (
+            game.main_keys.public_key().to_hex(),
+            "multipliers".to_string(),
+        ),
+        (game.nonce_keys.public_key().to_hex(), "nonces".to_string()),
+        (game.social_keys.public_key().to_hex(), "social".to_string()),
+    ]);
+
+    let rules = GameRules {
+        roll_scheme,
+        multipliers: game
+            .multipliers
+            .0
+            .iter()
+            .map(|note| MultiplierRule {
+                note_id: note.note_id.clone(),
+                multiplier: note.multiplier.get_multiplier(),
+                direction: note.direction,
+                threshold: note.get_threshold(roll_scheme),
+                max_amount_sat: note.get_max_amount_sat(),
+                min_amount_sat: note.min_amount_sat,
+                win_probability: note.multiplier.win_probability(roll_scheme),
+                house_edge: note.multiplier.house_edge(roll_scheme),
+            })
+            .collect(),
+    };
+    let rules = serde_json::to_string(&rules)?;
+
+    let rules = EventBuilder::new(
+        Kind::ApplicationSpecificData,
+        rules,
+        [nostr::Tag::from_standardized(TagStandard::Identifier(
+            FAIRNESS_RULES_IDENTIFIER.to_string(),
+        ))],
+    )
+    .to_event(&game.main_keys)
+    .context("Failed to sign game rules")?;
+
+    Ok(Fairness { roles, rules })
 }
 
 fn empty_string_as_none<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
@@ -464,3 +1658,599 @@ where
         Some(s) => FromStr::from_str(s).map_err(de::Error::custom).map(Some),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use crate::db::test_round;
+    use crate::game::test_schedule;
+    use crate::game::DEFAULT_SCHEDULE;
+    use crate::multiplier::test_multiplier_note;
+    use crate::multiplier::MultiplierPins;
+    use crate::nonce::set_active_nonce;
+    use crate::NONCE_KEY_NAME;
+    use nostr::Keys;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+
+    const ROUND_NOTE_ZAP_MESSAGE: &str = "You zapped the round note";
+
+    #[tokio::test]
+    async fn zapping_the_commitment_note_explains_the_mistake() {
+        let db = test_db().await;
+
+        let commitment_event_id = EventId::all_zeros();
+        set_active_nonce(
+            &db,
+            DEFAULT_GAME,
+            DEFAULT_SCHEDULE,
+            test_round(commitment_event_id),
+        )
+        .await
+        .unwrap();
+
+        let error = not_a_multiplier_note(&db, commitment_event_id, ROUND_NOTE_ZAP_MESSAGE).await;
+
+        assert_eq!(error.to_string(), ROUND_NOTE_ZAP_MESSAGE);
+    }
+
+    #[tokio::test]
+    async fn zapping_an_unknown_note_is_a_generic_error() {
+        let db = test_db().await;
+
+        let error = not_a_multiplier_note(&db, EventId::all_zeros(), ROUND_NOTE_ZAP_MESSAGE).await;
+
+        assert_eq!(
+            error.to_string(),
+            "Zapped note which wasn't a multiplier note"
+        );
+    }
+
+    #[test]
+    fn verify_roll_json_shape() {
+        let multipliers = Multipliers(vec![
+            test_multiplier_note("under"),
+            MultiplierNote {
+                direction: BetDirection::Over,
+                ..test_multiplier_note("over")
+            },
+        ]);
+        let roller =
+            PublicKey::parse("npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32")
+                .unwrap();
+
+        let response = verify_roll(
+            RollScheme::V1,
+            parse_nonce(&"00".repeat(32)).unwrap(),
+            0,
+            roller,
+            "Hello, world! 🔗",
+            &multipliers,
+        );
+
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            json!({
+                "schema_version": 3,
+                "roll_scheme": "v1",
+                "preimage": "3030303030303030303030303030303030303030303030303030303030303030\
+                             3030303030303030303030303030303030303030303030303030303030303030\
+                             6e7075623133306e776e347435783868306836643938336c6673327834347a6e\
+                             7671657a75636b6c75726a7a77746e376376306337336378736a656d78333248\
+                             656c6c6f2c20776f726c642120f09f949730",
+                "digest": "9d6b99966ce4ff8ccc8c7d73058aa3273cb782adc86b3bdfee7a7f134d1135be",
+                "roll_bits": 16,
+                "roll": 40299,
+                "multipliers": [
+                    {
+                        "note_id": "under",
+                        "multiplier": 2.0,
+                        "direction": "Under",
+                        "threshold": 31784,
+                        "win": false,
+                    },
+                    {
+                        "note_id": "over",
+                        "multiplier": 2.0,
+                        "direction": "Over",
+                        "threshold": 33751,
+                        "win": true,
+                    },
+                ],
+            })
+        );
+
+        // Rounds rolling 32 bits compare the roll against scaled thresholds.
+        let response = verify_roll(
+            RollScheme::V3,
+            parse_nonce(&"00".repeat(32)).unwrap(),
+            0,
+            roller,
+            "Hello, world! 🔗",
+            &multipliers,
+        );
+        assert_eq!(response.roll_bits, 32);
+        assert_eq!(response.roll, 4_215_185_038);
+        assert_eq!(response.multipliers[0].threshold, 31_784 << 16);
+        assert!(!response.multipliers[0].win);
+        assert!(response.multipliers[1].win);
+    }
+
+    #[test]
+    fn fairness_document_carries_signed_game_rules() {
+        let main_keys = Keys::generate();
+        let nonce_keys = Keys::generate();
+        let multipliers = Multipliers(vec![
+            test_multiplier_note("under"),
+            MultiplierNote {
+                direction: BetDirection::Over,
+                ..test_multiplier_note("over")
+            },
+        ]);
+
+        let game = Game {
+            name: DEFAULT_GAME.to_string(),
+            main_keys: main_keys.clone(),
+            nonce_keys: nonce_keys.clone(),
+            social_keys: Keys::generate(),
+            client: nostr_sdk::Client::new(&main_keys),
+            multipliers,
+            multiplier_pins: MultiplierPins::default(),
+            schedules: vec![test_schedule(DEFAULT_SCHEDULE, 60)],
+        };
+
+        let fairness = fairness_document(&game, RollScheme::V2).unwrap();
+
+        assert_eq!(
+            fairness.roles[&nonce_keys.public_key().to_hex()],
+            "nonces".to_string()
+        );
+
+        fairness.rules.verify().unwrap();
+        assert_eq!(fairness.rules.author(), main_keys.public_key());
+
+        let rules: GameRules = serde_json::from_str(&fairness.rules.content).unwrap();
+        assert_eq!(
+            rules,
+            GameRules {
+                roll_scheme: RollScheme::V2,
+                multipliers: vec![
+                    MultiplierRule {
+                        note_id: "under".to_string(),
+                        multiplier: 2.0,
+                        direction: BetDirection::Under,
+                        threshold: 31_784,
+                        max_amount_sat: 50_000,
+                        min_amount_sat: None,
+                        win_probability: Multiplier::built_in("X2")
+                            .unwrap()
+                            .win_probability(RollScheme::V2),
+                        house_edge: Multiplier::built_in("X2")
+                            .unwrap()
+                            .house_edge(RollScheme::V2),
+                    },
+                    MultiplierRule {
+                        note_id: "over".to_string(),
+                        multiplier: 2.0,
+                        direction: BetDirection::Over,
+                        threshold: Multiplier::built_in("X2")
+                            .unwrap()
+                            .get_higher_than(RollScheme::V2),
+                        max_amount_sat: 50_000,
+                        min_amount_sat: None,
+                        win_probability: Multiplier::built_in("X2")
+                            .unwrap()
+                            .win_probability(RollScheme::V2),
+                        house_edge: Multiplier::built_in("X2")
+                            .unwrap()
+                            .house_edge(RollScheme::V2),
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn nip05_documents_advertise_the_relays_of_each_account() {
+        let main_keys = Keys::generate();
+        let nonce_keys = Keys::generate();
+        let game = Game {
+            name: DEFAULT_GAME.to_string(),
+            main_keys: main_keys.clone(),
+            nonce_keys: nonce_keys.clone(),
+            social_keys: Keys::generate(),
+            client: nostr_sdk::Client::new(&main_keys),
+            multipliers: Multipliers(Vec::new()),
+            multiplier_pins: MultiplierPins::default(),
+            schedules: vec![test_schedule(DEFAULT_SCHEDULE, 60)],
+        };
+        let games = Games::new(vec![game]).unwrap();
+        let relay_hints = RelayHints {
+            main: vec!["wss://relay.damus.io".to_string()],
+            nonce: vec!["wss://nos.lol".to_string()],
+            social: vec!["wss://relay.primal.net".to_string()],
+        };
+
+        let documents = Nip05Documents::new(&games, RollScheme::V1, &relay_hints).unwrap();
+
+        let document = documents.get(Some(NONCE_KEY_NAME), false);
+        let response: Nip05Response = serde_json::from_str(&document.body).unwrap();
+        assert_eq!(
+            response.names,
+            HashMap::from([(NONCE_KEY_NAME.to_string(), nonce_keys.public_key().to_hex())])
+        );
+        assert_eq!(
+            response.relays[&nonce_keys.public_key().to_hex()],
+            relay_hints.nonce
+        );
+        assert!(response.fairness.is_none());
+
+        // Unknown names get all our accounts, each with its own relays.
+        let document = documents.get(Some("satoshi"), false);
+        let response: Nip05Response = serde_json::from_str(&document.body).unwrap();
+        assert_eq!(response.names.len(), 3);
+        assert_eq!(
+            response.relays[&main_keys.public_key().to_hex()],
+            relay_hints.main
+        );
+        assert_eq!(documents.get(None, false).etag, document.etag);
+        assert_ne!(documents.get(None, true).etag, document.etag);
+    }
+
+    #[tokio::test]
+    async fn failing_to_store_the_zap_cancels_the_invoice() {
+        let cancelled = AtomicBool::new(false);
+
+        let result = persist_zap_or_cancel_invoice(
+            "payment_hash",
+            async { bail!("database is locked") },
+            async {
+                cancelled.store(true, Ordering::SeqCst);
+                Ok(())
+            },
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(cancelled.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn storing_the_zap_keeps_the_invoice() {
+        let cancelled = AtomicBool::new(false);
+
+        persist_zap_or_cancel_invoice("payment_hash", async { Ok(()) }, async {
+            cancelled.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert!(!cancelled.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn bets_close_before_the_round_expires() {
+        let now = OffsetDateTime::now_utc();
+        let expires_at = Some(now + time::Duration::seconds(10));
+
+        assert!(check_bet_cutoff(expires_at, 0, now).is_ok());
+        assert!(check_bet_cutoff(expires_at, 10, now).is_ok());
+
+        let error = check_bet_cutoff(expires_at, 11, now).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Bets close 11 seconds before the end of a round, and this round ends in 10 seconds. \
+             Please bet again on the next round."
+        );
+        assert!(check_bet_cutoff(expires_at, 5, now + time::Duration::seconds(20)).is_err());
+
+        // Rounds from before we recorded their expiry.
+        assert!(check_bet_cutoff(None, 5, now).is_ok());
+    }
+
+    #[test]
+    fn invoices_of_bets_expire_when_their_round_is_revealed() {
+        let now = OffsetDateTime::now_utc();
+        let round_of = |secs| Some(now + time::Duration::seconds(secs));
+
+        // Placed at the start of a 10 minute round, revealed a minute after it ends.
+        assert_eq!(invoice_expiry_secs(round_of(600), 60, 3_600, now), 650);
+        assert_eq!(invoice_expiry_secs(round_of(600), 60, 300, now), 300);
+
+        // Placed at the end of a round.
+        assert_eq!(invoice_expiry_secs(round_of(1), 60, 3_600, now), 51);
+        assert_eq!(invoice_expiry_secs(round_of(-5), 60, 3_600, now), 50);
+
+        // Revealed right after it ends, the invoice still expires before that.
+        assert_eq!(invoice_expiry_secs(round_of(-5), 5, 3_600, now), 1);
+
+        // Rounds from before we recorded their expiry.
+        assert_eq!(invoice_expiry_secs(None, 60, 3_600, now), 50);
+        assert_eq!(invoice_expiry_secs(None, 60, 30, now), 30);
+    }
+
+    #[test]
+    fn callback_urls_only_work_for_their_own_endpoint() {
+        let main_keys = Keys::generate();
+        let game = Game {
+            name: "halloween".to_string(),
+            main_keys: main_keys.clone(),
+            nonce_keys: Keys::generate(),
+            social_keys: Keys::generate(),
+            client: nostr_sdk::Client::new(&main_keys),
+            multipliers: Multipliers(Vec::new()),
+            multiplier_pins: MultiplierPins::default(),
+            schedules: vec![test_schedule(DEFAULT_SCHEDULE, 60)],
+        };
+        let path = |hash: String| HashMap::from([("hash".to_string(), hash)]);
+        let check = |roles: &[Role], hash: String| {
+            check_callback_hash(&game, roles, "nostrdice.com", &path(hash))
+        };
+        let zap_roles = [Role::Nonce, Role::Social];
+
+        let main_hash = callback_hash(&game, Role::Main, "nostrdice.com");
+        let social_hash = callback_hash(&game, Role::Social, "nostrdice.com");
+        assert!(check(&[Role::Main], main_hash.clone()).is_ok());
+        assert!(check(&[Role::Main], main_hash.to_uppercase()).is_ok());
+        assert!(check(&zap_roles, social_hash.clone()).is_ok());
+
+        // Callback URLs of one endpoint cannot be used for another.
+        assert!(check(&zap_roles, main_hash.clone()).is_err());
+        assert!(check(&[Role::Main], social_hash).is_err());
+        assert!(check(&[Role::Main], callback_hash(&game, Role::Main, "evil.com")).is_err());
+
+        // Tampered hashes are rejected.
+        let mut tampered = main_hash.into_bytes();
+        tampered[0] = if tampered[0] == b'0' { b'1' } else { b'0' };
+        let (_, Json(body)) =
+            check(&[Role::Main], String::from_utf8(tampered).unwrap()).unwrap_err();
+        assert_eq!(body["code"], "INVALID_CALLBACK_HASH");
+        assert!(check(&[Role::Main], String::new()).is_err());
+    }
+
+    #[test]
+    fn zap_memo_length_boundary() {
+        assert!(check_zap_memo_length("", 3).is_ok());
+        assert!(check_zap_memo_length("abc", 3).is_ok());
+        assert!(check_zap_memo_length("abcd", 3).is_err());
+
+        // Characters, not bytes, are counted.
+        assert!(check_zap_memo_length("🎲🎲🎲", 3).is_ok());
+        assert!(check_zap_memo_length("🎲🎲🎲🎲", 3).is_err());
+    }
+
+    #[test]
+    fn donation_comments_and_payer_data_end_up_in_the_memo() {
+        assert_eq!(
+            donation_memo("Donation", None, None, 10).unwrap(),
+            "Donation"
+        );
+        assert_eq!(
+            donation_memo("Donation", Some("gm"), Some(r#"{"name":"Alice"}"#), 10).unwrap(),
+            "Donation from Alice: gm"
+        );
+        assert_eq!(
+            donation_memo("Donation", None, Some(r#"{"pubkey":"02ab"}"#), 10).unwrap(),
+            "Donation from 02ab"
+        );
+
+        let error = donation_memo("Donation", Some("a long comment"), None, 10).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<RouteError>(),
+            Some(&RouteError::CommentTooLong {
+                chars: 14,
+                max_chars: 10
+            })
+        );
+        assert!(donation_memo("Donation", None, Some("Alice"), 10).is_err());
+
+        let memo = donation_memo(&"🎲".repeat(200), None, None, 10).unwrap();
+        assert!(memo.len() <= MAX_INVOICE_MEMO_BYTES);
+        assert!(memo.chars().all(|c| c == '🎲'));
+    }
+
+    #[test]
+    fn donation_invoices_commit_to_the_metadata_and_payer_data() {
+        let metadata = lnurl_metadata("nostrdice_nonce", "nostrdice.com");
+        let payer_data = r#"{"name":"Alice"}"#;
+
+        assert_eq!(
+            donation_description_hash(&metadata, None),
+            sha256::Hash::hash(metadata.as_bytes())
+                .to_byte_array()
+                .to_vec()
+        );
+        assert_eq!(
+            donation_description_hash(&metadata, Some(payer_data)),
+            sha256::Hash::hash(format!("{metadata}{payer_data}").as_bytes())
+                .to_byte_array()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn game_account_advertises_the_highest_maximum_bet() {
+        let multipliers = Multipliers(
+            [
+                (Multiplier::built_in("X2").unwrap(), "note1x2", None),
+                (
+                    Multiplier::built_in("X10").unwrap(),
+                    "note1x10",
+                    Some(60_000),
+                ),
+            ]
+            .into_iter()
+            .map(|(multiplier, note_id, max_amount_sat)| MultiplierNote {
+                multiplier,
+                max_amount_sat,
+                ..test_multiplier_note(note_id)
+            })
+            .collect(),
+        );
+        let nonce = Sendable {
+            min_msat: 1_000,
+            max_msat: 11_000_000_000,
+        };
+        let mut limits = SendableLimits {
+            main_min_msat: 10_000,
+            main_max_msat: None,
+            nonce,
+            social: nonce,
+        };
+
+        assert_eq!(
+            limits.for_role(Role::Main, &multipliers),
+            Sendable {
+                min_msat: 10_000,
+                max_msat: 60_000_000,
+            }
+        );
+        assert_eq!(limits.for_role(Role::Nonce, &multipliers), nonce);
+
+        limits.main_max_msat = Some(20_000_000);
+        assert_eq!(
+            limits.for_role(Role::Main, &multipliers).max_msat,
+            20_000_000
+        );
+    }
+
+    #[test]
+    fn bet_terms_state_the_bet() {
+        let multiplier_note = MultiplierNote {
+            direction: BetDirection::Over,
+            ..test_multiplier_note("note1multiplier")
+        };
+
+        let terms = bet_terms_dm(
+            EventId::all_zeros(),
+            RollScheme::V1,
+            &multiplier_note,
+            21_000,
+            7,
+        );
+
+        assert!(terms.starts_with("If you pay the invoice, you bet 21 sats"));
+        assert!(terms.contains("bigger than 33751"));
+        assert!(terms.contains("by 2x"));
+        assert!(terms.contains("number 7 of the round"));
+        assert!(terms.contains(&EventId::all_zeros().to_bech32().unwrap()));
+        assert!(terms.contains("nostr:note1multiplier"));
+    }
+
+    #[test]
+    fn leaderboard_ranks_rollers_by_net_winnings() {
+        let winner = Keys::generate().public_key();
+        let loser = Keys::generate().public_key();
+        let totals = |roller, multiplier, bet_state, bets, amount_msat| db::RollerTotals {
+            roller,
+            multiplier,
+            bet_state,
+            bets,
+            amount_msat,
+        };
+
+        let entries = leaderboard(
+            vec![
+                totals(
+                    loser,
+                    Multiplier::built_in("X2").unwrap(),
+                    BetState::Loser,
+                    3,
+                    30_000_000,
+                ),
+                totals(
+                    winner,
+                    Multiplier::built_in("X2").unwrap(),
+                    BetState::Loser,
+                    1,
+                    1_000_000,
+                ),
+                totals(
+                    winner,
+                    Multiplier::built_in("X3").unwrap(),
+                    BetState::PaidWinner,
+                    2,
+                    2_000_000,
+                ),
+            ],
+            10,
+            PayoutRounding::Floor,
+            &HashSet::new(),
+        );
+
+        assert_eq!(
+            entries,
+            [
+                LeaderboardEntry {
+                    roller: winner.to_bech32().unwrap(),
+                    bets: 3,
+                    wagered_sat: 3_000,
+                    paid_sat: 6_000,
+                    net_sat: 3_000,
+                },
+                LeaderboardEntry {
+                    roller: loser.to_bech32().unwrap(),
+                    bets: 3,
+                    wagered_sat: 30_000,
+                    paid_sat: 0,
+                    net_sat: -30_000,
+                },
+            ]
+        );
+        assert_eq!(
+            leaderboard(Vec::new(), 10, PayoutRounding::Floor, &HashSet::new()),
+            []
+        );
+    }
+
+    #[test]
+    fn anonymous_rollers_are_not_on_the_leaderboard() {
+        let anonymous = Keys::generate().public_key();
+        let named = Keys::generate().public_key();
+        let totals = [anonymous, named]
+            .into_iter()
+            .map(|roller| db::RollerTotals {
+                roller,
+                multiplier: Multiplier::built_in("X2").unwrap(),
+                bet_state: BetState::PaidWinner,
+                bets: 1,
+                amount_msat: 1_000_000,
+            })
+            .collect();
+
+        let entries = leaderboard(
+            totals,
+            10,
+            PayoutRounding::Floor,
+            &HashSet::from([anonymous]),
+        );
+
+        assert_eq!(
+            entries
+                .into_iter()
+                .map(|entry| entry.roller)
+                .collect::<Vec<_>>(),
+            [named.to_bech32().unwrap()]
+        );
+    }
+
+    #[test]
+    fn leaderboard_windows() {
+        assert_eq!(parse_window("7d").unwrap(), time::Duration::days(7));
+        assert_eq!(parse_window("24h").unwrap(), time::Duration::hours(24));
+        assert_eq!(parse_window("30m").unwrap(), time::Duration::minutes(30));
+
+        for window in ["", "d", "7", "-7d", "7w", "7é"] {
+            assert!(parse_window(window).is_err(), "{window}");
+        }
+
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(
+            window_start(now, "7d").unwrap(),
+            now - time::Duration::days(7)
+        );
+        assert!(window_start(now, "4294967295d").is_err());
+    }
+}
@@ -3,25 +3,157 @@ use crate::db::BetState;
 use crate::db::Zap;
 use crate::multiplier::Multiplier;
 use crate::multiplier::Multipliers;
+use crate::payouts::calculate_price_money;
+use crate::payouts::PayoutRounding;
+use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
+use nostr::Event;
 use nostr::EventBuilder;
 use nostr::EventId;
 use nostr::PublicKey;
 use nostr::ToBech32;
 use sqlx::SqlitePool;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::sync::Arc;
 use time::Duration;
 use time::OffsetDateTime;
-use tokio::time::sleep;
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::time::sleep_until;
+use tokio::time::Instant;
+use yaml_rust2::YamlLoader;
+
+/// A winner who was just paid out.
+#[derive(Debug, Clone)]
+pub struct WinnerPaid {
+    pub roller: PublicKey,
+    pub multiplier: Multiplier,
+    pub amount_sat: u64,
+}
+
+/// The phrasing of social updates, with `{placeholder}`s filled in for every update.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SummaryTemplates {
+    /// Placeholders: `{roller}`, `{amount}` (the payout in sats) and `{multiplier}`.
+    pub highlight: String,
+    /// Placeholders: `{minutes}`, `{count}` (rolls) and `{winners}` (winning rolls).
+    pub headline: String,
+    pub winners_heading: String,
+    /// Placeholders: `{roller}`, `{multiplier}` and `{amount}` (the bet in sats).
+    pub winner: String,
+    pub losers_heading: String,
+    /// Placeholders: `{roller}`.
+    pub loser: String,
+    /// Placeholders: `{count}` (losers who were not named).
+    pub more_losers: String,
+    /// Placeholders: `{game}` and `{nonce}` (the npubs of the main and nonce accounts).
+    pub closing: String,
+    /// Placeholders: `{roller}`, `{amount}` (the payout in sats) and `{multiplier}`.
+    pub big_win: String,
+}
+
+impl Default for SummaryTemplates {
+    fn default() -> Self {
+        Self {
+            highlight: "Biggest win: {roller} won {amount} sats with a {multiplier} multiplier!"
+                .to_string(),
+            headline:
+                "Winner winner, chicken dinner! Thank you to everyone who played in the last \
+                       {minutes} minutes. Out of {count} rolls, {winners} were winning rolls. \
+                       Congrats!"
+                    .to_string(),
+            winners_heading: "Winners:".to_string(),
+            winner: "- {roller}: won {multiplier} x {amount}sats".to_string(),
+            losers_heading: "Player's that didn't win this time - please try again:".to_string(),
+            loser: "- {roller}".to_string(),
+            more_losers: "- and {count} more".to_string(),
+            closing: "Do you have what it takes? Follow nostr:{game} for another round and \
+                      nostr:{nonce} for the published nonces"
+                .to_string(),
+            big_win: "Big win! {roller} just won {amount} sats by hitting a {multiplier} \
+                      multiplier on NostrDice!"
+                .to_string(),
+        }
+    }
+}
+
+impl SummaryTemplates {
+    /// Parse a YAML templates file. Every template is optional and falls back to its default.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let docs = YamlLoader::load_from_str(contents).context("Invalid templates file")?;
+        let mut templates = Self::default();
+
+        let Some(doc) = docs.first() else {
+            return Ok(templates);
+        };
+        let Some(entries) = doc.as_hash() else {
+            bail!("Templates file must be a mapping of template names to templates");
+        };
+
+        for (key, value) in entries {
+            let (Some(key), Some(value)) = (key.as_str(), value.as_str()) else {
+                bail!("Invalid template {key:?}: {value:?}");
+            };
+
+            let template = match key {
+                "highlight" => &mut templates.highlight,
+                "headline" => &mut templates.headline,
+                "winners_heading" => &mut templates.winners_heading,
+                "winner" => &mut templates.winner,
+                "losers_heading" => &mut templates.losers_heading,
+                "loser" => &mut templates.loser,
+                "more_losers" => &mut templates.more_losers,
+                "closing" => &mut templates.closing,
+                "big_win" => &mut templates.big_win,
+                _ => bail!("Unknown template {key}"),
+            };
+            *template = value.to_string();
+        }
+
+        Ok(templates)
+    }
+}
+
+/// Replace every `{name}` in the `template` with its value.
+fn render(template: &str, values: &[(&str, String)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |rendered, (name, value)| {
+            rendered.replace(&format!("{{{name}}}"), value)
+        })
+}
+
+/// Limits keeping a summary publishable on relays which reject big events.
+#[derive(Debug, Clone, Copy)]
+pub struct SummaryLimits {
+    /// Longer summaries are split into a thread of notes of at most this many bytes each.
+    pub max_note_bytes: usize,
+    /// How many losers are listed by name. The rest are only counted.
+    pub max_losers_named: usize,
+}
 
 /// Posts updates on nostr every {TIME_WINDOW}minutes.
+///
+/// In between updates, any payout bigger than `big_win_threshold_sat` is announced immediately.
+#[allow(clippy::too_many_arguments)]
 pub async fn post_social_updates(
     client: nostr_sdk::Client,
     keys: nostr::Keys,
     db: SqlitePool,
     multipliers: Multipliers,
+    payout_rounding: PayoutRounding,
     game: PublicKey,
     nonce: PublicKey,
     time_window_minutes: u64,
+    big_win_threshold_sat: Option<u64>,
+    summary_limits: SummaryLimits,
+    templates: SummaryTemplates,
+    anonymous_rollers: Arc<HashSet<PublicKey>>,
+    mut winners: mpsc::UnboundedReceiver<WinnerPaid>,
+    mut ctrl_c: broadcast::Receiver<()>,
 ) {
     loop {
         if let Err(err) = post_social_inner(
@@ -29,30 +161,89 @@ pub async fn post_social_updates(
             keys.clone(),
             db.clone(),
             multipliers.clone(),
+            payout_rounding,
             game,
             nonce,
             time_window_minutes,
+            summary_limits,
+            &templates,
+            &anonymous_rollers,
         )
         .await
         {
             tracing::error!("Could not post social update {err:#}");
         }
-        sleep(tokio::time::Duration::from_secs(time_window_minutes * 60)).await;
+
+        let next_update =
+            Instant::now() + tokio::time::Duration::from_secs(time_window_minutes * 60);
+        loop {
+            select! {
+                _ = sleep_until(next_update) => break,
+                _ = ctrl_c.recv() => {
+                    tracing::warn!("Got Ctrl+C; shutting down social updates...");
+                    return;
+                },
+                Some(winner) = winners.recv() => {
+                    let msg = match big_win_announcement(
+                        &winner,
+                        big_win_threshold_sat,
+                        &templates,
+                        &anonymous_rollers,
+                    ) {
+                        Some(msg) => msg,
+                        None => continue,
+                    };
+
+                    match publish_note(&client, &keys, msg).await {
+                        Ok(note_id) => tracing::debug!("Published big win announcement: {note_id}"),
+                        Err(err) => tracing::error!("Could not post big win announcement {err:#}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the dedicated announcement for a payout bigger than the `threshold_sat`, if any.
+fn big_win_announcement(
+    winner: &WinnerPaid,
+    threshold_sat: Option<u64>,
+    templates: &SummaryTemplates,
+    anonymous_rollers: &HashSet<PublicKey>,
+) -> Option<String> {
+    let threshold_sat = threshold_sat?;
+
+    if winner.amount_sat <= threshold_sat {
+        return None;
     }
+
+    Some(render(
+        &templates.big_win,
+        &[
+            ("roller", mention(&winner.roller, anonymous_rollers)),
+            ("amount", winner.amount_sat.to_string()),
+            ("multiplier", winner.multiplier.get_content()),
+        ],
+    ))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn post_social_inner(
     client: nostr_sdk::Client,
     keys: nostr::Keys,
     db: SqlitePool,
     multipliers: Multipliers,
+    payout_rounding: PayoutRounding,
     game: PublicKey,
     nonce: PublicKey,
     time_window_minutes: u64,
+    summary_limits: SummaryLimits,
+    templates: &SummaryTemplates,
+    anonymous_rollers: &HashSet<PublicKey>,
 ) -> Result<()> {
     let now = OffsetDateTime::now_utc();
     let last_announcement_cut_off = now - Duration::minutes(time_window_minutes as i64);
-    let zaps = db::get_zaps_in_time_window(&db, last_announcement_cut_off, now).await?;
+    let zaps = db::get_settled_zaps_in_time_window(&db, last_announcement_cut_off, now).await?;
 
     let winners = filter_zaps(&multipliers, &zaps, BetState::PaidWinner);
 
@@ -63,24 +254,101 @@ async fn post_social_inner(
 
     let losers = filter_zaps(&multipliers, &zaps, BetState::Loser);
 
-    let msg = format!("Winner winner, chicken dinner! Thank you to everyone who played in the last {} minutes. Out of {} rolls, {} were winning rolls. Congrats!", time_window_minutes, winners.len() + losers.len(), winners.len());
-    let closing_message = format!(
-        "Do you have what it takes? Follow nostr:{} for another round and nostr:{} for the published nonces",
-        game.to_bech32().expect("npub"), nonce.to_bech32().expect("npub")
+    let msg = render(
+        &templates.headline,
+        &[
+            ("minutes", time_window_minutes.to_string()),
+            ("count", (winners.len() + losers.len()).to_string()),
+            ("winners", winners.len().to_string()),
+        ],
+    );
+    let closing_message = render(
+        &templates.closing,
+        &[
+            ("game", game.to_bech32().expect("npub")),
+            ("nonce", nonce.to_bech32().expect("npub")),
+        ],
     );
 
-    let winners_string = format_winners(&winners);
-    let losers_string = format_losers(losers, winners);
+    let mut lines = Vec::new();
+    if let Some((roller, multiplier, amount_sat)) =
+        biggest_win(&multipliers, &zaps, payout_rounding)
+    {
+        lines.push(render(
+            &templates.highlight,
+            &[
+                ("roller", mention(&roller, anonymous_rollers)),
+                ("amount", amount_sat.to_string()),
+                ("multiplier", multiplier.get_content()),
+            ],
+        ));
+    }
+    lines.push(msg);
+    lines.extend(format_winners(&winners, templates, anonymous_rollers));
+    lines.extend(format_losers(
+        losers,
+        winners,
+        summary_limits.max_losers_named,
+        templates,
+        anonymous_rollers,
+    ));
+    lines.push(closing_message);
 
-    let msg = format!(
-        "{} \n {}\n{}\n{}",
-        msg, winners_string, losers_string, closing_message
-    );
-    let note_id = publish_note(&client, &keys, msg).await?;
+    let notes = paginate(lines, summary_limits.max_note_bytes);
+    let note_id = publish_thread(&client, &keys, notes).await?;
     tracing::debug!("Published game summary: {note_id}",);
     Ok(())
 }
 
+/// Pack the `lines` into as few notes of at most `max_note_bytes` each as possible, keeping them in
+/// order. A line longer than the limit gets a note of its own.
+fn paginate(lines: Vec<String>, max_note_bytes: usize) -> Vec<String> {
+    let mut notes = Vec::new();
+    let mut note = String::new();
+
+    for line in lines {
+        if !note.is_empty() && note.len() + 1 + line.len() > max_note_bytes {
+            notes.push(std::mem::take(&mut note));
+        }
+
+        if !note.is_empty() {
+            note.push('\n');
+        }
+        note.push_str(&line);
+    }
+
+    if !note.is_empty() {
+        notes.push(note);
+    }
+
+    notes
+}
+
+/// The winner with the biggest payout in sats, and the multiplier they hit. Of several winners with
+/// the same payout, the one who bet first.
+fn biggest_win(
+    multipliers: &Multipliers,
+    zaps: &[Zap],
+    payout_rounding: PayoutRounding,
+) -> Option<(PublicKey, Multiplier, u64)> {
+    zaps.iter()
+        .filter(|zap| zap.bet_state == BetState::PaidWinner)
+        .filter_map(|zap| {
+            let multiplier = multipliers
+                .get_multiplier_note(&zap.multiplier_note_id)?
+                .multiplier;
+            let amount_sat = calculate_price_money(
+                zap.invoice.amount_milli_satoshis().unwrap_or_default(),
+                multiplier.get_multiplier(),
+                payout_rounding,
+            );
+
+            Some((zap, multiplier, amount_sat))
+        })
+        .min_by_key(|(zap, _, amount_sat)| (Reverse(*amount_sat), zap.bet_timestamp))
+        .map(|(zap, multiplier, amount_sat)| (zap.roller, multiplier, amount_sat))
+}
+
 fn filter_zaps(
     multipliers: &Multipliers,
     zaps: &[Zap],
@@ -108,27 +376,58 @@ fn filter_zaps(
         .collect::<Vec<_>>()
 }
 
-fn format_winners(winners: &Vec<(PublicKey, Multiplier, u64)>) -> String {
+/// The rollers who asked not to be named in social updates, one npub per line. Empty lines and
+/// lines starting with `#` are ignored.
+pub fn parse_anonymous_rollers(contents: &str) -> Result<HashSet<PublicKey>> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| PublicKey::parse(line).with_context(|| format!("Invalid npub: {line}")))
+        .collect()
+}
+
+/// How the `roller` is referred to publicly: tagged, unless they asked not to be named.
+fn mention(roller: &PublicKey, anonymous_rollers: &HashSet<PublicKey>) -> String {
+    if anonymous_rollers.contains(roller) {
+        return "anonymous".to_string();
+    }
+
+    format!("nostr:{}", roller.to_bech32().expect("npub"))
+}
+
+fn format_winners(
+    winners: &Vec<(PublicKey, Multiplier, u64)>,
+    templates: &SummaryTemplates,
+    anonymous_rollers: &HashSet<PublicKey>,
+) -> Vec<String> {
     if winners.is_empty() {
-        return String::new();
+        return Vec::new();
     }
-    let mut message = String::from("Winners:\n");
+    let mut lines = vec![templates.winners_heading.clone()];
     for (pubkey, multiplier, amount) in winners {
-        message.push_str(&format!(
-            "- nostr:{}: won {} x {}sats \n",
-            pubkey.to_bech32().expect("npub"),
-            multiplier.get_multiplier(),
-            amount / 1000
+        lines.push(render(
+            &templates.winner,
+            &[
+                ("roller", mention(pubkey, anonymous_rollers)),
+                ("multiplier", multiplier.get_multiplier().to_string()),
+                ("amount", (amount / 1000).to_string()),
+            ],
         ));
     }
-    message
+    lines
 }
+
+/// Lists at most `max_named` of the `players` who did not win, and how many more there were.
 fn format_losers(
     players: Vec<(PublicKey, Multiplier, u64)>,
     winners: Vec<(PublicKey, Multiplier, u64)>,
-) -> String {
+    max_named: usize,
+    templates: &SummaryTemplates,
+    anonymous_rollers: &HashSet<PublicKey>,
+) -> Vec<String> {
     if players.is_empty() {
-        return String::new();
+        return Vec::new();
     }
     let winners = winners
         .into_iter()
@@ -136,7 +435,6 @@ fn format_losers(
         .collect::<Vec<_>>();
 
     let mut tmp_list = vec![];
-    let mut message = String::from("Player's that didn't win this time - please try again:\n");
     for (pubkey, _, _) in players {
         // we don't want duplicates
         if tmp_list.contains(&pubkey) {
@@ -147,10 +445,54 @@ fn format_losers(
             continue;
         }
 
-        message.push_str(&format!("- nostr:{}\n", pubkey.to_bech32().expect("npub")));
         tmp_list.push(pubkey);
     }
-    message
+
+    if tmp_list.is_empty() {
+        return Vec::new();
+    }
+
+    let mut lines = vec![templates.losers_heading.clone()];
+    for pubkey in tmp_list.iter().take(max_named) {
+        lines.push(render(
+            &templates.loser,
+            &[("roller", mention(pubkey, anonymous_rollers))],
+        ));
+    }
+    if tmp_list.len() > max_named {
+        lines.push(render(
+            &templates.more_losers,
+            &[("count", (tmp_list.len() - max_named).to_string())],
+        ));
+    }
+    lines
+}
+
+/// Publish the `notes` as a thread: the first note is the root and every other note a reply to the
+/// one before it, as described in NIP-10. Returns the ID of the root note.
+async fn publish_thread(
+    client: &nostr_sdk::Client,
+    keys: &nostr::Keys,
+    notes: Vec<String>,
+) -> Result<EventId> {
+    let mut thread: Vec<Event> = Vec::new();
+    for note in notes {
+        let builder = match (thread.first(), thread.last()) {
+            (Some(root), Some(parent)) => {
+                EventBuilder::text_note_reply(note, parent, Some(root), None)
+            }
+            _ => EventBuilder::text_note(note, []),
+        };
+        let event = builder.to_event(keys)?;
+
+        client.send_event(event.clone()).await?;
+        thread.push(event);
+    }
+
+    thread
+        .first()
+        .map(|root| root.id)
+        .context("Summary without notes")
 }
 
 async fn publish_note(
@@ -164,3 +506,210 @@ async fn publish_note(
 
     Ok(event_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multiplier::test_multiplier_note;
+
+    fn winner(amount_sat: u64) -> WinnerPaid {
+        WinnerPaid {
+            roller: PublicKey::parse(
+                "npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32",
+            )
+            .unwrap(),
+            multiplier: Multiplier::built_in("X1000").unwrap(),
+            amount_sat,
+        }
+    }
+
+    #[test]
+    fn big_win_is_announced() {
+        let msg = big_win_announcement(
+            &winner(100_000),
+            Some(50_000),
+            &SummaryTemplates::default(),
+            &HashSet::new(),
+        )
+        .unwrap();
+
+        assert!(msg.contains("100000 sats"));
+        assert!(msg.contains("1000x"));
+    }
+
+    #[test]
+    fn small_win_is_not_announced() {
+        assert!(big_win_announcement(
+            &winner(50_000),
+            Some(50_000),
+            &SummaryTemplates::default(),
+            &HashSet::new()
+        )
+        .is_none());
+        assert!(big_win_announcement(
+            &winner(1_000),
+            Some(50_000),
+            &SummaryTemplates::default(),
+            &HashSet::new()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn no_announcement_without_threshold() {
+        assert!(big_win_announcement(
+            &winner(100_000),
+            None,
+            &SummaryTemplates::default(),
+            &HashSet::new()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn long_summaries_are_split_into_notes() {
+        let lines = vec!["a".repeat(6), "b".repeat(3), "c".repeat(10), "d".repeat(2)];
+
+        let notes = paginate(lines, 10);
+
+        assert_eq!(notes, ["aaaaaa\nbbb", "cccccccccc", "dd"]);
+        assert!(paginate(Vec::new(), 10).is_empty());
+    }
+
+    #[test]
+    fn only_some_losers_are_named() {
+        let players = (0..5)
+            .map(|_| {
+                (
+                    nostr::Keys::generate().public_key(),
+                    Multiplier::built_in("X2").unwrap(),
+                    1_000,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let lines = format_losers(
+            players.clone(),
+            Vec::new(),
+            3,
+            &SummaryTemplates::default(),
+            &HashSet::new(),
+        );
+        assert_eq!(lines.len(), 1 + 3 + 1);
+        assert_eq!(lines[4], "- and 2 more");
+
+        let lines = format_losers(
+            players,
+            Vec::new(),
+            5,
+            &SummaryTemplates::default(),
+            &HashSet::new(),
+        );
+        assert_eq!(lines.len(), 1 + 5);
+    }
+
+    #[test]
+    fn anonymous_rollers_are_not_named() {
+        let anonymous = winner(100_000);
+        let anonymous_rollers = parse_anonymous_rollers(
+            "# Asked not to be named\n\nnpub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32\n",
+        )
+        .unwrap();
+        assert!(anonymous_rollers.contains(&anonymous.roller));
+
+        let msg = big_win_announcement(
+            &anonymous,
+            Some(50_000),
+            &SummaryTemplates::default(),
+            &anonymous_rollers,
+        )
+        .unwrap();
+        assert!(msg.starts_with("Big win! anonymous just won"));
+
+        let named = nostr::Keys::generate().public_key();
+        let players = vec![
+            (anonymous.roller, Multiplier::built_in("X2").unwrap(), 1_000),
+            (named, Multiplier::built_in("X2").unwrap(), 1_000),
+        ];
+
+        let lines = format_winners(&players, &SummaryTemplates::default(), &anonymous_rollers);
+        assert_eq!(lines[1], "- anonymous: won 2 x 1sats");
+        assert!(lines[2].contains(&named.to_bech32().unwrap()));
+
+        let lines = format_losers(
+            players,
+            Vec::new(),
+            50,
+            &SummaryTemplates::default(),
+            &anonymous_rollers,
+        );
+        assert_eq!(lines[1], "- anonymous");
+        assert!(lines[2].contains(&named.to_bech32().unwrap()));
+
+        assert!(parse_anonymous_rollers("alice").is_err());
+    }
+
+    #[test]
+    fn templates_fall_back_to_the_defaults() {
+        let templates = SummaryTemplates::parse(
+            "winners_heading: Gewinner:\nwinner: \"- {roller} gewinnt {amount} sats mit {multiplier}x\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(templates.winners_heading, "Gewinner:");
+        assert_eq!(templates.closing, SummaryTemplates::default().closing);
+
+        let lines = format_winners(
+            &vec![(winner(1).roller, Multiplier::built_in("X2").unwrap(), 5_000)],
+            &templates,
+            &HashSet::new(),
+        );
+        assert_eq!(
+            lines,
+            [
+                "Gewinner:",
+                "- nostr:npub130nwn4t5x8h0h6d983lfs2x44znvqezucklurjzwtn7cv0c73cxsjemx32 gewinnt \
+                 5 sats mit 2x"
+            ]
+        );
+
+        assert_eq!(
+            SummaryTemplates::parse("").unwrap(),
+            SummaryTemplates::default()
+        );
+        assert!(SummaryTemplates::parse("headlines: typo").is_err());
+    }
+
+    #[test]
+    fn biggest_win_goes_to_the_first_of_the_biggest_payouts() {
+        use crate::db::test_zap;
+        use crate::multiplier::MultiplierNote;
+
+        let note = |multiplier, note_id: &str| MultiplierNote {
+            multiplier,
+            ..test_multiplier_note(note_id)
+        };
+        let multipliers = Multipliers(vec![
+            note(Multiplier::built_in("X2").unwrap(), "note1x2"),
+            note(Multiplier::built_in("X3").unwrap(), "note1x3"),
+        ]);
+
+        let bet = |note_id, bet_state, minutes_ago| Zap {
+            bet_timestamp: OffsetDateTime::now_utc() - Duration::minutes(minutes_ago),
+            ..test_zap(note_id, EventId::all_zeros(), bet_state)
+        };
+        let later = bet("note1x3", BetState::PaidWinner, 1);
+        let first = bet("note1x3", BetState::PaidWinner, 2);
+        let smaller = bet("note1x2", BetState::PaidWinner, 3);
+        let loser = bet("note1x3", BetState::Loser, 4);
+
+        let zaps = [later, first.clone(), smaller, loser];
+        let (roller, multiplier, amount_sat) =
+            biggest_win(&multipliers, &zaps, PayoutRounding::Floor).unwrap();
+
+        assert_eq!(roller, first.roller);
+        assert_eq!(multiplier.get_content(), "3x");
+        assert_eq!(amount_sat, 3_000);
+        assert!(biggest_win(&multipliers, &zaps[3..], PayoutRounding::Floor).is_none());
+    }
+}
@@ -1,58 +1,254 @@
+use crate::db::delete_held_zap_receipt;
+use crate::db::get_anon_donation;
+use crate::db::get_invoice_settle_index;
+use crate::db::get_rolled_zap_receipts;
 use crate::db::get_zap;
-use crate::db::upsert_zap;
+use crate::db::hold_zap_receipt;
+use crate::db::record_invoice_settle_index;
+use crate::db::record_unexpected_settlement;
+use crate::db::set_settled_at;
+use crate::db::settle_anon_donation;
+use crate::db::transition_bet_state;
+use crate::db::AnonDonation;
 use crate::db::BetState;
+use crate::db::HeldZapReceipt;
 use crate::db::Zap;
-use crate::multiplier::Multipliers;
-use crate::nonce;
-use crate::payouts;
+use crate::dm::send_dm;
+use crate::dm::DmSender;
+use crate::game::Game;
+use crate::game::Games;
+use crate::game::ROLES;
+use crate::metrics::METRICS;
+use crate::multiplier::BetDirection;
+use crate::multiplier::MultiplierNote;
+use crate::nonce::get_revealed_round;
+use crate::nonce::get_round;
+use crate::receipt_clients::ReceiptClients;
+use crate::relay_blacklist::RelayBlacklist;
+use crate::roll::RollScheme;
 use crate::utils;
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
+use bitcoin::hashes::sha256;
 use bitcoin::hashes::Hash;
-use bitcoin::key::Secp256k1;
-use bitcoin::secp256k1::SecretKey;
-use lightning_invoice::Currency;
-use lightning_invoice::InvoiceBuilder;
-use lightning_invoice::PaymentSecret;
 use nostr::prelude::ToBech32;
+use nostr::Event;
 use nostr::EventBuilder;
 use nostr::EventId;
 use nostr::Keys;
+use nostr::Tag;
 use nostr_sdk::Client;
-use nostr_sdk::Options;
 use sqlx::SqlitePool;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tonic_openssl_lnd::lnrpc;
 use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
 use tonic_openssl_lnd::LndLightningClient;
 
+/// Tags the zap receipt of a bet with the threshold its roll has to beat.
+const THRESHOLD_TAG: &str = "threshold";
+/// Tags the zap receipt of a bet with whether its roll has to be under or over the threshold.
+const DIRECTION_TAG: &str = "direction";
+/// Tags the zap receipt of a bet with its roll.
+const ROLL_TAG: &str = "roll";
+/// Tags the zap receipt of a bet with whether it won or lost.
+const OUTCOME_TAG: &str = "outcome";
+
+/// How often the held zap receipts of rolled bets are published.
+const HELD_RECEIPTS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait before resubscribing to invoices the first time the subscription dies. Doubled
+/// on every further attempt, up to [`MAX_RESUBSCRIBE_BACKOFF`].
+const MIN_RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// A subscription lasting this long was healthy, so that the backoff starts over once it dies.
+const HEALTHY_SUBSCRIPTION: Duration = Duration::from_secs(60);
+
+/// What handling the invoices paid to the LND node shared by all the `games` takes.
+#[derive(Clone)]
+pub struct PaidInvoices {
+    pub games: Games,
+    /// If not empty, zap receipts are only published to the requested relays on this list.
+    pub receipt_relay_allow_list: Vec<String>,
+    pub relay_blacklist: RelayBlacklist,
+    /// The clients zap receipts are published with.
+    pub receipt_clients: ReceiptClients,
+    /// Whether the zap receipts of bets are held until they are rolled, and tagged with their roll
+    /// and what it had to beat.
+    pub receipt_bet_tags: bool,
+    /// Whether donations without a zap request are publicly thanked for.
+    pub anon_donation_thanks: bool,
+    /// Lets rollers know that we received their bet.
+    pub dms: DmSender,
+    /// How long after a round stops taking bets its nonce is revealed, as told to its rollers.
+    pub reveal_nonce_after_secs: u64,
+    /// Lets the settlement task of each game know about bets paid after their round was revealed,
+    /// by game name.
+    pub revealed: Arc<HashMap<String, mpsc::UnboundedSender<EventId>>>,
+}
+
+/// Handle the invoices paid to the LND node as set up in `paid_invoices`, giving up on each one
+/// after the `handler_timeout`.
+///
+/// On Ctrl+C, the paid invoices being handled are handled to the end before the receipt clients
+/// are disconnected.
 pub async fn start_invoice_subscription(
     db: SqlitePool,
     mut lnd: LndLightningClient,
-    key: Keys,
-    client: Client,
-    multipliers: Multipliers,
+    paid_invoices: PaidInvoices,
+    handler_timeout: Duration,
+    mut ctrl_c: broadcast::Receiver<()>,
 ) {
+    // So that we also get the invoices settled while resubscribing or not running.
+    let handled_settle_index = match get_invoice_settle_index(&db).await {
+        Ok(settle_index) => settle_index,
+        Err(e) => {
+            tracing::error!("Failed to get invoice settle index, not catching up: {e:#}");
+            0
+        }
+    };
+    let settle_indexes = Arc::new(Mutex::new(SettleIndexes::new(handled_settle_index)));
+    let mut backoff = MIN_RESUBSCRIBE_BACKOFF;
+    let mut handlers = JoinSet::new();
+
     loop {
-        tracing::info!("Starting invoice subscription");
+        let settle_index = settle_indexes.lock().expect("not poisoned").received;
+        tracing::info!(settle_index, "Starting invoice subscription");
 
-        let sub = lnrpc::InvoiceSubscription::default();
-        if let Err(e) = start_subscription(&mut lnd, sub, &db, &key, &client, &multipliers).await {
-            tracing::error!(
-                "Invoice subscription died, waiting 10 seconds before reconnecting: {e:#}"
-            );
-            tokio::time::sleep(Duration::from_secs(10)).await
+        let sub = invoice_subscription(settle_index);
+        let started_at = Instant::now();
+        let subscription = start_subscription(
+            &mut lnd,
+            sub,
+            &settle_indexes,
+            &db,
+            &paid_invoices,
+            handler_timeout,
+            &mut handlers,
+        );
+
+        let res = tokio::select! {
+            res = subscription => Some(res),
+            _ = ctrl_c.recv() => None,
         };
+        let Some(res) = res else {
+            tracing::warn!("Got Ctrl+C; shutting down invoice subscription...");
+            drain_invoice_handlers(handlers, &paid_invoices.receipt_clients).await;
+            return;
+        };
+
+        let e = res
+            .err()
+            .unwrap_or_else(|| anyhow!("Invoice subscription ended"));
+
+        if started_at.elapsed() >= HEALTHY_SUBSCRIPTION {
+            backoff = MIN_RESUBSCRIBE_BACKOFF;
+        }
+
+        tracing::error!(
+            settle_index = settle_indexes.lock().expect("not poisoned").received,
+            "Invoice subscription died, waiting {} seconds before reconnecting: {e:#}",
+            backoff.as_secs()
+        );
+
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => (),
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down invoice subscription...");
+                drain_invoice_handlers(handlers, &paid_invoices.receipt_clients).await;
+                return;
+            },
+        }
+
+        backoff = next_resubscribe_backoff(backoff);
+    }
+}
+
+/// The settle indexes of the settled invoices we got, to know up to which one all were handled.
+///
+/// Paid invoices are handled concurrently, so that one settled later may be handled first. It must
+/// not be taken for every invoice before it being handled, or those would be skipped after a crash.
+#[derive(Debug)]
+struct SettleIndexes {
+    /// The settle index of the last settled invoice we got.
+    received: u64,
+    /// The settle indexes of the invoices we got which are still being handled.
+    handling: BTreeSet<u64>,
+}
+
+impl SettleIndexes {
+    /// Every invoice up to the one with the `handled` settle index has been handled before.
+    fn new(handled: u64) -> Self {
+        Self {
+            received: handled,
+            handling: BTreeSet::new(),
+        }
+    }
+
+    fn receive(&mut self, settle_index: u64) {
+        self.received = self.received.max(settle_index);
+        self.handling.insert(settle_index);
+    }
+
+    /// Returns the settle index up to which every invoice we got has been handled, now that the
+    /// one with the `settle_index` has been.
+    fn handled(&mut self, settle_index: u64) -> u64 {
+        self.handling.remove(&settle_index);
+
+        match self.handling.first() {
+            Some(first) => first.saturating_sub(1),
+            None => self.received,
+        }
+    }
+}
+
+/// Subscribe to invoices, including those settled after the one with the `settle_index`.
+fn invoice_subscription(settle_index: u64) -> lnrpc::InvoiceSubscription {
+    lnrpc::InvoiceSubscription {
+        settle_index,
+        ..Default::default()
     }
 }
 
+fn next_resubscribe_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_RESUBSCRIBE_BACKOFF)
+}
+
+/// Wait for the paid invoices being handled, so that their bets are not left half settled, then
+/// disconnect the clients their zap receipts were published with.
+async fn drain_invoice_handlers(mut handlers: JoinSet<()>, receipt_clients: &ReceiptClients) {
+    if !handlers.is_empty() {
+        tracing::info!(
+            count = handlers.len(),
+            "Waiting for paid invoices to be handled..."
+        );
+    }
+    while handlers.join_next().await.is_some() {}
+
+    receipt_clients.disconnect_all().await;
+}
+
 async fn start_subscription(
     lnd: &mut LndLightningClient,
     sub: lnrpc::InvoiceSubscription,
+    settle_indexes: &Arc<Mutex<SettleIndexes>>,
     db: &SqlitePool,
-    key: &Keys,
-    client: &Client,
-    multipliers: &Multipliers,
+    paid_invoices: &PaidInvoices,
+    handler_timeout: Duration,
+    handlers: &mut JoinSet<()>,
 ) -> Result<()> {
     let mut invoice_stream = lnd
         .subscribe_invoices(sub)
@@ -65,33 +261,48 @@ async fn start_subscription(
         .await
         .context("Failed to receive invoices")?
     {
+        // Forget the handlers which are done.
+        while handlers.try_join_next().is_some() {}
+
         match InvoiceState::from_i32(ln_invoice.state) {
             Some(InvoiceState::Settled) => {
+                let invoice_settle_index = ln_invoice.settle_index;
+                settle_indexes
+                    .lock()
+                    .expect("not poisoned")
+                    .receive(invoice_settle_index);
+
                 let db = db.clone();
-                let key = key.clone();
-                tokio::spawn({
-                    let client = client.clone();
-                    let multipliers = multipliers.clone();
+                handlers.spawn({
+                    let paid_invoices = paid_invoices.clone();
+                    let settle_indexes = settle_indexes.clone();
                     async move {
+                        let payment_hash = hex::encode(ln_invoice.r_hash);
+                        let mut dm_tasks = JoinSet::new();
                         let fut = handle_paid_invoice(
                             &db,
-                            hex::encode(ln_invoice.r_hash),
-                            key.clone(),
-                            client,
-                            multipliers.clone(),
+                            payment_hash.clone(),
+                            ln_invoice.r_preimage,
+                            &paid_invoices,
+                            &mut dm_tasks,
                         );
 
-                        match tokio::time::timeout(Duration::from_secs(30), fut).await {
-                            Ok(Ok(_)) => {
-                                tracing::info!("Handled paid invoice!");
-                            }
-                            Ok(Err(e)) => {
-                                tracing::error!("Failed to handle paid invoice: {}", e);
-                            }
-                            Err(_) => {
-                                tracing::error!("Timeout");
-                            }
+                        handle_with_timeout(&db, &payment_hash, handler_timeout, fut).await;
+
+                        // Handled or not, the invoice is not handled again after a restart, once
+                        // the ones settled before it are done too. Failures are logged for the
+                        // operator.
+                        let handled_settle_index = settle_indexes
+                            .lock()
+                            .expect("not poisoned")
+                            .handled(invoice_settle_index);
+                        if let Err(e) = record_invoice_settle_index(&db, handled_settle_index).await
+                        {
+                            tracing::error!("{e:#}");
                         }
+
+                        // Part of the handler, so that the DMs are sent before shutting down.
+                        while dm_tasks.join_next().await.is_some() {}
                     }
                 });
             }
@@ -105,86 +316,267 @@ async fn start_subscription(
     Ok(())
 }
 
+/// Wait at most `timeout` for the paid invoice with the `payment_hash` to be `handled`.
+///
+/// However handling it went, a paid bet is left paid, so that it is rolled once its round is
+/// revealed. LND does not tell us about the payment again, so the bet would be stuck otherwise.
+async fn handle_with_timeout(
+    db: &SqlitePool,
+    payment_hash: &str,
+    timeout: Duration,
+    handled: impl Future<Output = Result<()>>,
+) {
+    match tokio::time::timeout(timeout, handled).await {
+        Ok(Ok(())) => {
+            tracing::info!(payment_hash, "Handled paid invoice!");
+            return;
+        }
+        Ok(Err(e)) => {
+            tracing::error!(payment_hash, "Failed to handle paid invoice: {e:#}");
+        }
+        Err(_) => {
+            tracing::error!(
+                payment_hash,
+                timeout_secs = timeout.as_secs(),
+                "Timed out handling paid invoice"
+            );
+        }
+    }
+
+    if let Err(e) = recover_paid_bet(db, payment_hash).await {
+        tracing::error!(payment_hash, "Failed to mark bet as paid: {e:#}");
+    }
+}
+
+/// Mark the bet with the `payment_hash` as paid, if handling its payment did not get that far.
+async fn recover_paid_bet(db: &SqlitePool, payment_hash: &str) -> Result<()> {
+    let Some(zap) = get_zap(db, payment_hash.to_string()).await? else {
+        return Ok(());
+    };
+    if zap.bet_state != BetState::GameZapInvoiceRequested {
+        return Ok(());
+    }
+
+    set_settled_at(db, payment_hash, OffsetDateTime::now_utc()).await?;
+    let recovered = transition_bet_state(
+        db,
+        payment_hash,
+        BetState::GameZapInvoiceRequested,
+        BetState::ZapPaid,
+    )
+    .await?;
+    if recovered {
+        tracing::warn!(
+            payment_hash,
+            "Marked bet as paid without publishing its zap receipt"
+        );
+    }
+
+    Ok(())
+}
+
+/// Handle the payment of the invoice with the `payment_hash`, which LND settled with the
+/// `preimage`.
+///
+/// DMs to the roller are sent on the `dm_tasks`, which the caller has to wait for.
 async fn handle_paid_invoice(
     db: &SqlitePool,
     payment_hash: String,
-    keys: Keys,
-    client: Client,
-    multipliers: Multipliers,
+    preimage: Vec<u8>,
+    paid_invoices: &PaidInvoices,
+    dm_tasks: &mut JoinSet<()>,
 ) -> Result<()> {
-    match get_zap(db, payment_hash.clone()).await? {
+    let PaidInvoices {
+        games,
+        receipt_relay_allow_list,
+        relay_blacklist,
+        receipt_clients,
+        receipt_bet_tags,
+        anon_donation_thanks,
+        dms,
+        reveal_nonce_after_secs,
+        revealed,
+    } = paid_invoices;
+
+    let zap = match get_zap_kind(db, &payment_hash).await? {
+        Some(ZapKind::Zap(zap)) => zap,
+        Some(ZapKind::AnonDonation(donation)) => {
+            // LND may notify us of the payment more than once, but we only thank for it once.
+            if !settle_anon_donation(db, &payment_hash, OffsetDateTime::now_utc()).await? {
+                tracing::warn!(
+                    payment_hash,
+                    "Received a payment for an anonymous donation handled meanwhile"
+                );
+                return Ok(());
+            }
+
+            return handle_anon_donation(donation, games, *anon_donation_thanks).await;
+        }
         None => {
             tracing::warn!("Received a payment without bet.");
-            Ok(())
+            return Ok(());
         }
-        Some(
-            mut zap @ Zap {
-                bet_state: BetState::ZapInvoiceRequested,
-                ..
-            },
-        ) => {
+    };
+
+    let game = games
+        .get(&zap.game)
+        .with_context(|| format!("Received a payment for unknown game {}", zap.game))?;
+    let keys = game.main_keys.clone();
+    let client = game.client.clone();
+
+    match zap {
+        zap @ Zap {
+            bet_state: BetState::Donation,
+            ..
+        } => {
             let note_id = zap.request.id().to_hex();
             let amount_msat = zap.invoice.amount_milli_satoshis().unwrap_or_default();
-            tracing::info!(note_id, amount_msat, "Received a zap for non game note");
+            tracing::info!(note_id, amount_msat, "Received a donation zap");
+            set_settled_at(db, &payment_hash, OffsetDateTime::now_utc()).await?;
 
-            let client = ephermal_client(client, &mut zap).await?;
+            // LND may notify us of the payment more than once, but we only publish one receipt.
+            let paid = transition_bet_state(
+                db,
+                &payment_hash,
+                BetState::Donation,
+                BetState::DonationPaid,
+            )
+            .await?;
+            if !paid {
+                tracing::warn!(
+                    payment_hash,
+                    "Received a payment for a donation handled meanwhile"
+                );
+                return Ok(());
+            }
+
+            let keys = donation_keys(game, &zap).clone();
 
-            let event_id = publish_zap_receipt(&keys, &mut zap, client).await?;
+            let client = ephermal_client(
+                client,
+                &keys,
+                &zap,
+                receipt_relay_allow_list,
+                relay_blacklist,
+                receipt_clients,
+            )
+            .await?;
+
+            let event_id =
+                publish_zap_receipt(&keys, &zap, &preimage, [], client, relay_blacklist).await?;
 
             tracing::info!(
                 event_id = event_id.to_bech32().expect("bech32"),
-                "Broadcasted zap receipt for normal zap",
+                "Broadcasted zap receipt for donation",
             );
 
             Ok(())
         }
-        Some(
-            mut zap @ Zap {
-                bet_state: BetState::GameZapInvoiceRequested,
-                ..
-            },
-        ) => {
+        mut zap @ Zap {
+            bet_state: BetState::GameZapInvoiceRequested,
+            ..
+        } => {
             let note_id = zap.request.id().to_hex();
             let amount_msat = zap.invoice.amount_milli_satoshis().unwrap_or_default();
             tracing::info!(note_id, amount_msat, "Received a zap for game note");
             // At this stage, this `Zap` indicates that the roller has placed their bet. We will
-            // determine their outcome as soon as their nonce is revealed.
+            // only determine their outcome once the round's nonce has been revealed, see
+            // `payouts::settle_revealed_rounds`.
+            //
+            // Recorded before the bet moves on, so that its payout cannot overwrite it.
+            let settled_at = OffsetDateTime::now_utc();
+            set_settled_at(db, &payment_hash, settled_at).await?;
+            zap.settled_at = zap.settled_at.or(Some(settled_at));
+
+            // LND may notify us of the payment more than once, so only one notification gets to
+            // move the bet on.
+            let paid = transition_bet_state(
+                db,
+                &payment_hash,
+                BetState::GameZapInvoiceRequested,
+                BetState::ZapPaid,
+            )
+            .await?;
+            if !paid {
+                let bet_state = get_zap(db, payment_hash.clone())
+                    .await?
+                    .map_or(BetState::ZapPaid, |zap| zap.bet_state);
+                tracing::error!(
+                    payment_hash,
+                    ?bet_state,
+                    game = game.name,
+                    "Received a payment for a bet handled meanwhile"
+                );
+
+                record_unexpected_settlement(db, &payment_hash, &bet_state).await?;
+
+                return Ok(());
+            }
             zap.bet_state = BetState::ZapPaid;
-            upsert_zap(db, payment_hash, zap.clone(), &multipliers).await?;
 
-            let client = ephermal_client(client, &mut zap).await?;
+            let multiplier_note = game
+                .multipliers
+                .get_multiplier_note(&zap.multiplier_note_id);
+            if let Some(multiplier_note) = &multiplier_note {
+                METRICS.bet_paid(&game.name, &multiplier_note.multiplier, amount_msat);
+            }
 
-            tokio::spawn({
-                let db = db.clone();
-                let client = client.clone();
-                let zap = zap.clone();
-                async move {
-                    match nonce::get_active_nonce(&db).await {
-                        Ok(Some(round)) => {
-                            tracing::info!(
-                                nonce_commitment_note_id = round.get_note_id(),
-                                "Time to roll the dice"
-                            );
-                            if let Err(e) = payouts::roll_the_die(
-                                &db,
-                                &zap,
-                                client,
-                                multipliers,
-                                round.nonce,
-                                zap.index,
-                            )
-                            .await
-                            {
-                                tracing::error!("Failed to roll the die. Error: {e:#}");
-                            }
-                        }
-                        Ok(None) => tracing::error!("Failed to payout winner: No active round."),
-                        Err(e) => tracing::error!("Failed to get active nonce round. Error: {e:#}"),
+            let round = match zap.nonce_commitment_note_id {
+                Some(round_id) => get_round(db, round_id).await?.map(|(_, round)| round),
+                None => None,
+            };
+
+            // The round may have been settled before we got to this payment. Since the bet is
+            // `ZapPaid` by now, settling the round again rolls the die for it.
+            if let Some(round_id) = zap.nonce_commitment_note_id {
+                if get_revealed_round(db, round_id).await?.is_some() {
+                    tracing::warn!(
+                        payment_hash,
+                        %round_id,
+                        "Received a payment for a bet after its round was revealed"
+                    );
+
+                    if let Some(revealed) = revealed.get(&game.name) {
+                        let _ = revealed.send(round_id);
                     }
                 }
-            });
+            }
+
+            // Rollers only hear about the outcome once the nonce is revealed, so we let them know
+            // that their bet is in right away. Relays can be slow, so we do not wait for the DM.
+            if let Some(round_id) = zap.nonce_commitment_note_id {
+                let reveal_at = round
+                    .as_ref()
+                    .and_then(|round| round.expires_at)
+                    .map(|expires_at| expires_at + Duration::from_secs(*reveal_nonce_after_secs));
+                let message =
+                    bet_received_dm(round_id, zap.index, reveal_at, OffsetDateTime::now_utc());
+                let client = client.clone();
+                let (dms, roller) = (dms.clone(), zap.roller);
+                dm_tasks.spawn(async move { send_dm(&client, &dms, &roller, message).await });
+            }
+
+            // The roll is only known once the round has been revealed, see
+            // `publish_rolled_receipts`.
+            if *receipt_bet_tags && multiplier_note.is_some() {
+                hold_zap_receipt(db, &payment_hash, &preimage, OffsetDateTime::now_utc()).await?;
+                tracing::info!(payment_hash, "Holding zap receipt until the bet is rolled");
+
+                return Ok(());
+            }
 
-            let event_id = publish_zap_receipt(&keys, &mut zap, client).await?;
+            let client = ephermal_client(
+                client,
+                &keys,
+                &zap,
+                receipt_relay_allow_list,
+                relay_blacklist,
+                receipt_clients,
+            )
+            .await?;
+
+            let event_id =
+                publish_zap_receipt(&keys, &zap, &preimage, [], client, relay_blacklist).await?;
 
             tracing::info!(
                 event_id = event_id.to_bech32().expect("bech32"),
@@ -193,64 +585,974 @@ async fn handle_paid_invoice(
 
             Ok(())
         }
-        Some(_) => {
-            tracing::warn!("Ignoring other zaps which might have been donations.");
+        // We already handled the payment of these bets, so they must not be paid again. Either LND
+        // notified us twice, or something settled the invoice behind our back.
+        Zap {
+            bet_state:
+                bet_state @ (BetState::ZapPaid
+                | BetState::Rolling
+                | BetState::ZapFailed
+                | BetState::ZapAbandoned
+                | BetState::PaidWinner
+                | BetState::QueuedWinner
+                | BetState::DryRunWinner
+                | BetState::Loser
+                | BetState::DonationPaid),
+            roller,
+            nonce_commitment_note_id,
+            ..
+        } => {
+            tracing::error!(
+                payment_hash,
+                ?bet_state,
+                roller = roller.to_bech32().expect("npub"),
+                ?nonce_commitment_note_id,
+                game = game.name,
+                "Received a payment for an already handled bet"
+            );
+
+            record_unexpected_settlement(db, &payment_hash, &bet_state).await?;
+
             Ok(())
         }
     }
 }
 
-async fn publish_zap_receipt(keys: &Keys, zap: &mut Zap, client: Client) -> Result<EventId> {
-    let preimage = zap.request.id.to_bytes();
-    let invoice_hash = bitcoin::hashes::sha256::Hash::hash(&preimage);
+/// Confirms that the bet number `index` of the round with the `round_id` was paid, and tells the
+/// roller when they will know whether they won.
+fn bet_received_dm(
+    round_id: EventId,
+    index: usize,
+    reveal_at: Option<OffsetDateTime>,
+    now: OffsetDateTime,
+) -> String {
+    let reveal = match reveal_at.filter(|reveal_at| *reveal_at > now) {
+        Some(reveal_at) => format!(
+            "at {}",
+            reveal_at
+                .replace_nanosecond(0)
+                .unwrap_or(reveal_at)
+                .format(&Rfc3339)
+                .unwrap_or_else(|_| reveal_at.to_string())
+        ),
+        None => "shortly".to_string(),
+    };
+
+    format!(
+        "We received your payment. Your bet number {index} of the round nostr:{} is locked in. \
+         The nonce of the round will be revealed {reveal}, and we will let you know whether you \
+         won then.",
+        round_id.to_bech32().expect("valid note"),
+    )
+}
+
+/// What a paid invoice was for.
+enum ZapKind {
+    /// A bet or a donation with a zap request.
+    Zap(Zap),
+    /// A donation without a zap request.
+    AnonDonation(AnonDonation),
+}
+
+async fn get_zap_kind(db: &SqlitePool, payment_hash: &str) -> Result<Option<ZapKind>> {
+    if let Some(zap) = get_zap(db, payment_hash.to_string()).await? {
+        return Ok(Some(ZapKind::Zap(zap)));
+    }
+
+    let donation = get_anon_donation(db, payment_hash).await?;
+
+    Ok(donation.map(ZapKind::AnonDonation))
+}
+
+/// Anonymous donations have no zap request, so there is no zap receipt to publish and nobody to
+/// send a DM to. They never make it into the game's stats or social updates.
+async fn handle_anon_donation(
+    donation: AnonDonation,
+    games: &Games,
+    anon_donation_thanks: bool,
+) -> Result<()> {
+    let amount_sat = donation.amount_msat / 1_000;
+    tracing::info!(
+        payment_hash = donation.payment_hash,
+        amount_sat,
+        game = donation.game,
+        "Received an anonymous donation"
+    );
+
+    if !anon_donation_thanks {
+        return Ok(());
+    }
 
-    let payment_secret = zap.request.id.to_bytes();
+    let game = games
+        .get(&donation.game)
+        .with_context(|| format!("Received a donation for unknown game {}", donation.game))?;
 
-    let private_key =
-        SecretKey::from_hashed_data::<bitcoin::hashes::sha256::Hash>(zap.request.id.as_bytes());
+    let event = EventBuilder::text_note(
+        format!("Thank you for the anonymous donation of {amount_sat} sats!"),
+        [],
+    )
+    .to_event(&game.social_keys)?;
+    game.client.send_event(event).await?;
 
-    let amt_msats = zap
-        .invoice
-        .amount_milli_satoshis()
-        .expect("Invoice must have an amount");
+    Ok(())
+}
+
+/// Publish the zap receipt to every relay of the `client` independently, returning as soon as one
+/// of them accepted it.
+///
+/// Relays which are slow to answer or reject the receipt do not hold us up: the others get until
+/// their timeout in the background, and their failures are only logged.
+async fn publish_zap_receipt(
+    keys: &Keys,
+    zap: &Zap,
+    preimage: &[u8],
+    tags: impl IntoIterator<Item = Tag>,
+    client: Arc<Client>,
+    relay_blacklist: &RelayBlacklist,
+) -> Result<EventId> {
+    let event = zap_receipt(keys, zap, preimage, tags)?;
+    let event_id = event.id;
 
-    let fake_invoice = InvoiceBuilder::new(Currency::Bitcoin)
-        .amount_milli_satoshis(amt_msats)
-        .invoice_description(zap.invoice.description())
-        .current_timestamp()
-        .payment_hash(invoice_hash)
-        .payment_secret(PaymentSecret(payment_secret))
-        .min_final_cltv_expiry_delta(144)
-        .basic_mpp()
-        .build_signed(|hash| {
-            Secp256k1::signing_only().sign_ecdsa_recoverable(hash, &private_key)
-        })?;
+    let relays = client.relays().await.into_keys().collect::<Vec<_>>();
+    let (accepted_tx, mut accepted_rx) = mpsc::channel(relays.len().max(1));
+
+    tokio::spawn({
+        let relay_blacklist = relay_blacklist.clone();
+        async move {
+            let mut sends = JoinSet::new();
+            for relay in relays {
+                let client = client.clone();
+                let event = event.clone();
+                let accepted_tx = accepted_tx.clone();
+                let relay_blacklist = relay_blacklist.clone();
+                sends.spawn(async move {
+                    let relay_url = relay.to_string();
+                    match client.send_event_to([relay], event).await {
+                        Ok(_) => {
+                            relay_blacklist.accepted(&relay_url);
+                            let _ = accepted_tx.send(relay_url).await;
+                        }
+                        Err(e) => {
+                            relay_blacklist.failed(&relay_url, &e.to_string()).await;
+                            tracing::warn!(
+                                relay = relay_url,
+                                %event_id,
+                                "Failed to publish zap receipt: {e:#}"
+                            );
+                        }
+                    }
+                });
+            }
+            drop(accepted_tx);
+
+            while sends.join_next().await.is_some() {}
+        }
+    });
+
+    match accepted_rx.recv().await {
+        Some(relay) => {
+            tracing::debug!(relay, %event_id, "Relay accepted zap receipt");
+            Ok(event_id)
+        }
+        None => bail!("No relay accepted zap receipt {event_id}"),
+    }
+}
+
+/// A NIP-57 zap receipt for the `zap`, whose invoice was settled with the `preimage`, with the
+/// `tags` in addition to the standard ones.
+fn zap_receipt(
+    keys: &Keys,
+    zap: &Zap,
+    preimage: &[u8],
+    tags: impl IntoIterator<Item = Tag>,
+) -> Result<Event> {
+    // Clients reject receipts whose preimage does not hash to the payment hash of the invoice.
+    if sha256::Hash::hash(preimage) != *zap.invoice.payment_hash() {
+        bail!(
+            "Preimage does not match the invoice with payment hash {}",
+            zap.invoice.payment_hash()
+        );
+    }
 
     let event = EventBuilder::zap_receipt(
-        fake_invoice.to_string(),
+        zap.invoice.to_string(),
         Some(hex::encode(preimage)),
-        &zap.request.clone(),
+        &zap.request,
     )
+    .add_tags(tags)
     .to_event(keys)?;
 
-    let event_id = client.send_event(event.clone()).await?;
+    Ok(event)
+}
+
+/// The keys of the account the donation `zap` was for, which clients expect its receipt to be
+/// signed with.
+///
+/// Donations from before we checked whom their zap request was for fall back to the main account.
+fn donation_keys<'a>(game: &'a Game, zap: &Zap) -> &'a Keys {
+    utils::get_zapped_public_key(&zap.request)
+        .and_then(|recipient| {
+            ROLES
+                .into_iter()
+                .map(|role| game.keys(role))
+                .find(|keys| keys.public_key() == recipient)
+        })
+        .unwrap_or(&game.main_keys)
+}
+
+/// Publish the held zap receipts of the bets of the `games` in `paid_invoices` once they were
+/// rolled, every [`HELD_RECEIPTS_INTERVAL`].
+///
+/// Receipts are held until they could be published, also across restarts.
+pub async fn publish_rolled_receipts(
+    db: SqlitePool,
+    paid_invoices: PaidInvoices,
+    mut ctrl_c: broadcast::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(HELD_RECEIPTS_INTERVAL) => (),
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down held zap receipt task...");
+                return;
+            },
+        }
+
+        for game in paid_invoices.games.iter() {
+            let receipts = match get_rolled_zap_receipts(&db, &game.name).await {
+                Ok(receipts) => receipts,
+                Err(e) => {
+                    tracing::error!(game = game.name, "Failed to get held zap receipts: {e:#}");
+                    continue;
+                }
+            };
+
+            for receipt in receipts {
+                let payment_hash = receipt.payment_hash.clone();
+                match publish_rolled_receipt(&db, game, &paid_invoices, receipt).await {
+                    Ok(event_id) => tracing::info!(
+                        payment_hash,
+                        event_id = event_id.to_bech32().expect("bech32"),
+                        "Broadcasted held zap receipt for game zap",
+                    ),
+                    Err(e) => {
+                        tracing::error!(payment_hash, "Failed to publish held zap receipt: {e:#}")
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn publish_rolled_receipt(
+    db: &SqlitePool,
+    game: &Game,
+    paid_invoices: &PaidInvoices,
+    receipt: HeldZapReceipt,
+) -> Result<EventId> {
+    let zap = get_zap(db, receipt.payment_hash.clone())
+        .await?
+        .context("Unknown bet")?;
+    let multiplier_note = game
+        .multipliers
+        .get_multiplier_note(&zap.multiplier_note_id)
+        .context("Unknown multiplier note")?;
+    let round_id = zap.nonce_commitment_note_id.context("Bet without round")?;
+    let (_, round) = get_round(db, round_id).await?.context("Unknown round")?;
+
+    let client = ephermal_client(
+        game.client.clone(),
+        &game.main_keys,
+        &zap,
+        &paid_invoices.receipt_relay_allow_list,
+        &paid_invoices.relay_blacklist,
+        &paid_invoices.receipt_clients,
+    )
+    .await?;
+
+    let tags = bet_tags(&multiplier_note, round.roll_scheme, &receipt)?;
+    let event_id = publish_zap_receipt(
+        &game.main_keys,
+        &zap,
+        &receipt.preimage,
+        tags,
+        client,
+        &paid_invoices.relay_blacklist,
+    )
+    .await?;
+
+    delete_held_zap_receipt(db, &receipt.payment_hash).await?;
+
     Ok(event_id)
 }
 
-async fn ephermal_client(client: Client, zap: &mut Zap) -> anyhow::Result<Client> {
-    let og_client = client.clone();
-    let options = Options::default();
-    let client = Client::with_opts(
-        og_client.signer().await?,
-        options
-            .wait_for_send(true)
-            .send_timeout(Some(Duration::from_secs(20))),
+/// Tag the zap receipt of a bet with the threshold its roll had to beat in a round rolling with the
+/// `roll_scheme`, and with the roll and outcome of the `receipt`.
+fn bet_tags(
+    multiplier_note: &MultiplierNote,
+    roll_scheme: RollScheme,
+    receipt: &HeldZapReceipt,
+) -> Result<Vec<Tag>> {
+    let direction = match multiplier_note.direction {
+        BetDirection::Under => "under",
+        BetDirection::Over => "over",
+    };
+    let outcome = if receipt.win { "win" } else { "loss" };
+
+    Ok(vec![
+        Tag::parse(&[
+            THRESHOLD_TAG.to_string(),
+            multiplier_note.get_threshold(roll_scheme).to_string(),
+        ])?,
+        Tag::parse(&[DIRECTION_TAG.to_string(), direction.to_string()])?,
+        Tag::parse(&[ROLL_TAG.to_string(), receipt.roll.to_string()])?,
+        Tag::parse(&[OUTCOME_TAG.to_string(), outcome.to_string()])?,
+    ])
+}
+
+/// A client connected to our relays and to the relays requested in the zap request, reused from
+/// the `receipt_clients` if we published to the same relays for the `keys` before.
+///
+/// If `receipt_relay_allow_list` is not empty, requested relays which are not on it are ignored.
+/// Blacklisted requested relays are ignored too.
+async fn ephermal_client(
+    client: Client,
+    keys: &Keys,
+    zap: &Zap,
+    receipt_relay_allow_list: &[String],
+    relay_blacklist: &RelayBlacklist,
+    receipt_clients: &ReceiptClients,
+) -> anyhow::Result<Arc<Client>> {
+    let mut relays = client
+        .relays()
+        .await
+        .into_keys()
+        .map(|relay| relay.to_string())
+        .collect::<Vec<_>>();
+    relays.extend(
+        utils::allowed_relays(utils::get_relays(&zap.request)?, receipt_relay_allow_list)
+            .into_iter()
+            .filter(|relay| !relay_blacklist.is_blacklisted(relay)),
     );
-    let relays = og_client.relays().await;
-    let relays = relays.keys();
-    client.add_relays(relays).await?;
-    client.add_relays(utils::get_relays(&zap.request)?).await?;
-    client.connect().await;
-    client.set_zapper(og_client.zapper().await?).await;
-    Ok(client)
+
+    receipt_clients
+        .get_or_connect(&client, keys.public_key(), relays)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::get_rounds_with_paid_zaps;
+    use crate::db::get_unexpected_settlements;
+    use crate::db::insert_anon_donation;
+    use crate::db::test_db;
+    use crate::db::test_round;
+    use crate::db::test_zap;
+    use crate::db::upsert_zap;
+    use crate::dm::DmProtocol;
+    use crate::game::test_schedule;
+    use crate::game::DEFAULT_GAME;
+    use crate::game::DEFAULT_SCHEDULE;
+    use crate::multiplier::test_multiplier_note;
+    use crate::multiplier::Multiplier;
+    use crate::multiplier::MultiplierPins;
+    use crate::multiplier::Multipliers;
+    use crate::nonce::set_active_nonce;
+    use crate::nonce::set_reveal_event_id;
+    use lightning_invoice::Bolt11Invoice;
+    use lightning_invoice::Bolt11InvoiceDescription;
+    use nostr::JsonUtil;
+    use std::str::FromStr;
+
+    /// Handles the invoices paid for the `games`, without tagging or thanking for anything.
+    async fn test_paid_invoices(db: &SqlitePool, games: Games) -> PaidInvoices {
+        PaidInvoices {
+            games,
+            receipt_relay_allow_list: Vec::new(),
+            relay_blacklist: RelayBlacklist::load(db.clone(), Duration::from_secs(60))
+                .await
+                .unwrap(),
+            receipt_clients: ReceiptClients::new(Duration::from_secs(5)),
+            receipt_bet_tags: false,
+            anon_donation_thanks: false,
+            dms: DmSender::new(DmProtocol::Nip04),
+            reveal_nonce_after_secs: 60,
+            revealed: Arc::new(HashMap::new()),
+        }
+    }
+
+    fn test_games() -> (Games, Multipliers) {
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+        let main_keys = Keys::generate();
+        let games = Games::new(vec![Game {
+            name: DEFAULT_GAME.to_string(),
+            client: Client::new(&main_keys),
+            main_keys,
+            nonce_keys: Keys::generate(),
+            social_keys: Keys::generate(),
+            multipliers: multipliers.clone(),
+            multiplier_pins: MultiplierPins::default(),
+            schedules: vec![test_schedule(DEFAULT_SCHEDULE, 60)],
+        }])
+        .unwrap();
+
+        (games, multipliers)
+    }
+
+    #[test]
+    fn paid_bets_are_confirmed_with_the_reveal_time() {
+        let now = OffsetDateTime::from_unix_timestamp(1_726_000_000).unwrap();
+        let round_id = EventId::all_zeros();
+
+        let dm = bet_received_dm(round_id, 3, Some(now + Duration::from_secs(90)), now);
+        assert!(dm.contains("bet number 3"));
+        assert!(dm.contains(&round_id.to_bech32().unwrap()));
+        assert!(dm.contains("revealed at 2024-09-10T20:28:10Z"));
+
+        // Rounds past their reveal time, or whose expiry is unknown, are revealed any moment.
+        let dm = bet_received_dm(round_id, 3, Some(now - Duration::from_secs(1)), now);
+        assert!(dm.contains("revealed shortly"));
+        assert_eq!(bet_received_dm(round_id, 3, None, now), dm);
+    }
+
+    #[tokio::test]
+    async fn anonymous_donations_are_settled_without_a_bet() {
+        let db = test_db().await;
+        let (games, _) = test_games();
+        let paid_invoices = test_paid_invoices(&db, games).await;
+
+        let payment_hash = "00".repeat(32);
+        insert_anon_donation(
+            &db,
+            AnonDonation {
+                payment_hash: payment_hash.clone(),
+                amount_msat: 21_000,
+                game: DEFAULT_GAME.to_string(),
+                created_at: OffsetDateTime::now_utc(),
+                settled_at: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        // Looking the donation up does not settle it.
+        match get_zap_kind(&db, &payment_hash).await.unwrap() {
+            Some(ZapKind::AnonDonation(donation)) => assert!(donation.settled_at.is_none()),
+            _ => panic!("Expected an anonymous donation"),
+        }
+
+        handle_paid_invoice(
+            &db,
+            payment_hash.clone(),
+            vec![0; 32],
+            &paid_invoices,
+            &mut JoinSet::new(),
+        )
+        .await
+        .unwrap();
+
+        let settled_at = match get_zap_kind(&db, &payment_hash).await.unwrap() {
+            Some(ZapKind::AnonDonation(donation)) => donation.settled_at.unwrap(),
+            _ => panic!("Expected an anonymous donation"),
+        };
+        // The first settlement is kept.
+        assert!(
+            !settle_anon_donation(&db, &payment_hash, OffsetDateTime::now_utc())
+                .await
+                .unwrap()
+        );
+        match get_zap_kind(&db, &payment_hash).await.unwrap() {
+            Some(ZapKind::AnonDonation(donation)) => {
+                assert_eq!(donation.settled_at, Some(settled_at))
+            }
+            _ => panic!("Expected an anonymous donation"),
+        }
+        assert!(get_zap(&db, payment_hash).await.unwrap().is_none());
+        assert!(get_rounds_with_paid_zaps(&db, DEFAULT_GAME)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(get_unexpected_settlements(&db).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn payments_for_handled_bets_are_recorded() {
+        let db = test_db().await;
+        let (games, multipliers) = test_games();
+        let paid_invoices = test_paid_invoices(&db, games).await;
+
+        let handled = [
+            BetState::ZapPaid,
+            BetState::ZapFailed,
+            BetState::ZapAbandoned,
+            BetState::PaidWinner,
+            BetState::QueuedWinner,
+            BetState::Loser,
+        ];
+
+        for bet_state in handled.iter() {
+            let zap = test_zap("note1multiplier", EventId::all_zeros(), bet_state.clone());
+            let payment_hash = zap.invoice.payment_hash().to_string();
+            let preimage = zap.request.id.to_bytes().to_vec();
+            upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+                .await
+                .unwrap();
+
+            handle_paid_invoice(
+                &db,
+                payment_hash.clone(),
+                preimage,
+                &paid_invoices,
+                &mut JoinSet::new(),
+            )
+            .await
+            .unwrap();
+
+            // The bet is left alone.
+            let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+            assert_eq!(&zap.bet_state, bet_state);
+        }
+
+        let settlements = get_unexpected_settlements(&db).await.unwrap();
+        assert_eq!(
+            settlements
+                .iter()
+                .map(|settlement| settlement.bet_state.clone())
+                .collect::<Vec<_>>(),
+            handled
+        );
+    }
+
+    #[tokio::test]
+    async fn bets_whose_payment_took_too_long_to_handle_are_still_settled() {
+        let db = test_db().await;
+        let (_, multipliers) = test_games();
+
+        let zap = test_zap(
+            "note1multiplier",
+            EventId::all_zeros(),
+            BetState::GameZapInvoiceRequested,
+        );
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+
+        // E.g. a relay holding up the zap receipt before the bet moved on.
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        };
+        handle_with_timeout(&db, &payment_hash, Duration::from_millis(10), slow).await;
+
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::ZapPaid);
+        assert!(zap.settled_at.is_some());
+        assert_eq!(
+            get_rounds_with_paid_zaps(&db, DEFAULT_GAME).await.unwrap(),
+            vec![EventId::all_zeros()]
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_payment_notifications_pay_a_bet_once() {
+        let db = test_db().await;
+        let (games, multipliers) = test_games();
+        let paid_invoices = test_paid_invoices(&db, games).await;
+
+        let zap = test_zap(
+            "note1multiplier",
+            EventId::all_zeros(),
+            BetState::GameZapInvoiceRequested,
+        );
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        let preimage = zap.request.id.to_bytes().to_vec();
+        upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+
+        // The zap receipts cannot be published without relays, which does not matter here.
+        let _ = tokio::join!(
+            handle_paid_invoice(
+                &db,
+                payment_hash.clone(),
+                preimage.clone(),
+                &paid_invoices,
+                &mut JoinSet::new()
+            ),
+            handle_paid_invoice(
+                &db,
+                payment_hash.clone(),
+                preimage.clone(),
+                &paid_invoices,
+                &mut JoinSet::new()
+            ),
+        );
+        let _ = handle_paid_invoice(
+            &db,
+            payment_hash.clone(),
+            preimage,
+            &paid_invoices,
+            &mut JoinSet::new(),
+        )
+        .await;
+
+        let zap = get_zap(&db, payment_hash.clone()).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::ZapPaid);
+        assert!(zap.settled_at.is_some());
+
+        let settlements = get_unexpected_settlements(&db).await.unwrap();
+        assert_eq!(settlements.len(), 2);
+        assert!(settlements
+            .iter()
+            .all(|settlement| settlement.payment_hash == payment_hash
+                && settlement.bet_state == BetState::ZapPaid));
+    }
+
+    #[tokio::test]
+    async fn bets_paid_after_their_round_was_revealed_settle_it_again() {
+        let db = test_db().await;
+        let (games, multipliers) = test_games();
+        let (revealed, mut settlements) = mpsc::unbounded_channel();
+        let paid_invoices = PaidInvoices {
+            revealed: Arc::new(HashMap::from([(DEFAULT_GAME.to_string(), revealed)])),
+            ..test_paid_invoices(&db, games).await
+        };
+
+        let round_id = EventId::all_zeros();
+        set_active_nonce(&db, DEFAULT_GAME, DEFAULT_SCHEDULE, test_round(round_id))
+            .await
+            .unwrap();
+
+        let bet = || {
+            let zap = test_zap(
+                "note1multiplier",
+                round_id,
+                BetState::GameZapInvoiceRequested,
+            );
+            let payment_hash = zap.invoice.payment_hash().to_string();
+            let preimage = zap.request.id.to_bytes().to_vec();
+            (zap, payment_hash, preimage)
+        };
+
+        // Bets on a running round are settled once it is revealed.
+        let (zap, payment_hash, preimage) = bet();
+        upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+        let _ = handle_paid_invoice(
+            &db,
+            payment_hash,
+            preimage,
+            &paid_invoices,
+            &mut JoinSet::new(),
+        )
+        .await;
+        assert!(settlements.try_recv().is_err());
+
+        set_reveal_event_id(&db, round_id, EventId::from_slice(&[1; 32]).unwrap())
+            .await
+            .unwrap();
+
+        let (zap, payment_hash, preimage) = bet();
+        upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+        let _ = handle_paid_invoice(
+            &db,
+            payment_hash.clone(),
+            preimage,
+            &paid_invoices,
+            &mut JoinSet::new(),
+        )
+        .await;
+        assert_eq!(settlements.try_recv().unwrap(), round_id);
+
+        let zap = get_zap(&db, payment_hash).await.unwrap().unwrap();
+        assert_eq!(zap.bet_state, BetState::ZapPaid);
+    }
+
+    #[tokio::test]
+    async fn bets_are_only_transitioned_from_the_expected_state() {
+        let db = test_db().await;
+        let (_, multipliers) = test_games();
+
+        let zap = test_zap(
+            "note1multiplier",
+            EventId::all_zeros(),
+            BetState::GameZapInvoiceRequested,
+        );
+        let payment_hash = zap.invoice.payment_hash().to_string();
+        upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+            .await
+            .unwrap();
+
+        let (first, second) = tokio::join!(
+            transition_bet_state(
+                &db,
+                &payment_hash,
+                BetState::GameZapInvoiceRequested,
+                BetState::ZapPaid
+            ),
+            transition_bet_state(
+                &db,
+                &payment_hash,
+                BetState::GameZapInvoiceRequested,
+                BetState::ZapPaid
+            ),
+        );
+        assert!(first.unwrap() ^ second.unwrap());
+    }
+
+    #[tokio::test]
+    async fn subscription_resumes_after_the_last_handled_invoice_across_restarts() {
+        let db = test_db().await;
+        assert_eq!(get_invoice_settle_index(&db).await.unwrap(), 0);
+
+        // Invoices are handled concurrently, so their updates of the index may arrive out of order.
+        for settle_index in [1, 3, 2] {
+            record_invoice_settle_index(&db, settle_index)
+                .await
+                .unwrap();
+        }
+
+        // After a restart, LND is asked for the invoices settled while we were not running.
+        let settle_index = get_invoice_settle_index(&db).await.unwrap();
+        assert_eq!(invoice_subscription(settle_index).settle_index, 3);
+    }
+
+    #[test]
+    fn invoices_handled_out_of_order_are_recorded_once_those_before_them_are() {
+        let mut settle_indexes = SettleIndexes::new(10);
+        for settle_index in [11, 12, 13] {
+            settle_indexes.receive(settle_index);
+        }
+
+        // Invoice 11 is still being handled, so it must be handled again after a restart.
+        assert_eq!(settle_indexes.handled(12), 10);
+        assert_eq!(settle_indexes.handled(13), 10);
+        assert_eq!(settle_indexes.handled(11), 13);
+
+        settle_indexes.receive(14);
+        assert_eq!(settle_indexes.handled(14), 14);
+        assert_eq!(settle_indexes.received, 14);
+    }
+
+    #[test]
+    fn resubscribe_backoff_doubles_up_to_the_maximum() {
+        let mut backoff = MIN_RESUBSCRIBE_BACKOFF;
+        let mut backoffs = Vec::new();
+        for _ in 0..11 {
+            backoffs.push(backoff.as_secs());
+            backoff = next_resubscribe_backoff(backoff);
+        }
+
+        assert_eq!(backoffs, [1, 2, 4, 8, 16, 32, 64, 128, 256, 300, 300]);
+    }
+
+    #[test]
+    fn bet_tags_are_added_to_valid_zap_receipts() {
+        let keys = Keys::generate();
+        let request = EventBuilder::new(
+            nostr::Kind::ZapRequest,
+            "",
+            [
+                Tag::public_key(keys.public_key()),
+                Tag::event(EventId::all_zeros()),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        let zap = test_zap("note1multiplier", EventId::all_zeros(), BetState::ZapPaid);
+        // The test invoice was issued for the request it came with.
+        let preimage = zap.request.id.to_bytes();
+        let zap = Zap { request, ..zap };
+        let multiplier_note = MultiplierNote {
+            direction: BetDirection::Over,
+            ..test_multiplier_note("note1multiplier")
+        };
+
+        let held = HeldZapReceipt {
+            payment_hash: zap.invoice.payment_hash().to_string(),
+            preimage: preimage.to_vec(),
+            roll: 40_000,
+            win: true,
+        };
+
+        let receipt = zap_receipt(
+            &keys,
+            &zap,
+            &preimage,
+            bet_tags(&multiplier_note, RollScheme::V1, &held).unwrap(),
+        )
+        .unwrap();
+
+        receipt.verify().unwrap();
+        assert_eq!(receipt.kind, nostr::Kind::ZapReceipt);
+
+        let tags = receipt
+            .tags
+            .iter()
+            .map(|tag| tag.as_vec().to_vec())
+            .collect::<Vec<_>>();
+        for standard_tag in ["p", "e", "bolt11", "description", "preimage"] {
+            assert!(tags.iter().any(|tag| tag[0] == standard_tag));
+        }
+        assert!(tags.contains(&vec![
+            THRESHOLD_TAG.to_string(),
+            Multiplier::built_in("X2")
+                .unwrap()
+                .get_higher_than(RollScheme::V1)
+                .to_string()
+        ]));
+        assert!(tags.contains(&vec![DIRECTION_TAG.to_string(), "over".to_string()]));
+        assert!(tags.contains(&vec![ROLL_TAG.to_string(), "40000".to_string()]));
+        assert!(tags.contains(&vec![OUTCOME_TAG.to_string(), "win".to_string()]));
+
+        let receipt = zap_receipt(&keys, &zap, &preimage, []).unwrap();
+        assert!(!receipt
+            .tags
+            .iter()
+            .any(|tag| tag.as_vec()[0] == THRESHOLD_TAG));
+
+        // Receipts with someone else's preimage would be rejected by clients anyway.
+        assert!(zap_receipt(&keys, &zap, &[0; 32], []).is_err());
+    }
+
+    #[test]
+    fn receipts_of_zaps_on_social_notes_are_threaded_under_the_note() {
+        let (games, _) = test_games();
+        let game = games.default_game();
+        let social_note_id = EventId::from_slice(&[7; 32]).unwrap();
+
+        let mut zap = test_zap("", EventId::all_zeros(), BetState::Donation);
+        let preimage = zap.request.id.to_bytes();
+        zap.request = EventBuilder::new(
+            nostr::Kind::ZapRequest,
+            "Great post!",
+            [
+                Tag::public_key(game.social_keys.public_key()),
+                Tag::event(social_note_id),
+            ],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+
+        let keys = donation_keys(game, &zap);
+        assert_eq!(keys.public_key(), game.social_keys.public_key());
+
+        let receipt = zap_receipt(keys, &zap, &preimage, []).unwrap();
+        assert_eq!(receipt.pubkey, game.social_keys.public_key());
+        assert_eq!(utils::get_zapped_note_id(&receipt).unwrap(), social_note_id);
+        assert_eq!(
+            utils::get_zapped_public_key(&receipt),
+            Some(game.social_keys.public_key())
+        );
+
+        // Zap requests for none of our accounts are receipted by the main account.
+        zap.request = EventBuilder::new(nostr::Kind::ZapRequest, "", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert_eq!(
+            donation_keys(game, &zap).public_key(),
+            game.main_keys.public_key()
+        );
+    }
+
+    #[test]
+    fn zap_receipts_carry_the_settled_invoice_and_its_preimage() {
+        let keys = Keys::generate();
+        let zap = test_zap("note1multiplier", EventId::all_zeros(), BetState::ZapPaid);
+        let preimage = zap.request.id.to_bytes();
+
+        let receipt = zap_receipt(&keys, &zap, &preimage, []).unwrap();
+        let tag = |name: &str| {
+            receipt
+                .tags
+                .iter()
+                .map(|tag| tag.as_vec())
+                .find(|tag| tag[0] == name)
+                .map(|tag| tag[1].clone())
+                .unwrap()
+        };
+
+        let bolt11 = Bolt11Invoice::from_str(&tag("bolt11")).unwrap();
+        assert_eq!(bolt11, zap.invoice);
+        assert_eq!(bolt11.amount_milli_satoshis(), Some(1_000_000));
+
+        let preimage = hex::decode(tag("preimage")).unwrap();
+        assert_eq!(sha256::Hash::hash(&preimage), *bolt11.payment_hash());
+
+        assert_eq!(tag("description"), zap.request.as_json());
+        // NIP-57 clients also check that the invoice commits to the zap request of the receipt.
+        let Bolt11InvoiceDescription::Hash(description_hash) = bolt11.description() else {
+            panic!("Invoice without description hash");
+        };
+        assert_eq!(
+            description_hash.0,
+            sha256::Hash::hash(tag("description").as_bytes())
+        );
+    }
+
+    #[cfg(feature = "test-relay")]
+    #[tokio::test]
+    async fn zap_receipts_are_published_to_the_requested_relays() {
+        use crate::test_relay::TestRelay;
+        use nostr::Kind;
+
+        let db = test_db().await;
+        let relay = TestRelay::start().await.unwrap();
+        let (games, _) = test_games();
+        let game = games.get(DEFAULT_GAME).unwrap();
+
+        let mut zap = test_zap("note1multiplier", EventId::all_zeros(), BetState::ZapPaid);
+        let preimage = zap.request.id.to_bytes();
+        zap.request = EventBuilder::new(
+            Kind::ZapRequest,
+            "",
+            [Tag::parse(&["relays", &relay.url(), "ws://127.0.0.1:9"]).unwrap()],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+
+        let relay_blacklist = RelayBlacklist::load(db, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let client = ephermal_client(
+            game.client.clone(),
+            &game.main_keys,
+            &zap,
+            &[],
+            &relay_blacklist,
+            &ReceiptClients::new(Duration::from_secs(5)),
+        )
+        .await
+        .unwrap();
+        relay.wait_for_connection(&client).await.unwrap();
+
+        // The unreachable relay does not hold up the receipt.
+        let event_id = publish_zap_receipt(
+            &game.main_keys,
+            &zap,
+            &preimage,
+            [],
+            client,
+            &relay_blacklist,
+        )
+        .await
+        .unwrap();
+
+        let events = relay.events();
+        assert_eq!(events.len(), 1);
+
+        let receipt = &events[0];
+        assert_eq!(receipt.id, event_id);
+        assert_eq!(receipt.kind, Kind::ZapReceipt);
+        assert_eq!(receipt.author(), game.main_keys.public_key());
+        assert!(receipt
+            .tags
+            .iter()
+            .any(|tag| tag.as_vec() == ["description".to_string(), zap.request.as_json()]));
+    }
 }
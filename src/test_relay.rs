@@ -0,0 +1,175 @@
+//! An in-process Nostr relay, so that code paths which publish to relays can be exercised without
+//! external relays, e.g. in CI.
+//!
+//! The relay accepts every event and keeps it in memory. It answers subscriptions with the stored
+//! events matching their filters, but does not forward events published afterwards.
+
+use anyhow::Context;
+use anyhow::Result;
+use futures_util::SinkExt;
+use futures_util::StreamExt;
+use nostr::ClientMessage;
+use nostr::Event;
+use nostr::JsonUtil;
+use nostr::RelayMessage;
+use nostr_sdk::Client;
+use nostr_sdk::RelayStatus;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct TestRelay {
+    url: String,
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl TestRelay {
+    /// Start the relay on a random local port.
+    pub async fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .context("Failed to bind test relay")?;
+        let url = format!("ws://{}", listener.local_addr()?);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        tokio::spawn({
+            let events = events.clone();
+            async move {
+                loop {
+                    let stream = match listener.accept().await {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            tracing::error!("Test relay failed to accept connection: {e:#}");
+                            continue;
+                        }
+                    };
+
+                    tokio::spawn({
+                        let events = events.clone();
+                        async move {
+                            if let Err(e) = handle_connection(stream, events).await {
+                                tracing::debug!("Test relay connection closed: {e:#}");
+                            }
+                        }
+                    });
+                }
+            }
+        });
+
+        tracing::info!(%url, "Started test relay");
+
+        Ok(Self { url, events })
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// All events published to the relay so far.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().expect("not poisoned").clone()
+    }
+
+    /// Wait until the `client` is connected to the relay, so that it can publish right away.
+    pub async fn wait_for_connection(&self, client: &Client) -> Result<()> {
+        let relay = client.relay(&self.url).await?;
+
+        tokio::time::timeout(CONNECT_TIMEOUT, async {
+            while relay.status().await != RelayStatus::Connected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .context("Timed out connecting to test relay")
+    }
+}
+
+async fn handle_connection(stream: TcpStream, events: Arc<Mutex<Vec<Event>>>) -> Result<()> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await?;
+
+    while let Some(message) = ws.next().await {
+        let message = match message? {
+            Message::Text(message) => message,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        let responses = match ClientMessage::from_json(&message) {
+            Ok(ClientMessage::Event(event)) => {
+                let event_id = event.id;
+                events.lock().expect("not poisoned").push(*event);
+
+                vec![RelayMessage::ok(event_id, true, "")]
+            }
+            Ok(ClientMessage::Req {
+                subscription_id,
+                filters,
+            }) => {
+                let events = events.lock().expect("not poisoned");
+                let mut responses = events
+                    .iter()
+                    .filter(|event| filters.iter().any(|filter| filter.match_event(event)))
+                    .map(|event| RelayMessage::event(subscription_id.clone(), event.clone()))
+                    .collect::<Vec<_>>();
+                responses.push(RelayMessage::eose(subscription_id));
+
+                responses
+            }
+            Ok(_) => Vec::new(),
+            Err(e) => {
+                tracing::debug!("Test relay ignoring invalid message: {e:#}");
+                Vec::new()
+            }
+        };
+
+        for response in responses {
+            ws.send(Message::Text(response.as_json())).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::EventBuilder;
+    use nostr::Filter;
+    use nostr::Keys;
+    use nostr_sdk::Options;
+
+    #[tokio::test]
+    async fn stores_published_events_and_serves_them() {
+        let relay = TestRelay::start().await.unwrap();
+
+        let keys = Keys::generate();
+        let client = Client::with_opts(&keys, Options::default().wait_for_send(true));
+        client.add_relay(relay.url()).await.unwrap();
+        client.connect().await;
+        relay.wait_for_connection(&client).await.unwrap();
+
+        let event = EventBuilder::text_note("Hello, relay!", [])
+            .to_event(&keys)
+            .unwrap();
+        client.send_event(event.clone()).await.unwrap();
+
+        assert_eq!(relay.events(), vec![event.clone()]);
+
+        let fetched = client
+            .get_events_of(
+                vec![Filter::new().id(event.id)],
+                Some(Duration::from_secs(5)),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(fetched, vec![event]);
+    }
+}
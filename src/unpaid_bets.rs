@@ -0,0 +1,162 @@
+//! Cleans up the bets of rollers who requested an invoice but never paid it.
+
+use crate::db::delete_unpaid_bet;
+use crate::db::get_unpaid_bets;
+use crate::db::UnpaidBet;
+use anyhow::Context;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio::sync::Notify;
+use tonic_openssl_lnd::lnrpc;
+use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
+use tonic_openssl_lnd::LndLightningClient;
+
+/// How often we look for unpaid bets whose invoice expired, besides whenever a round is closed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Lets the sweeper know that a round was closed, so that the unpaid bets on it are cleaned up
+/// without waiting for the next regular sweep.
+#[derive(Clone, Default)]
+pub struct UnpaidBetSweeps {
+    round_closed: Arc<Notify>,
+}
+
+impl UnpaidBetSweeps {
+    pub fn round_closed(&self) {
+        self.round_closed.notify_one();
+    }
+}
+
+/// Regularly delete the unpaid bets whose invoice expired, and whenever a round is closed.
+pub async fn sweep_unpaid_bets(
+    db: SqlitePool,
+    mut lnd: LndLightningClient,
+    sweeps: UnpaidBetSweeps,
+    mut ctrl_c: broadcast::Receiver<()>,
+) {
+    loop {
+        if let Err(e) = delete_expired_unpaid_bets(&db, &mut lnd).await {
+            tracing::error!("Failed to delete expired unpaid bets: {e:#}");
+        }
+
+        select! {
+            _ = tokio::time::sleep(SWEEP_INTERVAL) => (),
+            _ = sweeps.round_closed.notified() => (),
+            _ = ctrl_c.recv() => {
+                tracing::warn!("Got Ctrl+C; shutting down unpaid bet sweeper...");
+                return;
+            },
+        }
+    }
+}
+
+async fn delete_expired_unpaid_bets(
+    db: &SqlitePool,
+    lnd: &mut LndLightningClient,
+) -> anyhow::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("after epoch");
+    let expired = expired_unpaid_bets(get_unpaid_bets(db).await?, now);
+
+    let mut deleted = 0;
+    for bet in expired {
+        // The invoice may have been paid just before it expired, without us having handled the
+        // payment yet, e.g. because we were not running. Only LND knows for sure.
+        let r_hash = hex::decode(&bet.payment_hash).context("Invalid payment hash")?;
+        let invoice = match lnd
+            .lookup_invoice(lnrpc::PaymentHash {
+                r_hash,
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(invoice) => invoice.into_inner(),
+            Err(e) => {
+                tracing::warn!(
+                    payment_hash = bet.payment_hash,
+                    "Failed to look up invoice of unpaid bet: {e:#}"
+                );
+                continue;
+            }
+        };
+
+        if InvoiceState::from_i32(invoice.state) != Some(InvoiceState::Canceled) {
+            continue;
+        }
+
+        if delete_unpaid_bet(db, &bet.payment_hash).await? {
+            deleted += 1;
+        }
+    }
+
+    if deleted > 0 {
+        tracing::info!(deleted, "Deleted unpaid bets whose invoice expired");
+    }
+
+    Ok(())
+}
+
+/// The `bets` whose invoice expired by `now`, the time since the UNIX epoch.
+fn expired_unpaid_bets(bets: Vec<UnpaidBet>, now: Duration) -> Vec<UnpaidBet> {
+    bets.into_iter()
+        .filter(|bet| bet.invoice.would_expire(now))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::get_zap;
+    use crate::db::test_db;
+    use crate::db::test_zap;
+    use crate::db::upsert_zap;
+    use crate::db::BetState;
+    use crate::multiplier::test_multiplier_note;
+    use crate::multiplier::Multipliers;
+    use nostr::EventId;
+
+    #[tokio::test]
+    async fn only_unpaid_bets_with_an_expired_invoice_are_deleted() {
+        let db = test_db().await;
+        let multipliers = Multipliers(vec![test_multiplier_note("note1multiplier")]);
+
+        let mut payment_hashes = Vec::new();
+        for bet_state in [BetState::GameZapInvoiceRequested, BetState::ZapPaid] {
+            let zap = test_zap("note1multiplier", EventId::all_zeros(), bet_state);
+            let payment_hash = zap.invoice.payment_hash().to_string();
+            upsert_zap(&db, payment_hash.clone(), zap, &multipliers)
+                .await
+                .unwrap();
+            payment_hashes.push(payment_hash);
+        }
+        let [unpaid, paid] = payment_hashes.try_into().unwrap();
+
+        let unpaid_bets = get_unpaid_bets(&db).await.unwrap();
+        assert_eq!(
+            unpaid_bets
+                .iter()
+                .map(|bet| bet.payment_hash.clone())
+                .collect::<Vec<_>>(),
+            vec![unpaid.clone()]
+        );
+
+        // The invoices of test bets expire after an hour.
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!(expired_unpaid_bets(unpaid_bets.clone(), now).is_empty());
+        assert_eq!(
+            expired_unpaid_bets(unpaid_bets, now + Duration::from_secs(2 * 60 * 60)).len(),
+            1
+        );
+
+        assert!(!delete_unpaid_bet(&db, &paid).await.unwrap());
+        assert!(delete_unpaid_bet(&db, &unpaid).await.unwrap());
+        assert!(get_zap(&db, unpaid).await.unwrap().is_none());
+        assert!(get_zap(&db, paid).await.unwrap().is_some());
+    }
+}
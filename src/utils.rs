@@ -1,9 +1,94 @@
+use anyhow::bail;
 use anyhow::Context;
 use nostr::event;
 use nostr::Event;
 use nostr::EventId;
+use nostr::Kind;
+use nostr::PublicKey;
 use nostr::UncheckedUrl;
 
+/// Validate a zap request to `recipient` for `amount_msats` as described in NIP-57.
+pub fn validate_zap_request(
+    zap_request: &Event,
+    recipient: &PublicKey,
+    amount_msats: u64,
+) -> anyhow::Result<()> {
+    if zap_request.kind() != Kind::ZapRequest {
+        bail!("Invalid Nostr event: not a zap request");
+    }
+
+    zap_request
+        .verify()
+        .context("Invalid zap request: bad signature")?;
+
+    let tags = zap_request.tags();
+
+    let public_keys = tags
+        .iter()
+        .filter_map(|tag| match tag.as_standardized() {
+            Some(event::TagStandard::PublicKey {
+                public_key,
+                uppercase: false,
+                ..
+            }) => Some(public_key),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    match public_keys.as_slice() {
+        [public_key] if *public_key == recipient => {}
+        [_] => bail!("Invalid zap request: zapping someone else"),
+        _ => bail!("Invalid zap request: must have exactly one p tag"),
+    }
+
+    let event_ids = tags
+        .iter()
+        .filter(|tag| {
+            matches!(
+                tag.as_standardized(),
+                Some(event::TagStandard::Event { .. })
+            )
+        })
+        .count();
+    if event_ids > 1 {
+        bail!("Invalid zap request: must not have more than one e tag");
+    }
+
+    // The amount tag is optional, but must not disagree with the amount we are asked to invoice.
+    for tag in tags.iter() {
+        if let Some(event::TagStandard::Amount { millisats, .. }) = tag.as_standardized() {
+            if *millisats != amount_msats {
+                bail!(
+                    "Invalid zap request: amount tag ({millisats} msat) does not match the \
+                     amount ({amount_msats} msat)"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The account the `zap_request` is for, if it names exactly one.
+pub fn get_zapped_public_key(zap_request: &Event) -> Option<PublicKey> {
+    let public_keys = zap_request
+        .tags()
+        .iter()
+        .filter_map(|tag| match tag.as_standardized() {
+            Some(event::TagStandard::PublicKey {
+                public_key,
+                uppercase: false,
+                ..
+            }) => Some(*public_key),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    match public_keys.as_slice() {
+        [public_key] => Some(*public_key),
+        _ => None,
+    }
+}
+
 pub fn get_zapped_note_id(zap_request: &Event) -> anyhow::Result<EventId> {
     let tags = zap_request.tags();
     let tags = tags
@@ -38,3 +123,124 @@ pub fn get_relays(zap_request: &Event) -> anyhow::Result<Vec<String>> {
 
     Ok(relays)
 }
+
+/// The `relays` which are on the `allow_list`, or all of them if the list is empty.
+///
+/// Relay URLs are compared ignoring trailing slashes.
+pub fn allowed_relays(relays: Vec<String>, allow_list: &[String]) -> Vec<String> {
+    if allow_list.is_empty() {
+        return relays;
+    }
+
+    relays
+        .into_iter()
+        .filter(|relay| {
+            allow_list
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/') == relay.trim_end_matches('/'))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::EventBuilder;
+    use nostr::Keys;
+    use nostr::Tag;
+    use nostr::TagStandard;
+
+    fn zap_request(tags: Vec<Tag>) -> Event {
+        EventBuilder::new(Kind::ZapRequest, "Let's go!", tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    fn amount(millisats: u64) -> Tag {
+        Tag::from_standardized(TagStandard::Amount {
+            millisats,
+            bolt11: None,
+        })
+    }
+
+    #[test]
+    fn valid_zap_requests_are_accepted() {
+        let recipient = Keys::generate().public_key();
+
+        let with_amount = zap_request(vec![
+            Tag::public_key(recipient),
+            Tag::event(EventId::all_zeros()),
+            amount(21_000),
+        ]);
+        validate_zap_request(&with_amount, &recipient, 21_000).unwrap();
+
+        let without_amount = zap_request(vec![
+            Tag::public_key(recipient),
+            Tag::event(EventId::all_zeros()),
+        ]);
+        validate_zap_request(&without_amount, &recipient, 21_000).unwrap();
+    }
+
+    #[test]
+    fn invalid_zap_requests_are_rejected() {
+        let recipient = Keys::generate().public_key();
+        let note = Tag::event(EventId::all_zeros());
+
+        for tags in [
+            vec![note.clone()],
+            vec![Tag::public_key(Keys::generate().public_key()), note.clone()],
+            vec![
+                Tag::public_key(recipient),
+                Tag::public_key(Keys::generate().public_key()),
+                note.clone(),
+            ],
+            vec![
+                Tag::public_key(recipient),
+                note.clone(),
+                Tag::event(EventId::from_slice(&[1; 32]).unwrap()),
+            ],
+            vec![Tag::public_key(recipient), note.clone(), amount(1_000)],
+        ] {
+            assert!(validate_zap_request(&zap_request(tags), &recipient, 21_000).is_err());
+        }
+
+        let not_a_zap_request = EventBuilder::text_note("Let's go!", [Tag::public_key(recipient)])
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert!(validate_zap_request(&not_a_zap_request, &recipient, 21_000).is_err());
+    }
+
+    fn relays(urls: &[&str]) -> Vec<String> {
+        urls.iter().map(|url| url.to_string()).collect()
+    }
+
+    #[test]
+    fn empty_allow_list_allows_every_relay() {
+        let requested = relays(&["wss://relay.damus.io", "wss://nos.lol"]);
+
+        assert_eq!(allowed_relays(requested.clone(), &[]), requested);
+    }
+
+    #[test]
+    fn only_allow_listed_relays_are_kept() {
+        let requested = relays(&[
+            "wss://relay.damus.io/",
+            "wss://nos.lol",
+            "wss://relay.example.com",
+        ]);
+        let allow_list = relays(&["wss://relay.damus.io", "wss://nos.lol/"]);
+
+        assert_eq!(
+            allowed_relays(requested, &allow_list),
+            relays(&["wss://relay.damus.io/", "wss://nos.lol"])
+        );
+    }
+
+    #[test]
+    fn no_requested_relay_is_allowed() {
+        let requested = relays(&["wss://relay.example.com"]);
+        let allow_list = relays(&["wss://relay.damus.io"]);
+
+        assert!(allowed_relays(requested, &allow_list).is_empty());
+    }
+}
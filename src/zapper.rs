@@ -1,52 +1,422 @@
+use crate::keysend::KeysendTarget;
+use anyhow::anyhow;
+use lightning_invoice::Bolt11Invoice;
+use nostr::bitcoin::hashes::sha256;
+use nostr::bitcoin::hashes::Hash;
 use nostr_sdk::zapper::async_trait;
 use nostr_sdk::NostrZapper;
 use nostr_sdk::ZapperBackend;
 use nostr_sdk::ZapperError;
+use std::cell::RefCell;
 use std::fmt::Display;
 use std::fmt::Formatter;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::task::JoinSet;
+use tonic_openssl_lnd::lnrpc::payment::PaymentStatus;
+use tonic_openssl_lnd::lnrpc::PaymentFailureReason;
 use tonic_openssl_lnd::routerrpc::SendPaymentRequest;
+use tonic_openssl_lnd::routerrpc::TrackPaymentRequest;
+use tonic_openssl_lnd::tonic::Code;
 use tonic_openssl_lnd::LndRouterClient;
 
+/// The TLV record carrying the preimage of a keysend payment.
+const KEYSEND_PREIMAGE_RECORD: u64 = 5482373484;
+
+/// How long to wait before retrying a failed payment, multiplied by the attempts made so far.
+const PAYMENT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How many payments the zapper makes at once.
+const MAX_CONCURRENT_PAYMENTS: usize = 10;
+
+tokio::task_local! {
+    /// The last payment the [`LndZapper`] made within a [`track_payment`].
+    static TRACKED_PAYMENT: RefCell<Option<TrackedPayment>>;
+}
+
+/// A payment made by the [`LndZapper`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackedPayment {
+    pub payment_hash: String,
+    /// The routing fee we paid, if the payment succeeded.
+    pub fee_msat: Option<u64>,
+}
+
+/// Run `f`, returning the payment the [`LndZapper`] made meanwhile, if any.
+///
+/// `Client::zap` fetches the invoice and calls the zapper itself, so this is how we find out which
+/// payment zapped a payout and what it cost us.
+pub async fn track_payment<F: Future>(f: F) -> (F::Output, Option<TrackedPayment>) {
+    TRACKED_PAYMENT
+        .scope(RefCell::new(None), async move {
+            let output = f.await;
+            let payment = TRACKED_PAYMENT.with(|payment| payment.take());
+
+            (output, payment)
+        })
+        .await
+}
+
+fn record_paid_payment_hash(payment_hash: String) {
+    // Outside of `track_payment` nobody is interested.
+    let _ = TRACKED_PAYMENT.try_with(|tracked| {
+        tracked.replace(Some(TrackedPayment {
+            payment_hash,
+            fee_msat: None,
+        }))
+    });
+}
+
+fn record_payment_fee(fee_msat: u64) {
+    let _ = TRACKED_PAYMENT.try_with(|tracked| {
+        if let Some(payment) = tracked.borrow_mut().as_mut() {
+            payment.fee_msat = Some(fee_msat);
+        }
+    });
+}
+
+/// How much we are willing to pay in routing fees for a payment, and how long we try to make it.
+#[derive(Debug, Clone, Copy)]
+pub struct PaymentLimits {
+    pub min_fee_sat: u64,
+    /// The fee budget of a payment, in parts per million of its amount.
+    pub fee_ppm: u64,
+    pub timeout_seconds: i32,
+}
+
+impl PaymentLimits {
+    /// The routing fee budget of a payment of `amount_sat`: `max(min_fee, amount * fee_ppm / 1M)`.
+    pub fn fee_limit_sat(&self, amount_sat: u64) -> u64 {
+        let proportional = (amount_sat as u128 * self.fee_ppm as u128 / 1_000_000) as u64;
+
+        self.min_fee_sat.max(proportional)
+    }
+}
+
+/// How a payment which failed for want of a route is retried with a higher fee limit.
+#[derive(Debug, Clone, Copy)]
+pub struct PaymentRetries {
+    /// Attempts to make a payment, including the first one.
+    pub max_attempts: u32,
+    /// The fee limit is doubled on every retry, but not beyond this.
+    pub max_fee_sat: u64,
+}
+
+impl PaymentRetries {
+    /// The fee limit to retry a payment with, which failed on attempt number `attempt` with a fee
+    /// limit of `fee_limit_sat`. [`None`] if the payment should not be retried.
+    fn retry_fee_limit_sat(
+        &self,
+        attempt: u32,
+        fee_limit_sat: u64,
+        reason: PaymentFailureReason,
+    ) -> Option<u64> {
+        let fee_related = matches!(
+            reason,
+            PaymentFailureReason::FailureReasonNoRoute
+                | PaymentFailureReason::FailureReasonInsufficientBalance
+        );
+        if !fee_related || attempt >= self.max_attempts || fee_limit_sat >= self.max_fee_sat {
+            return None;
+        }
+
+        Some(fee_limit_sat.saturating_mul(2).max(1).min(self.max_fee_sat))
+    }
+}
+
+#[derive(Debug)]
+pub enum Payment {
+    PayInvoice(PayInvoice),
+    PayKeysend(PayKeysend),
+    LookUp(LookUpPayment),
+}
+
 #[derive(Debug)]
 pub struct PayInvoice {
     pub payment_request: String,
-    pub sender: oneshot::Sender<Result<(), String>>,
+    pub fee_limit_sat: u64,
+    pub timeout_seconds: i32,
+    /// Receives the routing fee we paid, in msat.
+    pub sender: oneshot::Sender<Result<u64, String>>,
+}
+
+/// A spontaneous payment, for winners we cannot zap.
+#[derive(Debug)]
+pub struct PayKeysend {
+    pub target: KeysendTarget,
+    pub amount_sat: u64,
+    pub preimage: [u8; 32],
+    pub fee_limit_sat: u64,
+    pub timeout_seconds: i32,
+    /// Receives the routing fee we paid, in msat.
+    pub sender: oneshot::Sender<Result<u64, String>>,
 }
 
-pub fn start_zapper(lnd: LndRouterClient) -> mpsc::Sender<PayInvoice> {
-    let (sender, mut receiver) = mpsc::channel::<PayInvoice>(100);
+/// Look up what became of a payment we made earlier.
+#[derive(Debug)]
+pub struct LookUpPayment {
+    pub payment_hash: String,
+    pub sender: oneshot::Sender<Result<PaymentOutcome, String>>,
+}
 
-    tokio::spawn({
-        let mut lnd = lnd.clone();
-        async move {
-            while let Some(pay_invoice) = receiver.recv().await {
-                tracing::debug!("Zap payment request: {}", pay_invoice.payment_request);
+/// What became of a payment, as far as LND knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentOutcome {
+    Succeeded {
+        fee_msat: u64,
+    },
+    /// The payment may still succeed, so it must not be made again yet.
+    InFlight,
+    /// The payment failed, or LND never made it, so it can be made again.
+    Failed,
+}
 
-                let payment_request = SendPaymentRequest {
-                    payment_request: pay_invoice.payment_request.clone(),
-                    timeout_seconds: 60,
-                    fee_limit_sat: 100,
-                    ..Default::default()
-                };
+/// Start the task paying zap invoices and keysend payouts.
+///
+/// Up to [`MAX_CONCURRENT_PAYMENTS`] payments are made at once, each in its own task, so that a
+/// payment stuck in flight does not hold up the others.
+///
+/// The zapper keeps paying until it is told to `stop`, so that payouts requested while shutting
+/// down are still made. It then finishes the payments it is currently making, including their
+/// retries, before stopping.
+pub fn start_zapper(
+    lnd: LndRouterClient,
+    retries: PaymentRetries,
+    mut stop: oneshot::Receiver<()>,
+) -> (mpsc::Sender<Payment>, JoinHandle<()>) {
+    let (sender, mut receiver) = mpsc::channel::<Payment>(100);
 
-                let res = lnd
-                    .send_payment_v2(payment_request)
-                    .await
-                    .map(|_| ())
-                    .map_err(|e| e.to_string());
+    let handle = tokio::spawn(async move {
+        let limit = Arc::new(Semaphore::new(MAX_CONCURRENT_PAYMENTS));
+        let mut payments = JoinSet::new();
 
-                if pay_invoice.sender.send(res).is_err() {
+        loop {
+            let payment = tokio::select! {
+                payment = receiver.recv() => match payment {
+                    Some(payment) => payment,
+                    None => break,
+                },
+                Some(result) = payments.join_next(), if !payments.is_empty() => {
+                    if let Err(e) = result {
+                        tracing::error!("Payment task failed: {e:#}");
+                    }
+                    continue;
+                },
+                _ = &mut stop => {
+                    tracing::warn!("Shutting down zapper...");
+                    break;
+                },
+            };
+
+            let mut lnd = lnd.clone();
+            let limit = limit.clone();
+            payments.spawn(async move {
+                let (payment_request, sender) = match payment {
+                    Payment::LookUp(look_up) => {
+                        let res = look_up_payment(&mut lnd, &look_up.payment_hash).await;
+                        if look_up.sender.send(res).is_err() {
+                            tracing::error!("Receiver dropped");
+                        }
+                        return;
+                    }
+                    payment => payment_request(payment),
+                };
+                let res =
+                    send_payment_with_retries(&mut lnd, payment_request, retries, &limit).await;
+
+                if sender.send(res).is_err() {
                     tracing::error!("Receiver dropped");
                 }
-            }
+            });
+        }
 
-            tracing::warn!("Stopping zapper!");
+        while let Some(result) = payments.join_next().await {
+            if let Err(e) = result {
+                tracing::error!("Payment task failed: {e:#}");
+            }
         }
+
+        tracing::warn!("Stopping zapper!");
     });
 
-    sender
+    (sender, handle)
+}
+
+/// What to ask LND for to make the `payment`, and where to send its result.
+fn payment_request(payment: Payment) -> (SendPaymentRequest, oneshot::Sender<Result<u64, String>>) {
+    match payment {
+        Payment::PayInvoice(pay_invoice) => {
+            tracing::debug!("Zap payment request: {}", pay_invoice.payment_request);
+
+            let payment_request = SendPaymentRequest {
+                payment_request: pay_invoice.payment_request,
+                timeout_seconds: pay_invoice.timeout_seconds,
+                fee_limit_sat: pay_invoice.fee_limit_sat as i64,
+                ..Default::default()
+            };
+
+            (payment_request, pay_invoice.sender)
+        }
+        Payment::PayKeysend(pay_keysend) => {
+            tracing::debug!(
+                "Keysend payment of {} sats to {}",
+                pay_keysend.amount_sat,
+                pay_keysend.target.pubkey
+            );
+
+            (keysend_request(&pay_keysend), pay_keysend.sender)
+        }
+        Payment::LookUp(_) => unreachable!("Lookups are not payments"),
+    }
+}
+
+/// Look up what became of the payment with the `payment_hash`, without waiting for it to complete.
+async fn look_up_payment(
+    lnd: &mut LndRouterClient,
+    payment_hash: &str,
+) -> Result<PaymentOutcome, String> {
+    let payment_hash =
+        hex::decode(payment_hash).map_err(|e| format!("Invalid payment hash: {e}"))?;
+
+    let mut updates = match lnd
+        .track_payment_v2(TrackPaymentRequest {
+            payment_hash,
+            no_inflight_updates: false,
+        })
+        .await
+    {
+        Ok(updates) => updates.into_inner(),
+        Err(status) if status.code() == Code::NotFound => return Ok(PaymentOutcome::Failed),
+        Err(status) => return Err(status.to_string()),
+    };
+
+    // The first update is the current state of the payment.
+    let payment = updates
+        .message()
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| "Payment updates ended before the first one".to_string())?;
+
+    let outcome = match PaymentStatus::from_i32(payment.status) {
+        Some(PaymentStatus::Succeeded) => PaymentOutcome::Succeeded {
+            fee_msat: payment.fee_msat.max(0) as u64,
+        },
+        Some(PaymentStatus::Failed) => PaymentOutcome::Failed,
+        _ => PaymentOutcome::InFlight,
+    };
+
+    Ok(outcome)
+}
+
+/// Make a payment, retrying it with a higher fee limit while it fails for want of a route. Returns
+/// the routing fee we paid, in msat.
+///
+/// Every attempt waits for a permit of the `limit`, which is given back while waiting to retry, so
+/// that payments backing off do not hold up the others.
+async fn send_payment_with_retries(
+    lnd: &mut LndRouterClient,
+    mut payment_request: SendPaymentRequest,
+    retries: PaymentRetries,
+    limit: &Semaphore,
+) -> Result<u64, String> {
+    let mut attempt = 1;
+    loop {
+        let permit = limit.acquire().await.expect("semaphore is never closed");
+        let result = send_payment(lnd, payment_request.clone()).await;
+        drop(permit);
+
+        let reason = match result {
+            Ok(fee_msat) => return Ok(fee_msat),
+            Err(PaymentFailure::Failed(reason)) => reason,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let fee_limit_sat = payment_request.fee_limit_sat as u64;
+        let Some(retry_fee_limit_sat) = retries.retry_fee_limit_sat(attempt, fee_limit_sat, reason)
+        else {
+            return Err(PaymentFailure::Failed(reason).to_string());
+        };
+
+        tracing::warn!(
+            ?reason,
+            "Payment attempt {attempt} failed with a fee limit of {fee_limit_sat} sats, \
+             retrying with {retry_fee_limit_sat} sats"
+        );
+
+        tokio::time::sleep(PAYMENT_RETRY_BACKOFF * attempt).await;
+
+        payment_request.fee_limit_sat = retry_fee_limit_sat as i64;
+        attempt += 1;
+    }
+}
+
+enum PaymentFailure {
+    /// LND gave up on the payment.
+    Failed(PaymentFailureReason),
+    /// We could not find out what happened to the payment.
+    Other(String),
+}
+
+impl Display for PaymentFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentFailure::Failed(reason) => write!(f, "payment failed: {reason:?}"),
+            PaymentFailure::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Make a payment, waiting for it to succeed or fail. Returns the routing fee we paid, in msat.
+async fn send_payment(
+    lnd: &mut LndRouterClient,
+    payment_request: SendPaymentRequest,
+) -> Result<u64, PaymentFailure> {
+    let mut updates = lnd
+        .send_payment_v2(payment_request)
+        .await
+        .map_err(|e| PaymentFailure::Other(e.to_string()))?
+        .into_inner();
+
+    while let Some(payment) = updates
+        .message()
+        .await
+        .map_err(|e| PaymentFailure::Other(e.to_string()))?
+    {
+        match PaymentStatus::from_i32(payment.status) {
+            Some(PaymentStatus::Succeeded) => return Ok(payment.fee_msat.max(0) as u64),
+            Some(PaymentStatus::Failed) => {
+                return Err(PaymentFailure::Failed(payment.failure_reason()))
+            }
+            _ => {}
+        }
+    }
+
+    Err(PaymentFailure::Other(
+        "Payment updates ended before the payment completed".to_string(),
+    ))
+}
+
+fn keysend_request(pay_keysend: &PayKeysend) -> SendPaymentRequest {
+    let mut dest_custom_records = pay_keysend.target.custom_records.clone();
+    dest_custom_records.insert(KEYSEND_PREIMAGE_RECORD, pay_keysend.preimage.to_vec());
+
+    SendPaymentRequest {
+        dest: pay_keysend.target.pubkey.serialize().to_vec(),
+        amt: pay_keysend.amount_sat as i64,
+        payment_hash: sha256::Hash::hash(&pay_keysend.preimage)
+            .to_byte_array()
+            .to_vec(),
+        dest_custom_records,
+        timeout_seconds: pay_keysend.timeout_seconds,
+        fee_limit_sat: pay_keysend.fee_limit_sat as i64,
+        ..Default::default()
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -62,7 +432,72 @@ impl std::error::Error for LndPaymentError {}
 
 #[derive(Clone, Debug)]
 pub struct LndZapper {
-    pub sender: mpsc::Sender<PayInvoice>,
+    pub sender: mpsc::Sender<Payment>,
+    pub limits: PaymentLimits,
+    /// Only log the payments we would make, e.g. to try out a configuration on staging.
+    pub dry_run: bool,
+}
+
+impl LndZapper {
+    /// Pay `amount_sat` to the `target` without an invoice.
+    ///
+    /// Like zap invoices, keysend payments made within a [`track_payment`] are tracked.
+    pub async fn keysend(&self, target: KeysendTarget, amount_sat: u64) -> anyhow::Result<()> {
+        if self.dry_run {
+            tracing::info!(
+                pubkey = %target.pubkey,
+                "Dry run: not making keysend payment of {amount_sat} sats"
+            );
+
+            return Ok(());
+        }
+
+        let preimage = rand::random::<[u8; 32]>();
+        record_paid_payment_hash(sha256::Hash::hash(&preimage).to_string());
+
+        let (sender, receiver) = oneshot::channel();
+
+        self.sender
+            .send(Payment::PayKeysend(PayKeysend {
+                target,
+                amount_sat,
+                preimage,
+                fee_limit_sat: self.limits.fee_limit_sat(amount_sat),
+                timeout_seconds: self.limits.timeout_seconds,
+                sender,
+            }))
+            .await
+            .map_err(|_| anyhow!("Zapper stopped"))?;
+
+        let fee_msat = receiver
+            .await
+            .unwrap_or(Err("Did not receive a response".to_string()))
+            .map_err(LndPaymentError)?;
+        record_payment_fee(fee_msat);
+
+        Ok(())
+    }
+
+    /// Look up what became of the payment with the `payment_hash`, e.g. a payout whose outcome we
+    /// did not learn because LND became unreachable while making it.
+    pub async fn payment_outcome(&self, payment_hash: &str) -> anyhow::Result<PaymentOutcome> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.sender
+            .send(Payment::LookUp(LookUpPayment {
+                payment_hash: payment_hash.to_string(),
+                sender,
+            }))
+            .await
+            .map_err(|_| anyhow!("Zapper stopped"))?;
+
+        let outcome = receiver
+            .await
+            .unwrap_or(Err("Did not receive a response".to_string()))
+            .map_err(LndPaymentError)?;
+
+        Ok(outcome)
+    }
 }
 
 #[async_trait]
@@ -74,19 +509,216 @@ impl NostrZapper for LndZapper {
     }
 
     async fn pay(&self, invoice: String) -> nostr::Result<(), Self::Err> {
+        if self.dry_run {
+            tracing::info!("Dry run: not paying zap invoice {invoice}");
+
+            return Ok(());
+        }
+
+        let amount_sat = match Bolt11Invoice::from_str(&invoice) {
+            Ok(bolt11) => {
+                record_paid_payment_hash(bolt11.payment_hash().to_string());
+
+                bolt11.amount_milli_satoshis().unwrap_or_default() / 1_000
+            }
+            Err(e) => {
+                tracing::warn!("Paying zap invoice we cannot parse: {e}");
+
+                0
+            }
+        };
+
         let (sender, receiver) = oneshot::channel();
 
         self.sender
-            .send(PayInvoice {
+            .send(Payment::PayInvoice(PayInvoice {
                 payment_request: invoice,
+                fee_limit_sat: self.limits.fee_limit_sat(amount_sat),
+                timeout_seconds: self.limits.timeout_seconds,
                 sender,
-            })
+            }))
             .await
             .map_err(ZapperError::backend)?;
 
-        receiver
+        let fee_msat = receiver
             .await
             .unwrap_or(Err("Did not receive a response".to_string()))
-            .map_err(|e| ZapperError::Backend(Box::new(LndPaymentError(e))))
+            .map_err(|e| ZapperError::Backend(Box::new(LndPaymentError(e))))?;
+        record_payment_fee(fee_msat);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tracks_the_payment_hash_of_the_paid_invoice() {
+        let ((), payment) = track_payment(async {
+            record_paid_payment_hash("first".to_string());
+            record_payment_fee(1_000);
+            record_paid_payment_hash("second".to_string());
+        })
+        .await;
+
+        assert_eq!(
+            payment,
+            Some(TrackedPayment {
+                payment_hash: "second".to_string(),
+                fee_msat: None,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn tracks_the_fee_of_a_successful_payment() {
+        let ((), payment) = track_payment(async {
+            record_paid_payment_hash("paid".to_string());
+            record_payment_fee(1_234);
+        })
+        .await;
+
+        assert_eq!(payment.and_then(|payment| payment.fee_msat), Some(1_234));
+    }
+
+    #[test]
+    fn fee_limit_is_proportional_above_the_minimum() {
+        let limits = PaymentLimits {
+            min_fee_sat: 10,
+            fee_ppm: 5_000,
+            timeout_seconds: 60,
+        };
+
+        assert_eq!(limits.fee_limit_sat(0), 10);
+        assert_eq!(limits.fee_limit_sat(100), 10);
+        assert_eq!(limits.fee_limit_sat(2_000), 10);
+        assert_eq!(limits.fee_limit_sat(2_001), 10);
+        assert_eq!(limits.fee_limit_sat(10_000), 50);
+        assert_eq!(limits.fee_limit_sat(1_000_000), 5_000);
+        assert_eq!(limits.fee_limit_sat(100_000_000), 500_000);
+        assert_eq!(limits.fee_limit_sat(u64::MAX), u64::MAX / 200);
+    }
+
+    #[test]
+    fn payments_without_a_route_are_retried_with_escalating_fee_limits() {
+        let retries = PaymentRetries {
+            max_attempts: 4,
+            max_fee_sat: 100,
+        };
+        let no_route = PaymentFailureReason::FailureReasonNoRoute;
+
+        assert_eq!(retries.retry_fee_limit_sat(1, 10, no_route), Some(20));
+        assert_eq!(retries.retry_fee_limit_sat(2, 20, no_route), Some(40));
+        assert_eq!(retries.retry_fee_limit_sat(3, 40, no_route), Some(80));
+        assert_eq!(retries.retry_fee_limit_sat(4, 80, no_route), None);
+
+        // The fee limit is capped.
+        assert_eq!(retries.retry_fee_limit_sat(1, 60, no_route), Some(100));
+        assert_eq!(retries.retry_fee_limit_sat(2, 100, no_route), None);
+
+        assert_eq!(
+            retries.retry_fee_limit_sat(
+                1,
+                10,
+                PaymentFailureReason::FailureReasonInsufficientBalance
+            ),
+            Some(20)
+        );
+    }
+
+    #[test]
+    fn payments_failing_for_other_reasons_are_not_retried() {
+        let retries = PaymentRetries {
+            max_attempts: 4,
+            max_fee_sat: 100,
+        };
+
+        for reason in [
+            PaymentFailureReason::FailureReasonTimeout,
+            PaymentFailureReason::FailureReasonError,
+            PaymentFailureReason::FailureReasonIncorrectPaymentDetails,
+        ] {
+            assert_eq!(retries.retry_fee_limit_sat(1, 10, reason), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_runs_do_not_pay() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let zapper = LndZapper {
+            sender,
+            limits: PaymentLimits {
+                min_fee_sat: 10,
+                fee_ppm: 5_000,
+                timeout_seconds: 60,
+            },
+            dry_run: true,
+        };
+
+        let (result, payment) = track_payment(zapper.pay("lnbc1invoice".to_string())).await;
+        assert!(result.is_ok());
+        assert_eq!(payment, None);
+
+        let target = KeysendTarget {
+            pubkey: "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619"
+                .parse()
+                .unwrap(),
+            custom_records: Default::default(),
+        };
+        let (result, payment) = track_payment(zapper.keysend(target, 1_000)).await;
+        assert!(result.is_ok());
+        assert_eq!(payment, None);
+
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn keysend_payments_carry_their_preimage_and_the_target_records() {
+        let preimage = [7; 32];
+        let target = KeysendTarget {
+            pubkey: "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619"
+                .parse()
+                .unwrap(),
+            custom_records: [(696969, b"017rsl75kNnSke4mMHYE".to_vec())].into(),
+        };
+        let (sender, _receiver) = oneshot::channel();
+
+        let request = keysend_request(&PayKeysend {
+            target: target.clone(),
+            amount_sat: 2_100,
+            preimage,
+            fee_limit_sat: 10,
+            timeout_seconds: 60,
+            sender,
+        });
+
+        assert_eq!(request.dest, target.pubkey.serialize().to_vec());
+        assert_eq!(request.amt, 2_100);
+        assert_eq!(
+            request.payment_hash,
+            sha256::Hash::hash(&preimage).to_byte_array().to_vec()
+        );
+        assert_eq!(
+            request.dest_custom_records,
+            [
+                (696969, b"017rsl75kNnSke4mMHYE".to_vec()),
+                (KEYSEND_PREIMAGE_RECORD, preimage.to_vec()),
+            ]
+            .into()
+        );
+        assert_eq!(request.fee_limit_sat, 10);
+        assert_eq!(request.timeout_seconds, 60);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_tracked_without_a_payment() {
+        let ((), payment) = track_payment(async {}).await;
+        assert_eq!(payment, None);
+
+        // Payments outside of `track_payment` are fine too.
+        record_paid_payment_hash("untracked".to_string());
+        record_payment_fee(1_000);
     }
 }